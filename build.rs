@@ -2,20 +2,24 @@ use std::process::Command;
 use std::io::prelude::*;
 use std::fs::*;
 
+/// Hash that is used whenever git is not available or the source is not a repository
+const UNKNOWN_HASH: &str = "unknown";
+
 fn main() {
-    // Generate the git hash
-    let mut hash = if cfg!( any(unix) ) {
-        Command::new("/usr/bin/git").arg("rev-parse").arg("--short").arg("HEAD").output().unwrap_or_else(|e| {
-            panic!("failed to execute process: {}", e)
-        }).stdout
-    } else { panic!("You shall not pas...ehm..compile on a non-unix OS!") };
-    hash.pop();
+    // Generate the git hash using whatever git binary is in the PATH
+    let hash = match Command::new("git").arg("rev-parse").arg("--short").arg("HEAD").output() {
+        Ok(ref output) if output.status.success() => {
+            // Trim the line ending which is \r\n on windows
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        },
+        _ => UNKNOWN_HASH.to_string()
+    };
 
     // Write the constant to a file that is compiled into the project
     let mut f = File::create("src/git_hash.rs").unwrap();
     f.write_all("//! A dynamically generated file containing the current hash of the repository\n".to_string().as_bytes()).unwrap();
     f.write_all("/// The current hash of the project\n".to_string().as_bytes()).unwrap();
     f.write_all("pub const GIT_HASH: &'static str = \"".to_string().as_bytes()).unwrap();
-    f.write_all(hash.as_slice()).unwrap();
+    f.write_all(hash.as_bytes()).unwrap();
     f.write_all("\";".to_string().as_bytes()).unwrap();
 }
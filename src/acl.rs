@@ -0,0 +1,128 @@
+//! Access control lists restricting which hosts may query and download shared files
+use std::fmt;
+use std::str::FromStr;
+use std::net::IpAddr;
+
+/// Range of IP addresses in CIDR notation, a plain address is a range containing only that address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    /// Amount of leading bits of `addr` that have to match
+    pub prefix: u8
+}
+
+impl Cidr {
+    /// Whether the address lies within this range
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    // Shifting by the full width would overflow
+    if prefix == 0 { return true }
+    let shift = bits - prefix;
+    net >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| format!("Invalid address in '{}'", s))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => match prefix.parse() {
+                Ok(prefix) if prefix <= bits => prefix,
+                _ => return Err(format!("Invalid prefix length in '{}'", s))
+            },
+            None => bits
+        };
+        Ok(Cidr { addr: addr, prefix: prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Rules deciding which hosts are served, the default list permits everybody
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::acl::Acl;
+/// # fn main() {
+/// let acl = Acl::new()
+///     .allow("10.0.0.0/8".parse().unwrap())
+///     .deny("10.0.13.37".parse().unwrap())
+///     .token("secret".to_string());
+///
+/// let token = Some("secret".to_string());
+/// assert!(acl.permits(&"10.1.2.3".parse().unwrap(), token.as_ref()));
+/// // Denied ranges take precedence over allowed ones
+/// assert!(!acl.permits(&"10.0.13.37".parse().unwrap(), token.as_ref()));
+/// // Hosts have to be allowed explicitly once there is an allow list
+/// assert!(!acl.permits(&"192.168.0.1".parse().unwrap(), token.as_ref()));
+/// assert!(!acl.permits(&"10.1.2.3".parse().unwrap(), None));
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    /// Ranges that are served, everybody is served if it is empty
+    pub allow: Vec<Cidr>,
+    /// Ranges that are never served
+    pub deny: Vec<Cidr>,
+    /// Pre-shared token that has to be presented by other nodes
+    pub token: Option<String>
+}
+
+impl Default for Acl {
+    fn default() -> Acl {
+        Acl::new()
+    }
+}
+
+impl Acl {
+    /// Creates a list that permits everybody
+    pub fn new() -> Acl {
+        Acl {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            token: None
+        }
+    }
+
+    /// Serve a range of addresses, all other addresses are refused once a range is allowed
+    pub fn allow(mut self, range: Cidr) -> Acl {
+        self.allow.push(range);
+        self
+    }
+
+    /// Refuse a range of addresses even if it is allowed
+    pub fn deny(mut self, range: Cidr) -> Acl {
+        self.deny.push(range);
+        self
+    }
+
+    /// Require other nodes to present the given token
+    pub fn token(mut self, token: String) -> Acl {
+        self.token = Some(token);
+        self
+    }
+
+    /// Whether a host that presented `token` may be served
+    pub fn permits(&self, ip: &IpAddr, token: Option<&String>) -> bool {
+        if self.token.is_some() && self.token.as_ref() != token { return false }
+        if self.deny.iter().any(|range| range.contains(ip)) { return false }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
@@ -1,78 +1,551 @@
-use std::thread::spawn;
-use std::sync::{Arc, Mutex};
-use std::net::{TcpStream, TcpListener};
-use std::io::{Read, Write};
+use std::thread::{spawn, sleep, JoinHandle};
+use std::net::{TcpStream, TcpListener, SocketAddr, IpAddr, Shutdown};
+use std::io::Write;
+use std::sync::{mpsc, Arc};
+use std::cmp::min;
+use std::time::Duration;
+use std::ops::Range;
 
-use bincode::serde::*;
-use bincode::SizeLimit;
+use bincode::{serialize, deserialize};
 
-use file::File;
-use networking::{UDPSocket, BASE_PORT};
-use helpers::to_hex_string;
+use file::FileMetadata;
+use networking::{Overflow, MAX_DATAGRAM_PAYLOAD, read_frame, write_frame};
+use helpers::{to_hex_string, calculate_block_size};
+use peers::NodeId;
+use gossip::Availability;
+use bitfield::BlockSet;
+use stream::{StreamQuery, StreamResponse};
+use distribute::DistributionReport;
+use chunks::{MetadataChunk, MetadataAck, send_chunked};
+use relay::Relayed;
+use pex::{PeerExchange, exchange_sources};
+use workers::{WorkerPool, JobPriority};
+use node::Node;
+use config::Mode;
+use throttle::{RateLimiter, LocalSubnets, ConnectionLimiter, SourceFilter, is_plausible_source};
 
-pub fn announce(files: Arc<Mutex<Vec<File>>>) {
+/// Interval in seconds at which a node announces the files it shares
+const ANNOUNCE_INTERVAL: u64 = 10;
+/// Maximum amount of files listed in a single announcement to keep it within one datagram
+const ANNOUNCEMENT_FILES: usize = 32;
+/// Time in seconds after which pushing metadata to an unresponsive node is given up
+const PUSH_TIMEOUT: u64 = 5;
+/// Maximum amount of block requests of a single connection that are read ahead of the one being answered
+const MAX_QUEUED_REQUESTS: usize = 32;
+/// Maximum amount of received datagrams waiting to be handled by the announce listener, the oldest ones are dropped
+const MAX_QUEUED_DATAGRAMS: usize = 256;
+/// Amount of workers sending responses in addition to those pushing metadata
+const REPLY_WORKERS: usize = 2;
+/// Maximum amount of responses waiting to be sent by a worker, further ones are dropped
+const MAX_QUEUED_REPLIES: usize = 256;
+
+/// Datagram sent to the announce listener of a node
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    Query(Query),
+    Announcement(Announcement),
+    Availability(Availability),
+    StreamQuery(StreamQuery),
+    Distribution(DistributionReport),
+    MetadataChunk(MetadataChunk),
+    MetadataAck(MetadataAck),
+    Relayed(Relayed)
+}
+
+/// Query for the block list or metadata of a file, sent via multicast or directly to a known peer
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Query {
+    /// Hash of the requested file
+    pub hash: Vec<u8>,
+    /// Whether the metadata is requested instead of the block list
+    pub details: bool,
+    /// Only blocks (or block hashes) starting at this ID are requested
+    pub from_block: usize,
+    /// Pre-shared token required by the access control list of the queried node
+    pub token: Option<String>,
+    /// Whether the metadata is sent back in UDP chunks instead of being pushed via TCP
+    pub udp: bool
+}
+
+/// What a node takes part in, sent along with handshakes and announcements
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    /// Whether the node answers queries and serves blocks
+    pub serves: bool,
+    /// Whether the node downloads files
+    pub fetches: bool
+}
+
+impl Capabilities {
+    pub fn of(mode: Mode) -> Capabilities {
+        Capabilities {
+            serves: mode.serves(),
+            fetches: mode.fetches()
+        }
+    }
+}
+
+/// First frame sent on a block connection, introducing the requesting node
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Handshake {
+    /// ID of the requesting node
+    pub node_id: NodeId,
+    /// Pre-shared token required by the access control list of the serving node
+    pub token: Option<String>,
+    pub capabilities: Capabilities
+}
+
+/// Request for a block sent on a block connection after the handshake
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockRequest {
+    /// Hash of the file
+    pub hash: Vec<u8>,
+    pub block: usize,
+    /// Bytes within the block that are requested, e.g. the rest of a block that has been received partially. The
+    /// whole block is requested if it is missing.
+    pub range: Option<Range<usize>>
+}
+
+/// Request sent on a block connection after the handshake, the responses are sent in the order the requests arrived
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ConnectionRequest {
+    /// Answered with the requested bytes or an empty frame if they are not available
+    Block(BlockRequest),
+    /// Ask for the other sources of the file with the given hash this node knows, answered with a `PeerExchange`
+    Peers(Vec<u8>)
+}
+
+/// Periodic advertisement of the files a node shares, sent via multicast
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Announcement {
+    /// ID of the announcing node
+    pub node_id: NodeId,
+    /// Port on which the announcing node accepts queries and block requests
+    pub port: u16,
+    pub capabilities: Capabilities,
+    /// Files shared by the announcing node
+    pub files: Vec<AnnouncedFile>
+}
+
+/// Summary of a shared file contained in an announcement
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnnouncedFile {
+    pub hash: Vec<u8>,
+    pub name: String,
+    pub size: usize
+}
+
+/// Response to a block list query
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockListResponse {
+    /// Hash of the file the blocks belong to
+    pub hash: Vec<u8>,
+    /// ID of the responding node
+    pub node_id: NodeId,
+    /// Port on which the responding node accepts queries and block requests
+    pub port: u16,
+    /// Block ID the IDs in `blocks` are relative to
+    pub first_block: usize,
+    /// IDs of the blocks available at the responding node relative to `first_block`
+    pub blocks: BlockSet,
+    /// Index of this fragment and the amount of fragments the response has been split into to fit into datagrams
+    pub fragment: (u16, u16),
+    /// Set on the last fragment if the response is partial, contains the `from_block` filter to query the remaining
+    /// blocks with
+    pub more: Option<usize>
+}
+
+impl BlockListResponse {
+    /// Split the available `blocks` into as few responses as possible that each fit into a datagram, `port` is the
+    /// stable port of the responding node
+    pub fn fragment(hash: &[u8], node_id: &NodeId, port: u16, blocks: &[usize], more: Option<usize>) -> Vec<Vec<u8>> {
+        let mut fragments = 1;
+        loop {
+            let chunk_size = blocks.len().div_ceil(fragments);
+            let chunks = blocks.chunks(chunk_size.max(1)).collect::<Vec<_>>();
+            let encoded = chunks.iter().enumerate().map(|(index, chunk)| {
+                let first_block = chunk[0];
+                let relative = chunk.iter().map(|id| id - first_block).collect::<Vec<_>>();
+                serialize(&BlockListResponse {
+                    hash: hash.to_vec(),
+                    node_id: node_id.clone(),
+                    port: port,
+                    first_block: first_block,
+                    blocks: BlockSet::encode(&relative),
+                    fragment: (index as u16, chunks.len() as u16),
+                    more: if index + 1 == chunks.len() { more } else { None }
+                }).unwrap()
+            }).collect::<Vec<_>>();
+            // Single blocks always fit so splitting further terminates
+            if chunk_size <= 1 || encoded.iter().all(|fragment| fragment.len() <= MAX_DATAGRAM_PAYLOAD) { return encoded }
+            fragments *= 2;
+        }
+    }
+}
+
+/// Response to a metadata query
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetadataResponse {
+    /// ID of the responding node
+    pub node_id: NodeId,
+    /// Port on which the responding node accepts queries and block requests
+    pub port: u16,
+    /// Metadata of the requested file, only containing the block hashes starting at `first_block`
+    pub metadata: FileMetadata,
+    /// ID of the first block hash contained in the metadata
+    pub first_block: usize,
+    /// Set if the response is partial, contains the `from_block` filter to query the remaining hashes with
+    pub more: Option<usize>
+}
+
+pub fn announce(node: Node) {
     {
-        let files = files.clone();
+        let node = node.clone();
         spawn(move || {
-            let sock = UDPSocket::new().create_listener();
+            // Nodes with a port offset share the multicast port with the node without one and receive the datagrams
+            // sent directly to them on a port of their own
+            let (offset, serves) = { let config = node.config(); (config.port_offset, config.mode.serves()) };
+            let datagrams = node.socket().create_listener(offset > 0).start_receiver(MAX_QUEUED_DATAGRAMS, Overflow::DropOldest);
+            if offset > 0 { datagrams.attach(node.socket().create_port_listener(offset)); }
+            let (rate, burst) = { let config = node.config(); (config.query_rate, config.query_burst) };
+            let mut limiter = RateLimiter::new(rate, burst);
+            let mut subnets = LocalSubnets::new();
+            let max_pushes = node.config().max_metadata_pushes;
+            let pushes = ConnectionLimiter::new(max_pushes);
+            // Pushes never occupy more workers than there are permits, the remaining ones are left for the replies
+            let replies = WorkerPool::new(max_pushes + REPLY_WORKERS, MAX_QUEUED_REPLIES);
+            // All replies are sent from the same socket
+            let sender = match node.socket().create_sender() {
+                Ok(sender) => Arc::new(sender),
+                Err(e) => { fail!(Bind, "UDP: {}", e) }
+            };
             debug!("Announce thread started.");
             loop {
-                let (mut data, src) = sock.receive();
-                let file_details_requested = data.pop();
+                let datagram = match datagrams.recv() { Some(datagram) => datagram, None => break };
+                let mut src = datagram.src;
+                // Responses to spoofed sources would be sent to uninvolved hosts
+                if !is_plausible_source(&src) { continue; }
+                if node.config().source_filter == SourceFilter::LocalSubnet && !subnets.contains(&src.ip()) {
+                    debug!("Ignoring datagram from {} outside of the local subnets", src);
+                    continue;
+                }
+                let message = match deserialize(&datagram) {
+                    Ok(Message::Relayed(relayed)) => {
+                        // Anybody else could claim any origin, e.g. to have responses sent to uninvolved hosts
+                        let trusted = match src.ip() {
+                            IpAddr::V4(addr) => node.config().trusted_relays.contains(&addr),
+                            IpAddr::V6(_) => false
+                        };
+                        if !trusted || !is_plausible_source(&relayed.origin) {
+                            debug!("Ignoring datagram relayed by untrusted host {}", src);
+                            continue;
+                        }
+                        src = relayed.origin;
+                        deserialize(&relayed.datagram)
+                    },
+                    message => message
+                };
+                let query = match message {
+                    Ok(Message::Query(query)) => query,
+                    Ok(Message::Announcement(announcement)) => {
+                        // Own announcements are looped back by the multicast group, nodes that do not serve can not be
+                        // fetched from and anonymous nodes do not browse
+                        if announcement.node_id != node.id && announcement.capabilities.serves && !node.config().anonymous {
+                            let addr = SocketAddr::new(src.ip(), announcement.port);
+                            let mut discovery = node.discovery.lock().unwrap();
+                            for file in announcement.files {
+                                discovery.record_seeder(&file.hash, file.name, file.size, announcement.node_id.clone(), addr);
+                            }
+                        }
+                        continue;
+                    },
+                    Ok(Message::Availability(availability)) => {
+                        if node.config().gossips() && availability.node_id != node.id && limiter.allow(src.ip()) {
+                            node.peers.lock().unwrap().update(availability.node_id.clone(), SocketAddr::new(src.ip(), availability.port));
+                            node.availability.lock().unwrap().apply(availability);
+                        }
+                        continue;
+                    },
+                    Ok(Message::StreamQuery(query)) => {
+                        if !serves || !limiter.allow(src.ip()) || !node.config().acl.permits(&src.ip(), query.token.as_ref()) { continue; }
+                        let response = node.streams.lock().unwrap().iter().find(|s| s.id == query.id)
+                            .map(|stream| StreamResponse { port: node.port(), ..stream.response(&node.id, query.from_segment) });
+                        if let Some(response) = response {
+                            let sender = sender.clone();
+                            replies.submit(JobPriority::High, src.ip(), move || {
+                                if let Err(e) = sender.send(&serialize(&response).unwrap(), src) {
+                                    warn!("Failed to send the stream response to {}: {}", src, e);
+                                }
+                            });
+                        }
+                        continue;
+                    },
+                    Ok(Message::Distribution(report)) => {
+                        if limiter.allow(src.ip()) && report.node_id != node.id { node.distributions.lock().unwrap().apply(report); }
+                        continue;
+                    },
+                    // Chunked transfers use sockets of their own
+                    Ok(Message::MetadataChunk(_)) | Ok(Message::MetadataAck(_)) => continue,
+                    // Relayed datagrams are never relayed again
+                    Ok(Message::Relayed(_)) => continue,
+                    Err(_) => { warn!("Received malformed query from {}", src); continue; }
+                };
 
-                let mut files = files.lock().unwrap();
+                // Nodes that do not serve stay silent, as if they did not have any file
+                if !serves { continue; }
+                if !limiter.allow(src.ip()) {
+                    debug!("Ignoring query from {} exceeding the rate limit", src);
+                    continue;
+                }
+                node.discovery.lock().unwrap().record_query(&query.hash);
+                if !node.config().may_reveal(&query.hash) { continue; }
+                // Hosts that are not permitted are ignored as if this node did not exist
+                if !node.config().acl.permits(&src.ip(), query.token.as_ref()) { continue; }
 
-                debug!("Received request for file {:?}", to_hex_string(&data));
+                debug!("Received request for file {:?}", to_hex_string(&query.hash));
 
-                let matching_files = files.iter_mut().filter(|f| f.metadata.hash.0 == data);
-                if matching_files.size_hint().1 > Some(1) { exit!(1, "Got more than one matching file stored with the same UUID!"); }
+                // Paused files are neither advertised nor served
+                let shared = match node.files.get(&query.hash) {
+                    Some(shared) => shared,
+                    None => continue
+                };
 
-                for file in matching_files {
-                    if file_details_requested == Some(1) {
-                        // Attempt to send metadata and fail silently (fail = somebody else sent it earlier)
-                        match TcpStream::connect(src) {
-                            Ok(mut stream) => {
-                                let metadata = serialize(&file.metadata, SizeLimit::Infinite).unwrap();
-                                stream.write(&metadata).unwrap();
+                if query.details {
+                    let permit = match pushes.acquire() {
+                        Some(permit) => permit,
+                        None => { debug!("Dropping metadata push to {}, too many pushes in flight", src); continue; }
+                    };
+                    let max_hashes = node.config().max_response_hashes;
+                    // Only the requested window of block hashes is copied, so the file is locked as briefly as possible
+                    let response = {
+                        let file = shared.read().unwrap();
+                        if file.paused || !file.acl.as_ref().is_none_or(|acl| acl.permits(&src.ip(), query.token.as_ref())) { continue; }
+                        let hashes = &file.metadata.hash.1;
+                        let first_block = min(query.from_block, hashes.len());
+                        let end = min(first_block + max_hashes, hashes.len());
+                        MetadataResponse {
+                            node_id: node.id.clone(),
+                            port: node.port(),
+                            metadata: FileMetadata {
+                                algorithm: file.metadata.algorithm,
+                                name: file.metadata.name.clone(),
+                                hash: (file.metadata.hash.0.clone(), hashes[first_block..end].to_vec()),
+                                size: file.metadata.size,
+                                trailing_bytes: file.metadata.trailing_bytes.clone(),
+                                encryption: file.metadata.encryption.clone()
                             },
-                            Err(_) => {}
+                            first_block: first_block,
+                            more: if end < hashes.len() { Some(end) } else { None }
                         }
-                    } else if file_details_requested == Some(0) {
-                        // Send available blocks
-                        // Sort by connected clients
-                        file.blocks.sort_by(|a, b| a.1.cmp(&b.1));
-                        // Remove the client list and serialize
-                        let block_list = file.blocks.iter().map(|i| i.0).collect::<Vec<_>>();
+                    };
+                    let udp = query.udp;
+                    // Pushed by a worker so unresponsive targets can not stall the listener
+                    let queued = replies.submit(JobPriority::Low, src.ip(), move || {
+                        let _permit = permit;
+                        let response = serialize(&response).unwrap();
+                        if udp {
+                            if !send_chunked(&response, src) { debug!("{} stopped acknowledging the metadata", src); }
+                            return;
+                        }
+                        // Attempt to send metadata and fail silently (fail = somebody else sent it earlier)
+                        if let Ok(mut stream) = TcpStream::connect_timeout(&src, Duration::from_secs(PUSH_TIMEOUT)) {
+                            let _ = stream.set_write_timeout(Some(Duration::from_secs(PUSH_TIMEOUT)));
+                            let _ = stream.write_all(&response);
+                        }
+                    });
+                    if !queued { debug!("Dropping metadata push to {}, too many responses queued", src); }
+                } else {
+                    let (available, count) = {
+                        let file = shared.read().unwrap();
+                        if file.paused || !file.acl.as_ref().is_none_or(|acl| acl.permits(&src.ip(), query.token.as_ref())) { continue; }
+                        (file.blocks.iter().map(|b| b.0).collect::<Vec<_>>(), file.metadata.hash.1.len())
+                    };
+                    let node = node.clone();
+                    let sender = sender.clone();
+                    let queued = replies.submit(JobPriority::High, src.ip(), move || {
+                        // Send available blocks within the requested window
+                        // Remove the client list, sets of blocks carry no order so downloaders rank the sources themselves
+                        let mut block_list = node.advertised_blocks(&query.hash, available, count);
+                        block_list.retain(|id| *id >= query.from_block);
+                        block_list.sort();
+                        let max_blocks = node.config().max_response_blocks;
+                        let more = block_list.get(max_blocks).cloned();
+                        block_list.truncate(max_blocks);
                         // Do not send the list if its empty
-                        if block_list.len() > 0 {
-                            // Send the block list
-                            let block_list = serialize(&block_list, SizeLimit::Infinite).unwrap();
-                            UDPSocket::new().create_handle().send(&block_list, src);
+                        if !block_list.is_empty() {
+                            // Send the block list along with the stable address of this node
+                            for fragment in BlockListResponse::fragment(&query.hash, &node.id, node.port(), &block_list, more) {
+                                if let Err(e) = sender.send(&fragment, src) {
+                                    warn!("Failed to send the block list to {}: {}", src, e);
+                                    break;
+                                }
+                            }
                         }
-                    }
+                    });
+                    if !queued { debug!("Dropping block list for {}, too many responses queued", src); }
                 }
             }
         });
     }
 
+    // Nodes that do not serve do not accept block connections at all
+    if !node.config().mode.serves() { return; }
     spawn(move || {
-        let socket = TcpListener::bind(("0.0.0.0", BASE_PORT)).unwrap();
+        let port = node.port();
+        let socket = match TcpListener::bind(("0.0.0.0", port)) {
+            Ok(socket) => socket,
+            Err(e) => { fail!(Bind, "TCP port {}: {}", port, e) }
+        };
         for stream in socket.incoming() {
-            let files = files.clone();
-            spawn(move || {
-                let mut stream = stream.unwrap();
-                let mut buffer = Vec::new();
-                stream.read_to_end(&mut buffer).unwrap();
-
-                let (hash, block): (Vec<u8>, usize) = deserialize(&buffer).unwrap();
-                let files = files.lock().unwrap();
-                match files.iter().find(|file| file.metadata.hash.0 == hash) {
-                    Some(file) => {
-                        stream.write_all(&file.get_block(block)).unwrap();
-                    },
-                    None => { warn!("Block request for non-existent file"); }
-                }
-            });
+            let stream = match stream { Ok(s) => s, Err(_) => continue };
+            let node = node.clone();
+            spawn(move || serve_blocks(stream, node));
+        }
+    });
+}
+
+/// Answer the block requests of a connection in the order they arrive until the connection is closed
+/// Requests are read ahead into a bounded queue so pipelined requests are answered back to back
+fn serve_blocks(mut stream: TcpStream, node: Node) {
+    let ip = match stream.peer_addr() { Ok(addr) => addr.ip(), Err(_) => return };
+    let handshake = match read_frame(&mut stream).ok().and_then(|frame| deserialize::<Handshake>(&frame).ok()) {
+        Some(handshake) => handshake,
+        None => { warn!("Received malformed handshake from {}", ip); return }
+    };
+    let token = handshake.token;
+    let requester = handshake.node_id;
+    if !node.config().acl.permits(&ip, token.as_ref()) {
+        debug!("Refused block connection from {}", ip);
+        return;
+    }
+    debug!("Serving blocks to {} at {}{}", to_hex_string(&requester), ip,
+        if handshake.capabilities.serves { "" } else { ", which does not serve blocks itself" });
+
+    let mut reader = match stream.try_clone() { Ok(r) => r, Err(_) => return };
+    let (tx, rx) = mpsc::sync_channel(MAX_QUEUED_REQUESTS);
+    spawn(move || {
+        while let Ok(request) = read_frame(&mut reader) {
+            if tx.send(request).is_err() { break }
         }
     });
+
+    for request in rx.iter() {
+        let response = match deserialize::<ConnectionRequest>(&request) {
+            Ok(ConnectionRequest::Block(request)) => {
+                let block = read_block(&node, &request, &ip, token.as_ref());
+                if let Some(ref block) = block { node.upload.throttle(block.len()); }
+                block
+            },
+            Ok(ConnectionRequest::Peers(hash)) => Some(Arc::new(serialize(&exchange_peers(&node, &hash, &requester)).unwrap())),
+            Err(_) => { warn!("Received malformed block request"); break }
+        };
+        // An empty frame tells the client that the block is not available here
+        if write_frame(&mut stream, response.as_ref().map_or(&[][..], |response| &response[..])).is_err() { break }
+    }
+    // Unblock the reader in case the connection is closed because of an error
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Sources of a file known to this node that are passed on to `requester`, none if the file may not be revealed
+fn exchange_peers(node: &Node, hash: &Vec<u8>, requester: &NodeId) -> PeerExchange {
+    // Anonymous nodes do not take part in the peer exchange
+    {
+        let config = node.config();
+        if config.anonymous || !config.may_reveal(hash) { return PeerExchange { peers: Vec::new() } }
+    }
+    let addr = |id: &NodeId| node.peers.lock().unwrap().get(id).map(|peer| peer.addr);
+    let mut exchange = node.source_book.exchange(hash, requester, addr);
+    if exchange.peers.is_empty() {
+        // Nodes that only serve the file know its other sources from their gossip
+        let blocks = node.files.get(hash).map(|shared| shared.read().unwrap().metadata.hash.1.len());
+        let sources = blocks.and_then(|blocks| node.availability.lock().unwrap().sources(hash, blocks));
+        if let Some(sources) = sources { exchange = exchange_sources(&sources, requester, addr); }
+    }
+    trace!("Passing on {} sources of {} to {}", exchange.peers.len(), to_hex_string(hash), to_hex_string(requester));
+    exchange
+}
+
+/// Read a block or the requested range of it of a shared file that may be revealed to the requesting host and is not
+/// paused, recently served blocks are taken from memory
+fn read_block(node: &Node, request: &BlockRequest, ip: &IpAddr, token: Option<&String>) -> Option<Arc<Vec<u8>>> {
+    let (hash, block_id) = (&request.hash, request.block);
+    if !node.config().may_reveal(hash) { return None }
+    let shared = match node.files.get(hash) {
+        Some(shared) => shared,
+        None => { warn!("Block request for non-existent file"); return None }
+    };
+    {
+        let file = shared.read().unwrap();
+        let permitted = file.acl.as_ref().is_none_or(|acl| acl.permits(ip, token));
+        if file.paused || !permitted || block_id >= file.metadata.hash.1.len() || !file.has_block(block_id) {
+            warn!("Block request for non-existent file or block");
+            return None;
+        }
+        if let Some(ref range) = request.range {
+            if range.start >= range.end || range.end > calculate_block_size(file.metadata.size) {
+                warn!("Request for bytes {:?} outside of block {} of {}", range, block_id, file.metadata.name);
+                return None;
+            }
+        }
+    }
+
+    if let Some(ref range) = request.range {
+        // Ranges are only read from disk as far as they go, a block that is in memory anyway is cut to the range
+        let data = match node.hot_blocks.get(hash, block_id) {
+            Some(block) => block[range.clone()].to_vec(),
+            None => {
+                let file = shared.read().unwrap();
+                match file.try_get_block_range(block_id, range.clone()) {
+                    Ok(data) => data,
+                    Err(e) => { warn!("Failed to read block {} of {}: {}", block_id, file.metadata.name, e); return None }
+                }
+            }
+        };
+        shared.write().unwrap().uploaded += data.len();
+        return Some(Arc::new(data));
+    }
+
+    let data = node.hot_blocks.get_or_load(hash, block_id, || {
+        // Reads of other blocks of the same file proceed concurrently since they only need a read lock
+        let file = shared.read().unwrap();
+        match file.try_get_block(block_id) {
+            Ok(data) => Some(data),
+            // The block may still be stored in another local file if the copy of this one has been moved or deleted
+            Err(e) => node.blocks.lock().unwrap().read(&file.metadata.hash.1[block_id], file.metadata.algorithm).or_else(|| {
+                warn!("Failed to read block {} of {}: {}", block_id, file.metadata.name, e);
+                None
+            })
+        }
+    })?;
+    shared.write().unwrap().uploaded += data.len();
+    Some(data)
+}
+
+/// Periodically announce all shared files via multicast so other nodes learn what exists on the network
+pub fn start_announcer(node: Node) -> JoinHandle<()> {
+    spawn(move || {
+        let sock = node.socket().create_handle();
+        loop {
+            let files = node.files.list().iter().map(|file| {
+                let file = file.read().unwrap();
+                AnnouncedFile {
+                    hash: file.metadata.hash.0.clone(),
+                    name: file.metadata.name.clone(),
+                    size: file.metadata.size
+                }
+            }).collect::<Vec<_>>();
+
+            for chunk in files.chunks(ANNOUNCEMENT_FILES) {
+                let announcement = Message::Announcement(Announcement {
+                    node_id: node.id.clone(),
+                    port: node.port(),
+                    capabilities: node.capabilities(),
+                    files: chunk.to_vec()
+                });
+                if let Err(e) = sock.send_to_multicast(&serialize(&announcement).unwrap()) { warn!("Failed to announce the shared files: {}", e); }
+            }
+
+            sleep(Duration::from_secs(ANNOUNCE_INTERVAL));
+        }
+    })
 }
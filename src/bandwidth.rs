@@ -0,0 +1,220 @@
+//! Global upload and download rate limits that change with the time of day
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ext_time::now;
+
+/// Interval in seconds at which the scheduler checks whether other limits apply
+const SCHEDULE_INTERVAL: u64 = 10;
+const WEEKDAYS: [&'static str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Minute of the day, written as `HH:MM`
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct TimeOfDay(pub u16);
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TimeOfDay, String> {
+        let mut parts = s.splitn(2, ':');
+        let hours = parts.next().and_then(|h| h.parse::<u16>().ok());
+        let minutes = parts.next().and_then(|m| m.parse::<u16>().ok());
+        match (hours, minutes) {
+            (Some(h), Some(m)) if h < 24 && m < 60 => Ok(TimeOfDay(h * 60 + m)),
+            _ => Err(format!("Invalid time of day '{}', expected HH:MM", s))
+        }
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+/// Parse the abbreviated name of a weekday, returns the day counted from sunday
+pub fn parse_weekday(day: &str) -> Option<u8> {
+    WEEKDAYS.iter().position(|d| d.eq_ignore_ascii_case(day)).map(|day| day as u8)
+}
+
+/// Rate limits in bytes per second, `None` is unlimited
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub upload: Option<u64>,
+    pub download: Option<u64>
+}
+
+impl Limits {
+    pub fn unlimited() -> Limits {
+        Limits { upload: None, download: None }
+    }
+}
+
+/// Limits that apply during a time window
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// Start of the window
+    pub from: TimeOfDay,
+    /// End of the window, the window spans midnight if it is before the start
+    pub to: TimeOfDay,
+    /// Days counted from sunday on which the window starts, every day if it is empty
+    pub days: Vec<u8>,
+    pub limits: Limits
+}
+
+impl Profile {
+    /// Whether the profile applies at the given time
+    pub fn applies(&self, weekday: u8, time: TimeOfDay) -> bool {
+        if self.from <= self.to {
+            (self.days.is_empty() || self.days.contains(&weekday)) && time >= self.from && time < self.to
+        } else {
+            // The part after midnight belongs to the window that started on the previous day
+            let previous = (weekday + 6) % 7;
+            (time >= self.from && (self.days.is_empty() || self.days.contains(&weekday))) ||
+                (time < self.to && (self.days.is_empty() || self.days.contains(&previous)))
+        }
+    }
+}
+
+/// Limits by time window, the first profile that applies wins
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::bandwidth::{Schedule, Profile, Limits};
+/// # fn main() {
+/// let office = Limits { upload: Some(10000000), download: Some(10000000) };
+/// let mut schedule = Schedule::new();
+/// schedule.profiles.push(Profile { from: "08:00".parse().unwrap(), to: "18:00".parse().unwrap(), days: vec![1, 2, 3, 4, 5], limits: office });
+///
+/// // Office hours on a monday
+/// assert_eq!(schedule.limits_at(1, "09:30".parse().unwrap()), office);
+/// // Overnight and on weekends the default applies
+/// assert_eq!(schedule.limits_at(1, "23:00".parse().unwrap()), Limits::unlimited());
+/// assert_eq!(schedule.limits_at(0, "09:30".parse().unwrap()), Limits::unlimited());
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    /// Limits outside of all profiles
+    pub default: Limits,
+    pub profiles: Vec<Profile>
+}
+
+impl Default for Schedule {
+    fn default() -> Schedule {
+        Schedule::new()
+    }
+}
+
+impl Schedule {
+    /// Creates a schedule without any limits
+    pub fn new() -> Schedule {
+        Schedule {
+            default: Limits::unlimited(),
+            profiles: Vec::new()
+        }
+    }
+
+    /// Retrieve the limits at the given time of a day counted from sunday
+    pub fn limits_at(&self, weekday: u8, time: TimeOfDay) -> Limits {
+        self.profiles.iter().find(|profile| profile.applies(weekday, time)).map_or(self.default, |profile| profile.limits)
+    }
+
+    /// Retrieve the limits that apply right now in local time
+    pub fn current_limits(&self) -> Limits {
+        let now = now();
+        self.limits_at(now.tm_wday as u8, TimeOfDay((now.tm_hour * 60 + now.tm_min) as u16))
+    }
+}
+
+struct Bucket {
+    /// Bytes per second, `None` is unlimited
+    rate: Option<u64>,
+    /// Bytes that may be transferred right away, negative after a transfer larger than the bucket
+    tokens: f64,
+    last: Instant
+}
+
+/// Token bucket shared by all transfers in one direction, cloning it yields another handle to the same bucket
+#[derive(Clone)]
+pub struct Limiter {
+    bucket: Arc<Mutex<Bucket>>
+}
+
+impl Default for Limiter {
+    fn default() -> Limiter {
+        Limiter::new()
+    }
+}
+
+impl Limiter {
+    /// Creates a limiter without a limit
+    pub fn new() -> Limiter {
+        Limiter {
+            bucket: Arc::new(Mutex::new(Bucket { rate: None, tokens: 0.0, last: Instant::now() }))
+        }
+    }
+
+    /// Change the limit in bytes per second, `None` removes it
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = rate.map_or(0.0, |rate| bucket.tokens.min(rate as f64));
+        bucket.last = Instant::now();
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Block until `bytes` may be transferred without exceeding the limit
+    pub fn throttle(&self, bytes: usize) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let rate = match bucket.rate {
+                    Some(rate) if rate > 0 => rate as f64,
+                    _ => return
+                };
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.last);
+                bucket.tokens = (bucket.tokens + rate * (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9)).min(rate);
+                bucket.last = now;
+                // Transfers larger than the bucket go into debt which the following transfers have to wait for
+                if bucket.tokens >= 0.0 {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+                -bucket.tokens / rate
+            };
+            sleep(Duration::from_millis((wait * 1000.0).ceil() as u64));
+        }
+    }
+}
+
+/// Apply the limits of the schedule that apply at the current time to the limiters and keep them up to date
+/// The schedule may be replaced at any time, the new one is applied within seconds
+pub fn start_scheduler(schedule: Arc<Mutex<Schedule>>, upload: Limiter, download: Limiter) -> JoinHandle<()> {
+    spawn(move || {
+        let mut applied = None;
+        loop {
+            let limits = schedule.lock().unwrap().current_limits();
+            if applied != Some(limits) {
+                info!("Bandwidth limits: upload {}, download {}", describe(limits.upload), describe(limits.download));
+                upload.set_rate(limits.upload);
+                download.set_rate(limits.download);
+                applied = Some(limits);
+            }
+            sleep(Duration::from_secs(SCHEDULE_INTERVAL));
+        }
+    })
+}
+
+fn describe(rate: Option<u64>) -> String {
+    rate.map_or("unlimited".to_string(), |rate| format!("{} B/s", rate))
+}
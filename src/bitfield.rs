@@ -0,0 +1,118 @@
+//! Compact encodings of sets of block IDs for messages that have to fit into datagrams
+use std::cmp::min;
+
+/// One bit per block, the lowest bit of the first byte is the first block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bitfield {
+    len: usize,
+    bits: Vec<u8>
+}
+
+impl Bitfield {
+    /// Creates a bitfield of `len` blocks without any block set
+    pub fn new(len: usize) -> Bitfield {
+        Bitfield {
+            len: len,
+            bits: vec![0; len.div_ceil(8)]
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn set(&mut self, id: usize) {
+        if id < self.len { self.bits[id / 8] |= 1 << (id % 8); }
+    }
+
+    pub fn get(&self, id: usize) -> bool {
+        id < self.len && self.bits[id / 8] & (1 << (id % 8)) != 0
+    }
+
+    /// Retrieve the IDs of all set blocks in ascending order
+    pub fn ids(&self) -> Vec<usize> {
+        (0..self.len).filter(|id| self.get(*id)).collect()
+    }
+
+    /// Whether the encoding is consistent, e.g. after it has been received from another node
+    pub fn is_valid(&self) -> bool {
+        self.bits.len() == self.len.div_ceil(8)
+    }
+}
+
+/// Set of block IDs encoded either as a bitfield or as ranges, whichever is smaller
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::bitfield::BlockSet;
+/// # fn main() {
+/// // Contiguous blocks are encoded as ranges
+/// let complete = BlockSet::encode(&(0..10000).collect::<Vec<_>>());
+/// assert_eq!(complete, BlockSet::Ranges(vec![(0, 10000)]));
+///
+/// // Scattered blocks are encoded as a bitfield
+/// let scattered = (0..10000).filter(|id| id % 3 == 0).collect::<Vec<_>>();
+/// let encoded = BlockSet::encode(&scattered);
+/// assert!(match encoded { BlockSet::Bitfield(_) => true, _ => false });
+/// assert_eq!(encoded.ids(), scattered);
+/// # }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BlockSet {
+    Bitfield(Bitfield),
+    /// Ascending, non-overlapping ranges given as first block ID and amount of blocks
+    Ranges(Vec<(u32, u32)>)
+}
+
+impl BlockSet {
+    /// Encode a set of block IDs, the IDs have to be sorted in ascending order
+    pub fn encode(ids: &[usize]) -> BlockSet {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for id in ids.iter().map(|id| *id as u32) {
+            match ranges.last_mut() {
+                Some(range) if range.0 + range.1 == id => { range.1 += 1; continue },
+                Some(range) if range.0 + range.1 > id => continue,
+                _ => {}
+            }
+            ranges.push((id, 1));
+        }
+
+        // Both encodings carry a length prefix so only their contents are compared
+        let len = ids.last().map_or(0, |id| id + 1);
+        if ranges.len() * 8 <= len.div_ceil(8) {
+            BlockSet::Ranges(ranges)
+        } else {
+            let mut bitfield = Bitfield::new(len);
+            for id in ids { bitfield.set(*id); }
+            BlockSet::Bitfield(bitfield)
+        }
+    }
+
+    /// Encode the blocks that are set
+    pub fn from_bools(blocks: &[bool]) -> BlockSet {
+        BlockSet::encode(&(0..blocks.len()).filter(|id| blocks[*id]).collect::<Vec<_>>())
+    }
+
+    /// Decode the block IDs in ascending order, at most `limit` IDs are decoded to bound the work spent on a
+    /// malicious message
+    pub fn ids_within(&self, limit: usize) -> Vec<usize> {
+        match *self {
+            BlockSet::Bitfield(ref bitfield) if bitfield.is_valid() => bitfield.ids().into_iter().take_while(|id| *id < limit).collect(),
+            BlockSet::Bitfield(_) => Vec::new(),
+            BlockSet::Ranges(ref ranges) => ranges.iter()
+                .flat_map(|&(first, len)| first as usize..min(first as usize + len as usize, limit))
+                .collect()
+        }
+    }
+
+    /// Decode the block IDs in ascending order
+    pub fn ids(&self) -> Vec<usize> {
+        self.ids_within(usize::MAX)
+    }
+}
@@ -0,0 +1,312 @@
+//! Content-addressed index of the blocks stored in local files, so blocks shared by several files are only transferred
+//! once
+//!
+//! The index only points into files that are shared or have been downloaded, no block is stored twice. Every block is
+//! verified against its hash when it is read since the files may have changed in the meantime.
+//!
+//! Blocks that are served to other nodes are additionally kept in memory for a while, so a popular file is read from
+//! disk once instead of once per downloader.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Condvar};
+
+use bincode::{serialize, deserialize};
+
+use file::FileMetadata;
+use helpers::{HashAlgorithm, calculate_block_size, block_offset};
+
+/// Name of the file in the state directory the index is kept in
+const INDEX_FILE: &'static str = "blocks";
+/// Maximum amount of locations remembered per block, further copies are redundant
+const MAX_LOCATIONS: usize = 4;
+
+/// Place of a block in a local file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct BlockLocation {
+    /// Index of the file in the paths of the cache
+    path: usize,
+    offset: u64,
+    len: usize
+}
+
+/// Local files that contain a block by the hash of the block
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::cache::BlockCache;
+/// # use ddp::file::example_file;
+/// # use ddp::helpers::HashAlgorithm;
+/// # fn main() {
+/// let file = example_file("ddp-cache-example", 3000);
+///
+/// let mut cache = BlockCache::new();
+/// cache.insert(&file.metadata, &file.local_path);
+/// let block = &file.metadata.hash.1[0];
+/// assert_eq!(cache.read(block, HashAlgorithm::Sha256), Some(file.get_block(0)));
+///
+/// // Blocks that changed on disk are forgotten
+/// std::fs::write(&file.local_path, vec![8; 3000]).unwrap();
+/// assert_eq!(cache.read(block, HashAlgorithm::Sha256), None);
+/// assert!(!cache.contains(block));
+/// # }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BlockCache {
+    blocks: HashMap<Vec<u8>, Vec<BlockLocation>>,
+    /// Files containing blocks, referenced by the locations to keep the index small
+    paths: Vec<PathBuf>,
+    /// File the index is saved to, it is only kept in memory if this is missing
+    #[serde(skip)]
+    path: Option<PathBuf>
+}
+
+impl BlockCache {
+    /// Creates an index that is only kept in memory
+    pub fn new() -> BlockCache {
+        BlockCache::default()
+    }
+
+    /// Load the index kept in the state directory, an empty one is created if there is none yet
+    pub fn load(state_dir: &Path) -> io::Result<BlockCache> {
+        let path = state_dir.join(INDEX_FILE);
+        let mut cache = match fs::read(&path) {
+            Ok(data) => deserialize::<BlockCache>(&data)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{} is corrupted", path.display())))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => BlockCache::new(),
+            Err(e) => return Err(e)
+        };
+        cache.path = Some(path);
+        Ok(cache)
+    }
+
+    /// Save the index to the state directory it has been loaded from, does nothing if it is only kept in memory
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(())
+        };
+        if let Some(dir) = path.parent() { fs::create_dir_all(dir)?; }
+        // Write to a temporary file first so a crash never leaves a truncated index behind
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serialize(self).unwrap())?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Remember the blocks of a file whose local copy at `path` is stored as it is distributed
+    pub fn insert(&mut self, metadata: &FileMetadata, path: &Path) {
+        let path = self.path_index(path);
+        let len = calculate_block_size(metadata.size);
+        for (id, hash) in metadata.hash.1.iter().enumerate() {
+            self.insert_location(hash, BlockLocation { path: path, offset: block_offset(metadata.size, id), len: len });
+        }
+    }
+
+    /// Remember a single block of `len` bytes stored at `offset` in the file at `path`
+    pub fn insert_block(&mut self, hash: &[u8], path: &Path, offset: u64, len: usize) {
+        let path = self.path_index(path);
+        self.insert_location(hash, BlockLocation { path: path, offset: offset, len: len });
+    }
+
+    fn insert_location(&mut self, hash: &[u8], location: BlockLocation) {
+        let locations = self.blocks.entry(hash.to_vec()).or_default();
+        if !locations.contains(&location) && locations.len() < MAX_LOCATIONS { locations.push(location); }
+    }
+
+    fn path_index(&mut self, path: &Path) -> usize {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        match self.paths.iter().position(|p| *p == path) {
+            Some(index) => index,
+            None => { self.paths.push(path); self.paths.len() - 1 }
+        }
+    }
+
+    /// Whether any local file is known to contain the block
+    pub fn contains(&self, hash: &Vec<u8>) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Read a block from any of the files that contain it, locations whose content no longer matches are forgotten
+    pub fn read(&mut self, hash: &Vec<u8>, algorithm: HashAlgorithm) -> Option<Vec<u8>> {
+        let mut block = None;
+        let paths = &self.paths;
+        if let Some(locations) = self.blocks.get_mut(hash) {
+            locations.retain(|location| {
+                if block.is_some() { return true }
+                let path = match paths.get(location.path) { Some(path) => path, None => return false };
+                match read_location(path, location) {
+                    Ok(ref data) if &algorithm.digest(data) == hash => { block = Some(data.clone()); true },
+                    _ => { debug!("Forgetting stale copy of a block in {}", path.display()); false }
+                }
+            });
+            if locations.is_empty() { self.blocks.remove(hash); }
+        }
+        block
+    }
+
+    /// Amount of distinct blocks in the index
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Whether the index is empty
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+fn read_location(path: &Path, location: &BlockLocation) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(location.offset))?;
+    let mut data = vec![0; location.len];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Block of a file identified by the hash of the file and the ID of the block
+type BlockKey = (Vec<u8>, usize);
+
+/// Recently served blocks kept in memory, the least recently used ones are evicted once they exceed the capacity
+///
+/// Concurrent reads of the same block are coalesced, only the first one reads it from disk while the others wait for
+/// its result.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::cache::HotBlocks;
+/// # fn main() {
+/// let hot = HotBlocks::new(2048);
+/// let file = vec![1; 32];
+/// assert_eq!(*hot.get_or_load(&file, 0, || Some(vec![0; 1024])).unwrap(), vec![0; 1024]);
+///
+/// // The block is served from memory now
+/// assert!(hot.get_or_load(&file, 0, || panic!("read twice")).is_some());
+///
+/// // Reading two more blocks evicts the least recently used one
+/// hot.get_or_load(&file, 1, || Some(vec![1; 1024]));
+/// hot.get_or_load(&file, 2, || Some(vec![2; 1024]));
+/// assert_eq!(hot.len(), 2);
+/// assert!(hot.get_or_load(&file, 0, || None).is_none());
+///
+/// // A read that panics does not leave later readers of the block waiting
+/// let _ = std::panic::catch_unwind(|| hot.get_or_load(&file, 3, || panic!("failed read")));
+/// assert!(hot.get_or_load(&file, 3, || Some(vec![3; 1024])).is_some());
+/// # }
+/// ```
+pub struct HotBlocks {
+    /// Maximum amount of bytes kept in memory
+    capacity: usize,
+    state: Mutex<HotState>,
+    /// Notified whenever a block has been read from disk
+    loaded: Condvar
+}
+
+#[derive(Default)]
+struct HotState {
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+    /// Keys of the blocks by the time they were used last, the oldest one comes first
+    usage: BTreeMap<u64, BlockKey>,
+    /// Blocks that are currently read from disk
+    loading: HashSet<BlockKey>,
+    /// Sum of the sizes of all blocks in memory
+    size: usize,
+    /// Counter increased whenever a block is used
+    clock: u64
+}
+
+impl HotState {
+    fn touch(&mut self, key: &BlockKey) -> Option<Arc<Vec<u8>>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (data, used) = match self.blocks.get_mut(key) {
+            Some(&mut (ref data, ref mut used)) => (data.clone(), ::std::mem::replace(used, clock)),
+            None => return None
+        };
+        self.usage.remove(&used);
+        self.usage.insert(clock, key.clone());
+        Some(data)
+    }
+}
+
+/// Block that is being read from disk, marked as loaded again once dropped
+struct Loading<'a> {
+    hot: &'a HotBlocks,
+    key: BlockKey
+}
+
+impl<'a> Drop for Loading<'a> {
+    fn drop(&mut self) {
+        let mut state = self.hot.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.loading.remove(&self.key);
+        self.hot.loaded.notify_all();
+    }
+}
+
+impl HotBlocks {
+    /// Creates an empty cache keeping at most `capacity` bytes, a capacity of zero disables it
+    pub fn new(capacity: usize) -> HotBlocks {
+        HotBlocks {
+            capacity: capacity,
+            state: Mutex::new(HotState::default()),
+            loaded: Condvar::new()
+        }
+    }
+
+    /// Retrieve a block from memory or read it with `load`, waiting for a concurrent read of the same block instead of
+    /// reading it again
+    pub fn get_or_load<F: FnOnce() -> Option<Vec<u8>>>(&self, file: &[u8], block_id: usize, load: F) -> Option<Arc<Vec<u8>>> {
+        if self.capacity == 0 { return load().map(Arc::new) }
+        let key = (file.to_vec(), block_id);
+        {
+            let mut state = self.state.lock().unwrap();
+            loop {
+                if let Some(data) = state.touch(&key) { return Some(data) }
+                // A failed read is not shared, the next waiter tries again
+                if !state.loading.contains(&key) { break }
+                state = self.loaded.wait(state).unwrap();
+            }
+            state.loading.insert(key.clone());
+        }
+
+        // Wakes the waiters even if `load` panics, one of them reads the block instead
+        let loading = Loading { hot: self, key: key.clone() };
+        let data = load().map(Arc::new);
+        if let Some(ref data) = data {
+            let mut state = self.state.lock().unwrap();
+            if data.len() <= self.capacity {
+                state.clock += 1;
+                let clock = state.clock;
+                state.size += data.len();
+                state.blocks.insert(key.clone(), (data.clone(), clock));
+                state.usage.insert(clock, key);
+            }
+            while state.size > self.capacity {
+                let oldest = match state.usage.keys().next() { Some(used) => *used, None => break };
+                let key = state.usage.remove(&oldest).unwrap();
+                if let Some((evicted, _)) = state.blocks.remove(&key) { state.size -= evicted.len(); }
+            }
+        }
+        drop(loading);
+        data
+    }
+
+    /// Retrieve a block if it is kept in memory, without reading it otherwise
+    pub fn get(&self, file: &[u8], block_id: usize) -> Option<Arc<Vec<u8>>> {
+        self.state.lock().unwrap().touch(&(file.to_vec(), block_id))
+    }
+
+    /// Amount of blocks kept in memory
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().blocks.len()
+    }
+
+    /// Whether no block is kept in memory
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().blocks.is_empty()
+    }
+}
@@ -0,0 +1,200 @@
+//! Transfer of metadata in UDP chunks for nodes that can not accept the TCP connection it is usually pushed through,
+//! e.g. embedded targets behind a firewall that only lets UDP through
+//!
+//! The serving node splits the serialized response into chunks that carry a digest of their data and sends a window
+//! of them at a time. The receiver acknowledges how many chunks it has received without a gap, upon which the sender
+//! continues after them or retransmits the window if no acknowledgement arrives in time. Only the first chunk is sent
+//! until the receiver echoed the random ID of the transfer, so a query with a spoofed source can not turn the serving
+//! node into an amplifier.
+use std::collections::HashMap;
+use std::cmp::min;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bincode::{serialize, deserialize};
+use getrandom::getrandom;
+use sha2::{Sha256, Digest};
+
+use announce::Message;
+use networking::UDPSocket;
+
+/// Bytes of data per chunk, leaving room for the header so a chunk stays within `MAX_DATAGRAM_PAYLOAD`
+const CHUNK_SIZE: usize = 1024;
+/// Amount of chunks sent before waiting for an acknowledgement
+const WINDOW: u32 = 16;
+/// Time in milliseconds the sender waits for an acknowledgement before it retransmits the window
+const ACK_TIMEOUT: u64 = 300;
+/// Amount of retransmissions without progress after which the transfer is given up
+const MAX_RETRANSMISSIONS: usize = 8;
+/// Amount of retransmissions of the first chunk to a receiver that did not acknowledge anything yet
+const MAX_UNCONFIRMED_RETRANSMISSIONS: usize = 2;
+/// Upper bound for the amount of chunks of a transfer to avoid allocating arbitrary amounts of memory for garbage
+const MAX_CHUNKS: u32 = 16 * 1024;
+
+/// Part of a transfer, sent by the serving node
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetadataChunk {
+    /// Random ID of the transfer, echoed by the acknowledgements
+    pub transfer: u64,
+    pub index: u32,
+    /// Amount of chunks of the transfer
+    pub total: u32,
+    /// SHA-256 digest of `data`
+    pub digest: Vec<u8>,
+    pub data: Vec<u8>
+}
+
+impl MetadataChunk {
+    pub fn new(transfer: u64, index: u32, total: u32, data: Vec<u8>) -> MetadataChunk {
+        MetadataChunk {
+            transfer: transfer,
+            index: index,
+            total: total,
+            digest: Sha256::digest(&data).to_vec(),
+            data: data
+        }
+    }
+
+    /// Whether the data matches the digest
+    pub fn is_intact(&self) -> bool {
+        Sha256::digest(&self.data).as_slice() == &self.digest[..]
+    }
+}
+
+/// Acknowledgement of the receiver, sent back to the address the chunks originate from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MetadataAck {
+    pub transfer: u64,
+    /// Amount of chunks received without a gap
+    pub received: u32
+}
+
+/// Split `data` into the chunks of a transfer
+pub fn split(transfer: u64, data: &[u8]) -> Vec<MetadataChunk> {
+    if data.is_empty() { return vec![MetadataChunk::new(transfer, 0, 1, Vec::new())] }
+    let total = data.chunks(CHUNK_SIZE).count() as u32;
+    data.chunks(CHUNK_SIZE).enumerate().map(|(index, chunk)| MetadataChunk::new(transfer, index as u32, total, chunk.to_vec())).collect()
+}
+
+/// Send `data` to `target` in chunks, returns false if the target stopped acknowledging them
+pub fn send_chunked(data: &[u8], target: SocketAddr) -> bool {
+    let sock = match UDPSocket::new().create_sender() { Ok(sock) => sock, Err(_) => return false };
+    let mut id = [0; 8];
+    if getrandom(&mut id).is_err() { return false }
+    let transfer = u64::from_be_bytes(id);
+    let chunks = split(transfer, data).into_iter().map(|chunk| serialize(&Message::MetadataChunk(chunk)).unwrap()).collect::<Vec<_>>();
+    let total = chunks.len() as u32;
+
+    let mut acknowledged = 0;
+    let mut confirmed = false;
+    let mut retransmissions = 0;
+    while acknowledged < total {
+        let end = min(acknowledged + if confirmed { WINDOW } else { 1 }, total);
+        for chunk in &chunks[acknowledged as usize..end as usize] {
+            if sock.send(chunk, target).is_err() { return false }
+        }
+
+        // Wait until the whole window is acknowledged or the time is up
+        let deadline = Instant::now() + Duration::from_millis(ACK_TIMEOUT);
+        let mut received = acknowledged;
+        while received < end {
+            let now = Instant::now();
+            if now >= deadline { break }
+            let (datagram, src) = match sock.receive_timeout(deadline - now) { Some(datagram) => datagram, None => break };
+            if src != target { continue }
+            if let Ok(Message::MetadataAck(ack)) = deserialize(&datagram) {
+                if ack.transfer == transfer { received = received.max(min(ack.received, total)); confirmed = true; }
+            }
+        }
+
+        if received > acknowledged {
+            acknowledged = received;
+            retransmissions = 0;
+        } else {
+            retransmissions += 1;
+            let limit = if confirmed { MAX_RETRANSMISSIONS } else { MAX_UNCONFIRMED_RETRANSMISSIONS };
+            if retransmissions > limit { return false }
+        }
+    }
+    true
+}
+
+struct Transfer {
+    chunks: Vec<Option<Vec<u8>>>,
+    /// Amount of chunks received without a gap
+    received: u32,
+    done: bool
+}
+
+/// Reassembly of the transfers a node receives, keyed by their source and ID
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::chunks::{ChunkAssembler, split};
+/// # fn main() {
+/// let data = vec![7; 3000];
+/// let mut chunks = split(42, &data);
+/// assert_eq!(chunks.len(), 3);
+/// let src = "10.0.0.1:8888".parse().unwrap();
+/// let mut assembler = ChunkAssembler::new();
+///
+/// // Chunks after a gap are kept but only acknowledged once the gap is filled
+/// let (ack, complete) = assembler.add(chunks[2].clone(), src);
+/// assert_eq!((ack.unwrap().received, complete), (0, None));
+///
+/// // Corrupted chunks are dropped without an acknowledgement so they are sent again
+/// let mut corrupted = chunks[0].clone();
+/// corrupted.data[0] = 0;
+/// assert_eq!(assembler.add(corrupted, src), (None, None));
+///
+/// assembler.add(chunks.remove(0), src);
+/// let (ack, complete) = assembler.add(chunks.remove(0), src);
+/// assert_eq!(ack.unwrap().received, 3);
+/// assert_eq!(complete, Some(data));
+/// # }
+/// ```
+pub struct ChunkAssembler {
+    transfers: HashMap<(SocketAddr, u64), Transfer>
+}
+
+impl Default for ChunkAssembler {
+    fn default() -> ChunkAssembler {
+        ChunkAssembler::new()
+    }
+}
+
+impl ChunkAssembler {
+    pub fn new() -> ChunkAssembler {
+        ChunkAssembler {
+            transfers: HashMap::new()
+        }
+    }
+
+    /// Add a chunk received from `src`, returns the acknowledgement to send back and the data once it is complete.
+    /// Chunks of completed transfers are acknowledged again in case the last acknowledgement got lost.
+    pub fn add(&mut self, chunk: MetadataChunk, src: SocketAddr) -> (Option<MetadataAck>, Option<Vec<u8>>) {
+        if !chunk.is_intact() || chunk.total == 0 || chunk.total > MAX_CHUNKS || chunk.index >= chunk.total {
+            debug!("Dropping corrupted metadata chunk from {}", src);
+            return (None, None);
+        }
+        let (id, total) = (chunk.transfer, chunk.total);
+        let transfer = self.transfers.entry((src, id)).or_insert_with(|| Transfer {
+            chunks: vec![None; total as usize],
+            received: 0,
+            done: false
+        });
+        if transfer.chunks.len() != total as usize && !transfer.done { return (None, None) }
+        let ack = |received| Some(MetadataAck { transfer: id, received: received });
+        if transfer.done { return (ack(transfer.received), None) }
+
+        transfer.chunks[chunk.index as usize] = Some(chunk.data);
+        while transfer.chunks.get(transfer.received as usize).is_some_and(|chunk| chunk.is_some()) { transfer.received += 1; }
+        if transfer.received < total { return (ack(transfer.received), None) }
+
+        transfer.done = true;
+        let data = transfer.chunks.drain(..).flat_map(|chunk| chunk.unwrap()).collect();
+        (ack(transfer.received), Some(data))
+    }
+}
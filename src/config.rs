@@ -0,0 +1,699 @@
+//! Runtime configuration of a node
+use std::env;
+use std::net::Ipv4Addr;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use toml;
+use log::LogLevel;
+
+use helpers::{HashAlgorithm, from_hex_string};
+use transfer::SeedPolicy;
+use acl::Acl;
+use throttle::SourceFilter;
+use bandwidth::{Schedule, Profile, Limits, parse_weekday};
+use hooks::Hook;
+use discovery::DiscoveryWindow;
+use peers::BlockDeadline;
+use networking::{MulticastScope, check_port_offset};
+
+/// Default maximum amount of block IDs sent in a single block list response
+const DEFAULT_MAX_RESPONSE_BLOCKS: usize = 1024;
+/// Default maximum amount of block hashes sent in a single metadata response
+const DEFAULT_MAX_RESPONSE_HASHES: usize = 1024;
+/// Default maximum amount of outstanding block requests per connection
+pub const DEFAULT_PIPELINE_DEPTH: usize = 8;
+/// Default amount of queries per second answered per source
+const DEFAULT_QUERY_RATE: f64 = 10.0;
+/// Default amount of queries answered per source in a burst
+const DEFAULT_QUERY_BURST: f64 = 50.0;
+/// Default maximum amount of concurrent outbound metadata pushes
+const DEFAULT_MAX_METADATA_PUSHES: usize = 8;
+/// Default amount of bytes of recently served blocks kept in memory
+const DEFAULT_SERVE_CACHE_SIZE: usize = 32 * 1024 * 1024;
+/// Default time in seconds after which the blocks announced by a source are no longer trusted
+pub const DEFAULT_MAX_SOURCE_AGE: u64 = 120;
+/// Default time in seconds between two passes re-verifying the shared files
+const DEFAULT_SCRUB_INTERVAL: u64 = 24 * 60 * 60;
+/// Default amount of bytes per second read while re-verifying the shared files
+const DEFAULT_SCRUB_RATE: u64 = 4 * 1024 * 1024;
+
+/// Which parts of the protocol a node takes part in
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::config::Mode;
+/// # fn main() {
+/// let mode: Mode = "leech-only".parse().unwrap();
+/// assert!(mode.fetches() && !mode.serves());
+/// assert!("upload-only".parse::<Mode>().is_err());
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Serve and fetch files
+    Full,
+    /// Only serve files, e.g. a build server publishing artifacts
+    SeedOnly,
+    /// Only fetch files, e.g. a kiosk that must never upload anything
+    LeechOnly
+}
+
+impl Mode {
+    /// Whether the node answers queries and serves blocks
+    pub fn serves(&self) -> bool {
+        *self != Mode::LeechOnly
+    }
+
+    /// Whether the node downloads files
+    pub fn fetches(&self) -> bool {
+        *self != Mode::SeedOnly
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "full" => Ok(Mode::Full),
+            "seed-only" => Ok(Mode::SeedOnly),
+            "leech-only" => Ok(Mode::LeechOnly),
+            _ => Err(format!("Unknown mode '{}'", s))
+        }
+    }
+}
+
+/// Settings that control the behaviour of a node
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximum amount of block IDs returned per query, the rest has to be requested with a filter
+    pub max_response_blocks: usize,
+    /// Maximum amount of block hashes returned per metadata query, the rest has to be requested with a filter
+    pub max_response_hashes: usize,
+    /// Algorithm used to hash files that are prepared for sharing
+    pub hash_algorithm: HashAlgorithm,
+    /// Only reveal whitelisted files and never announce anything on untrusted networks
+    pub anonymous: bool,
+    /// Hashes of the files that are revealed in anonymous mode
+    pub whitelist: Vec<Vec<u8>>,
+    /// Policy deciding how long finished downloads are served, unless a transfer overrides it
+    pub seed_policy: SeedPolicy,
+    /// Maximum amount of block requests sent to a source before its responses arrive
+    pub pipeline_depth: usize,
+    /// Rules deciding which hosts may query this node and download from it
+    pub acl: Acl,
+    /// Pre-shared token presented to other nodes when querying them or downloading from them
+    pub token: Option<String>,
+    /// Amount of queries per second answered per source on average
+    pub query_rate: f64,
+    /// Amount of queries answered per source in a burst
+    pub query_burst: f64,
+    /// Sources whose queries and announcements are processed
+    pub source_filter: SourceFilter,
+    /// Maximum amount of metadata responses pushed to other nodes at the same time
+    pub max_metadata_pushes: usize,
+    /// Amount of bytes of recently served blocks kept in memory, zero reads every requested block from disk
+    pub serve_cache_size: usize,
+    /// Global upload and download limits by time of day
+    pub bandwidth: Schedule,
+    /// Hooks run whenever a download has finished
+    pub hooks: Vec<Hook>,
+    /// Directory in which state that persists across restarts is kept, e.g. the node ID
+    pub state_dir: Option<PathBuf>,
+    /// Whether block availability is gossiped and taken from the gossip of other nodes instead of polled
+    pub gossip: bool,
+    /// Whether metadata is requested in UDP chunks instead of being pushed via TCP, for nodes that can not accept
+    /// inbound TCP connections
+    pub udp_metadata: bool,
+    /// Offset added to every port the node binds so several nodes can run on one host, the multicast group is shared
+    pub port_offset: u16,
+    /// Whether the node serves files, fetches them or both
+    pub mode: Mode,
+    /// Multicast group the node meets other nodes in and how far its datagrams travel
+    pub multicast: MulticastScope,
+    /// Addresses of the local interfaces between which announcements and queries are relayed, empty if the node does
+    /// not relay
+    pub relay: Vec<Ipv4Addr>,
+    /// Addresses of the relays whose relayed datagrams are accepted, datagrams relayed by any other host are dropped
+    pub trusted_relays: Vec<Ipv4Addr>,
+    /// How long queries for metadata and block lists wait for responses
+    pub discovery: DiscoveryWindow,
+    /// Time a source may take to deliver a block before it is requested from another source
+    pub block_deadline: BlockDeadline,
+    /// Time after which the blocks announced by a source are no longer trusted until it confirms them again, `None`
+    /// trusts them for the whole download
+    pub max_source_age: Option<Duration>,
+    /// Time between two passes re-verifying the blocks of the shared files, `None` disables the re-verification
+    pub scrub_interval: Option<Duration>,
+    /// Amount of bytes per second read while re-verifying the shared files, zero reads as fast as possible
+    pub scrub_rate: u64,
+    /// Level at which log messages are printed, `None` keeps the level the logger has been initialized with
+    pub log_level: Option<LogLevel>,
+    /// Files shared by the node, directories share every regular file directly inside them
+    pub shares: Vec<PathBuf>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+impl Config {
+    /// Creates a configuration with the default values
+    pub fn new() -> Config {
+        Config {
+            max_response_blocks: DEFAULT_MAX_RESPONSE_BLOCKS,
+            max_response_hashes: DEFAULT_MAX_RESPONSE_HASHES,
+            hash_algorithm: HashAlgorithm::Sha256,
+            anonymous: false,
+            whitelist: Vec::new(),
+            seed_policy: SeedPolicy::new(),
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            acl: Acl::new(),
+            token: None,
+            query_rate: DEFAULT_QUERY_RATE,
+            query_burst: DEFAULT_QUERY_BURST,
+            source_filter: SourceFilter::LocalSubnet,
+            max_metadata_pushes: DEFAULT_MAX_METADATA_PUSHES,
+            serve_cache_size: DEFAULT_SERVE_CACHE_SIZE,
+            bandwidth: Schedule::new(),
+            hooks: Vec::new(),
+            state_dir: default_state_dir(),
+            gossip: false,
+            udp_metadata: false,
+            port_offset: 0,
+            mode: Mode::Full,
+            multicast: MulticastScope::new(),
+            relay: Vec::new(),
+            trusted_relays: Vec::new(),
+            discovery: DiscoveryWindow::new(),
+            block_deadline: BlockDeadline::new(),
+            max_source_age: Some(Duration::from_secs(DEFAULT_MAX_SOURCE_AGE)),
+            scrub_interval: Some(Duration::from_secs(DEFAULT_SCRUB_INTERVAL)),
+            scrub_rate: DEFAULT_SCRUB_RATE,
+            log_level: None,
+            shares: Vec::new()
+        }
+    }
+
+    /// Load a configuration file, settings that are missing from it keep their default values
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate ddp;
+    /// # use std::io::Write;
+    /// # use ddp::config::Config;
+    /// # fn main() {
+    /// let path = std::env::temp_dir().join("ddp-config-example.toml");
+    /// std::fs::File::create(&path).unwrap().write_all(b"
+    /// pipeline_depth = 16
+    /// max_source_age = 0
+    /// allow = [\"10.0.0.0/8\"]
+    ///
+    /// [[bandwidth.schedule]]
+    /// from = \"08:00\"
+    /// to = \"18:00\"
+    /// days = [\"mon\", \"tue\", \"wed\", \"thu\", \"fri\"]
+    /// upload = 10000000
+    /// download = 10000000
+    /// ").unwrap();
+    ///
+    /// let config = Config::load(&path).unwrap();
+    /// assert_eq!(config.pipeline_depth, 16);
+    /// assert_eq!(config.max_source_age, None);
+    /// assert_eq!(config.acl.allow.len(), 1);
+    /// assert_eq!(config.bandwidth.profiles[0].days, vec![1, 2, 3, 4, 5]);
+    /// assert_eq!(config.bandwidth.default.upload, None);
+    ///
+    /// std::fs::File::create(&path).unwrap().write_all(b"max_response_hashes = 0").unwrap();
+    /// assert!(Config::load(&path).is_err());
+    /// std::fs::File::create(&path).unwrap().write_all(b"pipeline_depth = 0").unwrap();
+    /// assert!(Config::load(&path).is_err());
+    /// # }
+    /// ```
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let mut content = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut content))
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let file: ConfigFile = toml::from_str(&content).map_err(|e| format!("Invalid configuration {}: {}", path.display(), e))?;
+        file.apply(Config::new())
+    }
+
+    /// Change the maximum amount of block IDs returned per query, at least one
+    pub fn max_response_blocks(mut self, limit: usize) -> Config {
+        self.max_response_blocks = limit;
+        self
+    }
+
+    /// Change the maximum amount of block hashes returned per metadata query, at least one
+    pub fn max_response_hashes(mut self, limit: usize) -> Config {
+        self.max_response_hashes = limit;
+        self
+    }
+
+    /// Change the algorithm used to hash files that are prepared for sharing
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> Config {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Enable the anonymous mode in which only queries for the given hashes are answered
+    pub fn anonymous(mut self, whitelist: Vec<Vec<u8>>) -> Config {
+        self.anonymous = true;
+        self.whitelist = whitelist;
+        self
+    }
+
+    /// Change the policy deciding how long finished downloads are served
+    pub fn seed_policy(mut self, policy: SeedPolicy) -> Config {
+        self.seed_policy = policy;
+        self
+    }
+
+    /// Change the maximum amount of block requests sent to a source before its responses arrive, at least one
+    pub fn pipeline_depth(mut self, depth: usize) -> Config {
+        self.pipeline_depth = depth;
+        self
+    }
+
+    /// Change the rules deciding which hosts may query this node and download from it
+    pub fn acl(mut self, acl: Acl) -> Config {
+        self.acl = acl;
+        self
+    }
+
+    /// Present a pre-shared token to other nodes
+    pub fn token(mut self, token: String) -> Config {
+        self.token = Some(token);
+        self
+    }
+
+    /// Change the amount of queries answered per source, on average and in a burst
+    pub fn query_rate(mut self, rate: f64, burst: f64) -> Config {
+        self.query_rate = rate;
+        self.query_burst = burst;
+        self
+    }
+
+    /// Change which sources queries and announcements are processed from
+    pub fn source_filter(mut self, filter: SourceFilter) -> Config {
+        self.source_filter = filter;
+        self
+    }
+
+    /// Change the maximum amount of metadata responses pushed to other nodes at the same time
+    pub fn max_metadata_pushes(mut self, limit: usize) -> Config {
+        self.max_metadata_pushes = limit;
+        self
+    }
+
+    /// Change the amount of bytes of recently served blocks kept in memory
+    pub fn serve_cache_size(mut self, size: usize) -> Config {
+        self.serve_cache_size = size;
+        self
+    }
+
+    /// Change the global upload and download limits by time of day
+    pub fn bandwidth(mut self, schedule: Schedule) -> Config {
+        self.bandwidth = schedule;
+        self
+    }
+
+    /// Run a hook whenever a download has finished
+    pub fn hook(mut self, hook: Hook) -> Config {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Change the directory in which persistent state is kept, `None` keeps all state in memory and generates a new
+    /// node ID at every start
+    pub fn state_dir(mut self, dir: Option<PathBuf>) -> Config {
+        self.state_dir = dir;
+        self
+    }
+
+    /// Gossip the availability of blocks and keep a live table of the blocks other nodes have instead of polling them
+    pub fn gossip(mut self, gossip: bool) -> Config {
+        self.gossip = gossip;
+        self
+    }
+
+    /// Whether availability gossip is sent and applied, anonymous nodes never take part in it
+    pub fn gossips(&self) -> bool {
+        self.gossip && !self.anonymous
+    }
+
+    /// Request metadata in UDP chunks instead of accepting it via TCP, e.g. behind a firewall that blocks inbound TCP
+    pub fn udp_metadata(mut self, udp: bool) -> Config {
+        self.udp_metadata = udp;
+        self
+    }
+
+    /// Move the ports of the node by `offset` so it does not collide with other nodes on the same host. A node binds
+    /// three consecutive ports starting at `BASE_PORT + offset`, so the offset has to be a multiple of three, see
+    /// `check_port_offset`.
+    pub fn port_offset(mut self, offset: u16) -> Config {
+        self.port_offset = offset;
+        self
+    }
+
+    /// Restrict the node to serving or to fetching files
+    pub fn mode(mut self, mode: Mode) -> Config {
+        self.mode = mode;
+        self
+    }
+
+    /// Change the multicast group and how far datagrams sent to it travel, all nodes that should see each other have
+    /// to use the same group
+    pub fn multicast(mut self, scope: MulticastScope) -> Config {
+        self.multicast = scope;
+        self
+    }
+
+    /// Relay announcements and queries between the subnets of the local interfaces with the given addresses
+    pub fn relay(mut self, interfaces: Vec<Ipv4Addr>) -> Config {
+        self.relay = interfaces;
+        self
+    }
+
+    /// Accept datagrams relayed by the relays with the given addresses in the local subnet, relayed datagrams are
+    /// answered as if they were sent by their origin
+    pub fn trusted_relays(mut self, relays: Vec<Ipv4Addr>) -> Config {
+        self.trusted_relays = relays;
+        self
+    }
+
+    /// Change how long queries for metadata and block lists wait for responses
+    pub fn discovery(mut self, window: DiscoveryWindow) -> Config {
+        self.discovery = window;
+        self
+    }
+
+    /// Change the time a source may take to deliver a block before it is requested from another source
+    pub fn block_deadline(mut self, deadline: BlockDeadline) -> Config {
+        self.block_deadline = deadline;
+        self
+    }
+
+    /// Change the time after which the blocks announced by a source are no longer trusted until it confirms them again
+    pub fn max_source_age(mut self, age: Option<Duration>) -> Config {
+        self.max_source_age = age;
+        self
+    }
+
+    /// Change how often and how fast the blocks of the shared files are re-verified
+    pub fn scrub(mut self, interval: Option<Duration>, rate: u64) -> Config {
+        self.scrub_interval = interval;
+        self.scrub_rate = rate;
+        self
+    }
+
+    /// Change the level at which log messages are printed
+    pub fn log_level(mut self, level: LogLevel) -> Config {
+        self.log_level = Some(level);
+        self
+    }
+
+    /// Share a file or every regular file directly inside a directory
+    pub fn share(mut self, path: PathBuf) -> Config {
+        self.shares.push(path);
+        self
+    }
+
+    /// Whether this node may reveal that it has the file with the given hash
+    pub fn may_reveal(&self, hash: &Vec<u8>) -> bool {
+        !self.anonymous || self.whitelist.contains(hash)
+    }
+
+    /// Files shared according to `shares` with directories expanded to the regular files inside them, paths that do
+    /// not exist are skipped with a warning
+    pub fn shared_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for path in self.shares.iter() {
+            if path.is_file() {
+                files.push(path.clone());
+                continue;
+            }
+            match fs::read_dir(path) {
+                Ok(entries) => {
+                    let mut entries = entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()).collect::<Vec<_>>();
+                    entries.sort();
+                    files.extend(entries);
+                },
+                Err(e) => warn!("Not sharing {}: {}", path.display(), e)
+            }
+        }
+        files
+    }
+
+    /// Names of the settings that differ from `other` but are only applied when a node starts, all other settings
+    /// can be changed while the node is running
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate ddp;
+    /// # use ddp::config::Config;
+    /// # fn main() {
+    /// let config = Config::new();
+    /// assert!(config.restart_required(&Config::new().pipeline_depth(32).query_rate(1.0, 5.0)) == vec!["query_rate", "query_burst"]);
+    /// assert!(config.restart_required(&Config::new().gossip(true)) == vec!["gossip"]);
+    /// # }
+    /// ```
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.anonymous != other.anonymous { changed.push("anonymous"); }
+        if self.query_rate != other.query_rate { changed.push("query_rate"); }
+        if self.query_burst != other.query_burst { changed.push("query_burst"); }
+        if self.max_metadata_pushes != other.max_metadata_pushes { changed.push("max_metadata_pushes"); }
+        if self.serve_cache_size != other.serve_cache_size { changed.push("serve_cache_size"); }
+        if self.state_dir != other.state_dir { changed.push("state_dir"); }
+        if self.gossip != other.gossip { changed.push("gossip"); }
+        if self.port_offset != other.port_offset { changed.push("port_offset"); }
+        if self.mode != other.mode { changed.push("mode"); }
+        if self.multicast != other.multicast { changed.push("multicast"); }
+        if self.relay != other.relay { changed.push("relay"); }
+        changed
+    }
+}
+
+/// `$DDP_HOME` if it is set, `.ddp` in the home directory of the user otherwise
+fn default_state_dir() -> Option<PathBuf> {
+    env::var_os("DDP_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")).map(|home| PathBuf::from(home).join(".ddp")))
+}
+
+/// Reject a limit of zero for the setting `name`, it would never let anything through
+fn at_least_one(name: &str, value: usize) -> Result<usize, String> {
+    if value == 0 { return Err(format!("{} has to be at least 1", name)) }
+    Ok(value)
+}
+
+/// Layout of a configuration file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    max_response_blocks: Option<usize>,
+    max_response_hashes: Option<usize>,
+    /// `sha256` or `blake3`
+    hash_algorithm: Option<String>,
+    /// Hex encoded hashes, enables the anonymous mode
+    whitelist: Option<Vec<String>>,
+    /// Multiple of the file size after which finished downloads are no longer served
+    seed_ratio: Option<f64>,
+    /// Seconds after which finished downloads are no longer served
+    seed_time: Option<u64>,
+    pipeline_depth: Option<usize>,
+    /// Ranges in CIDR notation
+    allow: Vec<String>,
+    deny: Vec<String>,
+    /// Pre-shared token that is required and presented
+    token: Option<String>,
+    query_rate: Option<f64>,
+    query_burst: Option<f64>,
+    /// `subnet` or `any`
+    source_filter: Option<String>,
+    max_metadata_pushes: Option<usize>,
+    /// Bytes
+    serve_cache_size: Option<usize>,
+    bandwidth: BandwidthSection,
+    hooks: Vec<HookSection>,
+    state_dir: Option<PathBuf>,
+    gossip: Option<bool>,
+    udp_metadata: Option<bool>,
+    port_offset: Option<u16>,
+    /// `full`, `seed-only` or `leech-only`
+    mode: Option<String>,
+    /// IPv4 multicast address
+    multicast_group: Option<Ipv4Addr>,
+    multicast_ttl: Option<u32>,
+    /// Addresses of local interfaces
+    relay: Vec<Ipv4Addr>,
+    /// Addresses of relays in the local subnet
+    trusted_relays: Vec<Ipv4Addr>,
+    /// Milliseconds waited for responses to the first attempt of a query
+    discovery_timeout: Option<u64>,
+    /// Milliseconds without responses after which a query ends early, 0 always waits for the timeout
+    discovery_quiet: Option<u64>,
+    /// Attempts after the first if nobody responded, each waits twice as long as the previous one
+    discovery_retries: Option<u32>,
+    /// Bytes per second below which a source is abandoned while it delivers a block, 0 disables the limit
+    min_block_throughput: Option<u64>,
+    /// Milliseconds every block may take in addition to the time given by the minimum throughput
+    block_grace: Option<u64>,
+    /// Seconds after which a source is abandoned while it delivers a block, 0 disables the limit
+    max_block_duration: Option<u64>,
+    /// Seconds after which the blocks announced by a source are queried again, 0 trusts them for the whole download
+    max_source_age: Option<u64>,
+    /// Seconds between two passes re-verifying the shared files, 0 disables the re-verification
+    scrub_interval: Option<u64>,
+    /// Bytes per second, 0 is unlimited
+    scrub_rate: Option<u64>,
+    /// `error`, `warn`, `info`, `debug` or `trace`
+    log_level: Option<String>,
+    /// Paths of files and directories
+    shares: Vec<PathBuf>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct BandwidthSection {
+    /// Bytes per second outside of all profiles
+    upload: Option<u64>,
+    download: Option<u64>,
+    schedule: Vec<ProfileSection>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileSection {
+    /// `HH:MM`
+    from: String,
+    to: String,
+    /// Abbreviated weekdays (`mon`, `tue`, ...), every day if missing
+    #[serde(default)]
+    days: Vec<String>,
+    upload: Option<u64>,
+    download: Option<u64>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HookSection {
+    /// Shell command, mutually exclusive with `webhook`
+    command: Option<String>,
+    /// `http://` URL
+    webhook: Option<String>
+}
+
+impl ConfigFile {
+    fn apply(self, mut config: Config) -> Result<Config, String> {
+        if let Some(limit) = self.max_response_blocks { config = config.max_response_blocks(at_least_one("max_response_blocks", limit)?); }
+        if let Some(limit) = self.max_response_hashes { config = config.max_response_hashes(at_least_one("max_response_hashes", limit)?); }
+        if let Some(algorithm) = self.hash_algorithm { config = config.hash_algorithm(algorithm.parse()?); }
+        if let Some(whitelist) = self.whitelist {
+            let hashes = whitelist.iter().map(|hash| from_hex_string(hash).ok_or(format!("Invalid hash '{}'", hash)));
+            config = config.anonymous(hashes.collect::<Result<_, _>>()?);
+        }
+        if self.seed_ratio.is_some() || self.seed_time.is_some() {
+            let mut policy = SeedPolicy::new();
+            if let Some(ratio) = self.seed_ratio { policy = policy.ratio(ratio); }
+            if let Some(time) = self.seed_time { policy = policy.time(Duration::from_secs(time)); }
+            config = config.seed_policy(policy);
+        }
+        if let Some(depth) = self.pipeline_depth { config = config.pipeline_depth(at_least_one("pipeline_depth", depth)?); }
+
+        let mut acl = config.acl.clone();
+        for range in self.allow.iter() { acl = acl.allow(range.parse()?); }
+        for range in self.deny.iter() { acl = acl.deny(range.parse()?); }
+        if let Some(token) = self.token {
+            acl = acl.token(token.clone());
+            config = config.token(token);
+        }
+        config = config.acl(acl);
+
+        if self.query_rate.is_some() || self.query_burst.is_some() {
+            let (rate, burst) = (self.query_rate.unwrap_or(config.query_rate), self.query_burst.unwrap_or(config.query_burst));
+            config = config.query_rate(rate, burst);
+        }
+        match self.source_filter.as_deref() {
+            Some("subnet") => config = config.source_filter(SourceFilter::LocalSubnet),
+            Some("any") => config = config.source_filter(SourceFilter::Any),
+            Some(filter) => return Err(format!("Unknown source filter '{}'", filter)),
+            None => {}
+        }
+        if let Some(limit) = self.max_metadata_pushes { config = config.max_metadata_pushes(limit); }
+        if let Some(size) = self.serve_cache_size { config = config.serve_cache_size(size); }
+        if let Some(dir) = self.state_dir { config = config.state_dir(Some(dir)); }
+        if let Some(gossip) = self.gossip { config = config.gossip(gossip); }
+        if let Some(udp) = self.udp_metadata { config = config.udp_metadata(udp); }
+        if let Some(offset) = self.port_offset {
+            check_port_offset(offset)?;
+            config = config.port_offset(offset);
+        }
+        if let Some(mode) = self.mode { config = config.mode(mode.parse()?); }
+        let mut scope = config.multicast;
+        if let Some(group) = self.multicast_group { scope = scope.group(group); }
+        if let Some(ttl) = self.multicast_ttl { scope = scope.ttl(ttl); }
+        scope.validate()?;
+        config = config.multicast(scope);
+        if !self.relay.is_empty() { config = config.relay(self.relay); }
+        if !self.trusted_relays.is_empty() { config = config.trusted_relays(self.trusted_relays); }
+        let mut window = config.discovery;
+        if let Some(timeout) = self.discovery_timeout { window = window.timeout(Duration::from_millis(timeout)); }
+        if let Some(quiet) = self.discovery_quiet {
+            window = window.quiet(if quiet > 0 { Some(Duration::from_millis(quiet)) } else { None });
+        }
+        if let Some(retries) = self.discovery_retries { window = window.retries(retries); }
+        config = config.discovery(window);
+        let mut deadline = config.block_deadline;
+        if let Some(throughput) = self.min_block_throughput {
+            deadline = deadline.min_throughput(if throughput > 0 { Some(throughput) } else { None });
+        }
+        if let Some(grace) = self.block_grace { deadline = deadline.grace(Duration::from_millis(grace)); }
+        if let Some(duration) = self.max_block_duration {
+            deadline = deadline.max_duration(if duration > 0 { Some(Duration::from_secs(duration)) } else { None });
+        }
+        config = config.block_deadline(deadline);
+        if let Some(age) = self.max_source_age {
+            config = config.max_source_age(if age > 0 { Some(Duration::from_secs(age)) } else { None });
+        }
+        let interval = self.scrub_interval.map_or(config.scrub_interval, |interval| if interval > 0 { Some(Duration::from_secs(interval)) } else { None });
+        let rate = self.scrub_rate.unwrap_or(config.scrub_rate);
+        config = config.scrub(interval, rate);
+        if let Some(level) = self.log_level {
+            config = config.log_level(level.parse().map_err(|_| format!("Unknown log level '{}'", level))?);
+        }
+        for path in self.shares { config = config.share(path); }
+
+        let mut schedule = Schedule::new();
+        schedule.default = Limits { upload: self.bandwidth.upload, download: self.bandwidth.download };
+        for profile in self.bandwidth.schedule {
+            let days = profile.days.iter().map(|day| parse_weekday(day).ok_or(format!("Unknown weekday '{}'", day)));
+            schedule.profiles.push(Profile {
+                from: profile.from.parse()?,
+                to: profile.to.parse()?,
+                days: days.collect::<Result<_, _>>()?,
+                limits: Limits { upload: profile.upload, download: profile.download }
+            });
+        }
+        config = config.bandwidth(schedule);
+
+        for hook in self.hooks {
+            config = config.hook(match (hook.command, hook.webhook) {
+                (Some(command), None) => Hook::Command(command),
+                (None, Some(url)) => url.parse()?,
+                _ => return Err("Every hook needs either a command or a webhook".to_string())
+            });
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,213 @@
+//! Local control socket through which other processes can inspect and command a running node
+//!
+//! Clients connect to `127.0.0.1:CONTROL_PORT` moved by the port offset of the node, send a single command line and read the response until the
+//! connection is closed. Responses consist of tab separated lines, except for the `events` command which streams the
+//! events of the node as lines of JSON until the client disconnects. The status of the transfers is also available as
+//! JSON to HTTP clients, e.g. monitoring tools, via `GET /status`.
+use std::thread::{spawn, JoinHandle};
+use std::net::{TcpListener, TcpStream, Shutdown};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use networking::BASE_PORT;
+use helpers::{to_hex_string, from_hex_string};
+use node::Node;
+use events::Event;
+use transfer::{TransferStatus, TransferState};
+use rate::TransferRate;
+use serde_json;
+
+/// Port of the control socket of a node without a port offset, only bound on the loopback interface
+pub const CONTROL_PORT: u16 = BASE_PORT + 2;
+/// Time in seconds a client has to send its command before the connection is closed
+const COMMAND_TIMEOUT: u64 = 10;
+
+pub fn start_control_server(node: Node) -> JoinHandle<()> {
+    spawn(move || {
+        let port = CONTROL_PORT + node.config().port_offset;
+        let socket = match TcpListener::bind(("127.0.0.1", port)) {
+            Ok(socket) => socket,
+            Err(e) => { warn!("Control socket unavailable: {}", e); return }
+        };
+        for stream in socket.incoming() {
+            let stream = match stream { Ok(s) => s, Err(_) => continue };
+            let node = node.clone();
+            // Every client is served by a thread of its own so a slow or silent one can not hold up the others
+            spawn(move || handle_connection(&node, stream));
+        }
+    })
+}
+
+/// Read the command of a client and answer it
+fn handle_connection(node: &Node, mut stream: TcpStream) {
+    if stream.set_read_timeout(Some(Duration::from_secs(COMMAND_TIMEOUT))).is_err() { return }
+    let mut command = String::new();
+    {
+        let mut reader = BufReader::new(&mut stream);
+        if reader.read_line(&mut command).is_err() { return }
+        // HTTP requests are read up to the end of their headers, which are of no interest
+        if command.starts_with("GET ") {
+            let mut header = String::new();
+            while reader.read_line(&mut header).map(|read| read > 0).unwrap_or(false) && !header.trim().is_empty() { header.clear(); }
+        }
+    }
+    if command.starts_with("GET ") {
+        serve_http(node, stream, command.split_whitespace().nth(1).unwrap_or(""));
+        return;
+    }
+    if command.trim() == "events" {
+        stream_events(node, stream);
+        return;
+    }
+    if command.trim() == "status" {
+        send_status(node, stream);
+        return;
+    }
+    let response = handle_command(node, command.trim());
+    let _ = stream.write_all(response.as_bytes());
+}
+
+fn handle_command(node: &Node, command: &str) -> String {
+    let mut args = command.split_whitespace();
+    match args.next() {
+        Some("discovered") => {
+            // One line per file: hash, size, name and the addresses of all seeders
+            node.discovered().iter().map(|file| {
+                format!("{}\t{}\t{}\t{}\n",
+                    to_hex_string(&file.hash),
+                    file.size.map_or("-".to_string(), |size| size.to_string()),
+                    file.name.clone().unwrap_or("-".to_string()),
+                    file.seeders.values().map(|addr| addr.to_string()).collect::<Vec<_>>().join(",")
+                )
+            }).collect()
+        },
+        Some("pause") => {
+            // Uploads are only paused if requested explicitly
+            let hash = args.next().and_then(from_hex_string);
+            let uploads = args.next() == Some("--uploads");
+            match hash {
+                Some(hash) => if node.pause(&hash, uploads) { "ok\n".to_string() } else { "error\tno such transfer\n".to_string() },
+                None => "error\tinvalid hash\n".to_string()
+            }
+        },
+        Some("resume") => {
+            match args.next().and_then(from_hex_string) {
+                Some(hash) => if node.resume(&hash) { "ok\n".to_string() } else { "error\tno such transfer\n".to_string() },
+                None => "error\tinvalid hash\n".to_string()
+            }
+        },
+        Some("link") => {
+            match args.next().and_then(from_hex_string).and_then(|hash| node.link(&hash)) {
+                Some(link) => format!("{}\n", link),
+                None => "error\tunknown file\n".to_string()
+            }
+        },
+        Some("reload") => {
+            match node.reload() {
+                Ok(()) => "ok\n".to_string(),
+                Err(e) => format!("error\t{}\n", e)
+            }
+        },
+        Some(c) => format!("error\tunknown command '{}'\n", c),
+        None => "error\tempty command\n".to_string()
+    }
+}
+
+/// Send the progress and rates of the transfers of the node to a client, waiting for the blocks being downloaded
+fn send_status(node: &Node, mut stream: TcpStream) {
+    let _ = stream.write_all(format_status(&node.transfers.status()).as_bytes());
+}
+
+/// One line per transfer: hash, state, downloaded and total bytes, current and average rate in bytes per second,
+/// estimated seconds remaining, name and the current rates of the sources
+fn format_status(transfers: &[TransferStatus]) -> String {
+    transfers.iter().map(|transfer| {
+        format!("{}\t{:?}\t{}\t{}\t{:.0}\t{:.0}\t{}\t{}\t{}\n",
+            to_hex_string(&transfer.hash),
+            transfer.state,
+            transfer.downloaded,
+            transfer.size,
+            transfer.rate.current,
+            transfer.rate.average,
+            transfer.rate.eta.map_or("-".to_string(), |eta| eta.to_string()),
+            transfer.name,
+            transfer.sources.iter().map(|&(ref id, rate)| format!("{}={:.0}", to_hex_string(id), rate)).collect::<Vec<_>>().join(",")
+        )
+    }).collect()
+}
+
+/// Status of a transfer as returned by the HTTP endpoint
+#[derive(Serialize)]
+struct StatusEntry {
+    hash: String,
+    name: String,
+    state: TransferState,
+    downloaded: usize,
+    size: usize,
+    rate: TransferRate,
+    /// Current rates of the sources by their hex encoded ID, the fastest first
+    sources: Vec<(String, f64)>
+}
+
+/// Answer an HTTP GET request for `path`, `/status` returns the status of the transfers as a JSON array
+fn serve_http(node: &Node, mut stream: TcpStream, path: &str) {
+    if path != "/status" {
+        let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        return;
+    }
+    let status = node.transfers.status().into_iter().map(|transfer| StatusEntry {
+        hash: to_hex_string(&transfer.hash),
+        name: transfer.name,
+        state: transfer.state,
+        downloaded: transfer.downloaded,
+        size: transfer.size,
+        rate: transfer.rate,
+        sources: transfer.sources.iter().map(|&(ref id, rate)| (to_hex_string(id), rate)).collect()
+    }).collect::<Vec<_>>();
+    let body = serde_json::to_vec(&status).unwrap();
+    let header = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+    let _ = stream.write_all(header.as_bytes()).and_then(|_| stream.write_all(&body));
+}
+
+/// Send the transfers of the node and every event from now on to a client until it disconnects
+fn stream_events(node: &Node, mut stream: TcpStream) {
+    // Subscribing first makes sure no event is missed between the snapshot and the stream
+    let events = node.events.subscribe();
+    // Taking the snapshot waits for the blocks being downloaded
+    let snapshot = node.transfers.snapshot();
+    for event in snapshot.into_iter().chain(events.iter()) {
+        let mut line = serde_json::to_vec(&event).unwrap();
+        line.push(b'\n');
+        if stream.write_all(&line).is_err() { return }
+    }
+}
+
+/// Send a command to the node running on this machine with the given port offset and return its response
+pub fn send_command(command: &str, port_offset: u16) -> io::Result<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", CONTROL_PORT + port_offset))?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// Receive the events of the node running on this machine with the given port offset, starting with its transfers.
+/// The receiver is disconnected once the node closes the connection.
+pub fn subscribe(port_offset: u16) -> io::Result<mpsc::Receiver<Event>> {
+    let mut stream = TcpStream::connect(("127.0.0.1", CONTROL_PORT + port_offset))?;
+    stream.write_all(b"events\n")?;
+    let (tx, rx) = mpsc::channel();
+    spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let line = match line { Ok(line) => line, Err(_) => return };
+            match serde_json::from_str(&line) {
+                Ok(event) => if tx.send(event).is_err() { return },
+                Err(e) => warn!("Received a malformed event: {}", e)
+            }
+        }
+    });
+    Ok(rx)
+}
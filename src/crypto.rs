@@ -0,0 +1,152 @@
+//! Encryption of payloads so nodes that merely relay a file can not read it
+//!
+//! The publisher encrypts the content with a symmetric key before it is hashed, so the swarm only ever sees, verifies
+//! and stores ciphertext. The key is passed to the recipients out of band and never sent over the network.
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::Path;
+
+use chacha20::ChaCha20Legacy;
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use getrandom::getrandom;
+
+use helpers::{to_hex_string, from_hex_string, HashAlgorithm};
+use storage::Storage;
+
+/// Length of a key in bytes
+pub const KEY_LENGTH: usize = 32;
+/// Length of a nonce in bytes
+pub const NONCE_LENGTH: usize = 8;
+/// Size of the chunks in which files are encrypted or decrypted in place
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Symmetric key shared between the publisher and the recipients of an encrypted file
+#[derive(Clone, PartialEq)]
+pub struct Key(pub [u8; KEY_LENGTH]);
+
+impl Key {
+    /// Generate a new random key
+    pub fn generate() -> Key {
+        let mut key = [0; KEY_LENGTH];
+        if let Err(e) = getrandom(&mut key) { fail!(Random, "{}", e) }
+        Key(key)
+    }
+
+    /// Parse a key from its hex representation
+    pub fn from_hex(hex: &str) -> Option<Key> {
+        from_hex_string(hex).and_then(|bytes| {
+            if bytes.len() != KEY_LENGTH { return None }
+            let mut key = [0; KEY_LENGTH];
+            key.copy_from_slice(&bytes);
+            Some(Key(key))
+        })
+    }
+
+    /// Hex representation of the key that can be passed to `from_hex`
+    pub fn to_hex(&self) -> String {
+        to_hex_string(&self.0).replace("-", "")
+    }
+}
+
+// Keys are kept out of logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+/// Parameters of an encrypted file that are distributed along with its metadata
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Encryption {
+    /// Nonce the content was encrypted with
+    pub nonce: Vec<u8>,
+    /// Hash of the key and the nonce to recognize a wrong key before decrypting garbage
+    pub key_check: Vec<u8>
+}
+
+impl Encryption {
+    /// Create the parameters for encrypting a file with a new random nonce
+    pub fn new(key: &Key) -> Encryption {
+        let nonce = generate_nonce();
+        Encryption {
+            key_check: key_check(key, &nonce),
+            nonce: nonce
+        }
+    }
+
+    /// Whether the file has been encrypted with the given key
+    pub fn matches(&self, key: &Key) -> bool {
+        key_check(key, &self.nonce) == self.key_check
+    }
+}
+
+fn key_check(key: &Key, nonce: &[u8]) -> Vec<u8> {
+    let mut data = key.0.to_vec();
+    data.extend_from_slice(nonce);
+    HashAlgorithm::Sha256.digest(&data)
+}
+
+/// Generate a new random nonce, a key must never be used twice with the same nonce
+pub fn generate_nonce() -> Vec<u8> {
+    let mut nonce = vec![0; NONCE_LENGTH];
+    if let Err(e) = getrandom(&mut nonce) { fail!(Random, "{}", e) }
+    nonce
+}
+
+/// Encrypt or decrypt `data` located at `offset` within the payload
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::crypto::{Key, generate_nonce, apply_keystream};
+/// # fn main() {
+/// let (key, nonce) = (Key::generate(), generate_nonce());
+/// let plaintext = (0..200).collect::<Vec<u8>>();
+///
+/// let mut ciphertext = plaintext.clone();
+/// apply_keystream(&key, &nonce, 0, &mut ciphertext);
+/// assert!(ciphertext != plaintext);
+///
+/// // Parts of the payload can be decrypted independently
+/// let mut part = ciphertext[70..150].to_vec();
+/// apply_keystream(&key, &nonce, 70, &mut part);
+/// assert_eq!(&part[..], &plaintext[70..150]);
+/// # }
+/// ```
+pub fn apply_keystream(key: &Key, nonce: &[u8], offset: u64, data: &mut [u8]) {
+    let mut cipher = ChaCha20Legacy::new(&key.0.into(), nonce.into());
+    cipher.seek(offset);
+    cipher.apply_keystream(data);
+}
+
+/// Encrypt or decrypt the first `size` bytes of a local file in place
+pub fn apply_keystream_to_file(key: &Key, nonce: &[u8], path: &Path, size: usize) -> io::Result<()> {
+    let mut f = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let len = ::std::cmp::min(CHUNK_SIZE, size - offset);
+        f.seek(SeekFrom::Start(offset as u64))?;
+        f.read_exact(&mut buf[..len])?;
+        apply_keystream(key, nonce, offset as u64, &mut buf[..len]);
+        f.seek(SeekFrom::Start(offset as u64))?;
+        f.write_all(&buf[..len])?;
+        offset += len;
+    }
+    f.sync_all()
+}
+
+/// Encrypt or decrypt the first `size` bytes of a storage in place
+pub fn apply_keystream_to_storage(key: &Key, nonce: &[u8], storage: &mut dyn Storage, size: usize) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < size {
+        let len = ::std::cmp::min(CHUNK_SIZE, size - offset);
+        let mut buf = storage.read_block(offset as u64, len)?;
+        apply_keystream(key, nonce, offset as u64, &mut buf);
+        storage.write_block(offset as u64, &buf)?;
+        offset += len;
+    }
+    storage.finalize()
+}
@@ -0,0 +1,172 @@
+//! Content that has been overheard on the network, regardless of whether it is shared or downloaded locally
+use std::collections::HashMap;
+use std::cmp::min;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use ext_time::PreciseTime;
+
+use peers::NodeId;
+
+/// Default time in milliseconds waited for responses to a query
+const DEFAULT_DISCOVERY_TIMEOUT: u64 = 1000;
+/// Default time in milliseconds without further responses after which a query ends early
+const DEFAULT_DISCOVERY_QUIET: u64 = 150;
+/// Default amount of times a query that nobody responded to is repeated
+const DEFAULT_DISCOVERY_RETRIES: u32 = 2;
+
+/// A file that other nodes have announced or asked for
+#[derive(Clone)]
+pub struct DiscoveredFile {
+    /// Content hash of the file
+    pub hash: Vec<u8>,
+    /// Name of the file, only known once a seeder announced it
+    pub name: Option<String>,
+    /// Size of the file in bytes, only known once a seeder announced it
+    pub size: Option<usize>,
+    /// Nodes that announced the file and the address they accept queries and block requests on
+    pub seeders: HashMap<NodeId, SocketAddr>,
+    /// Amount of queries for the file that have been overheard
+    pub queries: usize,
+    /// Time at which the file was last announced or queried
+    pub last_seen: PreciseTime
+}
+
+/// Collection of all overheard files indexed by their hash
+pub struct Discovery {
+    files: HashMap<Vec<u8>, DiscoveredFile>
+}
+
+impl Default for Discovery {
+    fn default() -> Discovery {
+        Discovery::new()
+    }
+}
+
+impl Discovery {
+    pub fn new() -> Discovery {
+        Discovery {
+            files: HashMap::new()
+        }
+    }
+
+    fn entry(&mut self, hash: &[u8]) -> &mut DiscoveredFile {
+        let file = self.files.entry(hash.to_vec()).or_insert_with(|| DiscoveredFile {
+            hash: hash.to_vec(),
+            name: None,
+            size: None,
+            seeders: HashMap::new(),
+            queries: 0,
+            last_seen: PreciseTime::now()
+        });
+        file.last_seen = PreciseTime::now();
+        file
+    }
+
+    /// Record that a node announced to be seeding a file
+    pub fn record_seeder(&mut self, hash: &[u8], name: String, size: usize, node_id: NodeId, addr: SocketAddr) {
+        let file = self.entry(hash);
+        file.name = Some(name);
+        file.size = Some(size);
+        file.seeders.insert(node_id, addr);
+    }
+
+    /// Record that some node asked for a file
+    pub fn record_query(&mut self, hash: &[u8]) {
+        self.entry(hash).queries += 1;
+    }
+
+    /// Retrieve everything that is known about a file
+    pub fn get(&self, hash: &Vec<u8>) -> Option<&DiscoveredFile> {
+        self.files.get(hash)
+    }
+
+    /// List all files that have been overheard
+    pub fn list(&self) -> Vec<DiscoveredFile> {
+        self.files.values().cloned().collect()
+    }
+}
+
+/// How long queries for metadata and block lists wait for responses
+///
+/// A query ends once the timeout has passed or once responses stopped arriving for the quiet period. Queries that
+/// nobody responded to are repeated with twice the timeout of the previous attempt.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::time::{Duration, Instant};
+/// # use ddp::discovery::DiscoveryWindow;
+/// # fn main() {
+/// let window = DiscoveryWindow::new().timeout(Duration::from_millis(500)).quiet(Some(Duration::from_millis(50)));
+/// assert_eq!(window.attempt_timeout(2), Duration::from_millis(2000));
+///
+/// // Without responses the whole timeout is waited
+/// let started = Instant::now();
+/// assert!(!window.is_over(0, started, None));
+///
+/// // Quiet after the last response ends the attempt early
+/// let last_response = Instant::now();
+/// std::thread::sleep(Duration::from_millis(60));
+/// assert!(window.is_over(0, started, Some(last_response)));
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiscoveryWindow {
+    /// Time waited for responses to the first attempt of a query
+    pub timeout: Duration,
+    /// Time without further responses after which a query ends early, it always runs until the timeout if missing
+    pub quiet: Option<Duration>,
+    /// Amount of times a query is repeated if nobody responded to it
+    pub retries: u32
+}
+
+impl Default for DiscoveryWindow {
+    fn default() -> DiscoveryWindow {
+        DiscoveryWindow::new()
+    }
+}
+
+impl DiscoveryWindow {
+    /// Creates a window with the default timeout, quiet period and retries
+    pub fn new() -> DiscoveryWindow {
+        DiscoveryWindow {
+            timeout: Duration::from_millis(DEFAULT_DISCOVERY_TIMEOUT),
+            quiet: Some(Duration::from_millis(DEFAULT_DISCOVERY_QUIET)),
+            retries: DEFAULT_DISCOVERY_RETRIES
+        }
+    }
+
+    /// Change the time waited for responses to the first attempt of a query
+    pub fn timeout(mut self, timeout: Duration) -> DiscoveryWindow {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Change the time without responses after which a query ends early, `None` waits for the whole timeout
+    pub fn quiet(mut self, quiet: Option<Duration>) -> DiscoveryWindow {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Change the amount of times a query is repeated if nobody responded to it
+    pub fn retries(mut self, retries: u32) -> DiscoveryWindow {
+        self.retries = retries;
+        self
+    }
+
+    /// Time waited for responses to the given attempt, starting at zero
+    pub fn attempt_timeout(&self, attempt: u32) -> Duration {
+        self.timeout * 2u32.pow(min(attempt, 16))
+    }
+
+    /// Whether the given attempt, started at `started`, waited long enough for responses
+    pub fn is_over(&self, attempt: u32, started: Instant, last_response: Option<Instant>) -> bool {
+        if started.elapsed() >= self.attempt_timeout(attempt) { return true }
+        match (self.quiet, last_response) {
+            (Some(quiet), Some(last_response)) => last_response.elapsed() >= quiet,
+            _ => false
+        }
+    }
+}
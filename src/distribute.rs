@@ -0,0 +1,267 @@
+//! Distribution of a single file from an origin to many nodes at once, e.g. an image deployed to a whole lab
+//!
+//! Downloaders serve the blocks they already have to each other while downloading and pick the rarest blocks first,
+//! each in an order of its own, so they fetch different blocks and trade them with their neighbours. The origin only
+//! advertises the blocks the fewest downloaders have so its upload goes to blocks that are missing in the swarm.
+//! Every downloader reports its completion to the origin, which announces the end of the distribution once all
+//! expected nodes are done. The reports are datagrams that may all get lost, so both sides also give up once nothing
+//! has happened for a while.
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use helpers::to_hex_string;
+use peers::NodeId;
+
+/// Minimum amount of blocks the origin advertises even if fewer are among the rarest
+const MIN_ADVERTISED_BLOCKS: usize = 64;
+/// Time in seconds without uploads or reports after which a node stops waiting for the end of a distribution
+pub const IDLE_TIMEOUT: u64 = 60;
+
+/// Report of a downloader or the origin about the progress of a distribution, sent via multicast
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DistributionReport {
+    /// ID of the reporting node
+    pub node_id: NodeId,
+    /// Hash of the distributed file
+    pub hash: Vec<u8>,
+    pub status: DistributionStatus
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DistributionStatus {
+    /// The reporting downloader has the whole file after downloading it for the given time
+    Complete { milliseconds: u64 },
+    /// The origin has seen every expected downloader complete, downloaders may stop serving the file
+    Finished
+}
+
+/// Completion of a downloader as seen by the origin
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub node_id: NodeId,
+    /// Time the downloader took according to its report
+    pub download_time: Duration,
+    /// Time since the start of the distribution at which the report arrived
+    pub reported_after: Duration
+}
+
+/// Progress of a distribution this node is the origin of
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::time::Duration;
+/// # use ddp::distribute::Distribution;
+/// # fn main() {
+/// let mut distribution = Distribution::new(Some(2));
+/// assert!(distribution.record(vec![1], Duration::from_secs(3)));
+/// // Reports are sent several times and only counted once
+/// assert!(!distribution.record(vec![1], Duration::from_secs(3)));
+/// assert!(!distribution.is_done());
+///
+/// distribution.record(vec![2], Duration::from_secs(5));
+/// assert!(distribution.is_done());
+/// assert_eq!(distribution.summary().len(), 3);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Distribution {
+    pub started: Instant,
+    /// Amount of downloaders after whose completion the distribution is finished, it runs until stopped if missing
+    pub expected: Option<usize>,
+    /// Downloaders that reported their completion in the order of their reports
+    pub completed: Vec<Completion>
+}
+
+impl Distribution {
+    pub fn new(expected: Option<usize>) -> Distribution {
+        Distribution {
+            started: Instant::now(),
+            expected: expected,
+            completed: Vec::new()
+        }
+    }
+
+    /// Record the completion of a downloader, returns false if it has been recorded before
+    pub fn record(&mut self, node_id: NodeId, download_time: Duration) -> bool {
+        if self.completed.iter().any(|completion| completion.node_id == node_id) { return false }
+        self.completed.push(Completion {
+            node_id: node_id,
+            download_time: download_time,
+            reported_after: self.started.elapsed()
+        });
+        true
+    }
+
+    /// Whether all expected downloaders completed
+    pub fn is_done(&self) -> bool {
+        self.expected.is_some_and(|expected| self.completed.len() >= expected)
+    }
+
+    /// One line per downloader with the time it took and the time its report arrived, followed by a line with the
+    /// fastest, median and slowest download time
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = self.completed.iter().map(|completion| {
+            format!("{} downloaded in {:.1}s, done {:.1}s into the distribution", to_hex_string(&completion.node_id),
+                seconds(completion.download_time), seconds(completion.reported_after))
+        }).collect::<Vec<_>>();
+        let mut times = self.completed.iter().map(|completion| completion.download_time).collect::<Vec<_>>();
+        times.sort();
+        if let (Some(first), Some(last)) = (times.first(), times.last()) {
+            lines.push(format!("{} nodes in {:.1}s, download times {:.1}s fastest, {:.1}s median, {:.1}s slowest",
+                times.len(), seconds(self.started.elapsed()), seconds(*first), seconds(times[times.len() / 2]), seconds(*last)));
+        }
+        lines
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_millis() as f64 / 1000.0
+}
+
+/// Detects that a distribution went quiet from a counter of its activity, e.g. the bytes uploaded
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::thread::sleep;
+/// # use std::time::Duration;
+/// # use ddp::distribute::IdleTimer;
+/// # fn main() {
+/// let mut timer = IdleTimer::new(Duration::from_millis(50));
+/// assert!(!timer.is_idle(0));
+/// sleep(Duration::from_millis(60));
+/// // Activity restarts the timer
+/// assert!(!timer.is_idle(1000));
+/// assert!(!timer.is_idle(1000));
+/// sleep(Duration::from_millis(60));
+/// assert!(timer.is_idle(1000));
+/// # }
+/// ```
+pub struct IdleTimer {
+    timeout: Duration,
+    activity: usize,
+    since: Instant
+}
+
+impl IdleTimer {
+    pub fn new(timeout: Duration) -> IdleTimer {
+        IdleTimer {
+            timeout: timeout,
+            activity: 0,
+            since: Instant::now()
+        }
+    }
+
+    /// Record the current value of the counter, returns whether it has not changed within the timeout
+    pub fn is_idle(&mut self, activity: usize) -> bool {
+        if activity != self.activity {
+            self.activity = activity;
+            self.since = Instant::now();
+        }
+        self.since.elapsed() >= self.timeout
+    }
+}
+
+/// Distributions this node takes part in, as the origin or as a downloader
+pub struct Distributions {
+    /// Progress of the distributions this node is the origin of by file hash
+    origins: HashMap<Vec<u8>, Distribution>,
+    /// Hashes of the files whose origin finished the distribution
+    finished: HashSet<Vec<u8>>
+}
+
+impl Default for Distributions {
+    fn default() -> Distributions {
+        Distributions::new()
+    }
+}
+
+impl Distributions {
+    pub fn new() -> Distributions {
+        Distributions {
+            origins: HashMap::new(),
+            finished: HashSet::new()
+        }
+    }
+
+    /// Become the origin of the distribution of a file
+    pub fn start(&mut self, hash: Vec<u8>, expected: Option<usize>) {
+        self.origins.insert(hash, Distribution::new(expected));
+    }
+
+    /// Apply a report of another node, reports about distributions this node is not the origin of are only of
+    /// interest once they are finished
+    pub fn apply(&mut self, report: DistributionReport) {
+        match report.status {
+            DistributionStatus::Complete { milliseconds } => {
+                if let Some(distribution) = self.origins.get_mut(&report.hash) {
+                    let node = to_hex_string(&report.node_id);
+                    if distribution.record(report.node_id, Duration::from_millis(milliseconds)) {
+                        info!("{} completed the download ({} of {})", node, distribution.completed.len(),
+                            distribution.expected.map_or("?".to_string(), |expected| expected.to_string()));
+                    }
+                }
+            },
+            DistributionStatus::Finished => { self.finished.insert(report.hash); }
+        }
+    }
+
+    pub fn get(&self, hash: &Vec<u8>) -> Option<&Distribution> {
+        self.origins.get(hash)
+    }
+
+    /// Whether this node is the origin of the distribution of a file
+    pub fn is_origin(&self, hash: &Vec<u8>) -> bool {
+        self.origins.contains_key(hash)
+    }
+
+    /// Whether the origin of a distribution announced that it is finished
+    pub fn is_finished(&self, hash: &Vec<u8>) -> bool {
+        self.finished.contains(hash)
+    }
+}
+
+/// Select the blocks the origin of a distribution advertises out of the `available` ones: those held by the fewest
+/// other nodes according to `holders`, topped up with the next rarest ones to at least `MIN_ADVERTISED_BLOCKS`. All
+/// blocks are advertised as long as nothing is known about the other nodes.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::distribute::rarest_blocks;
+/// # fn main() {
+/// let available = (0..100).collect::<Vec<_>>();
+/// let mut holders = vec![vec![vec![1]]; 100];
+/// for block in 10..20 { holders[block].clear(); }
+/// let advertised = rarest_blocks(&available, Some(&holders));
+/// assert_eq!(advertised.len(), 64);
+/// assert!((10..20).all(|block| advertised.contains(&block)));
+/// assert_eq!(rarest_blocks(&available, None), available);
+/// # }
+/// ```
+pub fn rarest_blocks(available: &[usize], holders: Option<&[Vec<NodeId>]>) -> Vec<usize> {
+    let holders = match holders {
+        Some(holders) => holders,
+        None => return available.to_vec()
+    };
+    let count = |id: usize| holders.get(id).map_or(0, |nodes| nodes.len());
+    let mut blocks = available.to_vec();
+    blocks.sort_by_key(|id| (count(*id), *id));
+    let rarest = match blocks.first() { Some(id) => count(*id), None => return blocks };
+    let tier = blocks.iter().take_while(|id| count(**id) == rarest).count();
+    blocks.truncate(tier.max(MIN_ADVERTISED_BLOCKS));
+    blocks.sort();
+    blocks
+}
+
+/// Position of a block in the order in which a node picks equally rare blocks, every node has an order of its own so
+/// downloaders starting at the same time fetch different blocks first
+pub fn spread(node_id: &NodeId, block: usize) -> u64 {
+    let seed = node_id.iter().take(8).fold(0u64, |seed, byte| seed << 8 | *byte as u64);
+    // Both steps are bijective so no two blocks share a position
+    (block as u64 ^ seed).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
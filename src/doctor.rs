@@ -0,0 +1,178 @@
+//! Self-test of the network setup to troubleshoot nodes that can not find each other
+//!
+//! The checks use their own sockets and only probe the ports of a node that may already be running on this machine.
+use std::fmt;
+use std::net::{UdpSocket, TcpListener, TcpStream, Ipv4Addr, IpAddr, SocketAddr, SocketAddrV4};
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+use bincode::{serialize, deserialize};
+use if_addrs::{get_if_addrs, IfAddr};
+
+use networking::{BASE_PORT, MulticastScope, write_frame};
+use announce::{Message, Announcement, Handshake, Capabilities};
+use config::Mode;
+use control::send_command;
+use peers::generate_node_id;
+
+/// Time to wait for the loopback announcement
+const LOOPBACK_TIMEOUT: u64 = 2;
+/// Time to wait for TCP connections to local ports
+const CONNECT_TIMEOUT: u64 = 1;
+
+/// Outcome of a single check
+pub struct Diagnostic {
+    /// What has been checked
+    pub check: String,
+    pub passed: bool,
+    /// What has been observed
+    pub detail: String,
+    /// What to do about a failed check
+    pub advice: Option<&'static str>
+}
+
+impl Diagnostic {
+    fn pass(check: String, detail: String) -> Diagnostic {
+        Diagnostic { check: check, passed: true, detail: detail, advice: None }
+    }
+
+    fn fail(check: String, detail: String, advice: &'static str) -> Diagnostic {
+        Diagnostic { check: check, passed: false, detail: detail, advice: Some(advice) }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}: {}", if self.passed { " OK " } else { "FAIL" }, self.check, self.detail)?;
+        if let Some(advice) = self.advice { write!(f, "\n       {}", advice)?; }
+        Ok(())
+    }
+}
+
+/// Run all checks, those of the multicast group and the ports for a node with the given scope and port offset
+pub fn diagnose(port_offset: u16, scope: MulticastScope) -> Vec<Diagnostic> {
+    let mut diagnostics = check_interfaces(scope.group);
+    diagnostics.push(check_loopback(scope.group));
+    diagnostics.append(&mut check_block_port(BASE_PORT + port_offset));
+    diagnostics.push(check_control_socket(port_offset));
+    diagnostics
+}
+
+/// Join the multicast group on every IPv4 interface
+fn check_interfaces(group: Ipv4Addr) -> Vec<Diagnostic> {
+    let interfaces = match get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => return vec![Diagnostic::fail("Interfaces".to_string(), e.to_string(),
+            "The network interfaces could not be listed, check the permissions of this process")]
+    };
+
+    let mut diagnostics = interfaces.iter().filter_map(|interface| match interface.addr {
+        IfAddr::V4(ref addr) => Some((interface.name.clone(), addr.ip)),
+        IfAddr::V6(_) => None
+    }).map(|(name, ip)| {
+        let check = format!("Multicast on {} ({})", name, ip);
+        let joined = UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), 0)).and_then(|sock| sock.join_multicast_v4(&group, &ip));
+        match joined {
+            Ok(_) => Diagnostic::pass(check, format!("joined {}", group)),
+            Err(e) => Diagnostic::fail(check, e.to_string(),
+                "Enable multicast on the interface (e.g. `ip link set <interface> multicast on`) or ignore it if it is not used")
+        }
+    }).collect::<Vec<_>>();
+
+    if diagnostics.is_empty() {
+        diagnostics.push(Diagnostic::fail("Interfaces".to_string(), "no IPv4 interface found".to_string(),
+            "Connect to a network, nodes only communicate via IPv4 multicast"));
+    }
+    diagnostics
+}
+
+/// Send an announcement to the multicast group and wait for it to be looped back
+fn check_loopback(group: Ipv4Addr) -> Diagnostic {
+    let check = "Multicast loopback".to_string();
+    let sock = match UdpSocket::bind((Ipv4Addr::new(0, 0, 0, 0), 0)) {
+        Ok(sock) => sock,
+        Err(e) => return Diagnostic::fail(check, e.to_string(), "Check whether this process may open UDP sockets")
+    };
+    // A scratch port is used so a running node neither interferes nor receives the probe
+    let port = sock.local_addr().map(|addr| addr.port()).unwrap_or(0);
+    let prepared = sock.join_multicast_v4(&group, &Ipv4Addr::new(0, 0, 0, 0))
+        .and_then(|_| sock.set_multicast_loop_v4(true))
+        .and_then(|_| sock.set_read_timeout(Some(Duration::from_millis(100))));
+    if let Err(e) = prepared {
+        return Diagnostic::fail(check, e.to_string(), "Enable multicast on the default interface");
+    }
+
+    let id = generate_node_id();
+    let probe = serialize(&Message::Announcement(Announcement {
+        node_id: id.clone(),
+        port: BASE_PORT,
+        capabilities: Capabilities::of(Mode::Full),
+        files: Vec::new()
+    })).unwrap();
+    let start = Instant::now();
+    if let Err(e) = sock.send_to(&probe, SocketAddrV4::new(group, port)) {
+        return Diagnostic::fail(check, e.to_string(), "Add a route for multicast traffic (e.g. `ip route add 224.0.0.0/4 dev <interface>`)");
+    }
+
+    let mut buf = vec![0; 2048];
+    while start.elapsed() < Duration::from_secs(LOOPBACK_TIMEOUT) {
+        match sock.recv_from(&mut buf) {
+            Ok((len, src)) => match deserialize(&buf[..len]) {
+                Ok(Message::Announcement(ref announcement)) if announcement.node_id == id => {
+                    return Diagnostic::pass(check, format!("announcement received from {} after {:?}", src.ip(), start.elapsed()));
+                },
+                _ => continue
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Diagnostic::fail(check, e.to_string(), "Check the firewall rules for UDP traffic")
+        }
+    }
+    Diagnostic::fail(check, format!("no announcement received within {}s", LOOPBACK_TIMEOUT),
+        "Allow incoming UDP traffic to the multicast group in the firewall")
+}
+
+/// Connect to the block port via the address of every IPv4 interface, using a temporary listener if no node is running
+fn check_block_port(port: u16) -> Vec<Diagnostic> {
+    let ips = get_if_addrs().map(|interfaces| interfaces.iter().filter_map(|interface| match interface.addr {
+        IfAddr::V4(ref addr) => Some(addr.ip),
+        IfAddr::V6(_) => None
+    }).collect::<Vec<_>>()).unwrap_or_default();
+
+    let (listener, running) = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(listener) => (Some(listener), false),
+        Err(ref e) if e.kind() == ErrorKind::AddrInUse => (None, true),
+        Err(e) => return vec![Diagnostic::fail(format!("Block port {}", port), e.to_string(),
+            "Check whether this process may listen on TCP ports")]
+    };
+    let owner = if running { "in use, presumably by a running node" } else { "free" };
+
+    let mut diagnostics = vec![Diagnostic::pass(format!("Block port {}", port), owner.to_string())];
+    for ip in ips {
+        let check = format!("Block port via {}", ip);
+        let addr = SocketAddr::new(IpAddr::V4(ip), port);
+        diagnostics.push(match TcpStream::connect_timeout(&addr, Duration::from_secs(CONNECT_TIMEOUT)) {
+            Ok(mut stream) => {
+                // Introduce the probe so a running node does not report a malformed handshake
+                let _ = write_frame(&mut stream, &serialize(&Handshake {
+                    node_id: generate_node_id(),
+                    token: None,
+                    capabilities: Capabilities::of(Mode::Full)
+                }).unwrap());
+                Diagnostic::pass(check, "reachable".to_string())
+            },
+            Err(e) => Diagnostic::fail(check, e.to_string(), "Allow incoming TCP connections to the block port in the firewall")
+        });
+    }
+    drop(listener);
+    diagnostics
+}
+
+/// Reach the control socket of a node running on this machine
+fn check_control_socket(port_offset: u16) -> Diagnostic {
+    let check = "Local node".to_string();
+    match send_command("discovered", port_offset) {
+        Ok(response) => Diagnostic::pass(check, format!("running, {} files discovered", response.lines().count())),
+        // Diagnosing a machine without a node is fine
+        Err(_) => Diagnostic::pass(check, "not running".to_string())
+    }
+}
@@ -0,0 +1,115 @@
+//! Fatal errors and the exit codes of the process
+//!
+//! The exit codes are stable so scripts wrapping the CLI can tell the causes of a failure apart:
+//!
+//! | Code | Error          | Cause                                                                      |
+//! |------|----------------|----------------------------------------------------------------------------|
+//! | 0    |                | Success                                                                    |
+//! | 1    | `Usage`        | Invalid arguments, configuration, links or metadata files                  |
+//! | 2    | `Transfer`     | Downloads, streams or repairs that failed                                  |
+//! | 3    | `Multicast`    | The multicast group could not be joined                                    |
+//! | 4    | `HashMismatch` | Data that does not match its hash, e.g. a damaged local copy               |
+//! | 5    | `Io`           | Local files that could not be read or written                              |
+//! | 6    | `Logger`       | The logger could not be set up                                             |
+//! | 7    | `Random`       | The random number generator of the system is unavailable                   |
+//! | 8    | `Bind`         | A port could not be bound, e.g. because another node uses it               |
+//! | 9    | `Control`      | The node running on this machine could not be reached                      |
+//! | 10   | `Diagnosis`    | Checks of `ddp doctor` failed                                              |
+//! | 11   | `Unsupported`  | The requested feature is not available in this build                       |
+use std::fmt;
+use std::process;
+
+/// Fatal error, carrying the message shown to the user
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Usage(String),
+    Transfer(String),
+    Multicast(String),
+    HashMismatch(String),
+    Io(String),
+    Logger(String),
+    Random(String),
+    Bind(String),
+    Control(String),
+    Diagnosis(String),
+    Unsupported(String)
+}
+
+impl Error {
+    /// Exit code of the process when it ends because of this error
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate ddp;
+    /// # use ddp::errors::Error;
+    /// # fn main() {
+    /// let error = Error::Bind("UDP port 8888: Address in use".to_string());
+    /// assert_eq!(error.code(), 8);
+    /// assert_eq!(error.to_string(), "Failed to bind: UDP port 8888: Address in use");
+    /// # }
+    /// ```
+    pub fn code(&self) -> i32 {
+        match *self {
+            Error::Usage(_) => 1,
+            Error::Transfer(_) => 2,
+            Error::Multicast(_) => 3,
+            Error::HashMismatch(_) => 4,
+            Error::Io(_) => 5,
+            Error::Logger(_) => 6,
+            Error::Random(_) => 7,
+            Error::Bind(_) => 8,
+            Error::Control(_) => 9,
+            Error::Diagnosis(_) => 10,
+            Error::Unsupported(_) => 11
+        }
+    }
+
+    fn message(&self) -> &str {
+        match *self {
+            Error::Usage(ref message) | Error::Transfer(ref message) | Error::Multicast(ref message) |
+            Error::HashMismatch(ref message) | Error::Io(ref message) | Error::Logger(ref message) |
+            Error::Random(ref message) | Error::Bind(ref message) | Error::Control(ref message) |
+            Error::Diagnosis(ref message) | Error::Unsupported(ref message) => message
+        }
+    }
+
+    /// Log the error and end the process with its exit code
+    pub fn exit(self) -> ! {
+        error!("{}", self);
+        process::exit(self.code())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            // The messages of these errors describe the failure on their own, the others carry the error of the system
+            Error::Usage(ref message) | Error::Transfer(ref message) | Error::HashMismatch(ref message) |
+            Error::Io(ref message) | Error::Diagnosis(ref message) | Error::Unsupported(ref message) => write!(f, "{}", message),
+            Error::Multicast(_) => write!(f, "Multicast support not available: {}", self.message()),
+            Error::Logger(_) => write!(f, "Failed to set logger: {}", self.message()),
+            Error::Random(_) => write!(f, "Random number generator unavailable: {}", self.message()),
+            Error::Bind(_) => write!(f, "Failed to bind: {}", self.message()),
+            Error::Control(_) => write!(f, "Failed to reach the local node: {}", self.message())
+        }
+    }
+}
+
+/// End the process with an `errors::Error` of the given kind and a formatted message
+///
+/// # Examples
+///
+/// ```should_panic
+/// # #[macro_use] extern crate ddp;
+/// # #[macro_use] extern crate log;
+/// # fn main() {
+/// fail!(Usage, "Invalid hash: {}", "XYZ");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! fail {
+    ($kind:ident, $($arg:tt)*) => {
+        $crate::errors::Error::$kind(format!($($arg)*)).exit()
+    };
+}
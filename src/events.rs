@@ -0,0 +1,106 @@
+//! Progress events of a node, e.g. for `ddp tui` which receives them through the control socket
+//!
+//! Events are fanned out to every subscriber through a bounded channel of its own. A subscriber that does not keep up
+//! loses events instead of stalling the downloads, a subscriber that has gone away is dropped with the next event.
+use std::sync::{mpsc, Arc, Mutex};
+
+use bitfield::BlockSet;
+use transfer::TransferState;
+use rate::TransferRate;
+
+/// Maximum amount of events waiting to be received by a single subscriber
+const MAX_QUEUED_EVENTS: usize = 4096;
+
+/// Something that happened at a node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A download that has been queued, or that exists already when subscribing
+    Transfer {
+        /// Hex encoded hash of the file
+        hash: String,
+        name: String,
+        /// Size of the file in bytes
+        size: usize,
+        /// Amount of blocks of the file
+        blocks: usize,
+        /// Blocks that are complete
+        completed: BlockSet,
+        state: TransferState
+    },
+    /// A block that has been received and verified or taken from a local copy
+    Block {
+        hash: String,
+        block: usize,
+        /// Hex encoded ID of the source, missing for blocks taken from local files
+        source: Option<String>,
+        bytes: usize
+    },
+    /// Rates of a download, published at most once a second while blocks arrive
+    Rate {
+        hash: String,
+        rate: TransferRate,
+        /// Current rates of the sources by their hex encoded ID, the fastest first
+        sources: Vec<(String, f64)>
+    },
+    /// A download changed its state
+    State {
+        hash: String,
+        state: TransferState
+    },
+    /// A message logged by the node
+    Log {
+        level: String,
+        message: String
+    }
+}
+
+/// Channel publishing the events of a node to all subscribers, cloning it yields another handle to the same channel
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::events::{Event, EventChannel};
+/// # fn main() {
+/// let events = EventChannel::new();
+/// // Events without subscribers are not even created
+/// events.publish(|| unreachable!());
+///
+/// let subscriber = events.subscribe();
+/// events.publish(|| Event::Log { level: "INFO".to_string(), message: "Download complete".to_string() });
+/// assert!(match subscriber.try_recv() { Ok(Event::Log { ref message, .. }) => message == "Download complete", _ => false });
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct EventChannel {
+    subscribers: Arc<Mutex<Vec<mpsc::SyncSender<Event>>>>
+}
+
+impl Default for EventChannel {
+    fn default() -> EventChannel {
+        EventChannel::new()
+    }
+}
+
+impl EventChannel {
+    pub fn new() -> EventChannel {
+        EventChannel {
+            subscribers: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    /// Receive all events published from now on
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::sync_channel(MAX_QUEUED_EVENTS);
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Send the event created by `event` to all subscribers, it is only created if there are any
+    pub fn publish<F: FnOnce() -> Event>(&self, event: F) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty() { return }
+        let event = event();
+        subscribers.retain(|subscriber| !matches!(subscriber.try_send(event.clone()), Err(mpsc::TrySendError::Disconnected(_))));
+    }
+}
@@ -1,20 +1,46 @@
 use pbr::{ProgressBar, Units};
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::io::BufReader;
 use std::fs::File as F;
-use std::io::{Seek, SeekFrom};
-use std::net::IpAddr;
+use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
+use std::ops::Range;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-use sha2::sha2::Sha256;
-use sha2::Digest;
+use helpers::{calculate_block_size, block_count, block_offset, trailing_offset, HashAlgorithm};
+use peers::{NodeId, PeerRegistry, BlockDeadline, generate_node_id};
+use transfer::Priority;
+use config::{DEFAULT_PIPELINE_DEPTH, DEFAULT_MAX_SOURCE_AGE, Mode};
+use announce::Capabilities;
+use crypto::{Key, Encryption, apply_keystream};
+use acl::Acl;
+use bandwidth::Limiter;
+use request::BlockListQueries;
+use gossip::AvailabilityTable;
+use cache::BlockCache;
+use discovery::DiscoveryWindow;
+use networking::MulticastScope;
+use library::Library;
+use history::SourceUsage;
+use registry::SharedFile;
+use events::EventChannel;
+use pex::SourceBook;
+use storage::{self, Storage, SharedStorage, FileStorage};
+use rate::{RateEstimator, RATE_WINDOW};
 
-use helpers::calculate_block_size;
+#[cfg(feature = "mmap")]
+use memmap2::{Mmap, MmapMut};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMetadata {
-    /// SHA256 Hash of the files content and the blocks
+    /// Name of the file as it was shared
+    pub name: String,
+    /// Algorithm used to calculate the hashes below
+    pub algorithm: HashAlgorithm,
+    /// Hash of the files content and the blocks
     pub hash: (
         Vec<u8>,
         Vec<Vec<u8>>
@@ -22,91 +48,598 @@ pub struct FileMetadata {
     /// Total size of the file in bytes
     pub size: usize,
     /// Trailing bytes
-    pub trailing_bytes: Vec<u8>
+    pub trailing_bytes: Vec<u8>,
+    /// Parameters of the encryption that was applied to the content before it was hashed, `None` if it is distributed
+    /// as plaintext
+    pub encryption: Option<Encryption>
+}
+
+/// Condition of a block of a local copy
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BlockState {
+    Intact,
+    /// The block is present but does not match its hash
+    Corrupt,
+    /// The local copy ends before the block or can not be read
+    Missing
 }
 
 pub struct File {
     pub metadata: FileMetadata,
     /// Block ID and people downloading it currently
     pub blocks: Vec<(usize, usize)>,
-    pub local_path: PathBuf
+    pub local_path: PathBuf,
+    /// Further local paths with the same content, e.g. because the file has been shared from several places. Blocks
+    /// are read from them if the local path can not be read.
+    pub copies: Vec<PathBuf>,
+    /// Whether serving the blocks of this file is paused
+    pub paused: bool,
+    /// Amount of bytes of this file that have been served to other nodes
+    pub uploaded: usize,
+    /// Key to encrypt the local plaintext copy with whenever it is served or verified, `None` if the local copy is
+    /// stored as it is distributed
+    pub key: Option<Key>,
+    /// Access control list of this file, checked in addition to the one of the node
+    pub acl: Option<Acl>,
+    /// Storage the blocks are read from, the local path and its copies unless a download uses another storage
+    pub storage: SharedStorage
 }
 
 pub struct FileHandle {
     pub file: Arc<Mutex<File>>,
-    pub sources: Vec<Vec<IpAddr>>
+    /// Nodes that have each block
+    pub sources: Vec<Vec<NodeId>>,
+    /// Time at which every source last confirmed the blocks it has, its entries in `sources` expire once that is longer
+    /// ago than `max_source_age`
+    pub source_confirmed: HashMap<NodeId, Instant>,
+    /// Age after which the blocks announced by a source are no longer trusted, `None` trusts them indefinitely
+    pub max_source_age: Option<Duration>,
+    /// Amount of connections to every source that failed in a row
+    pub connection_failures: HashMap<NodeId, usize>,
+    /// Sources other nodes told about along with the time they did and the blocks of the sources, they are kept when
+    /// the sources are updated
+    pub exchanged_sources: HashMap<NodeId, (Instant, Vec<usize>)>,
+    /// Time at which every source has last been asked for the other sources it knows
+    pub peers_exchanged: HashMap<NodeId, Instant>,
+    /// Ask the sources for the other sources they know
+    pub peer_exchange: bool,
+    /// Book the sources are recorded in to pass them on to other nodes
+    pub source_book: Option<SourceBook>,
+    /// Nodes that responded to queries for this file
+    pub peers: Arc<Mutex<PeerRegistry>>,
+    /// Whether a block has been downloaded and verified
+    pub completed: Vec<bool>,
+    /// Priorities of block ranges, later entries take precedence over earlier ones
+    pub priorities: Vec<(Range<usize>, Priority)>,
+    /// Storage the blocks are written to, the local path unless another one has been given
+    pub storage: SharedStorage,
+    /// Whether the storage has been allocated, which happens once the download starts
+    pub allocated: bool,
+    /// Whether the storage failed to store a block, the download is given up then
+    pub storage_failed: bool,
+    /// Whether requesting new blocks is paused
+    pub paused: bool,
+    /// Connections to sources that are kept alive between block requests
+    pub connections: HashMap<NodeId, TcpStream>,
+    /// Maximum amount of outstanding block requests per connection
+    pub pipeline_depth: usize,
+    /// Beginnings of blocks whose transfer broke off, only the rest of them is requested from the next source. They are
+    /// verified once the block is complete.
+    pub partial_blocks: HashMap<usize, Vec<u8>>,
+    /// Key to decrypt the file with once it is complete, encrypted files are kept as they are distributed without it
+    pub key: Option<Key>,
+    /// ID this node introduces itself with to sources
+    pub node_id: NodeId,
+    /// Pre-shared token presented to sources that require one
+    pub token: Option<String>,
+    /// Download limit shared with other handles
+    pub limiter: Limiter,
+    /// Socket to query block lists with, shared with other handles. A socket of its own is bound if it is missing.
+    pub queries: Option<BlockListQueries>,
+    /// Multicast group block lists are queried in and how far the queries travel
+    pub scope: MulticastScope,
+    /// Live table of the blocks other nodes have, sources are polled if it is missing or lacks the file
+    pub availability: Option<Arc<Mutex<AvailabilityTable>>>,
+    /// Time at which the sources have been taken from the availability table
+    pub sources_updated: Option<Instant>,
+    /// Index of the blocks in local files which are used before downloading them and which the file is added to once it
+    /// is complete
+    pub cache: Option<Arc<Mutex<BlockCache>>>,
+    /// How long block list queries wait for responses
+    pub discovery: DiscoveryWindow,
+    /// Time a source may take to deliver a block before it is requested from another source
+    pub block_deadline: BlockDeadline,
+    /// Whether the file is downloaded as part of a distribution, equally rare blocks are then picked in an order of
+    /// this node's own and blocks without sources are waited for since other downloaders pick them up over time
+    pub distributed: bool,
+    /// Time since which no block had a source, only tracked while distributing
+    pub stalled_since: Option<Instant>,
+    /// Shared entry of the file that other nodes download the completed blocks from while it is still downloading
+    pub seed: Option<SharedFile>,
+    /// Records of local files the metadata is added to once the download is complete
+    pub library: Option<Library>,
+    /// What every source contributed to the download so far
+    pub usage: HashMap<NodeId, SourceUsage>,
+    /// Rate at which blocks arrive from the network
+    pub rate: RateEstimator,
+    /// Rate at which blocks arrive from every source
+    pub source_rates: HashMap<NodeId, RateEstimator>,
+    /// Time at which the rates have last been published to the events
+    pub rate_published: Option<Instant>,
+    /// What this node takes part in, presented to the sources in the handshake
+    pub capabilities: Capabilities,
+    /// Channel the completed blocks are published to
+    pub events: Option<EventChannel>
 }
 
 impl File {
-    pub fn to_handle(self) -> FileHandle {
+    pub fn to_handle(self, peers: Arc<Mutex<PeerRegistry>>) -> FileHandle {
+        let storage = self.storage.clone();
         FileHandle {
+            completed: vec![false; block_count(self.metadata.size)],
             file: Arc::new(Mutex::new(self)),
-            sources: Vec::new()
+            sources: Vec::new(),
+            source_confirmed: HashMap::new(),
+            max_source_age: Some(Duration::from_secs(DEFAULT_MAX_SOURCE_AGE)),
+            connection_failures: HashMap::new(),
+            exchanged_sources: HashMap::new(),
+            peers_exchanged: HashMap::new(),
+            peer_exchange: true,
+            source_book: None,
+            peers: peers,
+            priorities: Vec::new(),
+            storage: storage,
+            allocated: false,
+            storage_failed: false,
+            paused: false,
+            connections: HashMap::new(),
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+            partial_blocks: HashMap::new(),
+            key: None,
+            node_id: generate_node_id(),
+            token: None,
+            limiter: Limiter::new(),
+            queries: None,
+            scope: MulticastScope::new(),
+            availability: None,
+            sources_updated: None,
+            cache: None,
+            discovery: DiscoveryWindow::new(),
+            block_deadline: BlockDeadline::new(),
+            distributed: false,
+            stalled_since: None,
+            seed: None,
+            library: None,
+            usage: HashMap::new(),
+            rate: RateEstimator::new(Duration::from_secs(RATE_WINDOW)),
+            source_rates: HashMap::new(),
+            rate_published: None,
+            capabilities: Capabilities::of(Mode::Full),
+            events: None
         }
     }
 
-    pub fn prepare(path: PathBuf) -> File {
-        let f = F::open(path.clone()).unwrap();
-        let size = f.metadata().unwrap().len();
+    /// Hash a local file and split it into blocks so it can be shared, fails if the file can not be read
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate ddp;
+    /// # use std::io::Write;
+    /// # use ddp::file::File;
+    /// # use ddp::helpers::{HashAlgorithm, trailing_length, block_count};
+    /// # fn main() {
+    /// let path = std::env::temp_dir().join("ddp-prepare-example");
+    /// for size in vec![0, 1, 1000, 2001, 4000] {
+    ///     std::fs::File::create(&path).unwrap().write_all(&vec![7; size]).unwrap();
+    ///     let file = File::prepare(path.clone(), HashAlgorithm::Sha256).unwrap();
+    ///     assert_eq!(file.metadata.trailing_bytes.len(), trailing_length(size));
+    ///     assert_eq!(file.metadata.hash.1.len(), block_count(size));
+    ///     assert!(file.verify());
+    /// }
+    /// # }
+    /// ```
+    pub fn prepare(path: PathBuf, algorithm: HashAlgorithm) -> io::Result<File> {
+        File::prepare_with(path, algorithm, None)
+    }
+
+    /// Hash a local file like `prepare` but encrypt its content with `key` before, so only holders of the key can read
+    /// it while the rest of the network distributes the ciphertext
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate ddp;
+    /// # use std::io::Write;
+    /// # use ddp::file::File;
+    /// # use ddp::crypto::Key;
+    /// # use ddp::helpers::HashAlgorithm;
+    /// # fn main() {
+    /// let path = std::env::temp_dir().join("ddp-prepare-encrypted-example");
+    /// std::fs::File::create(&path).unwrap().write_all(&vec![7; 3000]).unwrap();
+    /// let plain = File::prepare(path.clone(), HashAlgorithm::Sha256).unwrap();
+    /// let encrypted = File::prepare_encrypted(path.clone(), HashAlgorithm::Sha256, Key::generate()).unwrap();
+    /// assert!(encrypted.metadata.hash.0 != plain.metadata.hash.0);
+    /// assert!(encrypted.get_block(0) != plain.get_block(0));
+    /// assert!(encrypted.verify());
+    /// # }
+    /// ```
+    pub fn prepare_encrypted(path: PathBuf, algorithm: HashAlgorithm, key: Key) -> io::Result<File> {
+        File::prepare_with(path, algorithm, Some(key))
+    }
+
+    fn prepare_with(path: PathBuf, algorithm: HashAlgorithm, key: Option<Key>) -> io::Result<File> {
+        let encryption = key.as_ref().map(Encryption::new);
+        let encrypt = |offset: usize, data: &mut [u8]| {
+            if let (Some(key), Some(encryption)) = (key.as_ref(), encryption.as_ref()) {
+                apply_keystream(key, &encryption.nonce, offset as u64, data);
+            }
+        };
+
+        let f = F::open(path.clone())?;
+        let size = f.metadata()?.len();
         let block_size = calculate_block_size(size as usize);
         let mut pb = ProgressBar::new(size); pb.set_units(Units::Bytes);
         let reader = BufReader::with_capacity(block_size, f);
 
-        println!("File size: {}, Block size: {}", size, block_size);
+        println!("File size: {}, Block size: {}, Hash: {:?}", size, block_size, algorithm);
 
         let mut block_hashes = Vec::new();
 
-        let mut hash = Sha256::new();
-        let mut block_hash = Sha256::new();
+        let mut hash = algorithm.hasher();
+        let mut block_hash = algorithm.hasher();
         let mut block = Vec::new();
         for (id, byte) in reader.bytes().enumerate() {
-            match byte {
-                Ok(byte) => {
-                    if id % block_size == 0 && block.len() > 0 {
-                        pb.add(block_size as u64);
-
-                        // Create block hash
-                        block_hash.input(&block);
-                        let mut buf = vec![0; block_hash.output_bytes()];
-                        block_hash.result(&mut buf);
-                        block_hashes.push(buf.clone());
-                        block_hash.reset();
-
-                        // Add to main hash and clear block
-                        hash.input(&block);
-                        block.clear();
-                    }
-                    block.push(byte);
-                },
-                Err(e) => { exit!(1, "Error occurred whilst reading file ({:?})", e); }
+            let byte = byte?;
+            if id % block_size == 0 && !block.is_empty() {
+                pb.add(block_size as u64);
+                encrypt(id - block.len(), &mut block);
+
+                // Create block hash
+                block_hash.update(&block);
+                block_hashes.push(block_hash.finalize_reset());
+
+                // Add to main hash and clear block
+                hash.update(&block);
+                block.clear();
             }
+            block.push(byte);
         }
         pb.add(block_size as u64);
-        hash.input(&block);
-        let mut hash_res = vec![0; hash.output_bytes()];
-        hash.result(&mut hash_res);
+        encrypt(size as usize - block.len(), &mut block);
+        hash.update(&block);
+        let hash_res = hash.finalize_reset();
 
+        let mut file = File::from_local(FileMetadata {
+            name: path.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned()),
+            algorithm: algorithm,
+            hash: (
+                hash_res,
+                block_hashes
+            ),
+            trailing_bytes: block,
+            size: size as usize,
+            encryption: encryption.clone()
+        }, path.canonicalize()?);
+        file.key = key.clone();
+        Ok(file)
+    }
+
+    /// Create a shared file from its metadata and a complete local copy
+    pub fn from_local(metadata: FileMetadata, local_path: PathBuf) -> File {
         File {
-            blocks: (0..block_hashes.len()).map(|i| (i, 0)).collect(),
-            local_path: path.canonicalize().unwrap(),
-            metadata: FileMetadata {
-                hash: (
-                    hash_res,
-                    block_hashes
-                ),
-                trailing_bytes: block,
-                size: size as usize
-            }
+            blocks: (0..metadata.hash.1.len()).map(|i| (i, 0)).collect(),
+            storage: storage::shared(FileStorage::open(local_path.clone(), Vec::new())),
+            local_path: local_path,
+            copies: Vec::new(),
+            paused: false,
+            uploaded: 0,
+            key: None,
+            acl: None,
+            metadata: metadata
         }
     }
 
+    /// Create a file that is yet to be downloaded to `local_path` from its metadata
+    pub fn from_remote(metadata: FileMetadata, local_path: PathBuf) -> File {
+        File {
+            metadata: metadata,
+            blocks: Vec::new(),
+            storage: storage::shared(FileStorage::new(local_path.clone())),
+            local_path: local_path,
+            copies: Vec::new(),
+            paused: false,
+            uploaded: 0,
+            key: None,
+            acl: None
+        }
+    }
+
+    /// Add another local path with the same content, returns false if the file is known under that path already
+    pub fn add_copy(&mut self, path: PathBuf) -> bool {
+        if self.local_path == path || self.copies.contains(&path) { return false }
+        self.copies.push(path);
+        self.reopen();
+        true
+    }
+
+    /// Forget a local path of the file, the oldest remaining copy takes over if it was the local path. Returns false if
+    /// no local path is left.
+    pub fn remove_copy(&mut self, path: &Path) -> bool {
+        self.copies.retain(|copy| copy != path);
+        if self.local_path != path { return true }
+        if self.copies.is_empty() { return false }
+        self.local_path = self.copies.remove(0);
+        self.reopen();
+        true
+    }
+
+    /// Read from the current local path and copies, unless the blocks are stored elsewhere or still being downloaded
+    fn reopen(&mut self) {
+        if self.blocks.len() != self.metadata.hash.1.len() || self.storage.lock().unwrap().local_path().is_none() { return }
+        self.storage = storage::shared(FileStorage::open(self.local_path.clone(), self.copies.clone()));
+    }
+
+    /// Whether the local copy contains the given block, files that are still downloading only contain some
+    pub fn has_block(&self, block_id: usize) -> bool {
+        self.blocks.len() == self.metadata.hash.1.len() || self.blocks.iter().any(|&(id, _)| id == block_id)
+    }
+
     pub fn get_block(&self, block_id: usize) -> Vec<u8> {
-        let f = F::open(self.local_path.as_path()).unwrap();
-        let block_size = calculate_block_size(f.metadata().unwrap().len() as usize);
-        let mut reader = BufReader::with_capacity(block_size, f);
-        reader.seek(SeekFrom::Start((block_size * block_id) as u64)).unwrap();
-        let mut buf = vec![0; block_size];
-        reader.read_exact(&mut buf).unwrap();
-        buf
+        self.try_get_block(block_id).unwrap()
+    }
+
+    /// Read a block like `get_block` but return an error if the local copy can not be read
+    pub fn try_get_block(&self, block_id: usize) -> io::Result<Vec<u8>> {
+        self.try_get_block_range(block_id, 0..calculate_block_size(self.metadata.size))
+    }
+
+    /// Read the bytes of a block within `range` without reading the rest of the block, the range has to lie within the
+    /// block
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate ddp;
+    /// # use std::io::Write;
+    /// # use ddp::file::File;
+    /// # use ddp::helpers::HashAlgorithm;
+    /// # fn main() {
+    /// let path = std::env::temp_dir().join("ddp-block-range-example");
+    /// std::fs::File::create(&path).unwrap().write_all(&(0..300000).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+    /// let file = File::prepare(path, HashAlgorithm::Sha256).unwrap();
+    /// let block = file.get_block(1);
+    /// assert_eq!(file.try_get_block_range(1, 10..20).unwrap(), &block[10..20]);
+    /// # }
+    /// ```
+    pub fn try_get_block_range(&self, block_id: usize, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let offset = block_offset(self.metadata.size, block_id) + range.start as u64;
+        let mut buf = self.read_block(offset, range.end - range.start)?;
+        self.encrypt(offset, &mut buf);
+        Ok(buf)
+    }
+
+    fn read_block(&self, offset: u64, block_size: usize) -> io::Result<Vec<u8>> {
+        self.storage.lock().unwrap().read_block(offset, block_size)
+    }
+
+    /// Turn a part of the local plaintext copy into the distributed ciphertext, does nothing if there is no key
+    fn encrypt(&self, offset: u64, data: &mut [u8]) {
+        if let (Some(key), Some(encryption)) = (self.key.as_ref(), self.metadata.encryption.as_ref()) {
+            apply_keystream(key, &encryption.nonce, offset, data);
+        }
+    }
+
+    /// Re-hash the local copy of the file and compare it with the content hash of the metadata
+    pub fn verify(&self) -> bool {
+        let size = self.metadata.size;
+        let mut hash = self.metadata.algorithm.hasher();
+        let block_size = calculate_block_size(size);
+        let mut offset = 0;
+        while offset < size {
+            let mut buf = match self.read_block(offset as u64, min(block_size, size - offset)) { Ok(buf) => buf, Err(_) => return false };
+            self.encrypt(offset as u64, &mut buf);
+            hash.update(&buf);
+            offset += buf.len();
+        }
+        hash.finalize_reset() == self.metadata.hash.0
+    }
+
+    /// Check every block of the local copy against its hash, the state of the trailing bytes is appended as if they were
+    /// another block
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate ddp;
+    /// # use std::io::Write;
+    /// # use ddp::file::{File, BlockState};
+    /// # use ddp::helpers::HashAlgorithm;
+    /// # fn main() {
+    /// let path = std::env::temp_dir().join("ddp-audit-example");
+    /// std::fs::File::create(&path).unwrap().write_all(&vec![7; 3000]).unwrap();
+    /// let file = File::prepare(path.clone(), HashAlgorithm::Sha256).unwrap();
+    /// assert!(file.audit().iter().all(|state| *state == BlockState::Intact));
+    ///
+    /// // Flip the first byte and cut off the end
+    /// let mut data = vec![7; 2000];
+    /// data[0] = 8;
+    /// std::fs::File::create(&path).unwrap().write_all(&data).unwrap();
+    /// let states = file.audit();
+    /// assert_eq!(states[0], BlockState::Corrupt);
+    /// assert_eq!(states[1], BlockState::Intact);
+    /// assert_eq!(*states.last().unwrap(), BlockState::Missing);
+    /// # }
+    /// ```
+    pub fn audit(&self) -> Vec<BlockState> {
+        let len = F::open(self.local_path.as_path()).and_then(|f| f.metadata()).map_or(0, |m| m.len());
+        let size = self.metadata.size;
+        let block_size = calculate_block_size(size);
+        let mut states = self.metadata.hash.1.iter().enumerate().map(|(id, hash)| {
+            if block_offset(size, id) + block_size as u64 > len { return BlockState::Missing }
+            match self.try_get_block(id) {
+                Ok(ref data) if &self.metadata.algorithm.digest(data) == hash => BlockState::Intact,
+                Ok(_) => BlockState::Corrupt,
+                Err(_) => BlockState::Missing
+            }
+        }).collect::<Vec<_>>();
+
+        let offset = trailing_offset(size);
+        let trailing = self.metadata.trailing_bytes.len();
+        states.push(if offset + trailing as u64 > len { BlockState::Missing } else {
+            match self.read_block(offset, trailing) {
+                Ok(mut data) => {
+                    self.encrypt(offset, &mut data);
+                    if data == self.metadata.trailing_bytes { BlockState::Intact } else { BlockState::Corrupt }
+                },
+                Err(_) => BlockState::Missing
+            }
+        });
+        states
+    }
+}
+
+impl FileHandle {
+    /// Retrieve the priority of a block, blocks without an explicit priority are `Priority::Normal`
+    pub fn block_priority(&self, block_id: usize) -> Priority {
+        self.priorities.iter().rev()
+            .find(|&(blocks, _)| blocks.contains(&block_id))
+            .map_or(Priority::Normal, |&(_, priority)| priority)
+    }
+
+    /// Change the priority of a range of blocks
+    pub fn prioritize_blocks(&mut self, blocks: Range<usize>, priority: Priority) {
+        self.priorities.push((blocks, priority));
+    }
+
+    /// Change the priority of all blocks overlapping a range of bytes
+    pub fn prioritize_bytes(&mut self, bytes: Range<usize>, priority: Priority) {
+        let block_size = calculate_block_size(self.file.lock().unwrap().metadata.size);
+        self.prioritize_blocks(bytes.start / block_size..bytes.end.div_ceil(block_size), priority);
+    }
+
+    /// Stop requesting new blocks while keeping the already verified ones, optionally stop serving the file as well
+    pub fn pause(&mut self, uploads: bool) {
+        self.paused = true;
+        if uploads { self.file.lock().unwrap().paused = true; }
+    }
+
+    /// Continue requesting blocks and serving the file
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.file.lock().unwrap().paused = false;
+    }
+
+    /// Whether all blocks have been downloaded and verified
+    pub fn is_complete(&self) -> bool {
+        self.completed.iter().all(|completed| *completed)
+    }
+
+    /// Decrypt the file with `key` once it is complete, returns false if the file has not been encrypted with it
+    pub fn decrypt_with(&mut self, key: Key) -> bool {
+        let matches = self.file.lock().unwrap().metadata.encryption.as_ref().is_some_and(|e| e.matches(&key));
+        if matches { self.key = Some(key); }
+        matches
+    }
+
+    /// Write the blocks to `storage` instead of the local path, they are served from it as well
+    pub fn with_storage<S: Storage + 'static>(mut self, storage: S) -> FileHandle {
+        let storage = storage::shared(storage);
+        self.file.lock().unwrap().storage = storage.clone();
+        self.storage = storage;
+        self.allocated = false;
+        self
+    }
+
+    /// Whether the blocks are written to the local path, other storages are not indexed by the block cache and library
+    pub fn stores_locally(&self) -> bool {
+        self.file.lock().unwrap().storage.lock().unwrap().local_path().is_some()
+    }
+
+    /// Write data to the storage at the given offset, the storage has to be allocated already
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.storage.lock().unwrap().write_block(offset, data)
+    }
+
+    /// Persist all data written to the storage
+    pub fn finalize(&mut self) -> io::Result<()> {
+        self.storage.lock().unwrap().finalize()
+    }
+}
+
+/// Write `size` bytes to a file called `name` in the temporary directory and prepare it, used by the examples
+#[doc(hidden)]
+pub fn example_file(name: &str, size: usize) -> File {
+    let path = ::std::env::temp_dir().join(name);
+    ::std::fs::write(&path, vec![7; size]).unwrap();
+    File::prepare(path, HashAlgorithm::Sha256).unwrap()
+}
+
+/// Map a complete local file into memory to serve blocks without reading them through a buffer, returns `None` if the
+/// file can not be mapped
+///
+/// The file must not be truncated by another process while it is mapped.
+#[cfg(feature = "mmap")]
+pub fn map_file(path: &Path) -> Option<Mmap> {
+    let f = F::open(path).ok()?;
+    // Empty files can not be mapped
+    if f.metadata().ok()?.len() == 0 { return None }
+    unsafe { Mmap::map(&f) }.ok()
+}
+
+/// Map an allocated output file into memory to write blocks without seeking, returns `None` if it can not be mapped
+#[cfg(feature = "mmap")]
+pub fn map_output(f: &F) -> Option<MmapMut> {
+    if f.metadata().ok()?.len() == 0 { return None }
+    unsafe { MmapMut::map_mut(f) }.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use helpers::trailing_length;
+
+    /// Boundary sizes: empty, a single block, a block and a byte, whole blocks and whole blocks and a byte
+    const SIZES: [usize; 5] = [0, 1, 2, 999999, 1000000];
+
+    /// Prepare a file of `size` bytes, every test uses files of its own
+    fn prepared(test: &str, size: usize) -> File {
+        let path = ::std::env::temp_dir().join(format!("ddp-test-{}-{}", test, size));
+        fs::write(&path, (0..size).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+        File::prepare(path, HashAlgorithm::Sha256).unwrap()
+    }
+
+    #[test]
+    fn boundary_sizes_verify() {
+        for &size in SIZES.iter() {
+            let file = prepared("verify", size);
+            assert_eq!(file.metadata.trailing_bytes.len(), trailing_length(size));
+            assert_eq!(file.metadata.hash.1.len(), block_count(size));
+            assert!(file.verify());
+        }
+    }
+
+    #[test]
+    fn damaged_trailing_bytes_fail_verification() {
+        for &size in SIZES.iter().filter(|&&size| size > 0) {
+            let file = prepared("trailing", size);
+            let mut content = fs::read(&file.local_path).unwrap();
+            content[size - 1] ^= 0xff;
+            fs::write(&file.local_path, content).unwrap();
+            // The blocks are intact, only the content hash covers the trailing bytes
+            let file = File::from_local(file.metadata.clone(), file.local_path.clone());
+            assert!(!file.verify());
+        }
+    }
+
+    #[test]
+    fn truncated_file_fails_verification() {
+        for &size in SIZES.iter().filter(|&&size| size > 0) {
+            let file = prepared("truncated", size);
+            fs::OpenOptions::new().write(true).open(&file.local_path).unwrap().set_len(size as u64 - 1).unwrap();
+            let file = File::from_local(file.metadata.clone(), file.local_path.clone());
+            assert!(!file.verify());
+        }
     }
 }
@@ -0,0 +1,226 @@
+//! Gossip of block availability as an alternative to polling for block lists
+//!
+//! Nodes in gossip mode periodically multicast which blocks of their files they have. A full snapshot is sent every
+//! few rounds so new listeners catch up, in between only the blocks that became available are sent. Downloaders keep
+//! the updates in a live table from which the sources of every block are taken.
+use std::collections::HashMap;
+use std::thread::{spawn, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bincode::serialize;
+
+use announce::Message;
+use networking::MAX_DATAGRAM_SIZE;
+use peers::NodeId;
+use bitfield::BlockSet;
+use node::Node;
+
+/// Interval in seconds between two gossip rounds
+const GOSSIP_INTERVAL: u64 = 2;
+/// Every this many rounds a full snapshot is sent instead of the changes
+const SNAPSHOT_ROUNDS: u64 = 5;
+/// Time in seconds after which the availability of a node that stopped gossiping is forgotten
+const STALE_AFTER: u64 = 3 * SNAPSHOT_ROUNDS * GOSSIP_INTERVAL;
+/// Maximum amount of blocks of a gossiped file, snapshots of files with more blocks do not fit into a datagram
+const MAX_BLOCKS: usize = MAX_DATAGRAM_SIZE * 8;
+
+/// Blocks of a file a node has, multicast to every node in gossip mode
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Availability {
+    /// ID of the gossiping node
+    pub node_id: NodeId,
+    /// Port on which the gossiping node accepts queries and block requests
+    pub port: u16,
+    /// Hash of the file
+    pub hash: Vec<u8>,
+    /// Amount of blocks of the file
+    pub blocks: usize,
+    /// Number of this update, incremented with every update the node sends for the file
+    pub sequence: u64,
+    pub update: AvailabilityUpdate
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum AvailabilityUpdate {
+    /// The node has every block of the file
+    Complete,
+    /// All blocks the node has
+    Snapshot(BlockSet),
+    /// Blocks that became available since the previous update, each encoded as the distance to the previous block ID
+    Delta(Vec<u32>)
+}
+
+impl AvailabilityUpdate {
+    /// Encode the blocks a node has, `previous` are the blocks sent with the previous update
+    pub fn encode(available: &[bool], previous: Option<&[bool]>) -> AvailabilityUpdate {
+        match previous {
+            // Blocks that vanished can only be expressed by a snapshot
+            Some(previous) if previous.len() == available.len() && previous.iter().zip(available).all(|(p, a)| !p || *a) => {
+                let mut last = 0;
+                AvailabilityUpdate::Delta((0..available.len()).filter(|id| available[*id] && !previous[*id]).map(|id| {
+                    let delta = id - last;
+                    last = id;
+                    delta as u32
+                }).collect())
+            },
+            _ if available.iter().all(|a| *a) => AvailabilityUpdate::Complete,
+            _ => AvailabilityUpdate::Snapshot(BlockSet::from_bools(available))
+        }
+    }
+}
+
+struct NodeAvailability {
+    sequence: u64,
+    blocks: Vec<bool>,
+    updated: Instant
+}
+
+/// Live table of the blocks other nodes have, built from their gossip
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::gossip::{Availability, AvailabilityTable, AvailabilityUpdate};
+/// # fn main() {
+/// let mut table = AvailabilityTable::new();
+/// let update = |sequence, available: &[bool], previous: Option<&[bool]>| Availability {
+///     node_id: vec![1], port: 8888, hash: vec![42], blocks: 4, sequence: sequence,
+///     update: AvailabilityUpdate::encode(available, previous)
+/// };
+/// table.apply(update(1, &[true, false, false, false], None));
+/// table.apply(update(2, &[true, false, true, true], Some(&[true, false, false, false])));
+/// assert_eq!(table.sources(&vec![42], 4), Some(vec![vec![vec![1]], vec![], vec![vec![1]], vec![vec![1]]]));
+///
+/// // Changes that do not follow the last known update are ignored until the next snapshot arrives
+/// assert!(!table.apply(update(4, &[true; 4], Some(&[true, false, true, true]))));
+///
+/// // Block counts no snapshot could describe are rejected before anything is allocated
+/// assert!(!table.apply(Availability { blocks: usize::max_value(), ..update(5, &[true; 4], None) }));
+/// # }
+/// ```
+pub struct AvailabilityTable {
+    files: HashMap<Vec<u8>, HashMap<NodeId, NodeAvailability>>
+}
+
+impl Default for AvailabilityTable {
+    fn default() -> AvailabilityTable {
+        AvailabilityTable::new()
+    }
+}
+
+impl AvailabilityTable {
+    pub fn new() -> AvailabilityTable {
+        AvailabilityTable {
+            files: HashMap::new()
+        }
+    }
+
+    /// Apply an update, returns false if it does not fit the availability known so far or claims more blocks than a
+    /// gossiped file can have
+    pub fn apply(&mut self, availability: Availability) -> bool {
+        if availability.blocks > MAX_BLOCKS { return false }
+        let nodes = self.files.entry(availability.hash).or_default();
+        let count = availability.blocks;
+        let blocks = match availability.update {
+            AvailabilityUpdate::Complete => vec![true; count],
+            AvailabilityUpdate::Snapshot(set) => {
+                let mut blocks = vec![false; count];
+                for id in set.ids_within(count) { blocks[id] = true; }
+                blocks
+            },
+            AvailabilityUpdate::Delta(deltas) => {
+                let known = match nodes.get_mut(&availability.node_id) {
+                    Some(known) if known.sequence + 1 == availability.sequence && known.blocks.len() == count => known,
+                    _ => return false
+                };
+                let mut id = 0;
+                for delta in deltas {
+                    id += delta as usize;
+                    match known.blocks.get_mut(id) { Some(block) => *block = true, None => return false }
+                }
+                known.sequence = availability.sequence;
+                known.updated = Instant::now();
+                return true;
+            }
+        };
+        nodes.insert(availability.node_id, NodeAvailability {
+            sequence: availability.sequence,
+            blocks: blocks,
+            updated: Instant::now()
+        });
+        true
+    }
+
+    /// Retrieve the nodes that have each block of a file, `None` if no node gossiped about the file
+    pub fn sources(&self, hash: &Vec<u8>, blocks: usize) -> Option<Vec<Vec<NodeId>>> {
+        let nodes = match self.files.get(hash) {
+            Some(nodes) if !nodes.is_empty() => nodes,
+            _ => return None
+        };
+        let mut sources = vec![Vec::new(); blocks];
+        for (id, availability) in nodes.iter().filter(|&(_, a)| a.blocks.len() == blocks) {
+            for block in (0..blocks).filter(|block| availability.blocks[*block]) {
+                sources[block].push(id.clone());
+            }
+        }
+        Some(sources)
+    }
+
+    /// Forget the nodes that stopped gossiping
+    pub fn prune(&mut self) {
+        for nodes in self.files.values_mut() {
+            nodes.retain(|_, availability| availability.updated.elapsed() < Duration::from_secs(STALE_AFTER));
+        }
+        self.files.retain(|_, nodes| !nodes.is_empty());
+    }
+}
+
+/// Periodically multicast the availability of the blocks of all files this node serves
+pub fn start_gossip(node: Node) -> JoinHandle<()> {
+    spawn(move || {
+        let sock = node.socket().create_handle();
+        // Sequence number and blocks of the last update by file
+        let mut sent: HashMap<Vec<u8>, (u64, Vec<bool>)> = HashMap::new();
+        let mut round = 0u64;
+        loop {
+            let snapshot = round.is_multiple_of(SNAPSHOT_ROUNDS);
+            let updates = {
+                let files = node.files.list();
+                // Files with an access control list of their own are not revealed to everybody
+                files.iter().map(|file| file.read().unwrap())
+                    .filter(|file| !file.paused && file.acl.is_none() && node.config().may_reveal(&file.metadata.hash.0)).filter_map(|file| {
+                    let mut available = vec![false; file.metadata.hash.1.len()];
+                    let blocks = file.blocks.iter().map(|&(id, _)| id).collect();
+                    for id in node.advertised_blocks(&file.metadata.hash.0, blocks, available.len()) {
+                        if id < available.len() { available[id] = true; }
+                    }
+
+                    let hash = file.metadata.hash.0.clone();
+                    let previous = sent.get(&hash).map(|(_, blocks)| blocks.clone());
+                    if !snapshot && previous.as_ref() == Some(&available) { return None }
+                    let update = AvailabilityUpdate::encode(&available, if snapshot { None } else { previous.as_ref().map(|p| &p[..]) });
+                    let sequence = sent.get(&hash).map_or(0, |&(sequence, _)| sequence + 1);
+                    sent.insert(hash.clone(), (sequence, available.clone()));
+                    Some(Availability {
+                        node_id: node.id.clone(),
+                        port: node.port(),
+                        hash: hash,
+                        blocks: available.len(),
+                        sequence: sequence,
+                        update: update
+                    })
+                }).collect::<Vec<_>>()
+            };
+
+            for update in updates {
+                if let Err(e) = sock.send_to_multicast(&serialize(&Message::Availability(update)).unwrap()) {
+                    warn!("Failed to gossip the available blocks: {}", e);
+                }
+            }
+            node.availability.lock().unwrap().prune();
+            round += 1;
+            sleep(Duration::from_secs(GOSSIP_INTERVAL));
+        }
+    })
+}
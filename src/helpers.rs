@@ -1,18 +1,108 @@
-use sha2::sha2::Sha256;
-use sha2::Digest;
+use std::str::FromStr;
 
-pub fn to_hex_string(bytes: &Vec<u8>) -> String {
+use sha2::{Sha256, Digest};
+use blake3;
+
+/// Length in bytes of the digests of every supported hash algorithm
+pub const HASH_LENGTH: usize = 32;
+
+/// Algorithm used to hash the content and the blocks of a file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    /// SHA256, compatible with nodes that do not support anything else
+    Sha256,
+    /// BLAKE3, considerably faster to prepare and verify on fast disks
+    Blake3
+}
+
+/// Incremental hash function that can be plugged into the preparation and verification of files
+pub trait BlockHasher {
+    /// Feed data into the hash function
+    fn update(&mut self, data: &[u8]);
+    /// Return the digest of all data fed so far and reset the hash function
+    fn finalize_reset(&mut self) -> Vec<u8>;
+}
+
+impl BlockHasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finalize_reset(&mut self) -> Vec<u8> {
+        Digest::finalize_reset(self).to_vec()
+    }
+}
+
+impl BlockHasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finalize_reset(&mut self) -> Vec<u8> {
+        let hash = self.finalize().as_bytes().to_vec();
+        self.reset();
+        hash
+    }
+}
+
+impl HashAlgorithm {
+    /// Create a new incremental hash function for this algorithm
+    pub fn hasher(&self) -> Box<dyn BlockHasher> {
+        match *self {
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new())
+        }
+    }
+
+    /// Hash a chunk of data in one go
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize_reset()
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashAlgorithm, String> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err(format!("Unknown hash algorithm '{}'", s))
+        }
+    }
+}
+
+pub fn to_hex_string(bytes: &[u8]) -> String {
     bytes.chunks(8).map(|c| {
         c.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join("")
     }).collect::<Vec<String>>().join("-")
 }
 
+/// Parse a hex string as produced by `to_hex_string`, the dashes are optional
+pub fn from_hex_string(string: &str) -> Option<Vec<u8>> {
+    let digits = string.chars().filter(|c| *c != '-').collect::<Vec<_>>();
+    if digits.len() % 2 != 0 { return None }
+    digits.chunks(2).map(|pair| {
+        u8::from_str_radix(&pair.iter().cloned().collect::<String>(), 16).ok()
+    }).collect()
+}
+
+/// Format an amount of bytes with a binary unit, e.g. `1.5 MiB`
+pub fn format_bytes(bytes: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{:.0} {}", value, units[unit]) } else { format!("{:.1} {}", value, units[unit]) }
+}
+
 pub fn generate_uuid(input: &String) -> Vec<u8> {
-    let mut hash = Sha256::new();
-    hash.input_str(&input);
-    let mut buf = vec![0; hash.output_bytes()];
-    hash.result(&mut buf);
-    buf
+    Sha256::digest(input.as_bytes()).to_vec()
 }
 
 pub fn calculate_block_size(total_size: usize) -> usize {
@@ -25,11 +115,52 @@ pub fn calculate_block_size(total_size: usize) -> usize {
     block_size - 1
 }
 
-/// Panic with a given error code and print an optional message
+/// Calculate the length of the trailing bytes of a file which are sent along with the metadata instead of as a block
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::helpers::{calculate_block_size, trailing_length, block_count, trailing_offset};
+/// # fn main() {
+/// for size in vec![0, 1, 2, 999, 1000, 1001, 2000, 2001, 2500, 1000000, 1000001, 1999999, 2000000] {
+///     let block_size = calculate_block_size(size);
+///     let tail = trailing_length(size);
+///     // The blocks and the trailing bytes cover the whole file without overlapping
+///     assert_eq!(block_count(size) * block_size + tail, size);
+///     assert_eq!(trailing_offset(size) as usize + tail, size);
+///     // The trailing bytes never exceed a block and are only empty for empty files
+///     assert!(tail <= block_size);
+///     assert_eq!(tail == 0, size == 0);
+/// }
+/// # }
+/// ```
+pub fn trailing_length(total_size: usize) -> usize {
+    if total_size == 0 { return 0 }
+    (total_size - 1) % calculate_block_size(total_size) + 1
+}
+
+/// Calculate the amount of blocks that are transferred individually (excluding the trailing bytes)
+pub fn block_count(total_size: usize) -> usize {
+    (total_size - trailing_length(total_size)) / calculate_block_size(total_size)
+}
+
+/// Calculate the offset of a block within a file
+pub fn block_offset(total_size: usize, block_id: usize) -> u64 {
+    (block_id * calculate_block_size(total_size)) as u64
+}
+
+/// Calculate the offset of the trailing bytes within a file
+pub fn trailing_offset(total_size: usize) -> u64 {
+    (total_size - trailing_length(total_size)) as u64
+}
+
+/// Panic with a given error code and print an optional message, fatal errors use `fail!` instead so their exit codes
+/// are the documented ones of `errors::Error`
 /// # Examples
 ///
 /// ```should_panic
-/// # #[macro_use] extern crate structures;
+/// # #[macro_use] extern crate ddp;
 /// # #[macro_use] extern crate log;
 /// # fn main() {
 /// // An error code is required
@@ -38,7 +169,7 @@ pub fn calculate_block_size(total_size: usize) -> usize {
 /// ```
 ///
 /// ```should_panic
-/// # #[macro_use] extern crate structures;
+/// # #[macro_use] extern crate ddp;
 /// # #[macro_use] extern crate log;
 /// # fn main() {
 /// // Additionally you can provide an error message
@@ -47,7 +178,7 @@ pub fn calculate_block_size(total_size: usize) -> usize {
 /// ```
 ///
 /// ```should_panic
-/// # #[macro_use] extern crate structures;
+/// # #[macro_use] extern crate ddp;
 /// # #[macro_use] extern crate log;
 /// # fn main() {
 /// // It's even possible to use format arguments
@@ -69,3 +200,58 @@ macro_rules! exit {
         exit!($code, format!($res, $($arg)*));
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_size_grows_with_the_file() {
+        assert_eq!(calculate_block_size(0), 1);
+        assert_eq!(calculate_block_size(2001), 1);
+        assert_eq!(calculate_block_size(2002), 2);
+        assert_eq!(calculate_block_size(1000000), 999);
+    }
+
+    #[test]
+    fn empty_file() {
+        assert_eq!(trailing_length(0), 0);
+        assert_eq!(block_count(0), 0);
+        assert_eq!(trailing_offset(0), 0);
+    }
+
+    #[test]
+    fn file_of_exactly_one_block() {
+        // The only block is sent as the trailing bytes
+        assert_eq!(calculate_block_size(1), 1);
+        assert_eq!(trailing_length(1), 1);
+        assert_eq!(block_count(1), 0);
+        assert_eq!(trailing_offset(1), 0);
+    }
+
+    #[test]
+    fn file_of_one_block_and_one_byte() {
+        assert_eq!(calculate_block_size(2), 1);
+        assert_eq!(block_count(2), 1);
+        assert_eq!(block_offset(2, 0), 0);
+        assert_eq!(trailing_length(2), 1);
+        assert_eq!(trailing_offset(2), 1);
+    }
+
+    #[test]
+    fn file_of_whole_blocks() {
+        // 999999 bytes are 1001 blocks of 999 bytes, the last of which becomes the trailing bytes
+        assert_eq!(trailing_length(999999), 999);
+        assert_eq!(block_count(999999), 1000);
+        assert_eq!(block_offset(999999, 999), 998001);
+        assert_eq!(trailing_offset(999999), 999000);
+    }
+
+    #[test]
+    fn file_of_whole_blocks_and_one_byte() {
+        assert_eq!(trailing_length(1000000), 1);
+        assert_eq!(block_count(1000000), 1001);
+        assert_eq!(block_offset(1000000, 1000), 999000);
+        assert_eq!(trailing_offset(1000000), 999999);
+    }
+}
@@ -0,0 +1,183 @@
+//! History of the finished downloads of this node, kept in the state directory for capacity planning and to spot
+//! sources that are slow time and again
+//!
+//! Every finished download is appended to the history file as a line of JSON, so earlier entries survive a crash while
+//! writing and the history can be processed with other tools.
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use ext_time::{at, get_time, Timespec};
+use serde_json;
+
+use hooks::Outcome;
+
+/// Name of the file within the state directory that holds the history
+const HISTORY_FILE: &'static str = "history.jsonl";
+
+/// What a single source contributed to a download
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SourceUsage {
+    /// Amount of blocks received from the source
+    pub blocks: usize,
+    /// Amount of bytes received from the source
+    pub bytes: usize,
+    /// Amount of blocks abandoned because the source delivered them too slowly
+    pub timeouts: usize
+}
+
+/// Contribution of a source to a download as recorded in the history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerUsage {
+    /// Hex encoded ID of the source
+    pub node_id: String,
+    pub blocks: usize,
+    pub bytes: u64,
+    pub timeouts: usize,
+    /// Average throughput of the source in bytes per second as measured by the end of the download
+    pub throughput: f64
+}
+
+/// A finished download
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Hex encoded hash of the file
+    pub hash: String,
+    pub name: String,
+    pub path: PathBuf,
+    /// Size of the file in bytes
+    pub size: u64,
+    pub outcome: Outcome,
+    /// Unix time in seconds at which the download finished
+    pub finished: i64,
+    /// Seconds since the download has been queued
+    pub duration: f64,
+    /// Sources that delivered blocks, most bytes first
+    pub peers: Vec<PeerUsage>
+}
+
+impl HistoryEntry {
+    /// Create an entry of a download that finishes now
+    pub fn new(hash: String, name: String, path: PathBuf, size: u64, outcome: Outcome, duration: f64) -> HistoryEntry {
+        HistoryEntry {
+            hash: hash,
+            name: name,
+            path: path,
+            size: size,
+            outcome: outcome,
+            finished: get_time().sec,
+            duration: duration,
+            peers: Vec::new()
+        }
+    }
+
+    /// Average rate of the download in bytes per second
+    pub fn rate(&self) -> f64 {
+        if self.duration > 0.0 { self.size as f64 / self.duration } else { 0.0 }
+    }
+
+    /// Local time at which the download finished
+    pub fn finished_at(&self) -> String {
+        at(Timespec::new(self.finished, 0)).strftime("%Y-%m-%d %H:%M:%S").map(|time| time.to_string()).unwrap_or_default()
+    }
+}
+
+/// Statistics of a source over all downloads in the history
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerSummary {
+    /// Hex encoded ID of the source
+    pub node_id: String,
+    /// Amount of downloads the source contributed to
+    pub downloads: usize,
+    pub blocks: usize,
+    pub bytes: u64,
+    pub timeouts: usize,
+    /// Throughput of the source in bytes per second, averaged over its downloads weighted by the bytes it delivered
+    pub throughput: f64
+}
+
+/// Summarize the sources of all downloads in `entries`, the slowest first
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::path::PathBuf;
+/// # use ddp::history::{HistoryEntry, PeerUsage, summarize_peers};
+/// # use ddp::hooks::Outcome;
+/// # fn main() {
+/// let usage = |node: &str, bytes, throughput| PeerUsage { node_id: node.to_string(), blocks: 1, bytes: bytes, timeouts: 0, throughput: throughput };
+/// let mut first = HistoryEntry::new("AA".to_string(), "a".to_string(), PathBuf::from("a"), 300, Outcome::Completed, 1.0);
+/// first.peers = vec![usage("slow", 100, 10.0), usage("fast", 200, 1000.0)];
+/// let mut second = first.clone();
+/// second.peers = vec![usage("slow", 300, 50.0)];
+///
+/// let peers = summarize_peers(&[first, second]);
+/// assert_eq!(peers[0].node_id, "slow");
+/// assert_eq!(peers[0].downloads, 2);
+/// assert_eq!(peers[0].throughput, 40.0);
+/// # }
+/// ```
+pub fn summarize_peers(entries: &[HistoryEntry]) -> Vec<PeerSummary> {
+    let mut peers: HashMap<&String, PeerSummary> = HashMap::new();
+    for usage in entries.iter().flat_map(|entry| entry.peers.iter()) {
+        let summary = peers.entry(&usage.node_id).or_insert(PeerSummary {
+            node_id: usage.node_id.clone(),
+            downloads: 0,
+            blocks: 0,
+            bytes: 0,
+            timeouts: 0,
+            throughput: 0.0
+        });
+        // The sum of the throughputs weighted by the bytes is divided by the total bytes below
+        summary.throughput += usage.throughput * usage.bytes as f64;
+        summary.downloads += 1;
+        summary.blocks += usage.blocks;
+        summary.bytes += usage.bytes;
+        summary.timeouts += usage.timeouts;
+    }
+    let mut peers = peers.into_values().map(|mut summary| {
+        summary.throughput = if summary.bytes > 0 { summary.throughput / summary.bytes as f64 } else { 0.0 };
+        summary
+    }).collect::<Vec<_>>();
+    peers.sort_by(|a, b| a.throughput.partial_cmp(&b.throughput).unwrap_or(::std::cmp::Ordering::Equal));
+    peers
+}
+
+/// History file in the state directory
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf
+}
+
+impl History {
+    /// Open the history kept in the state directory, the file is created once the first entry is recorded
+    pub fn new(state_dir: &Path) -> History {
+        History {
+            path: state_dir.join(HISTORY_FILE)
+        }
+    }
+
+    /// Append an entry to the history
+    pub fn record(&self, entry: &HistoryEntry) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() { fs::create_dir_all(dir)?; }
+        let mut line = serde_json::to_vec(entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push(b'\n');
+        // A single write keeps the lines of concurrently finishing downloads apart
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(&line)
+    }
+
+    /// Read all entries in the order they have been recorded, lines that can not be parsed are skipped
+    pub fn entries(&self) -> io::Result<Vec<HistoryEntry>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e)
+        };
+        Ok(content.lines().filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(_) => { warn!("Skipping a malformed line of {}", self.path.display()); None }
+        }).collect())
+    }
+}
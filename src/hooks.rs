@@ -0,0 +1,154 @@
+//! Actions triggered when a download has finished, e.g. to deploy a downloaded artifact
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde_json;
+
+use helpers::to_hex_string;
+
+/// Time in seconds after which an unresponsive webhook is given up
+const WEBHOOK_TIMEOUT: u64 = 10;
+
+/// Action run when a download verifies successfully or fails
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hook {
+    /// Shell command that receives the report in `DDP_*` environment variables
+    Command(String),
+    /// `http://` URL the report is posted to as JSON
+    Webhook(String)
+}
+
+/// Outcome of a download
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Completed,
+    Failed
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self { Outcome::Completed => "completed", Outcome::Failed => "failed" })
+    }
+}
+
+/// Summary of a finished download that is passed to the hooks
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferReport {
+    pub outcome: Outcome,
+    /// Hex encoded hash of the file
+    pub hash: String,
+    pub path: PathBuf,
+    /// Size of the file in bytes
+    pub size: usize,
+    /// Seconds since the download has been queued
+    pub duration: f64,
+    /// Average speed in bytes per second
+    pub speed: f64
+}
+
+impl TransferReport {
+    pub fn new(outcome: Outcome, hash: &[u8], path: PathBuf, size: usize, duration: Duration) -> TransferReport {
+        let duration = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+        TransferReport {
+            outcome: outcome,
+            hash: to_hex_string(hash),
+            path: path,
+            size: size,
+            duration: duration,
+            speed: if duration > 0.0 { size as f64 / duration } else { 0.0 }
+        }
+    }
+}
+
+impl Hook {
+    /// Run the hook and wait for it to finish
+    pub fn run(&self, report: &TransferReport) -> Result<(), String> {
+        match *self {
+            Hook::Command(ref command) => run_command(command, report),
+            Hook::Webhook(ref url) => post_webhook(url, report)
+        }
+    }
+}
+
+/// Parses `exec:<command>` or a webhook URL
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::hooks::Hook;
+/// # fn main() {
+/// assert_eq!("exec:./deploy.sh".parse(), Ok(Hook::Command("./deploy.sh".to_string())));
+/// assert_eq!("http://ci.local/hook".parse(), Ok(Hook::Webhook("http://ci.local/hook".to_string())));
+/// assert!("https://ci.local/hook".parse::<Hook>().is_err());
+/// # }
+/// ```
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Hook, String> {
+        if let Some(command) = s.strip_prefix("exec:") {
+            Ok(Hook::Command(command.to_string()))
+        } else if s.starts_with("http://") {
+            Ok(Hook::Webhook(s.to_string()))
+        } else {
+            Err(format!("Invalid hook '{}', expected exec:<command> or an http:// URL", s))
+        }
+    }
+}
+
+fn run_command(command: &str, report: &TransferReport) -> Result<(), String> {
+    let mut shell = if cfg!(windows) { Command::new("cmd") } else { Command::new("sh") };
+    shell.arg(if cfg!(windows) { "/C" } else { "-c" }).arg(command)
+        .env("DDP_OUTCOME", report.outcome.to_string())
+        .env("DDP_HASH", &report.hash)
+        .env("DDP_PATH", &report.path)
+        .env("DDP_SIZE", report.size.to_string())
+        .env("DDP_DURATION", format!("{:.3}", report.duration))
+        .env("DDP_SPEED", format!("{:.0}", report.speed));
+    match shell.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("`{}` exited with {}", command, status)),
+        Err(e) => Err(format!("Failed to run `{}`: {}", command, e))
+    }
+}
+
+/// Post the report with a minimal HTTP/1.1 request, TLS is not supported
+fn post_webhook(url: &str, report: &TransferReport) -> Result<(), String> {
+    let rest = match url.find("://") {
+        Some(index) if &url[..index] == "http" => &url[index + 3..],
+        _ => return Err(format!("Unsupported webhook URL {}", url))
+    };
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/")
+    };
+    let addr = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+    let addr = addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+        .ok_or(format!("Failed to resolve {}", host))?;
+
+    let body = serde_json::to_string(report).unwrap();
+    let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, host, body.len(), body);
+    let timeout = Duration::from_secs(WEBHOOK_TIMEOUT);
+    let mut stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| format!("Failed to reach {}: {}", url, e))?;
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    stream.write_all(request.as_bytes()).map_err(|e| format!("Failed to post to {}: {}", url, e))?;
+
+    // Only the status line is of interest
+    let mut response = vec![0; 64];
+    let len = stream.read(&mut response).map_err(|e| format!("No response from {}: {}", url, e))?;
+    let status = String::from_utf8_lossy(&response[..len]).split_whitespace().nth(1).map(|s| s.to_string());
+    match status {
+        Some(ref status) if status.starts_with('2') => Ok(()),
+        Some(status) => Err(format!("{} responded with status {}", url, status)),
+        None => Err(format!("Malformed response from {}", url))
+    }
+}
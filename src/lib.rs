@@ -0,0 +1,116 @@
+//! The distributed distribution protocol, used to distribute files in a network in a distributed manner.
+//!
+//! Start a `node::Node` to share files with and fetch files from other nodes in the local network.
+#![allow(dead_code)]
+// Fields are initialized as `name: name` and constants spell out their lifetime throughout the crate
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate serde_derive;
+extern crate serde;
+extern crate ansi_term;
+extern crate bincode;
+extern crate sha2;
+extern crate sha1;
+extern crate blake3;
+extern crate time as ext_time;
+extern crate pbr;
+extern crate chacha20;
+extern crate getrandom;
+extern crate if_addrs;
+extern crate net2;
+extern crate toml;
+extern crate serde_json;
+#[cfg(feature = "mmap")]
+extern crate memmap2;
+#[cfg(feature = "fuse")]
+extern crate libc;
+
+#[macro_use]
+pub mod helpers;
+
+#[macro_use]
+pub mod errors;
+
+mod git_hash;
+pub use git_hash::GIT_HASH;
+
+pub mod logger;
+
+pub mod networking;
+
+pub mod file;
+
+pub mod announce;
+
+pub mod request;
+
+pub mod peers;
+
+pub mod config;
+
+pub mod discovery;
+
+pub mod control;
+
+pub mod transfer;
+
+pub mod uri;
+
+pub mod crypto;
+
+pub mod acl;
+
+pub mod throttle;
+
+pub mod doctor;
+
+pub mod bandwidth;
+
+pub mod hooks;
+
+pub mod gossip;
+
+pub mod bitfield;
+
+pub mod stream;
+
+pub mod cache;
+
+pub mod library;
+
+pub mod metafile;
+
+pub mod registry;
+
+pub mod distribute;
+
+pub mod torrent;
+
+pub mod history;
+
+pub mod scrub;
+
+pub mod chunks;
+
+pub mod relay;
+
+pub mod pex;
+
+pub mod workers;
+
+pub mod rate;
+
+pub mod storage;
+
+pub mod events;
+
+pub mod tui;
+
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+pub mod mount;
+
+pub mod node;
+
+/// Constant containing version string provided by cargo
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
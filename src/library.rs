@@ -0,0 +1,86 @@
+//! Metadata of the files shared or downloaded by this node, kept in the state directory so local copies can be checked
+//! against it long after they have been hashed
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bincode::{serialize, deserialize};
+
+use file::FileMetadata;
+use helpers::to_hex_string;
+
+/// Name of the directory in the state directory the records are kept in
+const LIBRARY_DIR: &'static str = "metadata";
+
+/// Metadata of a file along with the place of its local copy
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub metadata: FileMetadata,
+    pub path: PathBuf
+}
+
+/// Records of local files, one file per record named after the hash of the file
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::library::Library;
+/// # use ddp::file::example_file;
+/// # fn main() {
+/// let dir = std::env::temp_dir().join("ddp-library-example");
+/// let file = example_file("ddp-library-example-data", 3000);
+/// let path = file.local_path.clone();
+///
+/// let library = Library::new(&dir);
+/// library.insert(&file.metadata, &path).unwrap();
+/// assert_eq!(library.get(&file.metadata.hash.0).unwrap().metadata.size, 3000);
+/// assert_eq!(library.find(&path).unwrap().metadata.hash.0, file.metadata.hash.0);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Library {
+    dir: PathBuf
+}
+
+impl Library {
+    /// Open the records kept in the state directory, the directory is created once the first record is inserted
+    pub fn new(state_dir: &Path) -> Library {
+        Library {
+            dir: state_dir.join(LIBRARY_DIR)
+        }
+    }
+
+    /// Remember the metadata of the local copy at `path`, replacing an earlier record of the same file
+    pub fn insert(&self, metadata: &FileMetadata, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let record = Record {
+            metadata: metadata.clone(),
+            path: path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+        };
+        let target = self.dir.join(to_hex_string(&metadata.hash.0));
+        // Write to a temporary file first so a crash never leaves a truncated record behind
+        let temporary = target.with_extension("tmp");
+        fs::write(&temporary, serialize(&record).unwrap())?;
+        fs::rename(&temporary, target)
+    }
+
+    /// Retrieve the record of the file with the given hash
+    pub fn get(&self, hash: &[u8]) -> Option<Record> {
+        let data = fs::read(self.dir.join(to_hex_string(hash))).ok()?;
+        deserialize(&data).ok()
+    }
+
+    /// Retrieve the record of the local copy at `path`, the newest one if the file has been shared with different
+    /// content before
+    pub fn find(&self, path: &Path) -> Option<Record> {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        fs::read_dir(&self.dir).ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| Some((entry.metadata().and_then(|m| m.modified()).ok()?, fs::read(entry.path()).ok()?)))
+            .filter_map(|(modified, data)| deserialize::<Record>(&data).ok().map(|record| (modified, record)))
+            .filter(|(_, record)| record.path == path)
+            .max_by_key(|&(modified, _)| modified)
+            .map(|(_, record)| record)
+    }
+}
@@ -1,16 +1,26 @@
 //! Very sexy logger
-use log::{LogRecord, LogLevel, LogMetadata, set_logger, self};
+use log::{LogRecord, LogLevel, LogLevelFilter, LogMetadata, MaxLogLevelFilter, max_log_level, set_logger, self};
 use std::env;
 use std::str::FromStr;
+use std::sync::Mutex;
 pub use ansi_term::*;
 
-use std::error::Error;
+use events::{Event, EventChannel};
 
 const DEFAULT_LOGLEVEL: LogLevel = LogLevel::Info;
 
+/// Handle to change the level of the installed logger, set once it has been initialized
+static MAX_LEVEL: Mutex<Option<MaxLogLevelFilter>> = Mutex::new(None);
+/// Channel the printed messages are published to as well, set once a node has been started
+static EVENTS: Mutex<Option<EventChannel>> = Mutex::new(None);
+
+/// Strip the colours from a style on windows since its console prints the escape codes literally
+fn style(style: Style) -> Style {
+    if cfg!(windows) { Style::default() } else { style }
+}
+
 /// The logger type responsible for printing that sexy output you see when launching BitDMX
 pub struct Logger {
-    level: LogLevel,
     show_paths: bool
 }
 
@@ -34,50 +44,64 @@ impl Logger {
                 Err(_) => DEFAULT_LOGLEVEL
             };
             let show_paths = match env::var("PATHS") {
-                Ok(val) => val == String::from("true"),
+                Ok(val) => val == "true",
                 Err(_) => false
             };
             max_log_level.set(level.to_log_level_filter());
+            *MAX_LEVEL.lock().unwrap() = Some(max_log_level);
             Box::new(Logger {
-                level: level,
                 show_paths: show_paths
             })
         }) {
             Ok(_) => {},
             Err(e) => {
-                println!("{} Failed to set logger: {}", Colour::Fixed(160).bold().paint("       Error"), e.description());
-                ::std::process::exit(6);
+                println!("{} Failed to set logger: {}", style(Colour::Fixed(160).bold()).paint("       Error"), e);
+                ::std::process::exit(::errors::Error::Logger(e.to_string()).code());
             }
         }
     }
+
+    /// Change the level at which messages are printed while the logger is running, e.g. after reloading the
+    /// configuration. Does nothing if the logger has not been initialized.
+    pub fn set_level(level: LogLevel) {
+        if let Some(ref filter) = *MAX_LEVEL.lock().unwrap() { filter.set(level.to_log_level_filter()); }
+    }
+
+    /// Publish the printed messages to `events` as well, e.g. for `ddp tui`
+    pub fn publish_to(events: EventChannel) {
+        *EVENTS.lock().unwrap() = Some(events);
+    }
 }
 
 impl log::Log for Logger {
     fn enabled(&self, metadata: &LogMetadata) -> bool {
-        metadata.level() <= self.level
+        metadata.level() <= max_log_level()
     }
 
     fn log(&self, record: &LogRecord) {
         if self.enabled(record.metadata()) {
-            let path = match self.level {
-                LogLevel::Trace => {
+            let path = match max_log_level() {
+                LogLevelFilter::Trace => {
                     let loc = record.location();
                     format!("{}:{}", loc.file(), loc.line())
                 },
-                LogLevel::Debug => {
-                    format!("{}", record.location().module_path())
+                LogLevelFilter::Debug => {
+                    record.location().module_path().to_string()
                 },
                 _ => {String::new()}
             };
             let level = match record.level() {
-                LogLevel::Error => { Colour::Fixed(160).bold().paint("       Error") },
-                LogLevel::Warn  => { Colour::Fixed(214).bold().paint("     Warning") },
-                LogLevel::Info  => { Colour::Fixed( 10).bold().paint("        Info") },
-                LogLevel::Debug => { Colour::Fixed(244).bold().paint("       Debug") },
-                LogLevel::Trace => { Colour::Fixed(239).bold().paint("       Trace") },
+                LogLevel::Error => { style(Colour::Fixed(160).bold()).paint("       Error") },
+                LogLevel::Warn  => { style(Colour::Fixed(214).bold()).paint("     Warning") },
+                LogLevel::Info  => { style(Colour::Fixed( 10).bold()).paint("        Info") },
+                LogLevel::Debug => { style(Colour::Fixed(244).bold()).paint("       Debug") },
+                LogLevel::Trace => { style(Colour::Fixed(239).bold()).paint("       Trace") },
             };
+            if let Some(ref events) = *EVENTS.lock().unwrap() {
+                events.publish(|| Event::Log { level: record.level().to_string(), message: record.args().to_string() });
+            }
             if self.show_paths {
-                println!("{} {}\n             {}", level, record.args(), Colour::Fixed(239).paint(path));
+                println!("{} {}\n             {}", level, record.args(), style(Colour::Fixed(239).normal()).paint(path));
             } else {
                 println!("{} {}", level, record.args());
             }
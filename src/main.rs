@@ -1,63 +1,781 @@
-#![allow(dead_code)]
-#![feature(custom_derive, plugin)]
-#![plugin(serde_macros)]
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes)]
 
 #[macro_use] extern crate log;
-extern crate ansi_term;
-extern crate bincode;
-extern crate sha2;
-extern crate time as ext_time;
+#[macro_use] extern crate ddp;
 extern crate pbr;
+#[cfg(unix)] extern crate signal_hook;
 
-use std::sync::{Arc, Mutex};
-use std::path::PathBuf;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::path::{Path, PathBuf};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
 
-#[macro_use]
-mod helpers;
+use ddp::{VERSION, GIT_HASH};
+use ddp::logger::Logger;
+use ddp::config::{Config, Mode};
+use ddp::node::Node;
+use ddp::transfer::{Priority, TransferState};
+use ddp::control::{send_command, subscribe};
+use ddp::networking::{MulticastScope, check_port_offset};
+use ddp::helpers::{from_hex_string, to_hex_string, format_bytes, HASH_LENGTH};
+use ddp::uri::{Link, SCHEME};
+use ddp::crypto::Key;
+use ddp::acl::Cidr;
+use ddp::doctor::diagnose;
+use ddp::hooks::Hook;
+use ddp::file::{File, FileHandle, BlockState};
+use ddp::library::Library;
+use ddp::metafile::{self, MetaFile};
+use ddp::torrent::{self, Torrent};
+use ddp::history::{History, summarize_peers};
+use ddp::distribute::{IdleTimer, IDLE_TIMEOUT};
+use ddp::tui::{Dashboard, terminal_size, ENTER_SCREEN, LEAVE_SCREEN};
 
-mod git_hash;
-use git_hash::GIT_HASH;
+use pbr::{ProgressBar, Units};
+#[cfg(unix)] use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM, SIGWINCH}, iterator::Signals};
 
-mod logger;
-use logger::Logger;
+/// Commands that are forwarded to the control socket of the node running on this machine
+const CONTROL_COMMANDS: &'static [&'static str] = &["discovered", "status", "pause", "resume", "link", "reload"];
+/// Maximum amount of files whose metadata is requested at the same time
+const METADATA_CONCURRENCY: usize = 8;
+/// Interval in milliseconds at which the progress of downloads is updated
+const PROGRESS_INTERVAL: u64 = 500;
+/// Amount of downloads `ddp history` lists by default
+const HISTORY_ENTRIES: usize = 20;
+/// Interval in milliseconds at which `ddp tui` redraws the screen
+const REDRAW_INTERVAL: u64 = 250;
 
-mod networking;
-use networking::start_ping_server;
+fn main() {
+    Logger::init();
 
-mod file;
-use file::File;
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(|arg| arg.as_str()) {
+        Some(command) if CONTROL_COMMANDS.contains(&command) => control(args.clone()),
+        Some("share") => share(args[1..].to_vec()),
+        Some("distribute") => distribute(args[1..].to_vec()),
+        Some("fetch") => fetch(args[1..].to_vec()),
+        Some("doctor") => doctor(args[1..].to_vec()),
+        Some("mount") => mount(args[1..].to_vec()),
+        Some("verify") => verify(args[1..].to_vec()),
+        Some("export-meta") => export_meta(args[1..].to_vec()),
+        Some("export-torrent") => export_torrent(args[1..].to_vec()),
+        Some("history") => history(args[1..].to_vec()),
+        Some("tui") => tui(args[1..].to_vec()),
+        _ => run()
+    }
+}
 
-mod announce;
-use announce::announce;
+fn start_node(config: Config) -> Node {
+    info!("DDP node v{}-{}", VERSION, GIT_HASH);
+    let node = Node::new(config);
+    node.start();
+    node
+}
 
-mod request;
-// use request::*;
+/// Remove an option along with its value from the arguments and return the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    if index + 1 >= args.len() { fail!(Usage, "Missing value for {}", name); }
+    args.remove(index);
+    Some(args.remove(index))
+}
 
-/// Constant containing version string provided by cargo
-pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+/// Remove a flag from the arguments and return whether it was present
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != name);
+    args.len() != len
+}
 
-fn main() {
-    Logger::init();
-    info!("DDP node v{}-{}", VERSION, GIT_HASH);
+/// Build the configuration from a file given with `--config <path>`, the `--gossip`, `--udp-metadata`, `--seed-only`,
+/// `--leech-only` and `--anonymous` flags, the `--port-offset <n>`, `--multicast-group <ip>`, `--multicast-ttl <n>`,
+/// `--relay <ip>`, `--trusted-relay <ip>` and `--reveal <hash>` options and the access control options `--allow <cidr>`,
+/// `--deny <cidr>` and `--token <token>`
+fn parse_config(args: &mut Vec<String>) -> Config {
+    match read_config(args) {
+        Ok(config) => config,
+        Err(e) => { fail!(Usage, "{}", e); }
+    }
+}
+
+/// Build the configuration like `parse_config`, returning an error if the configuration file can not be loaded
+fn read_config(args: &mut Vec<String>) -> Result<Config, String> {
+    let mut config = match take_option(args, "--config") {
+        Some(path) => Config::load(Path::new(&path))?,
+        None => Config::new()
+    };
+    if take_flag(args, "--gossip") { config = config.gossip(true); }
+    if take_flag(args, "--udp-metadata") { config = config.udp_metadata(true); }
+    if let Some(offset) = take_port_offset(args)? { config = config.port_offset(offset); }
+    match (take_flag(args, "--seed-only"), take_flag(args, "--leech-only")) {
+        (true, true) => return Err("--seed-only and --leech-only exclude each other".to_string()),
+        (true, false) => config = config.mode(Mode::SeedOnly),
+        (false, true) => config = config.mode(Mode::LeechOnly),
+        (false, false) => {}
+    }
+    let scope = take_multicast_scope(args, config.multicast)?;
+    config = config.multicast(scope);
+    let mut relay = Vec::new();
+    while let Some(interface) = take_option(args, "--relay") {
+        relay.push(interface.parse().map_err(|_| format!("Invalid interface address '{}'", interface))?);
+    }
+    if !relay.is_empty() { config = config.relay(relay); }
+    let mut trusted = Vec::new();
+    while let Some(relay) = take_option(args, "--trusted-relay") {
+        trusted.push(relay.parse().map_err(|_| format!("Invalid relay address '{}'", relay))?);
+    }
+    if !trusted.is_empty() { config = config.trusted_relays(trusted); }
+    // Revealed hashes given on the command line extend the whitelist of the configuration file
+    let mut whitelist = config.whitelist.clone();
+    while let Some(hash) = take_option(args, "--reveal") {
+        whitelist.push(from_hex_string(&hash).ok_or(format!("Invalid hash '{}'", hash))?);
+    }
+    if take_flag(args, "--anonymous") || config.anonymous { config = config.anonymous(whitelist); }
+    else if whitelist.len() != config.whitelist.len() { return Err("--reveal requires the anonymous mode".to_string()); }
+    // Ranges given on the command line extend those of the configuration file
+    let mut acl = config.acl.clone();
+    while let Some(range) = take_option(args, "--allow") { acl = acl.allow(parse_cidr(&range)); }
+    while let Some(range) = take_option(args, "--deny") { acl = acl.deny(parse_cidr(&range)); }
+    if let Some(token) = take_option(args, "--token") {
+        acl = acl.token(token.clone());
+        config = config.token(token);
+    }
+    Ok(config.acl(acl))
+}
 
-    start_ping_server();
+/// Build the configuration again from `args` whenever the node receives SIGHUP or the `reload` command so options
+/// given on the command line keep applying on top of the configuration file
+fn watch_config(node: &Node, args: Vec<String>) {
+    node.set_reload_source(move || read_config(&mut args.clone()));
+    #[cfg(unix)]
+    {
+        let mut signals = match Signals::new([SIGHUP]) {
+            Ok(signals) => signals,
+            Err(e) => { warn!("Failed to listen for SIGHUP: {}", e); return }
+        };
+        let node = node.clone();
+        spawn(move || for _ in signals.forever() {
+            if let Err(e) = node.reload() { error!("Failed to reload the configuration: {}", e); }
+        });
+    }
+}
 
-    let files = Arc::new(Mutex::new(Vec::new()));
+fn parse_cidr(range: &str) -> Cidr {
+    match range.parse() {
+        Ok(range) => range,
+        Err(e) => { fail!(Usage, "{}", e); }
+    }
+}
+
+fn parse_key(hex: &str) -> Key {
+    match Key::from_hex(hex) {
+        Some(key) => key,
+        None => { fail!(Usage, "Invalid key, expected {} hex encoded bytes", ddp::crypto::KEY_LENGTH); }
+    }
+}
+
+/// Exit unless the mode of the node permits serving files
+fn require_serving(config: &Config) {
+    if !config.mode.serves() { fail!(Usage, "Nodes in leech-only mode do not serve files"); }
+}
 
+/// Exit unless the mode of the node permits fetching files
+fn require_fetching(config: &Config) {
+    if !config.mode.fetches() { fail!(Usage, "Nodes in seed-only mode do not fetch files"); }
+}
+
+/// Remove the `--port-offset <n>` option from the arguments and return the offset
+fn take_port_offset(args: &mut Vec<String>) -> Result<Option<u16>, String> {
+    match take_option(args, "--port-offset") {
+        Some(offset) => match offset.parse() {
+            Ok(offset) => check_port_offset(offset).map(|_| Some(offset)),
+            Err(_) => Err(format!("Invalid port offset '{}'", offset))
+        },
+        None => Ok(None)
+    }
+}
+
+/// Remove the `--multicast-group <ip>` and `--multicast-ttl <n>` options from the arguments and apply them to `scope`
+fn take_multicast_scope(args: &mut Vec<String>, mut scope: MulticastScope) -> Result<MulticastScope, String> {
+    if let Some(group) = take_option(args, "--multicast-group") {
+        scope = scope.group(group.parse().map_err(|_| format!("Invalid multicast group '{}'", group))?);
+    }
+    if let Some(ttl) = take_option(args, "--multicast-ttl") {
+        scope = scope.ttl(ttl.parse().map_err(|_| format!("Invalid multicast TTL '{}'", ttl))?);
+    }
+    scope.validate()?;
+    Ok(scope)
+}
+
+/// Forward a command to the local node with the port offset given by `--port-offset` and print its response
+fn control(mut args: Vec<String>) {
+    let offset = match take_port_offset(&mut args) {
+        Ok(offset) => offset.unwrap_or(0),
+        Err(e) => { fail!(Usage, "{}", e); }
+    };
+    match send_command(&args.join(" "), offset) {
+        Ok(response) => print!("{}", response),
+        Err(e) => { fail!(Control, "{}", e); }
+    }
+}
+
+/// Share files until the process is terminated, encrypting them with a given or generated key if requested. The path
+/// `-` shares stdin as a stream named after `--name`.
+fn share(mut args: Vec<String>) {
+    let encrypt = take_flag(&mut args, "--encrypt");
+    let key = take_option(&mut args, "--key").map(|hex| parse_key(&hex))
+        .or_else(|| if encrypt { Some(Key::generate()) } else { None });
+    let name = take_option(&mut args, "--name").unwrap_or("stdin".to_string());
+    let config_args = args.clone();
+    let config = parse_config(&mut args);
+    require_serving(&config);
+    // Files listed in the configuration are shared by the node itself
+    if args.is_empty() && config.shares.is_empty() {
+        fail!(Usage, "Usage: ddp share [--config <path>] [--gossip] [--port-offset <n>] [--multicast-group <ip>] [--multicast-ttl <n>] [--relay <ip>]... [--trusted-relay <ip>]... [--seed-only] [--anonymous [--reveal <hash>]...] [--encrypt | --key <key>] [--allow <cidr>]... [--deny <cidr>]... [--token <token>] \
+            [--name <name>] <path|->...");
+    }
+    if args.iter().any(|path| path == "-") && key.is_some() { fail!(Usage, "Streams can not be encrypted"); }
+
+    let node = start_node(config);
+    watch_config(&node, config_args);
+    if let Some(ref key) = key { info!("Encryption key: {}", key.to_hex()); }
+    for path in args.iter() {
+        if path == "-" {
+            let mut link = Link::new(node.share_stream(io::stdin(), name.clone()));
+            link.name = Some(name.clone());
+            info!("Sharing stdin as stream {}", link);
+            continue;
+        }
+        let shared = match key {
+            Some(ref key) => node.share_encrypted(PathBuf::from(path), key.clone()),
+            None => node.share(PathBuf::from(path))
+        };
+        let hash = match shared {
+            Ok(hash) => hash,
+            Err(e) => { fail!(Io, "Failed to share {}: {}", path, e) }
+        };
+        info!("Sharing {} as {}", path, node.link(&hash).unwrap());
+    }
+    loop { sleep(Duration::from_secs(3600)); }
+}
+
+/// Push a file to many nodes at once as the origin of a distribution, the downloaders join it with
+/// `ddp fetch --distribute`. Once the expected amount of downloaders completed, a summary is printed and the
+/// distribution is finished. It is finished as well once downloaders showed up but nothing has been uploaded and no
+/// downloader completed for a while, since their reports may have been lost.
+fn distribute(mut args: Vec<String>) {
+    let expected = take_option(&mut args, "--expect").map(|count| match count.parse::<usize>() {
+        Ok(count) if count > 0 => count,
+        _ => { fail!(Usage, "Invalid amount of downloaders: {}", count); }
+    });
+    // The origin learns which blocks are rare from the gossip of the downloaders
+    let config = parse_config(&mut args).gossip(true);
+    require_serving(&config);
+    if args.len() != 1 {
+        fail!(Usage, "Usage: ddp distribute [--config <path>] [--port-offset <n>] [--expect <count>] [--allow <cidr>]... [--deny <cidr>]... [--token <token>] <path>");
+    }
+
+    let node = start_node(config);
+    let hash = match node.distribute(PathBuf::from(&args[0]), expected) {
+        Ok(hash) => hash,
+        Err(e) => { fail!(Io, "Failed to share {}: {}", args[0], e) }
+    };
+    info!("Distributing {} as {}, fetch it with ddp fetch --distribute", args[0], node.link(&hash).unwrap());
+    if expected.is_none() { info!("Waiting for downloaders until the distribution is idle"); }
+    let mut idle = IdleTimer::new(Duration::from_secs(IDLE_TIMEOUT));
+    loop {
+        sleep(Duration::from_secs(1));
+        let distribution = match node.distribution(&hash) {
+            Some(distribution) => distribution,
+            None => continue
+        };
+        let activity = node.uploaded(&hash) + distribution.completed.len();
+        if !distribution.is_done() {
+            // Nobody showed up yet
+            if activity == 0 || !idle.is_idle(activity) { continue }
+            match distribution.expected {
+                Some(expected) => warn!("Only {} of {} downloaders reported their completion, nothing happened for {} seconds",
+                    distribution.completed.len(), expected, IDLE_TIMEOUT),
+                None => info!("Nothing happened for {} seconds", IDLE_TIMEOUT)
+            }
+        }
+        for line in distribution.summary() { info!("{}", line); }
+        node.finish_distribution(&hash);
+        break;
+    }
+}
+
+/// Parse a link or hex encoded hash, returns `None` if the argument is neither
+fn parse_target(arg: &str) -> Option<Link> {
+    if arg.starts_with(SCHEME) {
+        match arg.parse::<Link>() {
+            Ok(link) => Some(link),
+            Err(e) => { fail!(Usage, "Invalid link: {}", e); }
+        }
+    } else {
+        from_hex_string(arg).filter(|hash| hash.len() == HASH_LENGTH).map(Link::new)
+    }
+}
+
+/// Read a batch file listing one link or hash per line, optionally followed by the path to save the file to
+fn read_batch(path: &str) -> Vec<(Link, Option<PathBuf>)> {
+    let mut content = String::new();
+    if let Err(e) = fs::File::open(path).and_then(|mut f| f.read_to_string(&mut content)) {
+        fail!(Io, "Failed to read {}: {}", path, e);
+    }
+    content.lines().enumerate()
+        .map(|(number, line)| (number + 1, line.trim()))
+        .filter(|&(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let mut fields = line.split_whitespace();
+            match fields.next().and_then(parse_target) {
+                Some(link) => (link, fields.next().map(PathBuf::from)),
+                None => { fail!(Usage, "Invalid link or hash in line {} of {}", number, path); }
+            }
+        }).collect()
+}
+
+/// Download files referenced by links or hashes, given as arguments or listed in a batch file, and exit once all of
+/// them are complete. Encrypted files are decrypted if a key is given and kept as they are distributed otherwise.
+fn fetch(mut args: Vec<String>) {
+    if take_flag(&mut args, "--stream") { return fetch_stream(args) }
+    let key = take_option(&mut args, "--key").map(|hex| parse_key(&hex));
+    let mut hooks = Vec::new();
+    while let Some(command) = take_option(&mut args, "--exec") { hooks.push(Hook::Command(command)); }
+    while let Some(url) = take_option(&mut args, "--webhook") {
+        match url.parse() {
+            Ok(hook) => hooks.push(hook),
+            Err(e) => { fail!(Usage, "{}", e); }
+        }
+    }
+    let batch = take_option(&mut args, "--batch");
+    let distribute = take_flag(&mut args, "--distribute");
+    let mut metas = Vec::new();
+    while let Some(path) = take_option(&mut args, "--meta") {
+        match MetaFile::load(Path::new(&path)) {
+            Ok(metadata) => metas.push((metadata, None)),
+            Err(e) => { fail!(Usage, "{}", e); }
+        }
+    }
+    // Torrents exported by DDP carry the metadata as well
+    while let Some(path) = take_option(&mut args, "--torrent") {
+        match Torrent::load(Path::new(&path)).and_then(Torrent::to_metadata) {
+            Ok(metadata) => metas.push((metadata, None)),
+            Err(e) => { fail!(Usage, "{}", e); }
+        }
+    }
+    let mut config = parse_config(&mut args);
+    // Downloaders of a distribution learn from each other which blocks they have
+    if distribute { config = config.gossip(true); }
+    require_fetching(&config);
+    if distribute { require_serving(&config); }
+
+    let mut targets = batch.map_or(Vec::new(), |path| read_batch(&path));
+    let batched = !targets.is_empty();
+    for (index, arg) in args.iter().enumerate() {
+        match parse_target(arg) {
+            Some(link) => targets.push((link, None)),
+            // A single link, hash or metadata file may be followed by the path to save the file to
+            None if !batched && targets.len() == 1 && metas.is_empty() && index == args.len() - 1 => targets[0].1 = Some(PathBuf::from(arg)),
+            None if !batched && targets.is_empty() && metas.len() == 1 && args.len() == 1 => metas[0].1 = Some(PathBuf::from(arg)),
+            None => { fail!(Usage, "Invalid hash: {}", arg); }
+        }
+    }
+    if targets.is_empty() && metas.is_empty() {
+        fail!(Usage, "Usage: ddp fetch [--config <path>] [--gossip] [--udp-metadata] [--port-offset <n>] [--multicast-group <ip>] [--multicast-ttl <n>] [--trusted-relay <ip>]... [--leech-only] [--anonymous] [--distribute] [--key <key>] [--token <token>] [--exec <command>]... [--webhook <url>]... \
+            (<link|hash> [path] | <link|hash>... | --batch <file> | (--meta | --torrent) <file> [path] | ((--meta | --torrent) <file>)...)");
+    }
+    let mut seen = metas.iter().map(|(metadata, _)| metadata.hash.0.clone()).collect::<Vec<_>>();
+    targets.retain(|(link, _)| if seen.contains(&link.hash) { warn!("Ignoring duplicate {}", link); false } else { seen.push(link.hash.clone()); true });
+
+    let node = start_node(config);
+    let started = Instant::now();
+
+    // The metadata of these files is known already so they are downloaded without asking the network for it
+    let mut failed = 0;
+    let mut hashes = Vec::new();
+    let count = targets.len() + metas.len();
+    for (metadata, path) in metas {
+        let mut link = Link::new(metadata.hash.0.clone());
+        link.name = Some(metadata.name.clone());
+        // Only the file name is used so a metadata file can not point anywhere outside the working directory
+        let path = path.unwrap_or_else(|| Path::new(&metadata.name).file_name().map_or_else(|| PathBuf::from(to_hex_string(&link.hash)), PathBuf::from));
+        let file = node.fetch_metadata(metadata, path);
+        if queue_download(&node, file, &link, key.as_ref(), &hooks, distribute) { hashes.push(link.hash); } else { failed += 1; }
+    }
+
+    // Every metadata request binds sockets of its own so only a few are sent at the same time
+    for chunk in targets.chunks(METADATA_CONCURRENCY) {
+        let requests = chunk.iter().cloned().map(|(link, path)| {
+            let node = node.clone();
+            spawn(move || (node.fetch_link(&link, path), link))
+        }).collect::<Vec<_>>();
+
+        for request in requests {
+            let (file, link) = request.join().unwrap();
+            let file = match file {
+                Some(file) => file,
+                None => { error!("Failed to retrieve the metadata of {}", link); failed += 1; continue }
+            };
+            if queue_download(&node, file, &link, key.as_ref(), &hooks, distribute) { hashes.push(link.hash); } else { failed += 1; }
+        }
+    }
+    if hashes.is_empty() { fail!(Transfer, "None of the files could be fetched"); }
+
+    // All downloads share the transfer queue of this node and are summarized by a single progress bar
+    let total = hashes.iter().filter_map(|hash| node.transfers.progress(hash)).map(|(_, size)| size).sum::<usize>();
+    let mut progress = ProgressBar::new(total as u64);
+    progress.set_units(Units::Bytes);
+    let mut shown = 0;
+    loop {
+        let downloaded = hashes.iter().filter_map(|hash| node.transfers.progress(hash)).map(|(downloaded, _)| downloaded).sum::<usize>();
+        let finished = hashes.iter()
+            .filter(|hash| !matches!(node.transfers.state(hash), Some(TransferState::Downloading) | Some(TransferState::Paused)))
+            .count();
+        progress.message(&format!("{}/{} files ", finished, hashes.len()));
+        progress.add(downloaded.saturating_sub(shown) as u64);
+        shown = downloaded.max(shown);
+        if finished == hashes.len() { break }
+        sleep(Duration::from_millis(PROGRESS_INTERVAL));
+    }
+    progress.finish();
+    node.transfers.wait_for_hooks();
+
+    for hash in hashes.iter() {
+        match node.transfers.state(hash) {
+            Some(TransferState::Seeding) | Some(TransferState::Complete) => {},
+            state => { error!("Download of {} failed ({:?})", to_hex_string(hash), state); failed += 1; }
+        }
+    }
+    if failed > 0 { fail!(Transfer, "{} of {} downloads failed", failed, count); }
+    info!("Download complete");
+
+    if !distribute { return }
+    for hash in hashes.iter() { node.report_completion(hash, started.elapsed()); }
+    // The other downloaders may still need blocks from this node
+    info!("Serving the files until the distribution is finished");
+    let mut idle = IdleTimer::new(Duration::from_secs(IDLE_TIMEOUT));
+    while !hashes.iter().all(|hash| node.distribution_finished(hash)) {
+        sleep(Duration::from_secs(1));
+        // The report of the origin may have been lost
+        if idle.is_idle(hashes.iter().map(|hash| node.uploaded(hash)).sum()) {
+            warn!("The distribution did not finish, stopping after {} seconds without uploads", IDLE_TIMEOUT);
+            break;
+        }
+    }
+}
+
+/// Add a download to the transfer queue of the node, decrypting it with `key` if given and joining the distribution of
+/// the file if `distribute` is set. Returns false if the key does not match.
+fn queue_download(node: &Node, mut file: FileHandle, link: &Link, key: Option<&Key>, hooks: &[Hook], distribute: bool) -> bool {
+    let encrypted = file.file.lock().unwrap().metadata.encryption.is_some();
+    match key {
+        Some(key) if !file.decrypt_with(key.clone()) => { error!("The key does not match {}", link); return false },
+        Some(_) => {},
+        None if encrypted => warn!("{} is encrypted and will be stored as it is distributed", link),
+        None => {}
+    }
+    if distribute { node.join_distribution(&mut file); }
+    node.transfers.add(file, Priority::Normal);
+    for hook in hooks.iter() { node.transfers.add_hook(&link.hash, hook.clone()); }
+    true
+}
+
+/// Write the metadata of a file to a portable metadata file, it is taken from the records of this machine or requested
+/// from the network
+fn export_meta(mut args: Vec<String>) {
+    let config = parse_config(&mut args);
+    let link = match args.first().and_then(|arg| parse_target(arg)) {
+        Some(link) if args.len() <= 2 => link,
+        _ => { fail!(Usage, "Usage: ddp export-meta [--config <path>] [--token <token>] <link|hash> [output]"); }
+    };
+    let record = config.state_dir.as_ref().and_then(|dir| Library::new(dir).get(&link.hash));
+    let metadata = match record {
+        Some(record) => record.metadata,
+        None => {
+            info!("No metadata of {} has been recorded, requesting it from the network", link);
+            // Nothing is downloaded so the path of the handle is never used
+            match Node::new(config).fetch_link(&link, Some(PathBuf::new())) {
+                Some(handle) => handle.file.lock().unwrap().metadata.clone(),
+                None => { fail!(Transfer, "Failed to retrieve the metadata of {}", link); }
+            }
+        }
+    };
+
+    let output = args.get(1).map(PathBuf::from).unwrap_or_else(|| {
+        let name = Path::new(&metadata.name).file_name().map_or_else(|| to_hex_string(&link.hash), |name| name.to_string_lossy().into_owned());
+        PathBuf::from(format!("{}.{}", name, metafile::EXTENSION))
+    });
+    if let Err(e) = MetaFile::from_metadata(&metadata).save(&output) { fail!(Io, "{}", e); }
+    info!("Metadata of {} written to {}", link, output.display());
+}
+
+/// Write a torrent of a local file so BitTorrent clients can download it as well, the metadata recorded when the file
+/// was shared is used if there is any
+fn export_torrent(mut args: Vec<String>) {
+    let config = parse_config(&mut args);
+    if args.is_empty() || args.len() > 2 { fail!(Usage, "Usage: ddp export-torrent [--config <path>] <path> [output]"); }
+    let path = PathBuf::from(&args[0]);
+    if !path.is_file() { fail!(Usage, "{} is not a file", path.display()); }
+    let record = config.state_dir.as_ref().and_then(|dir| Library::new(dir).find(&path));
+    let file = match record {
+        Some(record) => File::from_local(record.metadata, record.path),
+        None => match File::prepare(path.clone(), config.hash_algorithm) {
+            Ok(file) => file,
+            Err(e) => { fail!(Io, "Failed to read {}: {}", path.display(), e) }
+        }
+    };
+    let torrent = match Torrent::from_file(&file) {
+        Ok(torrent) => torrent,
+        Err(e) => { fail!(Usage, "{}", e); }
+    };
+
+    let output = args.get(1).map(PathBuf::from).unwrap_or_else(|| PathBuf::from(format!("{}.{}", file.metadata.name, torrent::EXTENSION)));
+    if let Err(e) = torrent.save(&output) { fail!(Io, "{}", e); }
+    info!("Torrent of {} written to {}", to_hex_string(&file.metadata.hash.0), output.display());
+    info!("{}", torrent.magnet());
+}
+
+/// Download a stream while it is being shared and exit once it has ended
+fn fetch_stream(mut args: Vec<String>) {
+    let config = parse_config(&mut args);
+    require_fetching(&config);
+    let link = match args.first().and_then(|arg| parse_target(arg)) {
+        Some(link) if args.len() <= 2 => link,
+        _ => { fail!(Usage, "Usage: ddp fetch --stream [--config <path>] [--token <token>] <link|id> [path]"); }
+    };
+    let path = args.get(1).map(PathBuf::from).unwrap_or_else(|| {
+        let name = link.name.as_ref().and_then(|name| PathBuf::from(name).file_name().map(|n| n.to_owned()));
+        name.map_or_else(|| PathBuf::from(to_hex_string(&link.hash)), PathBuf::from)
+    });
+
+    let node = start_node(config);
+    if !node.fetch_stream(&link, path.clone()) { fail!(Transfer, "Download of stream {} failed", link); }
+    // The segments are only kept to be seeded while this process runs
+    let _ = fs::remove_dir_all(format!("{}.segments", path.display()));
+    info!("Stream saved to {}", path.display());
+}
+
+/// Check a local copy block by block against the metadata recorded when it was shared or downloaded, or against the
+/// metadata of the network if there is no record, and download the blocks that are not intact with `--repair`
+fn verify(mut args: Vec<String>) {
+    let repair = take_flag(&mut args, "--repair");
+    let key = take_option(&mut args, "--key").map(|hex| parse_key(&hex));
+    let config = parse_config(&mut args);
+    if repair { require_fetching(&config); }
+    if args.is_empty() || args.len() > 2 {
+        fail!(Usage, "Usage: ddp verify [--config <path>] [--repair] [--key <key>] [--token <token>] (<path> | <link|hash> [path])");
+    }
+    if repair && key.is_some() { fail!(Usage, "Decrypted copies can not be repaired, fetch them again instead"); }
+    let library = config.state_dir.as_ref().map(|dir| Library::new(dir));
+    let mut node = None;
+
+    // An existing path is only taken as such if it is the only argument, everything else has to be a link or hash
+    let record = match parse_target(&args[0]) {
+        Some(ref link) if args.len() == 1 || !Path::new(&args[0]).exists() => library.as_ref().and_then(|l| l.get(&link.hash)).ok_or(link.clone()),
+        _ if args.len() == 1 => match library.as_ref().and_then(|l| l.find(Path::new(&args[0]))) {
+            Some(record) => Ok(record),
+            None => { fail!(Usage, "No metadata is known for {}, pass the hash of the file along with the path", args[0]); }
+        },
+        _ => { fail!(Usage, "Invalid hash: {}", args[0]); }
+    };
+    let mut file = match record {
+        Ok(record) => File::from_local(record.metadata, args.get(1).map_or(record.path, PathBuf::from)),
+        Err(link) => {
+            let path = match args.get(1) {
+                Some(path) => PathBuf::from(path),
+                None => { fail!(Usage, "No metadata is known for {}, pass the path of the local copy as well", link); }
+            };
+            info!("No metadata of {} has been recorded, requesting it from the network", link);
+            let started = node.get_or_insert_with(|| start_node(config.clone()));
+            match started.fetch_link(&link, Some(path.clone())) {
+                Some(handle) => File::from_local(handle.file.lock().unwrap().metadata.clone(), path),
+                None => { fail!(Transfer, "Failed to retrieve the metadata of {}", link); }
+            }
+        }
+    };
+    file.key = key;
+
+    let states = file.audit();
+    let blocks = states.len() - 1;
+    let bad = |state: BlockState| (0..states.len()).filter(|id| states[*id] == state).collect::<Vec<_>>();
+    for &(state, label) in &[(BlockState::Corrupt, "corrupt"), (BlockState::Missing, "missing")] {
+        let ids = bad(state);
+        if ids.is_empty() { continue }
+        let trailing = if ids.last() == Some(&blocks) { " and the trailing bytes" } else { "" };
+        let ids = ids.into_iter().filter(|id| *id < blocks).collect::<Vec<_>>();
+        match ids.len() {
+            0 => warn!("The trailing bytes are {}", label),
+            1 if trailing.is_empty() => warn!("Block {} is {}", ids[0], label),
+            _ => warn!("Blocks {}{} are {}", format_ranges(&ids), trailing, label)
+        }
+    }
+    let intact = states[..blocks].iter().filter(|state| **state == BlockState::Intact).count();
+    info!("{}: {} of {} blocks intact", file.local_path.display(), intact, blocks);
+    if states.iter().all(|state| *state == BlockState::Intact) { return }
+    if !repair { fail!(HashMismatch, "{} is damaged, run again with --repair to download the damaged blocks", file.local_path.display()); }
+
+    let node = node.get_or_insert_with(|| start_node(config));
+    let hash = file.metadata.hash.0.clone();
+    node.transfers.add(node.repair(file, &states), Priority::High);
+    match node.transfers.wait(&hash) {
+        Some(TransferState::Seeding) | Some(TransferState::Complete) => info!("Repaired {} blocks", states.len() - 1 - intact),
+        state => { fail!(Transfer, "Repair failed ({:?})", state); }
+    }
+}
+
+/// Format sorted block IDs as ranges, e.g. `0-4, 7`
+fn format_ranges(ids: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for id in ids {
+        match ranges.last_mut() {
+            Some(range) if range.1 + 1 == *id => range.1 = *id,
+            _ => ranges.push((*id, *id))
+        }
+    }
+    ranges.iter().map(|&(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+        .collect::<Vec<_>>().join(", ")
+}
+
+/// List the most recent downloads recorded in the history or, with `--peers`, how every source performed across them
+fn history(mut args: Vec<String>) {
+    let peers = take_flag(&mut args, "--peers");
+    let limit = take_option(&mut args, "--limit").map_or(HISTORY_ENTRIES, |limit| match limit.parse() {
+        Ok(limit) => limit,
+        Err(_) => { fail!(Usage, "Invalid limit: {}", limit); }
+    });
+    let config = parse_config(&mut args);
+    if !args.is_empty() { fail!(Usage, "Usage: ddp history [--config <path>] [--peers] [--limit <count>]"); }
+    let dir = match config.state_dir {
+        Some(dir) => dir,
+        None => { fail!(Usage, "No state directory is configured, the history is not kept"); }
+    };
+    let entries = match History::new(&dir).entries() {
+        Ok(entries) => entries,
+        Err(e) => { fail!(Io, "Failed to read the history: {}", e); }
+    };
+
+    if peers {
+        for peer in summarize_peers(&entries).iter().take(limit) {
+            println!("{}  {} downloads, {} blocks, {}, {}/s, {} timeouts", peer.node_id, peer.downloads, peer.blocks,
+                format_bytes(peer.bytes as f64), format_bytes(peer.throughput), peer.timeouts);
+        }
+        return;
+    }
+    for entry in entries.iter().skip(entries.len().saturating_sub(limit)) {
+        println!("{}  {:9}  {}  {} in {:.1}s ({}/s) from {} peers  {}", entry.finished_at(), entry.outcome.to_string(), entry.name,
+            format_bytes(entry.size as f64), entry.duration, format_bytes(entry.rate()), entry.peers.len(), entry.hash);
+    }
+}
+
+/// Show the downloads, their sources and the log of the node running on this machine with the port offset given by
+/// `--port-offset` until interrupted
+fn tui(mut args: Vec<String>) {
+    let offset = match take_port_offset(&mut args) {
+        Ok(offset) => offset.unwrap_or(0),
+        Err(e) => { fail!(Usage, "{}", e); }
+    };
+    if !args.is_empty() { fail!(Usage, "Usage: ddp tui [--port-offset <n>]"); }
+    let events = match subscribe(offset) {
+        Ok(events) => events,
+        Err(e) => { fail!(Control, "{}", e); }
+    };
+    let interrupted = Arc::new(AtomicBool::new(false));
+    // The size is only looked up again once the terminal has been resized
+    let resized = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
     {
-        let mut files = files.lock().unwrap();
-        files.push(
-            File::prepare(PathBuf::from("./test"))
-        );
+        let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+            Ok(signals) => signals,
+            Err(e) => { fail!(Io, "Failed to listen for SIGINT: {}", e); }
+        };
+        let interrupted = interrupted.clone();
+        spawn(move || if signals.forever().next().is_some() { interrupted.store(true, Ordering::SeqCst); });
+        if let Err(e) = signal_hook::flag::register(SIGWINCH, resized.clone()) { warn!("Failed to listen for SIGWINCH: {}", e); }
     }
 
-    announce(files.clone());
+    let mut dashboard = Dashboard::new();
+    let (mut width, mut height) = terminal_size();
+    let mut connected = true;
+    print!("{}", ENTER_SCREEN);
+    while connected && !interrupted.load(Ordering::SeqCst) {
+        match events.recv_timeout(Duration::from_millis(REDRAW_INTERVAL)) {
+            Ok(event) => {
+                dashboard.apply(event);
+                while let Ok(event) = events.try_recv() { dashboard.apply(event); }
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => connected = false
+        }
+        // Every line is cleared up to its end and so is the rest of the screen, which avoids the flicker of clearing all
+        if resized.swap(false, Ordering::SeqCst) {
+            let size = terminal_size();
+            width = size.0;
+            height = size.1;
+        }
+        let screen = dashboard.render(width, height).join("\x1b[K\r\n");
+        print!("\x1b[H{}\x1b[K\x1b[J", screen);
+        let _ = io::stdout().flush();
+    }
+    print!("{}", LEAVE_SCREEN);
+    let _ = io::stdout().flush();
+    if !connected { fail!(Control, "The node closed the connection"); }
+}
+
+/// Check the network setup for a node with the port offset given by `--port-offset` and the multicast group given by
+/// `--multicast-group` and print what to do about problems
+fn doctor(mut args: Vec<String>) {
+    let offset = match take_port_offset(&mut args) {
+        Ok(offset) => offset.unwrap_or(0),
+        Err(e) => { fail!(Usage, "{}", e); }
+    };
+    let scope = match take_multicast_scope(&mut args, MulticastScope::new()) {
+        Ok(scope) => scope,
+        Err(e) => { fail!(Usage, "{}", e); }
+    };
+    let diagnostics = diagnose(offset, scope);
+    for diagnostic in diagnostics.iter() { println!("{}", diagnostic); }
+    let failed = diagnostics.iter().filter(|d| !d.passed).count();
+    if failed > 0 { fail!(Diagnosis, "{} of {} checks failed", failed, diagnostics.len()); }
+}
+
+/// Present the files discovered on the network at a mountpoint until it is unmounted, blocks are downloaded as they
+/// are read
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+fn mount(mut args: Vec<String>) {
+    let config_args = args.clone();
+    let config = parse_config(&mut args);
+    require_fetching(&config);
+    if args.len() != 1 { fail!(Usage, "Usage: ddp mount [--config <path>] [--gossip] [--token <token>] <mountpoint>"); }
+    let dir = config.state_dir.clone().unwrap_or_else(env::temp_dir).join("mount");
+
+    let node = start_node(config);
+    watch_config(&node, config_args);
+    info!("Mounting discovered files at {}", args[0]);
+    if let Err(e) = ddp::mount::mount(node, Path::new(&args[0]), dir) { fail!(Io, "Failed to mount {}: {}", args[0], e); }
+    info!("Unmounted {}", args[0]);
+}
+
+#[cfg(not(all(feature = "fuse", target_os = "linux")))]
+fn mount(_: Vec<String>) {
+    fail!(Unsupported, "Mounting is not supported by this build, rebuild with `--features fuse` on Linux");
+}
+
+fn run() {
+    let node = start_node(Config::new());
+
+    let uuid = node.share(PathBuf::from("./test")).unwrap();
 
     // Request some random file
     {
-        let uuid = files.lock().unwrap()[0].metadata.hash.0.clone();
-        std::thread::sleep(std::time::Duration::from_millis(200));
-        let mut file = File::from_metadata(&uuid, PathBuf::from("./download")).unwrap().to_handle();
-        file.download();
+        sleep(Duration::from_millis(200));
+        let file = node.fetch(&uuid, PathBuf::from("./download")).unwrap();
+        node.transfers.add(file, Priority::Normal);
+        info!("Transfer finished: {:?}", node.transfers.wait(&uuid).unwrap());
     }
 }
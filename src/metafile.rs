@@ -0,0 +1,121 @@
+//! Portable metadata files to hand the metadata of a file to somebody out of band, e.g. on a USB stick, so it can be
+//! fetched without asking the network for the metadata first
+//!
+//! A metadata file consists of the magic bytes `DDPMETA`, a format version byte and the bincode encoding of `MetaFile`
+//! for that version. Sizes are encoded as 64 bit integers so files are portable between architectures.
+use std::fs;
+use std::path::Path;
+
+use bincode::{serialize, deserialize};
+
+use file::FileMetadata;
+use crypto::Encryption;
+use helpers::{HashAlgorithm, calculate_block_size, block_count, trailing_length, HASH_LENGTH};
+
+/// Extension of metadata files
+pub const EXTENSION: &'static str = "ddpmeta";
+const MAGIC: &'static [u8] = b"DDPMETA";
+/// Version of the format written by this implementation
+const VERSION: u8 = 1;
+
+/// Content of a metadata file
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::file::example_file;
+/// # use ddp::metafile::MetaFile;
+/// # fn main() {
+/// let file = example_file("ddp-metafile-example", 3000);
+///
+/// let encoded = MetaFile::from_metadata(&file.metadata).encode();
+/// let metadata = MetaFile::decode(&encoded).unwrap().to_metadata().unwrap();
+/// assert_eq!(metadata.hash, file.metadata.hash);
+///
+/// // Truncated files are rejected
+/// assert!(MetaFile::decode(&encoded[..encoded.len() - 1]).is_err());
+/// # }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetaFile {
+    pub name: String,
+    pub algorithm: HashAlgorithm,
+    /// Hash of the content of the file
+    pub hash: Vec<u8>,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Size of every block in bytes, it is derived from the size of the file and only stored to detect mismatches
+    pub block_size: u64,
+    /// Hashes of the blocks in order
+    pub blocks: Vec<Vec<u8>>,
+    /// Bytes after the last block
+    pub trailing_bytes: Vec<u8>,
+    pub encryption: Option<Encryption>
+}
+
+impl MetaFile {
+    pub fn from_metadata(metadata: &FileMetadata) -> MetaFile {
+        MetaFile {
+            name: metadata.name.clone(),
+            algorithm: metadata.algorithm,
+            hash: metadata.hash.0.clone(),
+            size: metadata.size as u64,
+            block_size: calculate_block_size(metadata.size) as u64,
+            blocks: metadata.hash.1.clone(),
+            trailing_bytes: metadata.trailing_bytes.clone(),
+            encryption: metadata.encryption.clone()
+        }
+    }
+
+    /// Turn the content into metadata after checking that the blocks and trailing bytes add up to the size of the file
+    pub fn to_metadata(self) -> Result<FileMetadata, String> {
+        let size = self.size as usize;
+        if self.size > usize::MAX as u64 { return Err("The file is too large for this machine".to_string()) }
+        if self.block_size != calculate_block_size(size) as u64 {
+            return Err(format!("Unsupported block size {} for a file of {} bytes", self.block_size, size));
+        }
+        if self.blocks.len() != block_count(size) || self.trailing_bytes.len() != trailing_length(size) {
+            return Err("The blocks do not add up to the size of the file".to_string());
+        }
+        if self.hash.len() != HASH_LENGTH || self.blocks.iter().any(|block| block.len() != HASH_LENGTH) {
+            return Err("Invalid hash length".to_string());
+        }
+        Ok(FileMetadata {
+            name: self.name,
+            algorithm: self.algorithm,
+            hash: (self.hash, self.blocks),
+            size: size,
+            trailing_bytes: self.trailing_bytes,
+            encryption: self.encryption
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data.extend(serialize(self).unwrap());
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<MetaFile, String> {
+        if !data.starts_with(MAGIC) { return Err("Not a metadata file".to_string()) }
+        match data.get(MAGIC.len()) {
+            Some(&VERSION) => {},
+            Some(version) => return Err(format!("Unsupported metadata file version {}", version)),
+            None => return Err("Truncated metadata file".to_string())
+        }
+        deserialize(&data[MAGIC.len() + 1..]).map_err(|_| "Corrupted metadata file".to_string())
+    }
+
+    /// Read the metadata file at `path` and turn it into metadata
+    pub fn load(path: &Path) -> Result<FileMetadata, String> {
+        let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        MetaFile::decode(&data).and_then(MetaFile::to_metadata).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Write the metadata file to `path`
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.encode()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
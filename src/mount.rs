@@ -0,0 +1,337 @@
+//! Read-only filesystem presenting the files discovered on the network, their blocks are downloaded and verified as
+//! applications read them
+//!
+//! The FUSE kernel protocol is spoken directly over `/dev/fuse` so no FUSE library is required, mounting requires the
+//! privileges to call mount(2) though. Downloaded blocks are kept in a backing file per file and added to the block
+//! cache so they survive remounts.
+use std::cmp::min;
+use std::ffi::CString;
+use std::fs::{File as F, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use file::FileHandle;
+use helpers::{to_hex_string, calculate_block_size};
+use node::Node;
+
+const ROOT_INODE: u64 = 1;
+/// Largest read the kernel sends at once
+const MAX_READ: u32 = 128 * 1024;
+/// Size of the buffer requests are read into, the kernel requires room for the largest request plus its header
+const BUFFER_SIZE: usize = MAX_READ as usize + 4096;
+/// Time in seconds the kernel may cache attributes and directory entries
+const ATTR_TIMEOUT: u64 = 1;
+/// Interval in seconds at which the block cache is saved while blocks are being downloaded
+const CACHE_SAVE_INTERVAL: u64 = 10;
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_ACCESS: u32 = 34;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+/// File listed in the root directory, its inode is its index plus two
+struct Entry {
+    hash: Vec<u8>,
+    name: String,
+    size: usize,
+    /// Download of the file, created once it is read for the first time
+    handle: Option<Arc<Mutex<FileHandle>>>
+}
+
+/// State of a mounted filesystem, cloning it yields another handle to the same filesystem
+#[derive(Clone)]
+struct Filesystem {
+    node: Node,
+    /// Directory the backing files are kept in
+    dir: PathBuf,
+    entries: Arc<Mutex<Vec<Entry>>>,
+    /// Time at which the block cache has been saved
+    saved: Arc<Mutex<Instant>>
+}
+
+/// Fixed fields of every request
+struct Request {
+    opcode: u32,
+    unique: u64,
+    inode: u64
+}
+
+impl Filesystem {
+    /// Add the files that have been discovered since the last refresh, files stay listed once they are known
+    fn refresh(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        for file in self.node.discovered() {
+            if entries.iter().any(|entry| entry.hash == file.hash) { continue }
+            let (name, size) = match (file.name, file.size) {
+                (Some(name), Some(size)) => (name, size),
+                // Files are only listed once a seeder announced them
+                _ => continue
+            };
+            let hex = to_hex_string(&file.hash);
+            // Names must neither contain a separator nor collide with the name of another file
+            let mut name = if name.is_empty() || name.contains('/') || name == "." || name == ".." { hex.clone() } else { name };
+            if entries.iter().any(|entry| entry.name == name) { name = format!("{}.{}", name, &hex[..8]); }
+            entries.push(Entry { hash: file.hash, name: name, size: size, handle: None });
+        }
+    }
+
+    fn lookup(&self, name: &[u8]) -> Option<u64> {
+        self.refresh();
+        let entries = self.entries.lock().unwrap();
+        entries.iter().position(|entry| entry.name.as_bytes() == name).map(|index| index as u64 + 2)
+    }
+
+    /// Size of a file, `None` for the root directory and unknown inodes
+    fn size(&self, inode: u64) -> Option<usize> {
+        if inode < 2 { return None }
+        self.entries.lock().unwrap().get(inode as usize - 2).map(|entry| entry.size)
+    }
+
+    /// Retrieve the download of a file, requesting its metadata if it is read for the first time
+    fn handle(&self, inode: u64) -> Result<Arc<Mutex<FileHandle>>, i32> {
+        let (hash, size) = {
+            let entries = self.entries.lock().unwrap();
+            match entries.get((inode as usize).wrapping_sub(2)) {
+                Some(&Entry { handle: Some(ref handle), .. }) => return Ok(handle.clone()),
+                Some(entry) => (entry.hash.clone(), entry.size),
+                None => return Err(libc::ENOENT)
+            }
+        };
+        let path = self.dir.join(to_hex_string(&hash));
+        let handle = match self.node.fetch(&hash, path) {
+            Some(ref handle) if handle.file.lock().unwrap().metadata.size != size => {
+                warn!("Size of {} does not match its announcement", to_hex_string(&hash));
+                return Err(libc::EIO);
+            },
+            Some(handle) => Arc::new(Mutex::new(handle)),
+            None => { warn!("Failed to retrieve the metadata of {}", to_hex_string(&hash)); return Err(libc::EIO) }
+        };
+        // Another read may have created the handle in the meantime
+        let mut entries = self.entries.lock().unwrap();
+        let entry = &mut entries[inode as usize - 2];
+        Ok(entry.handle.get_or_insert(handle).clone())
+    }
+
+    /// Download the blocks covering a range of a file and read it from the backing file
+    fn read(&self, inode: u64, offset: u64, len: u32) -> Result<Vec<u8>, i32> {
+        let size = match self.size(inode) { Some(size) => size as u64, None => return Err(libc::ENOENT) };
+        if offset >= size { return Ok(Vec::new()) }
+        let end = min(offset + len as u64, size);
+
+        let handle = self.handle(inode)?;
+        let mut handle = handle.lock().unwrap();
+        let block_size = calculate_block_size(size as usize) as u64;
+        // The bytes after the last block are the trailing bytes which are part of the metadata
+        if !handle.download_range((offset / block_size) as usize..((end + block_size - 1) / block_size) as usize) {
+            return Err(libc::EIO);
+        }
+        self.save_cache();
+
+        let path = handle.file.lock().unwrap().local_path.clone();
+        let mut data = vec![0; (end - offset) as usize];
+        match F::open(&path).and_then(|f| f.read_exact_at(&mut data, offset)) {
+            Ok(_) => Ok(data),
+            Err(e) => { warn!("Failed to read {}: {}", path.display(), e); Err(libc::EIO) }
+        }
+    }
+
+    /// Save the block cache unless it has been saved recently
+    fn save_cache(&self) {
+        let mut saved = self.saved.lock().unwrap();
+        if saved.elapsed() < Duration::from_secs(CACHE_SAVE_INTERVAL) { return }
+        if let Err(e) = self.node.blocks.lock().unwrap().save() { warn!("Failed to save the block cache: {}", e); }
+        *saved = Instant::now();
+    }
+
+    fn attr(&self, inode: u64) -> Option<Vec<u8>> {
+        let (size, mode, links) = match inode {
+            ROOT_INODE => (0, libc::S_IFDIR | 0o555, 2),
+            _ => (self.size(inode)? as u64, libc::S_IFREG | 0o444, 1)
+        };
+        let mut attr = Vec::with_capacity(88);
+        for value in &[inode, size, (size + 511) / 512, 0, 0, 0] { attr.extend_from_slice(&value.to_ne_bytes()); }
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        for value in &[0, 0, 0, mode, links, uid, gid, 0, 4096, 0u32] { attr.extend_from_slice(&value.to_ne_bytes()); }
+        Some(attr)
+    }
+
+    /// Directory entries starting at `offset`, fitting into `len` bytes
+    fn readdir(&self, offset: u64, len: u32) -> Vec<u8> {
+        self.refresh();
+        let mut names = vec![(ROOT_INODE, ".".to_string(), libc::DT_DIR), (ROOT_INODE, "..".to_string(), libc::DT_DIR)];
+        for (index, entry) in self.entries.lock().unwrap().iter().enumerate() {
+            names.push((index as u64 + 2, entry.name.clone(), libc::DT_REG));
+        }
+
+        let mut data = Vec::new();
+        for (index, (inode, name, kind)) in names.into_iter().enumerate().skip(offset as usize) {
+            let padded = (24 + name.len() + 7) & !7;
+            if data.len() + padded > len as usize { break }
+            data.extend_from_slice(&inode.to_ne_bytes());
+            data.extend_from_slice(&(index as u64 + 1).to_ne_bytes());
+            data.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            data.extend_from_slice(&(kind as u32).to_ne_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.resize(data.len() + padded - 24 - name.len(), 0);
+        }
+        data
+    }
+}
+
+/// Mount the files discovered by `node` at `mountpoint` and answer requests until it is unmounted, backing files are
+/// kept in `dir`
+pub fn mount(node: Node, mountpoint: &Path, dir: PathBuf) -> io::Result<()> {
+    ::std::fs::create_dir_all(&dir)?;
+    let device = OpenOptions::new().read(true).write(true).open("/dev/fuse")?;
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let options = format!("fd={},rootmode=40000,user_id={},group_id={}", device.as_raw_fd(), uid, gid);
+    let invalid = |_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a null byte");
+    let target = CString::new(mountpoint.as_os_str().as_bytes()).map_err(invalid)?;
+    let (source, fstype, data) = (CString::new("ddp").unwrap(), CString::new("fuse.ddp").unwrap(), CString::new(options).unwrap());
+    let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_RDONLY;
+    if unsafe { libc::mount(source.as_ptr(), target.as_ptr(), fstype.as_ptr(), flags, data.as_ptr() as *const libc::c_void) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let fs = Filesystem {
+        node: node,
+        dir: dir,
+        entries: Arc::new(Mutex::new(Vec::new())),
+        saved: Arc::new(Mutex::new(Instant::now()))
+    };
+    let device = Arc::new(device);
+    let mut buf = vec![0; BUFFER_SIZE];
+    loop {
+        let len = match (&*device).read(&mut buf) {
+            Ok(len) => len,
+            // The request has been interrupted before it was read
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) || e.kind() == io::ErrorKind::Interrupted => continue,
+            // The filesystem has been unmounted
+            Err(ref e) if e.raw_os_error() == Some(libc::ENODEV) => break,
+            Err(e) => return Err(e)
+        };
+        if len < 40 { continue }
+        let request = Request {
+            opcode: u32_at(&buf, 4),
+            unique: u64_at(&buf, 8),
+            inode: u64_at(&buf, 16)
+        };
+        if !handle(&fs, &device, &request, &buf[40..len]) { break }
+    }
+    if let Err(e) = fs.node.blocks.lock().unwrap().save() { warn!("Failed to save the block cache: {}", e); }
+    Ok(())
+}
+
+/// Answer a request, returns false once the filesystem is shut down
+fn handle(fs: &Filesystem, device: &Arc<F>, request: &Request, payload: &[u8]) -> bool {
+    let result = match request.opcode {
+        FUSE_INIT => {
+            let mut init = Vec::with_capacity(64);
+            for value in &[FUSE_KERNEL_VERSION, min(u32_at(payload, 4), FUSE_KERNEL_MINOR_VERSION), u32_at(payload, 8), 0] {
+                init.extend_from_slice(&value.to_ne_bytes());
+            }
+            // Background requests, congestion threshold, largest write, timestamp granularity
+            for value in &[16u16, 12] { init.extend_from_slice(&value.to_ne_bytes()); }
+            for value in &[MAX_READ, 1] { init.extend_from_slice(&value.to_ne_bytes()); }
+            init.resize(64, 0);
+            Ok(init)
+        },
+        FUSE_LOOKUP if request.inode == ROOT_INODE => {
+            let name = payload.split(|b| *b == 0).next().unwrap_or(&[]);
+            match fs.lookup(name).and_then(|inode| fs.attr(inode).map(|attr| (inode, attr))) {
+                Some((inode, attr)) => {
+                    let mut entry = Vec::with_capacity(128);
+                    for value in &[inode, 0, ATTR_TIMEOUT, ATTR_TIMEOUT] { entry.extend_from_slice(&value.to_ne_bytes()); }
+                    entry.extend_from_slice(&[0; 8]);
+                    entry.extend_from_slice(&attr);
+                    Ok(entry)
+                },
+                None => Err(libc::ENOENT)
+            }
+        },
+        FUSE_LOOKUP => Err(libc::ENOENT),
+        FUSE_GETATTR => match fs.attr(request.inode) {
+            Some(attr) => {
+                let mut out = Vec::with_capacity(104);
+                out.extend_from_slice(&ATTR_TIMEOUT.to_ne_bytes());
+                out.extend_from_slice(&[0; 8]);
+                out.extend_from_slice(&attr);
+                Ok(out)
+            },
+            None => Err(libc::ENOENT)
+        },
+        FUSE_OPEN | FUSE_OPENDIR => if fs.attr(request.inode).is_some() { Ok(vec![0; 16]) } else { Err(libc::ENOENT) },
+        FUSE_READDIR => Ok(fs.readdir(u64_at(payload, 8), u32_at(payload, 16))),
+        FUSE_READ => {
+            // Reads may have to wait for blocks to be downloaded so they are answered in the background
+            let (fs, device, unique, inode) = (fs.clone(), device.clone(), request.unique, request.inode);
+            let (offset, len) = (u64_at(payload, 8), u32_at(payload, 16));
+            spawn(move || reply(&device, unique, fs.read(inode, offset, len)));
+            return true;
+        },
+        FUSE_STATFS => {
+            let mut statfs = vec![0; 80];
+            statfs[40..44].copy_from_slice(&4096u32.to_ne_bytes());
+            statfs[44..48].copy_from_slice(&255u32.to_ne_bytes());
+            statfs[48..52].copy_from_slice(&4096u32.to_ne_bytes());
+            Ok(statfs)
+        },
+        FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH | FUSE_ACCESS => Ok(Vec::new()),
+        // These requests do not expect a reply
+        FUSE_FORGET | FUSE_BATCH_FORGET | FUSE_INTERRUPT => return true,
+        FUSE_DESTROY => { reply(device, request.unique, Ok(Vec::new())); return false },
+        _ => Err(libc::ENOSYS)
+    };
+    reply(device, request.unique, result);
+    true
+}
+
+/// Send the reply to a request, errors are sent as negative error numbers
+fn reply(device: &F, unique: u64, result: Result<Vec<u8>, i32>) {
+    let (error, data) = match result {
+        Ok(data) => (0, data),
+        Err(error) => (-error, Vec::new())
+    };
+    let mut message = Vec::with_capacity(16 + data.len());
+    message.extend_from_slice(&(16 + data.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&error.to_ne_bytes());
+    message.extend_from_slice(&unique.to_ne_bytes());
+    message.extend_from_slice(&data);
+    // Every reply has to be written at once, the request it belongs to may have been interrupted in the meantime
+    if let Err(e) = (&*device).write(&message) { debug!("Failed to reply to request {}: {}", unique, e); }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    if let Some(slice) = data.get(offset..offset + 4) { bytes.copy_from_slice(slice); }
+    u32::from_ne_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    if let Some(slice) = data.get(offset..offset + 8) { bytes.copy_from_slice(slice); }
+    u64::from_ne_bytes(bytes)
+}
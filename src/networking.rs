@@ -1,23 +1,150 @@
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::mem;
 use std::net::{ UdpSocket, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream };
+use std::ops::Deref;
 use std::str::FromStr;
-use std::error::Error;
+use std::sync::{Arc, Mutex, Condvar};
 use std::thread::{spawn, JoinHandle};
-use std::io::{Read, Write};
-use std::time::Duration;
+use std::io::{self, Read, Write, ErrorKind};
+use std::time::{Duration, Instant};
 
 use ext_time::{Duration as ext_Duration, PreciseTime};
+use net2::{UdpBuilder, UdpSocketExt};
+#[cfg(unix)]
+use net2::unix::UnixUdpBuilderExt;
 
-const ANNOUNCE_MULTICAST: &'static str = "224.0.1.0";
+pub const ANNOUNCE_MULTICAST: &'static str = "224.0.1.0";
+/// Default time to live of multicast datagrams, which keeps them within the local segment
+pub const DEFAULT_MULTICAST_TTL: u32 = 1;
 pub const BASE_PORT: u16 = 8888;
+/// Maximum size of a datagram that fits into a single packet on common links, larger messages are fragmented
+pub const MAX_DATAGRAM_PAYLOAD: usize = 1200;
+/// Maximum size of a datagram every node accepts, larger datagrams are neither sent nor received. Messages should stay
+/// below `MAX_DATAGRAM_PAYLOAD` where possible since larger ones rely on IP fragmentation.
+pub const MAX_DATAGRAM_SIZE: usize = 16 * 1024;
+/// Amount of consecutive ports a node binds: the block, ping and control port
+pub const PORTS_PER_NODE: u16 = 3;
+/// Largest port offset of a node, whose last port has to stay a valid port
+pub const MAX_PORT_OFFSET: u16 = (u16::MAX - BASE_PORT - (PORTS_PER_NODE - 1)) / PORTS_PER_NODE * PORTS_PER_NODE;
+/// Upper bound for the length of a frame to avoid allocating arbitrary amounts of memory for garbage
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
 
-pub fn start_ping_server() -> JoinHandle<()> {
-    spawn(|| {
-        let tcp_sock = TcpListener::bind(("0.0.0.0", BASE_PORT + 1)).unwrap();
+/// Write `data` to a stream, prefixed with its length so several messages can share one connection
+pub fn write_frame<W: Write>(stream: &mut W, data: &[u8]) -> io::Result<()> {
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data)
+}
+
+/// Read a frame written by `write_frame` from a stream
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Frame of {} bytes exceeds the limit", length)));
+    }
+    let mut data = vec![0; length];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Read a frame from a connection like `read_frame`, failing with `ErrorKind::TimedOut` if it has not arrived
+/// completely by `deadline`. Without a deadline the connection blocks until the frame arrives.
+pub fn read_frame_before(stream: &mut TcpStream, deadline: Option<Instant>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    read_frame_into(stream, deadline, &mut data)?;
+    Ok(data)
+}
+
+/// Read a frame like `read_frame_before` and append it to `data`. If reading fails, e.g. because the deadline passed,
+/// `data` keeps the part of the frame that arrived.
+pub fn read_frame_into(stream: &mut TcpStream, deadline: Option<Instant>, data: &mut Vec<u8>) -> io::Result<()> {
+    match deadline {
+        Some(deadline) => read_frame_into_from(&mut DeadlineReader { stream: stream, deadline: deadline }, data),
+        None => {
+            stream.set_read_timeout(None)?;
+            read_frame_into_from(stream, data)
+        }
+    }
+}
+
+fn read_frame_into_from<R: Read>(stream: &mut R, data: &mut Vec<u8>) -> io::Result<()> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("Frame of {} bytes exceeds the limit", length)));
+    }
+    let mut buf = [0; 64 * 1024];
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk = min(remaining, buf.len());
+        let read = match stream.read(&mut buf[..chunk]) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed within a frame")),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        data.extend_from_slice(&buf[..read]);
+        remaining -= read;
+    }
+    Ok(())
+}
+
+/// Reader that shortens the read timeout of a connection to the time left until a deadline before every read
+struct DeadlineReader<'a> {
+    stream: &'a mut TcpStream,
+    deadline: Instant
+}
+
+impl<'a> Read for DeadlineReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        if now >= self.deadline { return Err(io::Error::new(ErrorKind::TimedOut, "deadline exceeded")) }
+        self.stream.set_read_timeout(Some(self.deadline - now))?;
+        self.stream.read(buf).map_err(|e| match e.kind() {
+            // Timeouts surface as either kind depending on the platform
+            ErrorKind::WouldBlock => io::Error::new(ErrorKind::TimedOut, "deadline exceeded"),
+            _ => e
+        })
+    }
+}
+
+/// Check that a port offset keeps every port valid and is a multiple of `PORTS_PER_NODE`, so the ports of nodes on one
+/// host never overlap
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::networking::{check_port_offset, MAX_PORT_OFFSET};
+/// # fn main() {
+/// assert!(check_port_offset(0).is_ok());
+/// assert!(check_port_offset(6).is_ok());
+/// // The ping and control port of a node at offset 1 would be the block and ping port of a node at offset 2
+/// assert!(check_port_offset(1).is_err());
+/// assert!(check_port_offset(MAX_PORT_OFFSET + 3).is_err());
+/// # }
+/// ```
+pub fn check_port_offset(offset: u16) -> Result<(), String> {
+    if offset > MAX_PORT_OFFSET { return Err(format!("Port offset {} exceeds the maximum of {}", offset, MAX_PORT_OFFSET)) }
+    if !offset.is_multiple_of(PORTS_PER_NODE) { return Err(format!("Port offset {} is not a multiple of {}", offset, PORTS_PER_NODE)) }
+    Ok(())
+}
+
+/// Answer pings on `port`, which is `BASE_PORT + 1` unless the node uses a port offset
+pub fn start_ping_server(port: u16) -> JoinHandle<()> {
+    spawn(move || {
+        let tcp_sock = match TcpListener::bind(("0.0.0.0", port)) {
+            Ok(socket) => socket,
+            Err(e) => { fail!(Bind, "TCP port {}: {}", port, e) }
+        };
         for stream in tcp_sock.incoming() {
-            let mut stream = stream.unwrap();
-            let mut buf = Vec::new();
-            stream.read(&mut [0]).unwrap();
-            stream.write_all(&mut buf).unwrap();
+            let mut stream = match stream { Ok(s) => s, Err(_) => continue };
+            // Pingers that go away are of no concern
+            if stream.read_exact(&mut [0]).is_err() { continue }
+            let _ = stream.write_all(&[0]);
         }
     })
 }
@@ -28,13 +155,9 @@ pub fn ping(mut target: SocketAddr) -> Option<ext_Duration> {
         Ok(mut stream) => {
             stream.set_read_timeout(Some(Duration::from_millis(5000))).unwrap();
             let start = PreciseTime::now();
-            match stream.write(&[1]) {
-                Ok(_) => {
-                    match stream.read(&mut [0]) {
-                        Ok(_) => Some(start.to(PreciseTime::now())),
-                        Err(_) => None
-                    }
-                },
+            // The server closes the connection after responding
+            match stream.write_all(&[1]).and_then(|_| stream.read_to_end(&mut Vec::new())) {
+                Ok(_) => Some(start.to(PreciseTime::now())),
                 Err(_) => None
             }
         },
@@ -42,11 +165,71 @@ pub fn ping(mut target: SocketAddr) -> Option<ext_Duration> {
     }
 }
 
+/// Multicast group the nodes of a network meet in and how far datagrams sent to it travel. The default group and
+/// time to live keep the datagrams within the local segment, nodes in other subnets can be reached with a group of a
+/// wider scope (e.g. the organization-local `239.192.0.0/14`), a time to live above the amount of multicast routers
+/// in between or a relay.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::networking::MulticastScope;
+/// # fn main() {
+/// let scope = MulticastScope::new().group("239.192.0.8".parse().unwrap()).ttl(4);
+/// assert_eq!(scope.group.to_string(), "239.192.0.8");
+/// assert!(MulticastScope::new().ttl(0).validate().is_err());
+/// assert!(MulticastScope::new().group("10.0.0.1".parse().unwrap()).validate().is_err());
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MulticastScope {
+    pub group: Ipv4Addr,
+    /// Amount of multicast routers a datagram may pass plus one
+    pub ttl: u32
+}
+
+impl Default for MulticastScope {
+    fn default() -> MulticastScope {
+        MulticastScope::new()
+    }
+}
+
+impl MulticastScope {
+    /// The default group and time to live
+    pub fn new() -> MulticastScope {
+        MulticastScope {
+            group: Ipv4Addr::from_str(ANNOUNCE_MULTICAST).expect("Failed to convert MULTICAST const to IP."),
+            ttl: DEFAULT_MULTICAST_TTL
+        }
+    }
+
+    /// Change the multicast group
+    pub fn group(mut self, group: Ipv4Addr) -> MulticastScope {
+        self.group = group;
+        self
+    }
+
+    /// Change the time to live of the datagrams sent to the group
+    pub fn ttl(mut self, ttl: u32) -> MulticastScope {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Check that the group is a multicast address and the datagrams leave the host
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.group.is_multicast() { return Err(format!("{} is not a multicast address", self.group)) }
+        if self.ttl == 0 || self.ttl > 255 { return Err(format!("Multicast TTL {} is not within 1 and 255", self.ttl)) }
+        Ok(())
+    }
+}
+
 /// Builder struct for `UDPSocketHandle`
 #[derive(Debug)]
 pub struct UDPSocket {
     local_addr: Ipv4Addr,
     multicast_addr: Ipv4Addr,
+    multicast_ttl: u32,
     /// The base port on which the sockets are based on
     pub port: u16
 }
@@ -59,16 +242,30 @@ pub struct UDPSocketHandle {
     multicast_addr: SocketAddr
 }
 
+impl Default for UDPSocket {
+    fn default() -> UDPSocket {
+        UDPSocket::new()
+    }
+}
+
 impl UDPSocket {
     /// Creates a new `UDPSocketHandle` builder
     pub fn new() -> UDPSocket {
         UDPSocket {
             local_addr: Ipv4Addr::new(0, 0, 0, 0),
             multicast_addr: Ipv4Addr::from_str(ANNOUNCE_MULTICAST).expect("Failed to convert MULTICAST const to IP."),
+            multicast_ttl: DEFAULT_MULTICAST_TTL,
             port: BASE_PORT
         }
     }
 
+    /// Change the multicast group the socket will attempt to join and the time to live of the datagrams sent to it
+    pub fn scope(mut self, scope: MulticastScope) -> UDPSocket {
+        self.multicast_addr = scope.group;
+        self.multicast_ttl = scope.ttl;
+        self
+    }
+
     /// Change the port of the resulting socket
     pub fn port(mut self, port: u16) -> UDPSocket {
         self.port = port;
@@ -77,13 +274,13 @@ impl UDPSocket {
 
     /// Change the local address on which the socket will bind to
     pub fn local_addr(mut self, ip: &'static str) -> UDPSocket {
-        self.local_addr = FromStr::from_str(&ip).ok().expect("Failed to resolve IP.");
+        self.local_addr = FromStr::from_str(ip).expect("Failed to resolve IP.");
         self
     }
 
     /// Change the multicast group the socket will attempt to join
     pub fn multicast_addr(mut self, ip: &'static str) -> UDPSocket {
-        self.multicast_addr = FromStr::from_str(&ip).ok().expect("Failed to resolve IP.");
+        self.multicast_addr = FromStr::from_str(ip).expect("Failed to resolve IP.");
         self
     }
 
@@ -94,11 +291,11 @@ impl UDPSocket {
             None => 0
         };
         let sock = match UdpSocket::bind(SocketAddrV4::new(self.local_addr, port)) {
-            Ok(s) => s, Err(e) => {exit!(8, "Error binding UDP socket: {}", e.description());}
+            Ok(s) => s, Err(e) => { fail!(Bind, "UDP port {}: {}", port, e) }
         };
-        match sock.join_multicast_v4(&self.multicast_addr, &self.local_addr) {
+        match sock.join_multicast_v4(&self.multicast_addr, &self.local_addr).and_then(|_| sock.set_multicast_ttl_v4(self.multicast_ttl)) {
             Ok(_) => sock,
-            Err(_) => { exit!(1, "Multicast support not available. (NET_ERR)"); }
+            Err(e) => { fail!(Multicast, "{}", e) }
         }
     }
 
@@ -110,42 +307,310 @@ impl UDPSocket {
         }
     }
 
-    pub fn create_listener(&mut self) -> UDPSocketHandle {
+    /// Create a handle that binds to a random port without joining the multicast group, e.g. to send replies to single
+    /// nodes from
+    pub fn create_sender(&mut self) -> io::Result<UDPSocketHandle> {
+        let sock = UdpSocket::bind(SocketAddrV4::new(self.local_addr, 0))?;
+        sock.set_multicast_ttl_v4(self.multicast_ttl)?;
+        Ok(UDPSocketHandle {
+            socket: sock,
+            multicast_addr: SocketAddr::V4(SocketAddrV4::new(self.multicast_addr, self.port))
+        })
+    }
+
+    /// Create a handle that binds to the base port and shares it with the listeners of other nodes on the same host,
+    /// which all receive the datagrams sent to the multicast group. With `group_only` the handle only receives those,
+    /// datagrams sent directly to the base port are left to the listener of the node without a port offset.
+    pub fn create_listener(&mut self, group_only: bool) -> UDPSocketHandle {
+        // Sockets bound to the group address do not receive unicast datagrams, which is not supported everywhere
+        let ip = if group_only && cfg!(unix) { self.multicast_addr } else { self.local_addr };
+        let sock = match bind_reusable(SocketAddrV4::new(ip, self.port)) {
+            Ok(s) => s, Err(e) => { fail!(Bind, "UDP port {}: {}", self.port, e) }
+        };
+        if let Err(e) = sock.join_multicast_v4(&self.multicast_addr, &self.local_addr).and_then(|_| sock.set_multicast_ttl_v4(self.multicast_ttl)) {
+            fail!(Multicast, "{}", e)
+        }
         UDPSocketHandle {
-            socket: self.assemble_socket(Some(0)),
+            socket: sock,
             multicast_addr: SocketAddr::V4(SocketAddrV4::new(self.multicast_addr, self.port))
         }
     }
+
+    /// Create a handle that receives the datagrams sent to the multicast group on each of the local `interfaces`,
+    /// given by their address. Like a listener with `group_only` it leaves the datagrams sent directly to the base port
+    /// to the listener of the node.
+    pub fn create_relay_listener(&mut self, interfaces: &[Ipv4Addr]) -> UDPSocketHandle {
+        let ip = if cfg!(unix) { self.multicast_addr } else { self.local_addr };
+        let sock = match bind_reusable(SocketAddrV4::new(ip, self.port)) {
+            Ok(s) => s, Err(e) => { fail!(Bind, "UDP port {}: {}", self.port, e) }
+        };
+        for interface in interfaces {
+            if let Err(e) = sock.join_multicast_v4(&self.multicast_addr, interface) { fail!(Multicast, "{} on {}", e, interface) }
+        }
+        UDPSocketHandle {
+            socket: sock,
+            multicast_addr: SocketAddr::V4(SocketAddrV4::new(self.multicast_addr, self.port))
+        }
+    }
+
+    /// Create a handle that sends datagrams to the multicast group through the local interface with the address
+    /// `interface`, without them being looped back to the sockets of this host
+    pub fn create_interface_handle(&mut self, interface: Ipv4Addr) -> UDPSocketHandle {
+        let sock = match UdpSocket::bind(SocketAddrV4::new(interface, 0)) {
+            Ok(s) => s, Err(e) => { fail!(Bind, "UDP on {}: {}", interface, e) }
+        };
+        let configured = sock.set_multicast_if_v4(&interface)
+            .and_then(|_| sock.set_multicast_ttl_v4(self.multicast_ttl))
+            .and_then(|_| sock.set_multicast_loop_v4(false));
+        if let Err(e) = configured { fail!(Multicast, "{} on {}", e, interface) }
+        UDPSocketHandle {
+            socket: sock,
+            multicast_addr: SocketAddr::V4(SocketAddrV4::new(self.multicast_addr, self.port))
+        }
+    }
+
+    /// Create a handle that binds exclusively to the base port plus `delta`, e.g. for the datagrams sent directly to
+    /// a node with a port offset
+    pub fn create_port_listener(&mut self, delta: u16) -> UDPSocketHandle {
+        UDPSocketHandle {
+            socket: self.assemble_socket(Some(delta)),
+            multicast_addr: SocketAddr::V4(SocketAddrV4::new(self.multicast_addr, self.port))
+        }
+    }
+}
+
+/// Bind a UDP socket that other sockets may bind to the same port as well
+fn bind_reusable(addr: SocketAddrV4) -> io::Result<UdpSocket> {
+    let builder = UdpBuilder::new_v4()?;
+    builder.reuse_address(true)?;
+    #[cfg(unix)]
+    builder.reuse_port(true)?;
+    builder.bind(addr)
 }
 
 impl UDPSocketHandle {
-    /// Send a datagram `data` to the `target` address
-    pub fn send(&self, data: &[u8], target: SocketAddr) -> usize {
+    /// Send a datagram `data` to the `target` address, datagrams exceeding `MAX_DATAGRAM_SIZE` are refused
+    pub fn send(&self, data: &[u8], target: SocketAddr) -> io::Result<usize> {
+        if data.len() > MAX_DATAGRAM_SIZE {
+            let message = format!("datagram of {} bytes exceeds the limit of {} bytes", data.len(), MAX_DATAGRAM_SIZE);
+            return Err(io::Error::new(ErrorKind::InvalidInput, message));
+        }
         trace!("UDP SEND {:?} -> {:?}", data, target);
-        self.socket.send_to(data, target).ok().expect("Failed to send transmission")
+        self.socket.send_to(data, target)
     }
 
     /// Broadcast a datagram `data` to the previously joined multicast group
-    pub fn send_to_multicast(&self, data: &[u8]) -> usize {
+    pub fn send_to_multicast(&self, data: &[u8]) -> io::Result<usize> {
         self.send(data, self.multicast_addr)
     }
 
     /// Receive a datagram from any sender
     pub fn receive(&self) -> (Vec<u8>, SocketAddr) {
-        let mut buf = vec![0; 1000000];//2048];
-        let (len, src) = self.socket.recv_from(&mut buf).ok().expect("Failed to receive package.");
-        buf.truncate(len);
-        trace!("UDP RECV {:?} <- {:?}", buf, src);
-        (buf, src)
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE + 1];
+        loop {
+            match self.receive_into(&mut buf) {
+                Ok(Some((len, src))) => {
+                    buf.truncate(len);
+                    return (buf, src);
+                },
+                Ok(None) => continue,
+                Err(e) => { panic!("Failed to receive package. ({:?})", e); }
+            }
+        }
+    }
+
+    /// Receive a datagram from any sender, giving up once `timeout` passes without one
+    pub fn receive_timeout(&self, timeout: Duration) -> Option<(Vec<u8>, SocketAddr)> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE + 1];
+        loop {
+            let now = Instant::now();
+            if now >= deadline { return None }
+            if self.socket.set_read_timeout(Some(deadline - now)).is_err() { return None }
+            match self.receive_into(&mut buf) {
+                Ok(Some((len, src))) => {
+                    buf.truncate(len);
+                    return Some((buf, src));
+                },
+                Ok(None) => continue,
+                // Timeouts surface as either kind depending on the platform
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return None,
+                Err(e) => { warn!("Failed to receive package. ({:?})", e); return None }
+            }
+        }
+    }
+
+    /// Receive a datagram into `buf` which has to be larger than `MAX_DATAGRAM_SIZE` to detect oversized datagrams,
+    /// returns `None` for datagrams that are dropped
+    fn receive_into(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.socket.recv_from(buf) {
+            Ok((len, src)) if len > MAX_DATAGRAM_SIZE => {
+                debug!("Dropping oversized datagram from {}", src);
+                Ok(None)
+            },
+            Ok((len, src)) => {
+                trace!("UDP RECV {:?} <- {:?}", &buf[..len], src);
+                Ok(Some((len, src)))
+            },
+            // Windows reports ICMP port unreachable messages of previous sends as a reset on the next receive
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Receive datagrams on a separate thread into a queue of at most `capacity` datagrams, `overflow` decides which
+    /// datagrams are dropped while the consumer is not keeping up. The buffers are reused once the datagrams are dropped.
+    pub fn start_receiver(self, capacity: usize, overflow: Overflow) -> DatagramQueue {
+        let queue = DatagramQueue {
+            state: Arc::new((Mutex::new(QueueState { datagrams: VecDeque::new(), dropped: 0, closed: false }), Condvar::new())),
+            // Queued datagrams, the one being received and the one being processed hold a buffer each
+            pool: BufferPool { buffers: Arc::new(Mutex::new(Vec::new())), capacity: capacity + 2 },
+            capacity: capacity,
+            overflow: overflow
+        };
+        queue.attach(self);
+        queue
+    }
+
+    pub fn try_clone(&self) -> io::Result<UDPSocketHandle> {
+        Ok(UDPSocketHandle {
+            socket: self.socket.try_clone()?,
+            multicast_addr: self.multicast_addr
+        })
+    }
+}
+
+/// Datagrams to drop when a receive queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Keep the queued datagrams, e.g. when they build on each other
+    DropNewest,
+    /// Make room for the new datagram, e.g. when fresh datagrams are more relevant than stale ones
+    DropOldest
+}
+
+/// Receive buffers that are handed back once the datagram received into them has been processed
+#[derive(Clone)]
+struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    /// Maximum amount of idle buffers that are kept
+    capacity: usize
+}
+
+impl BufferPool {
+    fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_else(|| vec![0; MAX_DATAGRAM_SIZE + 1])
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity { buffers.push(buf); }
+    }
+}
+
+/// Datagram received by `UDPSocketHandle::start_receiver`, dereferences to its contents
+pub struct Datagram {
+    buf: Vec<u8>,
+    len: usize,
+    /// Address of the sender
+    pub src: SocketAddr,
+    pool: BufferPool
+}
+
+impl Deref for Datagram {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
     }
+}
+
+impl Drop for Datagram {
+    fn drop(&mut self) {
+        self.pool.put(mem::take(&mut self.buf));
+    }
+}
 
-    pub fn try_clone(&self) -> Result<UDPSocketHandle, ()> {
-        match self.socket.try_clone() {
-            Ok(sock) => Ok(UDPSocketHandle {
-                socket: sock,
-                multicast_addr: self.multicast_addr
-            }),
-            Err(_) => Err(())
+struct QueueState {
+    datagrams: VecDeque<Datagram>,
+    /// Amount of datagrams dropped because the queue was full
+    dropped: usize,
+    /// Set once a receiving thread has stopped
+    closed: bool
+}
+
+/// Bounded queue of received datagrams
+pub struct DatagramQueue {
+    state: Arc<(Mutex<QueueState>, Condvar)>,
+    pool: BufferPool,
+    capacity: usize,
+    overflow: Overflow
+}
+
+impl DatagramQueue {
+    /// Receive the datagrams of another socket into the queue as well, on a thread of its own
+    pub fn attach(&self, socket: UDPSocketHandle) {
+        let state = self.state.clone();
+        let pool = self.pool.clone();
+        let (capacity, overflow) = (self.capacity, self.overflow);
+        spawn(move || {
+            let (lock, available) = &*state;
+            loop {
+                let mut buf = pool.take();
+                let (len, src) = match socket.receive_into(&mut buf) {
+                    Ok(Some(received)) => received,
+                    Ok(None) => { pool.put(buf); continue },
+                    Err(e) => {
+                        error!("Failed to receive datagrams: {}", e);
+                        lock.lock().unwrap().closed = true;
+                        available.notify_all();
+                        return;
+                    }
+                };
+                let datagram = Datagram { buf: buf, len: len, src: src, pool: pool.clone() };
+
+                let mut queue = lock.lock().unwrap();
+                if queue.datagrams.len() >= capacity {
+                    queue.dropped += 1;
+                    // Only log every few drops to not flood the log under load
+                    if queue.dropped.is_power_of_two() { debug!("Dropped {} datagrams since the queue was full", queue.dropped); }
+                    match overflow {
+                        Overflow::DropNewest => continue,
+                        Overflow::DropOldest => { queue.datagrams.pop_front(); }
+                    }
+                }
+                queue.datagrams.push_back(datagram);
+                available.notify_one();
+            }
+        });
+    }
+
+    /// Wait for the next datagram, `None` if the socket failed and no datagrams are left
+    pub fn recv(&self) -> Option<Datagram> {
+        let (lock, available) = &*self.state;
+        let mut queue = lock.lock().unwrap();
+        loop {
+            if let Some(datagram) = queue.datagrams.pop_front() { return Some(datagram) }
+            if queue.closed { return None }
+            queue = available.wait(queue).unwrap();
         }
     }
+
+    /// Wait at most `timeout` for the next datagram
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Datagram> {
+        let deadline = Instant::now() + timeout;
+        let (lock, available) = &*self.state;
+        let mut queue = lock.lock().unwrap();
+        loop {
+            if let Some(datagram) = queue.datagrams.pop_front() { return Some(datagram) }
+            let now = Instant::now();
+            if queue.closed || now >= deadline { return None }
+            queue = available.wait_timeout(queue, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Amount of datagrams dropped so far because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.state.0.lock().unwrap().dropped
+    }
 }
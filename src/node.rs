@@ -0,0 +1,514 @@
+//! Entry point of the library, bundling the state that is shared between all parts of a node
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::Duration;
+
+use bincode::serialize;
+
+use config::Config;
+use file::{File, FileMetadata, FileHandle, BlockState};
+use peers::{NodeId, PeerRegistry, generate_node_id, load_node_id};
+use discovery::{Discovery, DiscoveredFile};
+use networking::{UDPSocket, BASE_PORT, start_ping_server};
+use relay::start_relay;
+use announce::{announce, start_announcer, Message, Capabilities};
+use control::start_control_server;
+use helpers::to_hex_string;
+use uri::Link;
+use crypto::Key;
+use acl::Acl;
+use transfer::TransferManager;
+use bandwidth::{Schedule, Limiter, start_scheduler};
+use scrub::start_scrubber;
+use pex::SourceBook;
+use request::BlockListQueries;
+use gossip::{AvailabilityTable, start_gossip};
+use stream::{Stream, spool_stream, fetch_stream};
+use cache::{BlockCache, HotBlocks};
+use library::Library;
+use registry::FileRegistry;
+use distribute::{Distributions, Distribution, DistributionReport, DistributionStatus, rarest_blocks};
+use logger::Logger;
+use events::EventChannel;
+
+/// Amount of times a distribution report is sent
+const DISTRIBUTION_REPORTS: usize = 3;
+/// Time in milliseconds between two sends of a distribution report
+const DISTRIBUTION_REPORT_INTERVAL: u64 = 200;
+
+/// Source of the configuration that is applied when a node is reloaded
+type ReloadSource = Arc<Mutex<Option<Box<dyn Fn() -> Result<Config, String> + Send>>>>;
+
+/// A node in the network, cloning it yields another handle to the same node
+#[derive(Clone)]
+pub struct Node {
+    /// ID of this node
+    pub id: NodeId,
+    /// Settings of the node, swapped as a whole when the configuration is reloaded
+    config: Arc<RwLock<Config>>,
+    /// Hashes of the files shared because the configuration lists them, by their path
+    config_shares: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+    reload_source: ReloadSource,
+    /// Files that are shared by this node
+    pub files: FileRegistry,
+    /// Nodes that responded to queries of this node
+    pub peers: Arc<Mutex<PeerRegistry>>,
+    /// Content that has been overheard on the network
+    pub discovery: Arc<Mutex<Discovery>>,
+    /// Downloads of this node
+    pub transfers: TransferManager,
+    /// Global limit of the blocks served by this node
+    pub upload: Limiter,
+    /// Global limit of the blocks downloaded by this node
+    pub download: Limiter,
+    /// Limits by time of day that are applied to `upload` and `download`
+    pub schedule: Arc<Mutex<Schedule>>,
+    /// Socket shared by all downloads to query block lists
+    pub queries: BlockListQueries,
+    /// Blocks other nodes have according to their gossip
+    pub availability: Arc<Mutex<AvailabilityTable>>,
+    /// Streams shared by this node
+    pub streams: Arc<Mutex<Vec<Stream>>>,
+    /// Index of the blocks in local files, kept in the state directory
+    pub blocks: Arc<Mutex<BlockCache>>,
+    /// Blocks recently served to other nodes
+    pub hot_blocks: Arc<HotBlocks>,
+    /// Metadata of the local files, kept in the state directory
+    pub library: Option<Library>,
+    /// Distributions this node is the origin of or downloads from
+    pub distributions: Arc<Mutex<Distributions>>,
+    /// Progress of the downloads and messages logged by the node
+    pub events: EventChannel,
+    /// Sources of the files downloaded by this node, passed on to other nodes
+    pub source_book: SourceBook
+}
+
+impl Node {
+    /// Create a node with the ID kept in the state directory, the node does not communicate until it is started
+    pub fn new(config: Config) -> Node {
+        let id = match config.state_dir {
+            // A persistent ID would let anonymous nodes be recognized across networks
+            Some(_) if config.anonymous => generate_node_id(),
+            Some(ref dir) => load_node_id(dir).unwrap_or_else(|e| {
+                warn!("Failed to load the node ID from {}, using a temporary one: {}", dir.display(), e);
+                generate_node_id()
+            }),
+            None => generate_node_id()
+        };
+        debug!("Node ID {}", to_hex_string(&id));
+        let blocks = match config.state_dir {
+            Some(ref dir) => BlockCache::load(dir).unwrap_or_else(|e| {
+                warn!("Failed to load the block cache from {}, starting with an empty one: {}", dir.display(), e);
+                BlockCache::new()
+            }),
+            None => BlockCache::new()
+        };
+        let events = EventChannel::new();
+        Node {
+            id: id,
+            files: FileRegistry::new(),
+            peers: Arc::new(Mutex::new(PeerRegistry::new())),
+            discovery: Arc::new(Mutex::new(Discovery::new())),
+            transfers: TransferManager::new(events.clone()),
+            upload: Limiter::new(),
+            download: Limiter::new(),
+            schedule: Arc::new(Mutex::new(config.bandwidth.clone())),
+            queries: BlockListQueries::new(config.multicast),
+            availability: Arc::new(Mutex::new(AvailabilityTable::new())),
+            streams: Arc::new(Mutex::new(Vec::new())),
+            blocks: Arc::new(Mutex::new(blocks)),
+            hot_blocks: Arc::new(HotBlocks::new(config.serve_cache_size)),
+            library: config.state_dir.as_ref().map(|dir| Library::new(dir)),
+            distributions: Arc::new(Mutex::new(Distributions::new())),
+            events: events,
+            source_book: SourceBook::new(),
+            config: Arc::new(RwLock::new(config)),
+            config_shares: Arc::new(Mutex::new(HashMap::new())),
+            reload_source: Arc::new(Mutex::new(None))
+        }
+    }
+
+    /// Current settings of the node, the guard should be dropped quickly since it blocks reloads
+    pub fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap()
+    }
+
+    /// Port on which the node accepts block connections and datagrams sent directly to it
+    pub fn port(&self) -> u16 {
+        BASE_PORT + self.config().port_offset
+    }
+
+    /// Builder for sockets that send to and receive from the multicast group of the node
+    pub fn socket(&self) -> UDPSocket {
+        UDPSocket::new().scope(self.config().multicast)
+    }
+
+    /// What the node takes part in according to its mode
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::of(self.config().mode)
+    }
+
+    /// Start all background threads that answer queries, serve blocks and listen for commands and share the files
+    /// listed in the configuration
+    pub fn start(&self) {
+        let (anonymous, gossip, mode, relay) = {
+            let config = self.config();
+            if let Some(level) = config.log_level { Logger::set_level(level); }
+            (config.anonymous, config.gossips(), config.mode, !config.relay.is_empty())
+        };
+        Logger::publish_to(self.events.clone());
+        start_ping_server(self.port() + 1);
+        announce(self.clone());
+        // Announcing shared files would reveal them to everybody, nodes that do not serve have nothing to announce
+        if !anonymous && mode.serves() { start_announcer(self.clone()); }
+        if gossip && mode.serves() { start_gossip(self.clone()); }
+        start_control_server(self.clone());
+        if relay { start_relay(self.clone()); }
+        if mode.fetches() { self.transfers.start(self.files.clone(), self.config.clone()); }
+        start_scheduler(self.schedule.clone(), self.upload.clone(), self.download.clone());
+        if mode.serves() { start_scrubber(self.clone()); }
+        self.update_shares();
+    }
+
+    /// Set where the configuration is read from when the node is reloaded, e.g. a function loading a configuration file
+    pub fn set_reload_source<F: Fn() -> Result<Config, String> + Send + 'static>(&self, source: F) {
+        *self.reload_source.lock().unwrap() = Some(Box::new(source));
+    }
+
+    /// Read the configuration again from the reload source and apply it, the running configuration is kept if that
+    /// fails
+    pub fn reload(&self) -> Result<(), String> {
+        let config = match *self.reload_source.lock().unwrap() {
+            Some(ref source) => source()?,
+            None => return Err("The configuration has not been loaded from anywhere".to_string())
+        };
+        self.reconfigure(config);
+        Ok(())
+    }
+
+    /// Replace the configuration of the running node. Bandwidth limits, the log level and the shared files are
+    /// applied immediately, access rules and limits of responses to the next query and settings of downloads to the
+    /// next download. Settings that are only applied when the node starts keep their old value until it is restarted.
+    pub fn reconfigure(&self, mut config: Config) {
+        let mut current = self.config.write().unwrap();
+        for setting in current.restart_required(&config) {
+            warn!("Changing {} requires a restart, the new value is ignored until then", setting);
+        }
+        config.anonymous = current.anonymous;
+        config.query_rate = current.query_rate;
+        config.query_burst = current.query_burst;
+        config.max_metadata_pushes = current.max_metadata_pushes;
+        config.serve_cache_size = current.serve_cache_size;
+        config.state_dir = current.state_dir.clone();
+        config.gossip = current.gossip;
+        config.port_offset = current.port_offset;
+        config.mode = current.mode;
+        config.multicast = current.multicast;
+        config.relay = current.relay.clone();
+        if let Some(level) = config.log_level { Logger::set_level(level); }
+        self.set_schedule(config.bandwidth.clone());
+        *current = config;
+        drop(current);
+        self.update_shares();
+        info!("Configuration reloaded");
+    }
+
+    /// Share the files listed in the configuration that are not shared yet and stop sharing those that have been
+    /// removed from it
+    fn update_shares(&self) {
+        let paths = {
+            let config = self.config();
+            let paths = config.shared_files();
+            if config.mode.serves() { paths } else {
+                if !paths.is_empty() { warn!("Not sharing the configured files since the node is in leech-only mode"); }
+                Vec::new()
+            }
+        };
+        let mut shares = self.config_shares.lock().unwrap();
+        let removed = shares.keys().filter(|path| !paths.contains(path)).cloned().collect::<Vec<_>>();
+        for path in removed {
+            let hash = shares.remove(&path).unwrap();
+            // The file may be shared from another place under the same hash, only the configured copy is removed
+            let local_path = path.canonicalize().unwrap_or_else(|_| path.clone());
+            if let Some(file) = self.files.get(&hash) {
+                let remaining = file.write().unwrap().remove_copy(&local_path);
+                if !remaining { self.files.remove(&hash); }
+                info!("Stopped sharing {}", path.display());
+            }
+        }
+        for path in paths {
+            if shares.contains_key(&path) { continue }
+            // Unreadable files are tried again with the next reload
+            match self.share(path.clone()) {
+                Ok(hash) => {
+                    info!("Sharing {} as {}", path.display(), self.link(&hash).unwrap());
+                    shares.insert(path, hash);
+                },
+                Err(e) => warn!("Failed to share {}: {}", path.display(), e)
+            }
+        }
+    }
+
+    /// Replace the bandwidth schedule, the limits of the new schedule are applied while transfers keep running
+    pub fn set_schedule(&self, schedule: Schedule) {
+        let limits = schedule.current_limits();
+        self.upload.set_rate(limits.upload);
+        self.download.set_rate(limits.download);
+        *self.schedule.lock().unwrap() = schedule;
+    }
+
+    /// Prepare a local file and share it with the network, returns the hash of the file. Files with the same content
+    /// as a shared one are added to it as another copy.
+    pub fn share(&self, path: PathBuf) -> io::Result<Vec<u8>> {
+        // A path that is shared already does not have to be hashed again
+        if let Some(hash) = path.canonicalize().ok().and_then(|path| self.files.find_path(&path)) {
+            info!("{} is shared already", path.display());
+            return Ok(hash);
+        }
+        let algorithm = self.config().hash_algorithm;
+        let file = File::prepare(path, algorithm)?;
+        let hash = file.metadata.hash.0.clone();
+        self.cache_blocks(&file);
+        if let Some(ref library) = self.library {
+            if let Err(e) = library.insert(&file.metadata, &file.local_path) { warn!("Failed to record the metadata: {}", e); }
+        }
+        let path = file.local_path.clone();
+        if !self.files.insert_or_merge(file) {
+            info!("{} has the same content as {}, sharing it as another copy", path.display(), to_hex_string(&hash));
+        }
+        Ok(hash)
+    }
+
+    /// Share a local file as the origin of a distribution that finishes once `expected` downloaders completed it or
+    /// runs until stopped, returns the hash of the file
+    pub fn distribute(&self, path: PathBuf, expected: Option<usize>) -> io::Result<Vec<u8>> {
+        let hash = self.share(path)?;
+        self.distributions.lock().unwrap().start(hash.clone(), expected);
+        Ok(hash)
+    }
+
+    /// Make a download take part in a distribution, its blocks are served to the other downloaders while it is running
+    pub fn join_distribution(&self, handle: &mut FileHandle) {
+        handle.distributed = true;
+        // Encrypted files are only served once they are complete and decrypted
+        if handle.key.is_some() { return }
+        let (metadata, path, storage) = {
+            let file = handle.file.lock().unwrap();
+            (file.metadata.clone(), file.local_path.clone(), file.storage.clone())
+        };
+        let hash = metadata.hash.0.clone();
+        let mut seed = File::from_remote(metadata, path);
+        seed.storage = storage;
+        if !self.files.insert(seed) { debug!("{} is shared already", to_hex_string(&hash)); }
+        handle.seed = self.files.get(&hash);
+    }
+
+    /// Blocks out of `blocks` to advertise for a file with `count` blocks, the origin of a distribution only advertises
+    /// the rarest ones
+    pub fn advertised_blocks(&self, hash: &Vec<u8>, blocks: Vec<usize>, count: usize) -> Vec<usize> {
+        if !self.distributions.lock().unwrap().is_origin(hash) { return blocks }
+        let holders = self.availability.lock().unwrap().sources(hash, count);
+        rarest_blocks(&blocks, holders.as_ref().map(|holders| &holders[..]))
+    }
+
+    /// Report to the origin of a distribution that the download of a file completed after `elapsed`
+    pub fn report_completion(&self, hash: &[u8], elapsed: Duration) {
+        let milliseconds = elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64;
+        self.report_distribution(hash, DistributionStatus::Complete { milliseconds: milliseconds });
+    }
+
+    /// Tell the downloaders of a distribution this node is the origin of that they may stop serving the file
+    pub fn finish_distribution(&self, hash: &[u8]) {
+        self.report_distribution(hash, DistributionStatus::Finished);
+    }
+
+    fn report_distribution(&self, hash: &[u8], status: DistributionStatus) {
+        let report = Message::Distribution(DistributionReport {
+            node_id: self.id.clone(),
+            hash: hash.to_vec(),
+            status: status
+        });
+        let report = serialize(&report).unwrap();
+        let socket = self.socket().create_handle();
+        // Datagrams may get lost, the receivers only count a report once
+        for attempt in 0..DISTRIBUTION_REPORTS {
+            if attempt > 0 { sleep(Duration::from_millis(DISTRIBUTION_REPORT_INTERVAL)); }
+            if let Err(e) = socket.send_to_multicast(&report) { warn!("Failed to report the distribution: {}", e); }
+        }
+    }
+
+    /// Progress of a distribution this node is the origin of
+    pub fn distribution(&self, hash: &Vec<u8>) -> Option<Distribution> {
+        self.distributions.lock().unwrap().get(hash).cloned()
+    }
+
+    /// Bytes of a shared file uploaded to other nodes so far
+    pub fn uploaded(&self, hash: &Vec<u8>) -> usize {
+        self.files.get(hash).map_or(0, |file| file.read().unwrap().uploaded)
+    }
+
+    /// Whether the origin of a distribution announced that it is finished
+    pub fn distribution_finished(&self, hash: &Vec<u8>) -> bool {
+        self.distributions.lock().unwrap().is_finished(hash)
+    }
+
+    /// Add the blocks of a file whose local copy is stored as it is distributed to the block cache
+    pub fn cache_blocks(&self, file: &File) {
+        if file.key.is_some() { return }
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.insert(&file.metadata, &file.local_path);
+        if let Err(e) = blocks.save() { warn!("Failed to save the block cache: {}", e); }
+    }
+
+    /// Encrypt a local file with `key` and share the ciphertext with the network, returns the hash of the ciphertext
+    pub fn share_encrypted(&self, path: PathBuf, key: Key) -> io::Result<Vec<u8>> {
+        let algorithm = self.config().hash_algorithm;
+        let file = File::prepare_encrypted(path, algorithm, key)?;
+        let hash = file.metadata.hash.0.clone();
+        if !self.files.insert(file) { warn!("{} is shared already", to_hex_string(&hash)); }
+        Ok(hash)
+    }
+
+    /// Share the data read from `reader` until it ends, e.g. stdin, as a stream whose segments are shared as soon as
+    /// they are complete. Returns the ID of the stream.
+    pub fn share_stream<R: Read + Send + 'static>(&self, reader: R, name: String) -> Vec<u8> {
+        let stream = Stream::new(name);
+        let id = stream.id.clone();
+        // The segments are kept apart from regular downloads since they are only of use to this node while it shares them
+        let dir = self.config().state_dir.clone().unwrap_or_else(env::temp_dir).join("streams").join(to_hex_string(&id));
+        self.streams.lock().unwrap().push(stream);
+        spool_stream(self.clone(), reader, id.clone(), dir);
+        id
+    }
+
+    /// Download a stream to `path` while it is still being shared, returns false if it stalled or a segment failed
+    pub fn fetch_stream(&self, link: &Link, path: PathBuf) -> bool {
+        fetch_stream(self, link, path)
+    }
+
+    /// Whether the mode of the node permits downloads, warns if it does not
+    fn may_fetch(&self) -> bool {
+        let fetches = self.config().mode.fetches();
+        if !fetches { warn!("Not fetching since the node is in seed-only mode"); }
+        fetches
+    }
+
+    /// Request the metadata of a file from the network and create a handle to download it to `path`
+    pub fn fetch(&self, hash: &[u8], path: PathBuf) -> Option<FileHandle> {
+        if !self.may_fetch() { return None }
+        let (token, window, udp, scope) = { let config = self.config(); (config.token.clone(), config.discovery, config.udp_metadata, config.multicast) };
+        File::from_metadata(hash, path, self.peers.clone(), &[], token, &window, udp, scope).map(|file| self.handle(file))
+    }
+
+    /// Request the metadata of a linked file, querying the peers of the link directly, and create a handle to download
+    /// it to `path` or the name contained in the link
+    pub fn fetch_link(&self, link: &Link, path: Option<PathBuf>) -> Option<FileHandle> {
+        if !self.may_fetch() { return None }
+        // Only the file name of the link is used so it can not point anywhere outside the working directory
+        let path = path.unwrap_or_else(|| {
+            let name = link.name.as_ref().and_then(|name| PathBuf::from(name).file_name().map(|n| n.to_owned()));
+            name.map_or_else(|| PathBuf::from(to_hex_string(&link.hash)), PathBuf::from)
+        });
+        let (token, window, udp, scope) = { let config = self.config(); (config.token.clone(), config.discovery, config.udp_metadata, config.multicast) };
+        let file = File::from_metadata(&link.hash, path, self.peers.clone(), &link.peers, token, &window, udp, scope)?;
+        if link.size.is_some_and(|size| size != file.metadata.size) {
+            warn!("Size of {} does not match the link", to_hex_string(&link.hash));
+            return None;
+        }
+        Some(self.handle(file))
+    }
+
+    /// Create a handle to download a file whose metadata is already known, e.g. from a metadata file, to `path`
+    pub fn fetch_metadata(&self, metadata: FileMetadata, path: PathBuf) -> FileHandle {
+        self.handle(File::from_remote(metadata, path))
+    }
+
+    /// Create a handle to download a file with the settings of this node
+    fn handle(&self, file: File) -> FileHandle {
+        let mut handle = file.to_handle(self.peers.clone());
+        let config = self.config();
+        handle.pipeline_depth = config.pipeline_depth.max(1);
+        handle.token = config.token.clone();
+        handle.node_id = self.id.clone();
+        handle.limiter = self.download.clone();
+        handle.queries = Some(self.queries.clone());
+        handle.scope = config.multicast;
+        if config.gossips() { handle.availability = Some(self.availability.clone()); }
+        handle.peer_exchange = !config.anonymous;
+        handle.cache = Some(self.blocks.clone());
+        handle.discovery = config.discovery;
+        handle.block_deadline = config.block_deadline;
+        handle.max_source_age = config.max_source_age;
+        handle.library = self.library.clone();
+        handle.capabilities = Capabilities::of(config.mode);
+        handle.events = Some(self.events.clone());
+        handle.source_book = Some(self.source_book.clone());
+        handle
+    }
+
+    /// Create a handle that only downloads the blocks of a local copy that are not intact according to `states`, as
+    /// returned by `File::audit`
+    pub fn repair(&self, file: File, states: &[BlockState]) -> FileHandle {
+        let mut handle = self.handle(file);
+        for (completed, state) in handle.completed.iter_mut().zip(states) { *completed = *state == BlockState::Intact; }
+        handle
+    }
+
+    /// Create a link to a shared or discovered file
+    pub fn link(&self, hash: &Vec<u8>) -> Option<Link> {
+        let mut link = Link::new(hash.clone());
+        if let Some(file) = self.files.get(hash) {
+            let file = file.read().unwrap();
+            link.size = Some(file.metadata.size);
+            link.name = Some(file.metadata.name.clone());
+            return Some(link);
+        }
+        self.discovery.lock().unwrap().get(hash).map(|file| {
+            link.size = file.size;
+            link.name = file.name.clone();
+            link.peers = file.seeders.values().cloned().collect();
+            link
+        })
+    }
+
+    /// Restrict which hosts may query and download a shared file in addition to the rules of the node, returns false
+    /// if the file is not shared
+    pub fn set_acl(&self, hash: &Vec<u8>, acl: Acl) -> bool {
+        match self.files.get(hash) {
+            Some(file) => { file.write().unwrap().acl = Some(acl); true },
+            None => false
+        }
+    }
+
+    /// List all content that has been overheard on the network, including content that is neither shared nor downloaded
+    pub fn discovered(&self) -> Vec<DiscoveredFile> {
+        self.discovery.lock().unwrap().list()
+    }
+
+    /// Pause the download of a file and optionally the serving of it, returns false if the file is neither
+    /// downloaded nor shared
+    pub fn pause(&self, hash: &Vec<u8>, uploads: bool) -> bool {
+        let mut found = self.transfers.pause(hash, uploads);
+        if uploads {
+            if let Some(file) = self.files.get(hash) {
+                file.write().unwrap().paused = true;
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Resume the download and serving of a file, returns false if the file is neither downloaded nor shared
+    pub fn resume(&self, hash: &Vec<u8>) -> bool {
+        let mut found = self.transfers.resume(hash);
+        if let Some(file) = self.files.get(hash) {
+            file.write().unwrap().paused = false;
+            found = true;
+        }
+        found
+    }
+}
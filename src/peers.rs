@@ -0,0 +1,296 @@
+//! Registry of remote nodes that answered our queries
+//!
+//! Nodes are identified by a random ID that persists across restarts, so a node keeps its reputation when its address
+//! changes and a host with several addresses counts as a single node. Addresses are only routes to a node.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::Duration as StdDuration;
+
+use ext_time::{PreciseTime, Duration};
+use getrandom::getrandom;
+
+use helpers::{to_hex_string, from_hex_string, HASH_LENGTH};
+
+/// Identifier of a node in the network
+pub type NodeId = Vec<u8>;
+
+/// Weight of a new measurement in the moving averages of the peer statistics
+const SMOOTHING: f64 = 0.3;
+/// Name of the file within the state directory that holds the ID of this node
+const NODE_ID_FILE: &'static str = "node_id";
+/// Default throughput in bytes per second below which a source is abandoned while it delivers a block
+const DEFAULT_MIN_BLOCK_THROUGHPUT: u64 = 16 * 1024;
+/// Default time in seconds every block may take in addition to the time given by the minimum throughput
+const DEFAULT_BLOCK_GRACE: u64 = 5;
+/// Default time in seconds after which a source is abandoned while it delivers a block, regardless of its size
+const DEFAULT_MAX_BLOCK_DURATION: u64 = 60;
+
+/// Generate a new random node ID
+pub fn generate_node_id() -> NodeId {
+    let mut id = vec![0; HASH_LENGTH];
+    getrandom(&mut id).expect("Failed to generate a node ID");
+    id
+}
+
+/// Load the ID of this node from the state directory, generating and storing one at the first start
+pub fn load_node_id(state_dir: &Path) -> io::Result<NodeId> {
+    let path = state_dir.join(NODE_ID_FILE);
+    let mut content = String::new();
+    match File::open(&path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut content)?;
+            match from_hex_string(content.trim()) {
+                Some(ref id) if id.len() == HASH_LENGTH => return Ok(id.clone()),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is corrupted", path.display())))
+            }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e)
+    }
+
+    let id = generate_node_id();
+    fs::create_dir_all(state_dir)?;
+    File::create(&path)?.write_all(to_hex_string(&id).as_bytes())?;
+    Ok(id)
+}
+
+/// A remote node that has responded to a query
+#[derive(Clone)]
+pub struct Peer {
+    /// ID of the remote node
+    pub id: NodeId,
+    /// Address on which the remote node has been seen last, it is tried first when connecting
+    pub addr: SocketAddr,
+    /// All addresses on which the remote node has been seen, most recent first
+    pub routes: Vec<SocketAddr>,
+    /// Time at which the last response of this node arrived
+    pub last_seen: PreciseTime
+}
+
+/// Transfer performance of a peer measured while downloading blocks from it
+#[derive(Debug, Clone, Copy)]
+pub struct PeerStats {
+    /// Moving average of the throughput in bytes per second
+    pub throughput: f64,
+    /// Moving average of the time it takes to establish a connection in seconds
+    pub latency: f64,
+    /// Amount of blocks successfully received
+    pub blocks: usize,
+    /// Amount of failed connections, empty responses or abandoned blocks
+    pub failures: usize,
+    /// Amount of blocks abandoned because the peer delivered them too slowly
+    pub timeouts: usize
+}
+
+impl PeerStats {
+    /// Estimated time in seconds it takes the peer to deliver a block of the given size, penalized by failures
+    pub fn estimate(&self, block_size: usize) -> f64 {
+        let transfer = if self.throughput > 0.0 { block_size as f64 / self.throughput } else { 0.0 };
+        (self.latency + transfer) * (1 + self.failures) as f64
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.num_microseconds().unwrap_or(i64::MAX) as f64 / 1000000.0
+}
+
+/// Collection of all known peers indexed by their node ID
+pub struct PeerRegistry {
+    peers: HashMap<NodeId, Peer>,
+    /// Node last seen at each address
+    routes: HashMap<IpAddr, NodeId>,
+    /// Transfer statistics indexed by node ID
+    stats: HashMap<NodeId, PeerStats>
+}
+
+impl Default for PeerRegistry {
+    fn default() -> PeerRegistry {
+        PeerRegistry::new()
+    }
+}
+
+impl PeerRegistry {
+    pub fn new() -> PeerRegistry {
+        PeerRegistry {
+            peers: HashMap::new(),
+            routes: HashMap::new(),
+            stats: HashMap::new()
+        }
+    }
+
+    /// Insert a peer or refresh the address and timestamp of an already known one, the previous addresses are kept as
+    /// alternative routes
+    pub fn update(&mut self, id: NodeId, addr: SocketAddr) {
+        // An address that moved to another node is no longer a route to the previous one
+        if let Some(previous) = self.routes.insert(addr.ip(), id.clone()) {
+            if previous != id {
+                if let Some(peer) = self.peers.get_mut(&previous) { peer.routes.retain(|route| route.ip() != addr.ip()); }
+            }
+        }
+        let peer = self.peers.entry(id.clone()).or_insert(Peer {
+            id: id,
+            addr: addr,
+            routes: Vec::new(),
+            last_seen: PreciseTime::now()
+        });
+        peer.routes.retain(|route| *route != addr);
+        peer.routes.insert(0, addr);
+        peer.addr = addr;
+        peer.last_seen = PreciseTime::now();
+    }
+
+    /// Retrieve a peer by its node ID
+    pub fn get(&self, id: &NodeId) -> Option<&Peer> {
+        self.peers.get(id)
+    }
+
+    /// Retrieve the ID of the node last seen at the given IP
+    pub fn node_at(&self, ip: &IpAddr) -> Option<&NodeId> {
+        self.routes.get(ip)
+    }
+
+    /// Retrieve the addresses of a node, most recent first
+    pub fn routes(&self, id: &NodeId) -> Vec<SocketAddr> {
+        self.peers.get(id).map_or(Vec::new(), |peer| peer.routes.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Record a successfully received block of `bytes` length that took `latency` to connect and `duration` in total
+    pub fn record_block(&mut self, id: &NodeId, bytes: usize, latency: Duration, duration: Duration) {
+        let throughput = bytes as f64 / seconds(duration).max(0.000001);
+        let latency = seconds(latency);
+        let stats = self.stats.entry(id.clone()).or_insert(PeerStats {
+            throughput: throughput,
+            latency: latency,
+            blocks: 0,
+            failures: 0,
+            timeouts: 0
+        });
+        stats.throughput += SMOOTHING * (throughput - stats.throughput);
+        stats.latency += SMOOTHING * (latency - stats.latency);
+        stats.blocks += 1;
+    }
+
+    /// Record a failed connection attempt or unusable response
+    pub fn record_failure(&mut self, id: &NodeId) {
+        self.stats_entry(id).failures += 1;
+    }
+
+    /// Record a block that has been abandoned because the peer did not deliver it in time, it counts as a failure
+    pub fn record_timeout(&mut self, id: &NodeId) {
+        let stats = self.stats_entry(id);
+        stats.failures += 1;
+        stats.timeouts += 1;
+    }
+
+    /// Statistics of a peer, created without any measurements if there are none yet
+    fn stats_entry(&mut self, id: &NodeId) -> &mut PeerStats {
+        self.stats.entry(id.clone()).or_insert(PeerStats {
+            throughput: 0.0,
+            latency: 0.0,
+            blocks: 0,
+            failures: 0,
+            timeouts: 0
+        })
+    }
+
+    /// Retrieve the transfer statistics of a peer
+    pub fn stats(&self, id: &NodeId) -> Option<&PeerStats> {
+        self.stats.get(id)
+    }
+
+    /// Sort sources by the estimated time they take to deliver a block, unmeasured ones first so they get measured
+    pub fn rank(&self, sources: &mut [NodeId], block_size: usize) {
+        sources.sort_by(|a, b| {
+            let a = self.stats.get(a).map_or(0.0, |s| s.estimate(block_size));
+            let b = self.stats.get(b).map_or(0.0, |s| s.estimate(block_size));
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        });
+    }
+}
+
+/// Time a source may take to deliver a single block before the block is abandoned and requested from another source
+///
+/// A block has to arrive within the grace period plus the time it takes at the minimum throughput, but never later
+/// than the maximum duration. Both limits can be disabled.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::time::Duration;
+/// # use ddp::peers::BlockDeadline;
+/// # fn main() {
+/// let deadline = BlockDeadline::new().min_throughput(Some(1000)).grace(Duration::from_secs(2)).max_duration(Some(Duration::from_secs(10)));
+/// assert_eq!(deadline.allowed(4000), Some(Duration::from_secs(6)));
+/// assert_eq!(deadline.allowed(100000), Some(Duration::from_secs(10)));
+///
+/// let unlimited = deadline.min_throughput(None).max_duration(None);
+/// assert_eq!(unlimited.allowed(100000), None);
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockDeadline {
+    /// Throughput in bytes per second below which a block is abandoned, blocks may arrive arbitrarily slow if missing
+    pub min_throughput: Option<u64>,
+    /// Time every block may take in addition to the time given by the minimum throughput
+    pub grace: StdDuration,
+    /// Time after which a block is abandoned regardless of its size, blocks may take arbitrarily long if missing
+    pub max_duration: Option<StdDuration>
+}
+
+impl Default for BlockDeadline {
+    fn default() -> BlockDeadline {
+        BlockDeadline::new()
+    }
+}
+
+impl BlockDeadline {
+    /// Creates a deadline with the default minimum throughput, grace period and maximum duration
+    pub fn new() -> BlockDeadline {
+        BlockDeadline {
+            min_throughput: Some(DEFAULT_MIN_BLOCK_THROUGHPUT),
+            grace: StdDuration::from_secs(DEFAULT_BLOCK_GRACE),
+            max_duration: Some(StdDuration::from_secs(DEFAULT_MAX_BLOCK_DURATION))
+        }
+    }
+
+    /// Change the throughput below which a block is abandoned, `None` disables the limit
+    pub fn min_throughput(mut self, throughput: Option<u64>) -> BlockDeadline {
+        self.min_throughput = throughput;
+        self
+    }
+
+    /// Change the time every block may take in addition to the time given by the minimum throughput
+    pub fn grace(mut self, grace: StdDuration) -> BlockDeadline {
+        self.grace = grace;
+        self
+    }
+
+    /// Change the time after which a block is abandoned regardless of its size, `None` disables the limit
+    pub fn max_duration(mut self, duration: Option<StdDuration>) -> BlockDeadline {
+        self.max_duration = duration;
+        self
+    }
+
+    /// Time a block of `bytes` length may take to arrive, `None` if it may take arbitrarily long
+    pub fn allowed(&self, bytes: usize) -> Option<StdDuration> {
+        let by_throughput = self.min_throughput.filter(|throughput| *throughput > 0)
+            .map(|throughput| self.grace + StdDuration::from_millis(bytes as u64 * 1000 / throughput));
+        match (by_throughput, self.max_duration) {
+            (Some(allowed), Some(max)) => Some(if allowed < max { allowed } else { max }),
+            (allowed, max) => allowed.or(max)
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Peer exchange, learning further sources of a file from the nodes it is downloaded from
+//!
+//! Multicast only reaches the segments it is forwarded to, so on larger bridged networks a downloader may only discover
+//! part of the nodes that have a file. Every node keeps the sources its downloads know of in a source book. Downloaders
+//! ask the nodes they download from for the sources in their book now and then via the block connection and add them
+//! to their own sources, which in turn end up in their book, so sources spread from node to node.
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bitfield::BlockSet;
+use peers::NodeId;
+
+/// Maximum amount of sources passed on in a single exchange
+pub const MAX_EXCHANGED_PEERS: usize = 32;
+/// Time in seconds after which the sources recorded for a file are no longer passed on
+const STALE_AFTER: u64 = 120;
+
+/// Sources of every block of a file along with the time they have been recorded
+type RecordedSources = (Vec<Vec<NodeId>>, Instant);
+
+/// Source of a file passed on to another node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExchangedPeer {
+    pub node_id: NodeId,
+    /// Address on which the source accepts queries and block requests
+    pub addr: SocketAddr,
+    /// Blocks of the file the source has
+    pub blocks: BlockSet
+}
+
+/// Response to a request for the sources of a file a node knows
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerExchange {
+    pub peers: Vec<ExchangedPeer>
+}
+
+/// Sources of the files a node downloads, passed on to the nodes that ask for them. Cloning it yields another handle to
+/// the same book.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::pex::SourceBook;
+/// # fn main() {
+/// let book = SourceBook::new();
+/// let (a, b) = (vec![1], vec![2]);
+/// book.record(&vec![42], &[vec![a.clone()], vec![a.clone(), b.clone()]]);
+///
+/// let addr = "10.0.0.1:8888".parse().unwrap();
+/// let exchange = book.exchange(&vec![42], &b, |_| Some(addr));
+/// // The requesting node is not told about itself
+/// assert_eq!(exchange.peers.len(), 1);
+/// assert_eq!(exchange.peers[0].node_id, a);
+/// assert_eq!(exchange.peers[0].blocks.ids_within(2), vec![0, 1]);
+/// assert!(book.exchange(&vec![43], &b, |_| Some(addr)).peers.is_empty());
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct SourceBook {
+    /// Sources of every block of a file by the hash of the file, along with the time they have been recorded
+    files: Arc<Mutex<HashMap<Vec<u8>, RecordedSources>>>
+}
+
+impl SourceBook {
+    pub fn new() -> SourceBook {
+        SourceBook::default()
+    }
+
+    /// Record the sources of every block of a file, replacing the ones recorded before
+    pub fn record(&self, hash: &[u8], sources: &[Vec<NodeId>]) {
+        let mut files = self.files.lock().unwrap();
+        files.retain(|_, &mut (_, recorded)| recorded.elapsed() < Duration::from_secs(STALE_AFTER));
+        files.insert(hash.to_vec(), (sources.to_vec(), Instant::now()));
+    }
+
+    /// Sources of a file to pass on to `requester`, none if the recorded ones are stale. `addr` looks up the address
+    /// of a source, sources without one are left out.
+    pub fn exchange<F: Fn(&NodeId) -> Option<SocketAddr>>(&self, hash: &Vec<u8>, requester: &NodeId, addr: F) -> PeerExchange {
+        let sources = {
+            let files = self.files.lock().unwrap();
+            match files.get(hash) {
+                Some(&(ref sources, recorded)) if recorded.elapsed() < Duration::from_secs(STALE_AFTER) => sources.clone(),
+                _ => Vec::new()
+            }
+        };
+        exchange_sources(&sources, requester, addr)
+    }
+}
+
+/// Sources of every block of a file to pass on to `requester` along with the blocks they have, the ones with the most
+/// blocks first
+pub fn exchange_sources<F: Fn(&NodeId) -> Option<SocketAddr>>(sources: &[Vec<NodeId>], requester: &NodeId, addr: F) -> PeerExchange {
+    let mut blocks: HashMap<NodeId, Vec<usize>> = HashMap::new();
+    for (block, nodes) in sources.iter().enumerate() {
+        for node in nodes.iter().filter(|node| *node != requester) {
+            blocks.entry(node.clone()).or_default().push(block);
+        }
+    }
+    let mut sources = blocks.into_iter().collect::<Vec<_>>();
+    sources.sort_by_key(|source| Reverse(source.1.len()));
+    PeerExchange {
+        peers: sources.into_iter().filter_map(|(node_id, blocks)| addr(&node_id).map(|addr| ExchangedPeer {
+            node_id: node_id,
+            addr: addr,
+            blocks: BlockSet::encode(&blocks)
+        })).take(MAX_EXCHANGED_PEERS).collect()
+    }
+}
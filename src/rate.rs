@@ -0,0 +1,113 @@
+//! Transfer rates and the time remaining of downloads
+//!
+//! Every download keeps a rate estimator for itself and one for each of its sources, fed with the bytes of every block
+//! received from the network. The current rate is averaged over a rolling window so it follows changes quickly but
+//! does not jump with every block, the estimated time remaining is based on it.
+use std::cmp::{min, max};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Time in seconds over which the current rate is averaged
+pub const RATE_WINDOW: u64 = 10;
+
+/// Rates of a transfer in bytes per second
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TransferRate {
+    /// Rate within the rolling window
+    pub current: f64,
+    /// Rate since the transfer started
+    pub average: f64,
+    /// Estimated seconds until the transfer is complete, `None` while nothing arrives
+    pub eta: Option<u64>
+}
+
+/// Rolling-window estimate of the rate at which bytes arrive
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::time::{Duration, Instant};
+/// # use ddp::rate::RateEstimator;
+/// # fn main() {
+/// let mut rate = RateEstimator::new(Duration::from_secs(10));
+/// assert_eq!(rate.eta(1000), None);
+///
+/// let now = Instant::now();
+/// rate.record_at(now - Duration::from_secs(20), 10000);
+/// rate.record_at(now - Duration::from_secs(5), 4000);
+/// rate.record_at(now - Duration::from_secs(1), 6000);
+///
+/// // Only the last two blocks are within the window
+/// assert!((rate.current() - 1000.0).abs() < 10.0);
+/// assert!((rate.average() - 1000.0).abs() < 10.0);
+/// assert_eq!(rate.eta(5000), Some(5));
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateEstimator {
+    window: Duration,
+    /// Time and size of the samples within the window
+    samples: VecDeque<(Instant, usize)>,
+    /// Time of the first sample or at which the estimator has been created, whichever is earlier
+    started: Instant,
+    total: usize
+}
+
+impl RateEstimator {
+    pub fn new(window: Duration) -> RateEstimator {
+        RateEstimator {
+            window: window,
+            samples: VecDeque::new(),
+            started: Instant::now(),
+            total: 0
+        }
+    }
+
+    /// Record bytes that arrived just now
+    pub fn record(&mut self, bytes: usize) {
+        self.record_at(Instant::now(), bytes);
+    }
+
+    /// Record bytes that arrived at the given time, samples have to be recorded in the order they arrived
+    pub fn record_at(&mut self, time: Instant, bytes: usize) {
+        self.started = min(self.started, time);
+        self.total += bytes;
+        self.samples.push_back((time, bytes));
+        while self.samples.front().is_some_and(|&(time, _)| time.elapsed() > self.window) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second within the window, which drops as the transfer stalls
+    pub fn current(&self) -> f64 {
+        let bytes = self.samples.iter().filter(|&&(time, _)| time.elapsed() <= self.window).map(|&(_, bytes)| bytes).sum::<usize>();
+        // Transfers younger than the window would appear slower than they are
+        let span = min(max(self.started.elapsed(), Duration::from_secs(1)), self.window);
+        bytes as f64 / seconds(span)
+    }
+
+    /// Bytes per second since the first sample
+    pub fn average(&self) -> f64 {
+        self.total as f64 / seconds(max(self.started.elapsed(), Duration::from_secs(1)))
+    }
+
+    /// Seconds until `remaining` bytes have arrived at the current rate, `None` if nothing arrived within the window
+    pub fn eta(&self, remaining: usize) -> Option<u64> {
+        let current = self.current();
+        if current > 0.0 { Some((remaining as f64 / current).round() as u64) } else { None }
+    }
+
+    /// Rates of a transfer with `remaining` bytes left
+    pub fn rate(&self, remaining: usize) -> TransferRate {
+        TransferRate {
+            current: self.current(),
+            average: self.average(),
+            eta: if remaining > 0 { self.eta(remaining) } else { Some(0) }
+        }
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
@@ -0,0 +1,112 @@
+//! Files shared by a node, indexed by their hash with a lock per file
+//!
+//! The index itself is only locked while entries are looked up, added or removed, never while a file is used. Queries,
+//! block requests and transfers working on different files therefore do not wait for each other and reads of the same
+//! file run concurrently. A file lock must not be held while the index is locked to rule out lock order inversions.
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use file::File;
+
+/// Shared file along with the lock guarding it
+pub type SharedFile = Arc<RwLock<File>>;
+
+/// Index of the shared files, cloning it yields another handle to the same index
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::file::{File, example_file};
+/// # use ddp::helpers::HashAlgorithm;
+/// # use ddp::registry::FileRegistry;
+/// # fn main() {
+/// let file = example_file("ddp-registry-example", 3000);
+/// let (path, hash) = (file.local_path.clone(), file.metadata.hash.0.clone());
+///
+/// let registry = FileRegistry::new();
+/// assert!(registry.insert(file));
+/// // Every file is only shared once
+/// assert!(!registry.insert(File::prepare(path.clone(), HashAlgorithm::Sha256).unwrap()));
+///
+/// // Identical content at another path becomes another copy of the same file
+/// let copy = std::env::temp_dir().join("ddp-registry-example-copy");
+/// std::fs::copy(&path, &copy).unwrap();
+/// assert!(!registry.insert_or_merge(File::prepare(copy.clone(), HashAlgorithm::Sha256).unwrap()));
+/// assert_eq!(registry.len(), 1);
+/// assert_eq!(registry.find_path(&copy.canonicalize().unwrap()), Some(hash.clone()));
+///
+/// registry.get(&hash).unwrap().write().unwrap().paused = true;
+/// assert!(registry.list().iter().all(|file| file.read().unwrap().paused));
+/// assert!(registry.remove(&hash).is_some());
+/// assert_eq!(registry.len(), 0);
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct FileRegistry {
+    files: Arc<RwLock<HashMap<Vec<u8>, SharedFile>>>
+}
+
+impl FileRegistry {
+    pub fn new() -> FileRegistry {
+        FileRegistry::default()
+    }
+
+    /// Share a file, returns false if a file with the same hash is shared already
+    pub fn insert(&self, file: File) -> bool {
+        let hash = file.metadata.hash.0.clone();
+        let mut files = self.files.write().unwrap();
+        if files.contains_key(&hash) { return false }
+        files.insert(hash, Arc::new(RwLock::new(file)));
+        true
+    }
+
+    /// Share a file like `insert`, but if a file with the same hash is shared already its local path is added to the
+    /// copies of that file instead. Returns false in that case.
+    pub fn insert_or_merge(&self, file: File) -> bool {
+        let shared = match self.files.write().unwrap().entry(file.metadata.hash.0.clone()) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => { entry.insert(Arc::new(RwLock::new(file))); return true }
+        };
+        // The index is released before the file is locked
+        shared.write().unwrap().add_copy(file.local_path);
+        false
+    }
+
+    /// Hash of the shared file that has a copy at the given path
+    pub fn find_path(&self, path: &Path) -> Option<Vec<u8>> {
+        self.list().into_iter().filter_map(|shared| {
+            let file = shared.read().unwrap();
+            if file.local_path == path || file.copies.iter().any(|copy| copy == path) { Some(file.metadata.hash.0.clone()) } else { None }
+        }).next()
+    }
+
+    /// Retrieve the shared file with the given hash
+    pub fn get(&self, hash: &Vec<u8>) -> Option<SharedFile> {
+        self.files.read().unwrap().get(hash).cloned()
+    }
+
+    /// Stop sharing the file with the given hash
+    pub fn remove(&self, hash: &Vec<u8>) -> Option<SharedFile> {
+        self.files.write().unwrap().remove(hash)
+    }
+
+    pub fn contains(&self, hash: &Vec<u8>) -> bool {
+        self.files.read().unwrap().contains_key(hash)
+    }
+
+    /// Retrieve all shared files, files added or removed afterwards are not reflected
+    pub fn list(&self) -> Vec<SharedFile> {
+        self.files.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.read().unwrap().is_empty()
+    }
+}
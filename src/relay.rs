@@ -0,0 +1,95 @@
+//! Relaying of announcements and queries between the subnets of a node with several interfaces
+//!
+//! Multicast datagrams usually do not leave the segment they are sent in. A node that straddles several subnets can
+//! relay them: it receives the datagrams sent to the multicast group on each of the configured interfaces and sends
+//! them to the group on all other ones, wrapped along with their original source so responses are sent directly to the
+//! querying node instead of the relay. This requires unicast routes between the subnets. Relayed datagrams are not
+//! relayed again, so relays never forward in circles but only bridge a single hop. Nodes only accept relayed datagrams
+//! from the addresses of the relays they trust, see `Config::trusted_relays`.
+use std::net::{Ipv4Addr, IpAddr, SocketAddr};
+use std::thread::{spawn, JoinHandle};
+
+use bincode::{serialize, deserialize};
+use if_addrs::{get_if_addrs, IfAddr};
+
+use acl::Cidr;
+use announce::Message;
+use networking::{UDPSocketHandle, Overflow};
+use node::Node;
+use throttle::is_plausible_source;
+
+/// Maximum amount of received datagrams waiting to be relayed, the oldest ones are dropped
+const MAX_QUEUED_DATAGRAMS: usize = 256;
+
+/// Datagram received by a relay, sent to the multicast group of another subnet
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Relayed {
+    /// Address the datagram has been sent from, responses are sent there
+    pub origin: SocketAddr,
+    /// The serialized message
+    pub datagram: Vec<u8>
+}
+
+/// Local interface of a relay
+struct Interface {
+    addr: Ipv4Addr,
+    /// Subnet of the interface, datagrams from it are relayed to the other interfaces
+    subnet: Cidr,
+    sock: UDPSocketHandle
+}
+
+/// Whether a message is relayed to other subnets, responses are sent directly to the origin and relayed datagrams are
+/// not relayed again
+fn is_relayed(message: &Message) -> bool {
+    match *message {
+        Message::Query(_) | Message::Announcement(_) | Message::Availability(_) | Message::StreamQuery(_) |
+        Message::Distribution(_) => true,
+        Message::MetadataChunk(_) | Message::MetadataAck(_) | Message::Relayed(_) => false
+    }
+}
+
+/// Subnet of the local interface with the address `addr`
+fn subnet_of(addr: Ipv4Addr) -> Option<Cidr> {
+    let interfaces = match get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => { warn!("Failed to look up the local interfaces: {}", e); return None }
+    };
+    interfaces.iter().filter_map(|interface| match interface.addr {
+        IfAddr::V4(ref v4) if v4.ip == addr => Some(Cidr { addr: IpAddr::V4(v4.ip), prefix: v4.prefixlen }),
+        _ => None
+    }).next()
+}
+
+/// Relay announcements and queries between the interfaces listed in the configuration of the node
+pub fn start_relay(node: Node) -> JoinHandle<()> {
+    let addrs = node.config().relay.clone();
+    if addrs.len() < 2 { fail!(Usage, "A relay needs the addresses of at least two local interfaces"); }
+    let interfaces = addrs.iter().map(|&addr| match subnet_of(addr) {
+        Some(subnet) => Interface { addr: addr, subnet: subnet, sock: node.socket().create_interface_handle(addr) },
+        None => { fail!(Usage, "{} is not the address of a local interface", addr); }
+    }).collect::<Vec<_>>();
+    let datagrams = node.socket().create_relay_listener(&addrs).start_receiver(MAX_QUEUED_DATAGRAMS, Overflow::DropOldest);
+    info!("Relaying between {}", addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>().join(", "));
+
+    spawn(move || {
+        while let Some(datagram) = datagrams.recv() {
+            let src = datagram.src;
+            if !is_plausible_source(&src) { continue; }
+            // The interface a datagram arrived on is not known, the subnet of its source tells which one it was
+            let ingress = match interfaces.iter().position(|interface| interface.subnet.contains(&src.ip())) {
+                Some(ingress) => ingress,
+                None => continue
+            };
+            match deserialize::<Message>(&datagram) {
+                Ok(ref message) if is_relayed(message) => {},
+                _ => continue
+            }
+            let relayed = serialize(&Message::Relayed(Relayed { origin: src, datagram: datagram.to_vec() })).unwrap();
+            for (index, interface) in interfaces.iter().enumerate() {
+                if index == ingress { continue; }
+                trace!("Relaying datagram from {} to {}", src, interface.addr);
+                if let Err(e) = interface.sock.send_to_multicast(&relayed) { debug!("Failed to relay to {}: {}", interface.addr, e); }
+            }
+        }
+    })
+}
@@ -1,128 +1,308 @@
-use std::cmp::Ordering;
+use std::cmp::{min, Reverse, Ordering};
 use std::collections::HashMap;
-use std::net::{TcpListener, IpAddr, SocketAddr, TcpStream, Shutdown};
+use std::net::{TcpListener, SocketAddr, TcpStream, IpAddr};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::{spawn, sleep};
-use std::time::Duration;
-use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use std::io::{self, Read};
+use std::ops::Range;
 use std::path::PathBuf;
-use std::fs::File as F;
-use std::io::{Seek, SeekFrom};
 
-use bincode::serde::*;
-use bincode::SizeLimit;
+use bincode::{serialize, deserialize};
 
-use sha2::sha2::Sha256;
-use sha2::Digest;
+use ext_time::PreciseTime;
 
-use ext_time::{Duration as ext_Duration, PreciseTime};
+use helpers::{to_hex_string, calculate_block_size, block_count, block_offset, trailing_length, trailing_offset};
 
-use helpers::{to_hex_string, calculate_block_size};
+use networking::{UDPSocket, UDPSocketHandle, MulticastScope, Overflow, read_frame_into, write_frame};
 
-use networking::{UDPSocket, ping, BASE_PORT};
+use file::{FileMetadata, File, FileHandle, BlockState};
 
-use file::{FileMetadata, File, FileHandle};
+use announce::{Message, Query, Handshake, BlockRequest, ConnectionRequest, BlockListResponse, MetadataResponse};
 
+use peers::{NodeId, PeerRegistry};
 
-fn convert_block_sources(filesize: usize, sources: HashMap<IpAddr, Vec<usize>>) -> Vec<Vec<IpAddr>> {
-    let block_count = filesize / calculate_block_size(filesize);
+use crypto::{Key, apply_keystream_to_file, apply_keystream_to_storage};
+
+use discovery::DiscoveryWindow;
+
+use distribute::spread;
+
+use throttle::{local_addresses, is_plausible_source};
+
+
+use chunks::ChunkAssembler;
+
+use events::Event;
+use rate::{RateEstimator, TransferRate, RATE_WINDOW};
+use pex::PeerExchange;
+
+
+/// Interval in seconds at which the sources are taken from the availability table again
+const SOURCE_REFRESH_INTERVAL: u64 = 1;
+/// Maximum amount of received block lists waiting to be routed, newer ones are dropped while the queue is full
+const MAX_QUEUED_DATAGRAMS: usize = 256;
+/// Maximum amount of block lists waiting to be handled by a single download
+const MAX_PENDING_RESPONSES: usize = 1024;
+/// Time in seconds a distributed download waits for sources of its missing blocks before it gives up
+const DISTRIBUTION_STALL_TIMEOUT: u64 = 60;
+/// Amount of connections to a source that may fail in a row before all of its blocks are dropped from the sources
+const MAX_CONNECTION_FAILURES: usize = 2;
+/// Interval in seconds at which every source is asked for the other sources it knows
+const PEER_EXCHANGE_INTERVAL: u64 = 60;
+/// Minimum interval in milliseconds between the rate events of a download
+const RATE_EVENT_INTERVAL: u64 = 1000;
+/// Interval in milliseconds at which the listener for pushed metadata checks for connections
+const METADATA_ACCEPT_INTERVAL: u64 = 50;
+
+/// Receiver of the block lists for a single download
+type ResponseSender = mpsc::SyncSender<(BlockListResponse, SocketAddr)>;
+
+/// Socket shared by all downloads of a node to query block lists, responses are routed to the querying download by
+/// the hash they contain. Cloning it yields another handle to the same socket.
+#[derive(Clone)]
+pub struct BlockListQueries {
+    sock: Arc<UDPSocketHandle>,
+    /// Receivers of the responses by file hash
+    pending: Arc<Mutex<HashMap<Vec<u8>, ResponseSender>>>
+}
+
+impl BlockListQueries {
+    /// Bind the socket that queries the multicast group of `scope` and start the thread that routes the responses
+    pub fn new(scope: MulticastScope) -> BlockListQueries {
+        let sock = UDPSocket::new().scope(scope).create_handle();
+        let datagrams = sock.try_clone().unwrap().start_receiver(MAX_QUEUED_DATAGRAMS, Overflow::DropNewest);
+        let pending: Arc<Mutex<HashMap<Vec<u8>, mpsc::SyncSender<_>>>> = Arc::new(Mutex::new(HashMap::new()));
+        let routes = pending.clone();
+        spawn(move || {
+            while let Some(datagram) = datagrams.recv() {
+                let src = datagram.src;
+                let response: BlockListResponse = match deserialize(&datagram) {
+                    Ok(response) => response,
+                    Err(_) => { warn!("Received malformed block list from {}", src); continue }
+                };
+                // Late responses to queries that are no longer pending are dropped
+                if let Some(tx) = routes.lock().unwrap().get(&response.hash) {
+                    if let Err(mpsc::TrySendError::Full(_)) = tx.try_send((response, src)) {
+                        debug!("Dropped a block list from {} since the download is not keeping up", src);
+                    }
+                }
+            }
+        });
+        BlockListQueries {
+            sock: Arc::new(sock),
+            pending: pending
+        }
+    }
+
+    /// Send a query via multicast and receive the responses until the returned receiver is dropped
+    fn query(&self, query: Query) -> QueryResponses {
+        let (tx, rx) = mpsc::sync_channel(MAX_PENDING_RESPONSES);
+        let hash = query.hash.clone();
+        self.pending.lock().unwrap().insert(hash.clone(), tx);
+        if let Err(e) = self.sock.send_to_multicast(&serialize(&Message::Query(query)).unwrap()) { warn!("Failed to send the query: {}", e); }
+        QueryResponses { queries: self.clone(), hash: hash, rx: rx }
+    }
+
+    /// Send a query to a single node, the responses are received by the pending query for the same file
+    fn send(&self, query: Query, target: SocketAddr) {
+        if let Err(e) = self.sock.send(&serialize(&Message::Query(query)).unwrap(), target) { debug!("Failed to query {}: {}", target, e); }
+    }
+}
+
+/// Responses to a pending block list query, the query stops being pending once this is dropped
+struct QueryResponses {
+    queries: BlockListQueries,
+    hash: Vec<u8>,
+    rx: mpsc::Receiver<(BlockListResponse, SocketAddr)>
+}
+
+impl Drop for QueryResponses {
+    fn drop(&mut self) {
+        self.queries.pending.lock().unwrap().remove(&self.hash);
+    }
+}
+
+/// Restructure the blocks announced by every source into the sources of every block, skipping the sources for which
+/// `is_self` holds since this node would download from itself
+fn convert_block_sources<F: Fn(&NodeId) -> bool>(filesize: usize, sources: HashMap<NodeId, Vec<usize>>, is_self: F) -> Vec<Vec<NodeId>> {
+    let block_count = block_count(filesize);
     // Restructure block_sources to be a vector of blocks
-    // Each block is a vector of sources where a source is a touple of the 'rank' transmitted by that source and the IP of it
-    let mut block_sources: Vec<Vec<_>> = (0..block_count).map(|_| Vec::new()).collect();
+    // Each block is a vector of the IDs of its sources, they are ranked right before the block is downloaded
+    let mut block_sources: Vec<Vec<NodeId>> = (0..block_count).map(|_| Vec::new()).collect();
     for (source, blocks) in sources.iter() {
-        for (rank, block) in blocks.iter().enumerate() {
-            block_sources[*block].push((rank, source));
-        }
-    }
-    // Sort the block sources by rank and strip it afterwards
-    for block in block_sources.iter_mut() { block.sort_by(|a, b| {
-        let comparison = a.0.cmp(&b.0);
-        if comparison == Ordering::Equal {
-            // In case a == b we compare their ping and use the better one
-            let a_ping = ping(SocketAddr::new(*a.1, BASE_PORT + 1));
-            let b_ping = ping(SocketAddr::new(*b.1, BASE_PORT + 1));
-            a_ping.cmp(&b_ping)
-        } else { comparison }
-    })};
-    block_sources.into_iter().map(|block| {
-        block.into_iter().map(|source| source.1.clone()).collect()
-    }).collect()
+        if is_self(source) {
+            debug!("Skipping {} as a source since it is this node", to_hex_string(source));
+            continue;
+        }
+        for block in blocks.iter() {
+            match block_sources.get_mut(*block) {
+                Some(block_sources) => if !block_sources.contains(source) { block_sources.push(source.clone()) },
+                None => warn!("Source {} announced non-existent block {}", to_hex_string(source), block)
+            }
+        }
+    }
+    block_sources
+}
+
+/// Metadata put together from the responses of one or more nodes, partial responses are continued by asking the
+/// responder for the remaining block hashes
+struct MetadataAssembly {
+    hash: Vec<u8>,
+    token: Option<String>,
+    udp: bool,
+    peers: Arc<Mutex<PeerRegistry>>,
+    sock: UDPSocketHandle,
+    metadata: Option<FileMetadata>
 }
 
-pub fn sort_by_block_availability(sources: Vec<Vec<IpAddr>>) -> Vec<usize> {
-    let mut block_availability: Vec<_> = sources.into_iter().enumerate().map(|(id, block)| (id, block.len())).collect();
-    // Put the available ones at the top and sort them by their availability (lowest first to speed up distribution)
-    block_availability.sort_by(|a, b|
-        // If none of them is available they are equal
-        if a.1 == 0 && b.1 == 0 { Ordering::Equal }
-        // If a is not available then b is better
-        else if a.1 == 0 { Ordering::Greater }
-        // If b is not available then a is better
-        else if b.1 == 0 { Ordering::Less }
-        // If both are available then their availability will be compared
-        else { a.1.cmp(&b.1) }
-    );
-    // Strip the availability value
-    let block_availability = block_availability.into_iter().map(|(id, _)| id).collect();
-    block_availability
+impl MetadataAssembly {
+    /// Add a response received from `src`, returns the metadata once it is complete or `Some(None)` if it turned out
+    /// to be inconsistent
+    fn add(&mut self, mut response: MetadataResponse, src: IpAddr) -> Option<Option<FileMetadata>> {
+        if response.metadata.hash.0 != self.hash {
+            warn!("Ignoring metadata of {} from {} that was not requested", to_hex_string(&response.metadata.hash.0), src);
+            return None;
+        }
+
+        // Ignore responses that do not continue where the previous one ended (e.g. late responses of other nodes)
+        let received = self.metadata.as_ref().map_or(0, |m| m.hash.1.len());
+        if response.first_block != received { return None; }
+
+        let service_addr = SocketAddr::new(src, response.port);
+        self.peers.lock().unwrap().update(response.node_id, service_addr);
+
+        match self.metadata {
+            Some(ref mut m) => m.hash.1.append(&mut response.metadata.hash.1),
+            None => self.metadata = Some(response.metadata)
+        }
+
+        match response.more {
+            Some(from_block) => {
+                // The response was partial so ask the responder directly for the remaining block hashes
+                let query = Query { hash: self.hash.clone(), details: true, from_block: from_block, token: self.token.clone(), udp: self.udp };
+                if let Err(e) = self.sock.send(&serialize(&Message::Query(query)).unwrap(), service_addr) {
+                    debug!("Failed to query {}: {}", service_addr, e);
+                }
+                None
+            },
+            None => {
+                // Reject metadata whose blocks and trailing bytes do not add up to the file size
+                let valid = self.metadata.as_ref().is_some_and(|m| {
+                    m.trailing_bytes.len() == trailing_length(m.size) && m.hash.1.len() == block_count(m.size)
+                });
+                if !valid { warn!("Received inconsistent metadata for {}", to_hex_string(&self.hash)); }
+                Some(if valid { self.metadata.take() } else { None })
+            }
+        }
+    }
 }
 
 impl File {
-    pub fn from_metadata(uuid: &Vec<u8>, path: PathBuf) -> Option<File> {
-        let mut uuid = uuid.clone();
+    /// Request the metadata of a file via multicast and directly from the `hints` which are likely to have it, presenting
+    /// `token` to nodes that require one and waiting for responses as long as `window` permits. With `udp` the metadata
+    /// is received in chunks on the query socket instead of being pushed via TCP. The query is sent to the multicast
+    /// group of `scope`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_metadata(uuid: &[u8], path: PathBuf, peers: Arc<Mutex<PeerRegistry>>, hints: &[SocketAddr],
+                         token: Option<String>, window: &DiscoveryWindow, udp: bool, scope: MulticastScope) -> Option<File> {
+        let uuid = uuid.to_vec();
 
         info!("Requesting metadata for {}", to_hex_string(&uuid));
 
-        let sock = UDPSocket::new().create_handle();
+        let sock = UDPSocket::new().scope(scope).create_handle();
         let sock_addr = sock.socket.local_addr().unwrap();
-        let (tcp_tx, tcp_rx) = mpsc::channel();
+        let (tx, rx) = mpsc::channel();
+        let mut assembly = MetadataAssembly {
+            hash: uuid.clone(),
+            token: token.clone(),
+            udp: udp,
+            peers: peers,
+            sock: sock.try_clone().unwrap(),
+            metadata: None
+        };
 
-        // TCP receive thread
-        let hash_copy = uuid.clone();
-        let tcp_ready = Arc::new(Mutex::new(false));
-        let tcp_ready_thread = tcp_ready.clone();
-        spawn(move || {
-            let tcp_sock = TcpListener::bind(sock_addr).unwrap();
-            *tcp_ready_thread.lock().unwrap() = true;
-            let mut stream = match tcp_sock.accept() {
-                Ok((sock, _)) => sock,
-                Err(_) => {
-                    tcp_tx.send(None).unwrap();
-                    return
+        // The receive threads end with the last attempt since nobody waits for the metadata afterwards
+        let deadline = Instant::now() + (0..window.retries + 1).map(|attempt| window.attempt_timeout(attempt)).sum::<Duration>();
+        if udp {
+            let receiver = sock.try_clone().unwrap();
+            spawn(move || {
+                let mut chunks = ChunkAssembler::new();
+                loop {
+                    let now = Instant::now();
+                    if now >= deadline { return }
+                    let (datagram, src) = match receiver.receive_timeout(deadline - now) { Some(datagram) => datagram, None => continue };
+                    let chunk = match deserialize(&datagram) {
+                        Ok(Message::MetadataChunk(chunk)) => chunk,
+                        _ => continue
+                    };
+                    let (ack, data) = chunks.add(chunk, src);
+                    // The chunks are sent again if the acknowledgement gets lost
+                    if let Some(ack) = ack { let _ = receiver.send(&serialize(&Message::MetadataAck(ack)).unwrap(), src); }
+                    let response = match data.map(|data| deserialize::<MetadataResponse>(&data)) {
+                        Some(Ok(response)) => response,
+                        Some(Err(_)) => { warn!("Received malformed metadata from {}", src); continue }
+                        None => continue
+                    };
+                    if let Some(metadata) = assembly.add(response, src.ip()) {
+                        let _ = tx.send(metadata);
+                        return
+                    }
                 }
+            });
+        } else {
+            // TCP receive thread, the listener is bound before the first query is sent and polled so it is closed in time
+            let tcp_sock = match TcpListener::bind(sock_addr).and_then(|socket| socket.set_nonblocking(true).map(|_| socket)) {
+                Ok(socket) => socket,
+                Err(e) => { fail!(Bind, "TCP port {}: {}", sock_addr.port(), e) }
             };
-            let mut buf = Vec::new();
-            stream.read_to_end(&mut buf).unwrap();
-            let metadata: FileMetadata = deserialize(&buf).unwrap();
-            if metadata.hash.0 != hash_copy { exit!(2, "Hash mismatch! (remote vs local)"); }
-            tcp_tx.send(Some(metadata)).unwrap();
-        });
-
-        uuid.push(1); // Request file details in addition to block lists
-        loop {
-            if *tcp_ready.lock().unwrap() == true { break; }
-            sleep(Duration::from_millis(10));
+            spawn(move || {
+                loop {
+                    let now = Instant::now();
+                    if now >= deadline { return }
+                    let (mut stream, src) = match tcp_sock.accept() {
+                        Ok(accepted) => accepted,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            sleep(Duration::from_millis(METADATA_ACCEPT_INTERVAL));
+                            continue
+                        },
+                        Err(_) => {
+                            let _ = tx.send(None);
+                            return
+                        }
+                    };
+                    // A pusher that stalls can not hold the listener beyond the last attempt either
+                    let prepared = stream.set_nonblocking(false).and_then(|_| stream.set_read_timeout(Some(deadline - now)));
+                    if prepared.is_err() { continue }
+                    let mut buf = Vec::new();
+                    if stream.read_to_end(&mut buf).is_err() { continue }
+                    let response = match deserialize(&buf) {
+                        Ok(response) => response,
+                        Err(_) => { warn!("Received malformed metadata from {}", src); continue }
+                    };
+                    if let Some(metadata) = assembly.add(response, src.ip()) {
+                        let _ = tx.send(metadata);
+                        return
+                    }
+                }
+            });
         }
-        sock.send_to_multicast(&uuid); // Send request
 
-        let start = PreciseTime::now();
-        let mut metadata = None;
-        let mut received_metadata = false;
-        while start.to(PreciseTime::now()) < ext_Duration::seconds(1) {
-            if !received_metadata {
-                match tcp_rx.try_recv() {
-                    Ok(m) => {
-                        metadata = m;
-                        received_metadata = true;
-                    },
-                    Err(_) => {}
-                }
-            } else {
-                return Some(File {
-                    metadata: metadata.unwrap(),
-                    blocks: Vec::new(),
-                    local_path: path
-                })
+        // Request file details in addition to block lists
+        let query = Query { hash: uuid.clone(), details: true, from_block: 0, token: token, udp: udp };
+        let query = serialize(&Message::Query(query)).unwrap();
+        for attempt in 0..window.retries + 1 {
+            if attempt > 0 { debug!("Nobody responded with the metadata of {}, asking again", to_hex_string(&uuid)); }
+            if let Err(e) = sock.send_to_multicast(&query) { warn!("Failed to send the query: {}", e); }
+            for hint in hints {
+                if let Err(e) = sock.send(&query, *hint) { debug!("Failed to query {}: {}", hint, e); }
+            }
+
+            // The metadata is handed over once it is complete so there is no quiet period to wait for
+            match rx.recv_timeout(window.attempt_timeout(attempt)) {
+                Ok(Some(metadata)) => return Some(File::from_remote(metadata, path)),
+                Ok(None) | Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
             }
         }
 
@@ -134,90 +314,629 @@ impl FileHandle {
 
     fn update_sources(&mut self) {
         let file_size = self.file.lock().unwrap().metadata.size;
-        let mut uuid = self.file.lock().unwrap().metadata.hash.0.clone();
-        uuid.push(0); // Do not request file details but only the available blocks
+        let uuid = self.file.lock().unwrap().metadata.hash.0.clone();
+        let scope = self.scope;
+        let queries = self.queries.get_or_insert_with(|| BlockListQueries::new(scope)).clone();
+        let window = self.discovery;
 
-        let (udp_tx, udp_rx) = mpsc::channel();
-        let sock = UDPSocket::new().create_handle();
-        sock.send_to_multicast(&uuid);
-        spawn(move || {
-            loop {
-                // TODO: Set datagram size dynamically
-                udp_tx.send(sock.receive()).unwrap();
+        let mut block_sources: HashMap<NodeId, Vec<usize>> = HashMap::new();
+        // Time at which the block list of every node arrived
+        let mut confirmed: HashMap<NodeId, Instant> = HashMap::new();
+        // Received and total fragments of the current response of every node
+        let mut fragments: HashMap<NodeId, (u16, u16)> = HashMap::new();
+        // Bound the decoding of malicious responses by the amount of blocks of the file, including the trailing one
+        let limit = block_count(file_size) + 1;
+        for attempt in 0..window.retries + 1 {
+            if attempt > 0 { debug!("Nobody responded with the blocks of {}, asking again", to_hex_string(&uuid)); }
+            // Do not request file details but only the available blocks
+            let query = Query { hash: uuid.clone(), details: false, from_block: 0, token: self.token.clone(), udp: false };
+            let responses = queries.query(query);
+
+            let started = Instant::now();
+            let mut last_response = None;
+            while !window.is_over(attempt, started, last_response) {
+                if let Ok((response, src)) = responses.rx.recv_timeout(Duration::from_millis(10)) {
+                    last_response = Some(Instant::now());
+                    confirmed.insert(response.node_id.clone(), Instant::now());
+                    let mut data = response.blocks.ids_within(limit.saturating_sub(response.first_block))
+                        .into_iter().map(|id| id + response.first_block).collect::<Vec<_>>();
+                    let (index, total) = response.fragment;
+                    let received = fragments.entry(response.node_id.clone()).or_insert((0, total));
+                    // The first fragment of a follow-up response starts a new count
+                    if index == 0 { *received = (0, total); }
+                    received.0 += 1;
+                    // Remember the stable address of the responder since the datagram originates from a throwaway socket
+                    let service_addr = SocketAddr::new(src.ip(), response.port);
+                    self.peers.lock().unwrap().update(response.node_id.clone(), service_addr);
+                    if let Some(from_block) = response.more {
+                        // The response was partial so ask the responder directly for the remaining blocks
+                        let query = Query { hash: uuid.clone(), details: false, from_block: from_block, token: self.token.clone(), udp: false };
+                        queries.send(query, service_addr);
+                    }
+                    // Nodes with several addresses may respond via each of them
+                    block_sources.entry(response.node_id).or_default().append(&mut data);
+                }
+            }
+            if !block_sources.is_empty() { break }
+        }
+
+        for (node_id, &(received, total)) in fragments.iter().filter(|&(_, &(received, total))| received < total) {
+            debug!("Received {} of {} fragments of the block list from {}", received, total, to_hex_string(node_id));
+        }
+
+        let local = local_addresses();
+        let sources = convert_block_sources(file_size, block_sources, |source| self.is_self(source, &local));
+        self.sources = sources;
+        // Sources that responded are reachable again even if connections to them failed before
+        for source in confirmed.keys() { self.connection_failures.remove(source); }
+        self.source_confirmed = confirmed;
+        self.apply_exchanged_sources();
+    }
+
+    /// Whether a source is this node, either by its ID or because it is only reachable via local addresses
+    fn is_self(&self, source: &NodeId, local: &[IpAddr]) -> bool {
+        if *source == self.node_id { return true }
+        let routes = self.peers.lock().unwrap().routes(source);
+        !routes.is_empty() && routes.iter().all(|route| route.ip().is_loopback() || local.contains(&route.ip()))
+    }
+
+    /// Take the sources from the availability table, returns false if the table knows nothing about the file
+    fn refresh_sources(&mut self) -> bool {
+        let sources = match self.availability {
+            Some(ref availability) => {
+                let file = self.file.lock().unwrap();
+                availability.lock().unwrap().sources(&file.metadata.hash.0, file.metadata.hash.1.len())
+            },
+            None => None
+        };
+        self.sources_updated = Some(Instant::now());
+        match sources {
+            Some(mut sources) => {
+                trace!("Took the sources of {} blocks from the availability table", sources.len());
+                let local = local_addresses();
+                // The table forgets nodes that stopped gossiping, but it does not know about failing connections
+                for block in sources.iter_mut() { block.retain(|source| !self.is_self(source, &local) && !self.is_failing(source)); }
+                let now = Instant::now();
+                self.source_confirmed = sources.iter().flat_map(|block| block.iter()).map(|source| (source.clone(), now)).collect();
+                self.sources = sources;
+                self.apply_exchanged_sources();
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Drop the blocks of the sources that have not confirmed them within the maximum source age, returns whether there
+    /// were any
+    fn expire_sources(&mut self) -> bool {
+        let max_age = match self.max_source_age {
+            Some(max_age) => max_age,
+            None => return false
+        };
+        let expired = self.source_confirmed.iter()
+            .filter(|&(_, confirmed)| confirmed.elapsed() > max_age)
+            .map(|(source, _)| source.clone()).collect::<Vec<_>>();
+        for source in expired.iter() {
+            debug!("{} has not confirmed its blocks within {:?}", to_hex_string(source), max_age);
+            self.forget_source(source);
+        }
+        !expired.is_empty()
+    }
+
+    /// Drop all blocks of a source from the sources along with the connection to it
+    fn forget_source(&mut self, source: &NodeId) {
+        for block in self.sources.iter_mut() { block.retain(|s| s != source); }
+        self.source_confirmed.remove(source);
+        self.exchanged_sources.remove(source);
+        self.connections.remove(source);
+    }
+
+    /// Add the sources another node told about, unless they are this node or known to be failing
+    fn add_exchanged_peers(&mut self, exchange: PeerExchange, from: &NodeId) {
+        let local = local_addresses();
+        let blocks = self.completed.len();
+        let now = Instant::now();
+        let mut added = 0;
+        for peer in exchange.peers {
+            if peer.node_id == self.node_id || self.is_failing(&peer.node_id) || !is_plausible_source(&peer.addr)
+                || local.contains(&peer.addr.ip()) { continue }
+            {
+                // Addresses the node learned itself are more trustworthy than those passed on by others
+                let mut peers = self.peers.lock().unwrap();
+                if peers.get(&peer.node_id).is_none() { peers.update(peer.node_id.clone(), peer.addr); }
+            }
+            if !self.exchanged_sources.contains_key(&peer.node_id) && !self.source_confirmed.contains_key(&peer.node_id) { added += 1; }
+            self.exchanged_sources.insert(peer.node_id, (now, peer.blocks.ids_within(blocks)));
+        }
+        if added > 0 { debug!("Learned of {} further sources from {}", added, to_hex_string(from)); }
+        self.apply_exchanged_sources();
+    }
+
+    /// Merge the sources other nodes told about into the sources and record the result in the source book
+    fn apply_exchanged_sources(&mut self) {
+        let blocks = self.completed.len();
+        if self.sources.len() < blocks { self.sources.resize(blocks, Vec::new()); }
+        for (source, &(told, ref ids)) in self.exchanged_sources.iter() {
+            if self.is_failing(source) { continue }
+            for id in ids.iter().filter(|id| **id < blocks) {
+                if !self.sources[*id].contains(source) { self.sources[*id].push(source.clone()); }
+            }
+            // They expire like any other source unless a query confirms them
+            self.source_confirmed.entry(source.clone()).or_insert(told);
+        }
+        if let Some(ref book) = self.source_book {
+            book.record(&self.file.lock().unwrap().metadata.hash.0, &self.sources);
+        }
+    }
+
+    /// Whether so many connections to a source failed in a row that it is most likely gone
+    fn is_failing(&self, source: &NodeId) -> bool {
+        self.connection_failures.get(source).is_some_and(|failures| *failures >= MAX_CONNECTION_FAILURES)
+    }
+
+    /// Record a connection to a source that failed, the source is dropped once several failed in a row until it
+    /// responds to a query again
+    fn connection_failed(&mut self, source: &NodeId) {
+        self.peers.lock().unwrap().record_failure(source);
+        *self.connection_failures.entry(source.clone()).or_insert(0) += 1;
+        if self.is_failing(source) {
+            info!("Connections to {} keep failing, dropping it as a source", to_hex_string(source));
+            self.forget_source(source);
+        }
+    }
+
+    /// Allocate the storage for the size of the file, existing content is kept unless `truncate` is set. Returns false
+    /// and gives up the download if the storage can not be allocated.
+    fn allocate(&mut self, truncate: bool) -> bool {
+        let (size, name) = {
+            let file = self.file.lock().unwrap();
+            (file.metadata.size, file.metadata.name.clone())
+        };
+        let allocated = self.storage.lock().unwrap().allocate(size, truncate);
+        if let Err(e) = allocated {
+            error!("Failed to allocate {}: {}", name, e);
+            self.storage_failed = true;
+            return false;
+        }
+        self.allocated = true;
+        true
+    }
+
+    /// Keep the blocks that are intact in an existing file at the destination, e.g. left by an earlier attempt, returns
+    /// how many were kept
+    fn keep_existing(&mut self) -> usize {
+        if !self.stores_locally() { return 0 }
+        let (metadata, path) = {
+            let file = self.file.lock().unwrap();
+            (file.metadata.clone(), file.local_path.clone())
+        };
+        if !path.is_file() { return 0 }
+        let states = File::from_local(metadata, path).audit();
+        let mut kept = 0;
+        for (completed, state) in self.completed.iter_mut().zip(states) {
+            if !*completed && state == BlockState::Intact {
+                *completed = true;
+                kept += 1;
             }
+        }
+        kept
+    }
+
+    /// Publish that a block of `bytes` has been completed, `source` is missing if it has been taken from a local file
+    fn publish_block(&mut self, block: usize, source: Option<&NodeId>, bytes: usize) {
+        // Blocks taken from local files would make the download appear faster than the network is
+        if let Some(source) = source {
+            self.rate.record(bytes);
+            self.source_rates.entry(source.clone()).or_insert_with(|| RateEstimator::new(Duration::from_secs(RATE_WINDOW))).record(bytes);
+        }
+        let events = match self.events { Some(ref events) => events.clone(), None => return };
+        let hash = to_hex_string(&self.file.lock().unwrap().metadata.hash.0);
+        events.publish(|| Event::Block {
+            hash: hash.clone(),
+            block: block,
+            source: source.map(|source| to_hex_string(source)),
+            bytes: bytes
         });
+        if source.is_some() && self.rate_published.is_none_or(|published| published.elapsed() >= Duration::from_millis(RATE_EVENT_INTERVAL)) {
+            self.rate_published = Some(Instant::now());
+            events.publish(|| {
+                let (rate, sources) = self.rates();
+                Event::Rate { hash: hash, rate: rate, sources: sources.into_iter().map(|(id, rate)| (to_hex_string(&id), rate)).collect() }
+            });
+        }
+    }
+
+    /// Rates of the download along with the current rates of its sources, the fastest first
+    pub fn rates(&self) -> (TransferRate, Vec<(NodeId, f64)>) {
+        let size = self.file.lock().unwrap().metadata.size;
+        let complete = self.completed.iter().filter(|c| **c).count();
+        let remaining = if complete == self.completed.len() { 0 } else { size.saturating_sub(complete * calculate_block_size(size)) };
+        let mut sources = self.source_rates.iter().map(|(id, rate)| (id.clone(), rate.current())).filter(|&(_, rate)| rate > 0.0).collect::<Vec<_>>();
+        sources.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        (self.rate.rate(remaining), sources)
+    }
+
+    /// Take the blocks that are already stored in other local files from the block cache, returns how many were found
+    fn fill_from_cache(&mut self) -> usize {
+        let cache = match self.cache {
+            Some(ref cache) => cache.clone(),
+            None => return 0
+        };
+        let (hashes, algorithm, size) = {
+            let file = self.file.lock().unwrap();
+            (file.metadata.hash.1.clone(), file.metadata.algorithm, file.metadata.size)
+        };
+        let mut found = 0;
+        for id in (0..hashes.len()).filter(|id| !self.completed[*id]).collect::<Vec<_>>() {
+            // The lock is only held while reading a single block so other downloads are not stalled
+            let block = match cache.lock().unwrap().read(&hashes[id], algorithm) {
+                Some(block) => block,
+                None => continue
+            };
+            if self.write_at(block_offset(size, id), &block).is_ok() {
+                self.completed[id] = true;
+                found += 1;
+                self.publish_block(id, None, block.len());
+            }
+        }
+        found
+    }
+
+    /// Pick the next block to download, the highest priority first and the rarest among those to speed up distribution
+    fn pick_block(&self) -> Option<usize> {
+        (0..self.completed.len())
+            .filter(|id| !self.completed[*id] && self.sources.get(*id).is_some_and(|s| !s.is_empty()))
+            .min_by_key(|id| (Reverse(self.block_priority(*id)), self.sources[*id].len(), self.pick_order(*id)))
+    }
+
+    /// Position of a block among the blocks of equal priority and rarity, in order unless the file is distributed
+    fn pick_order(&self, block_id: usize) -> u64 {
+        if self.distributed { spread(&self.node_id, block_id) } else { block_id as u64 }
+    }
+
+    /// Offer all completed blocks to other nodes if the file is served while it is downloading
+    fn publish_blocks(&self) {
+        if let Some(ref seed) = self.seed {
+            let blocks = (0..self.completed.len()).filter(|id| self.completed[*id]).map(|id| (id, 0)).collect();
+            seed.write().unwrap().blocks = blocks;
+        }
+    }
+
+    /// Pick up to `limit` blocks available at `source` in the order of the block picker, starting with `first`
+    fn pick_pipeline(&self, source: &NodeId, first: usize, limit: usize) -> Vec<usize> {
+        let mut blocks = (0..self.completed.len())
+            .filter(|id| *id != first && !self.completed[*id] && self.sources.get(*id).is_some_and(|s| s.contains(source)))
+            .collect::<Vec<_>>();
+        blocks.sort_by_key(|id| (Reverse(self.block_priority(*id)), self.sources[*id].len(), self.pick_order(*id)));
+        blocks.truncate(limit.saturating_sub(1));
+        blocks.insert(0, first);
+        blocks
+    }
+
+    /// Open a connection to a source via the first of its routes that is reachable and introduce this node with a
+    /// handshake
+    fn connect(&self, source: &NodeId) -> io::Result<TcpStream> {
+        let routes = self.peers.lock().unwrap().routes(source);
+        let mut error = io::Error::new(io::ErrorKind::NotFound, "no route to the source");
+        for route in routes {
+            let mut stream = match TcpStream::connect(route) {
+                Ok(stream) => stream,
+                Err(e) => { error = e; continue }
+            };
+            write_frame(&mut stream, &serialize(&Handshake {
+                node_id: self.node_id.clone(),
+                token: self.token.clone(),
+                capabilities: self.capabilities
+            }).unwrap())?;
+            return Ok(stream);
+        }
+        Err(error)
+    }
+
+    /// Request several blocks from a source at once over a kept-alive connection and store the ones it delivers,
+    /// returns the IDs of the received blocks. Blocks that do not arrive by the block deadline are abandoned if
+    /// `deadline` is set, e.g. because other sources remain to be tried.
+    fn download_pipelined(&mut self, source: &NodeId, blocks: &[usize], deadline: bool) -> Vec<usize> {
+        let (hash, size) = {
+            let file = self.file.lock().unwrap();
+            (file.metadata.hash.0.clone(), file.metadata.size)
+        };
 
         let start = PreciseTime::now();
-        let mut block_sources: HashMap<IpAddr, Vec<usize>> = HashMap::new();
-        while start.to(PreciseTime::now()) < ext_Duration::seconds(1) {
-            match udp_rx.try_recv() {
-                Ok(d) => {
-                    let mut data: Vec<usize> = deserialize(&d.0).unwrap();
-                    let ip = d.1.ip();
-                    if match block_sources.get_mut(&ip) {
-                        Some(v) => { v.append(&mut data); false},
-                        None => true
-                    } {
-                        block_sources.insert(ip, data);
-                    }
+        let mut stream = match self.connections.remove(source) {
+            Some(stream) => stream,
+            None => match self.connect(source) {
+                Ok(stream) => stream,
+                Err(_) => { self.connection_failed(source); return Vec::new() }
+            }
+        };
+        let latency = start.to(PreciseTime::now());
+
+        // Send all requests up front so the source never idles waiting for the next one
+        let block_size = calculate_block_size(size);
+        for block_id in blocks.iter() {
+            // Only the rest of a block that broke off earlier is requested
+            let range = self.partial_blocks.get(block_id).map(|partial| partial.len()..block_size);
+            let request = BlockRequest { hash: hash.clone(), block: *block_id, range: range };
+            if write_frame(&mut stream, &serialize(&ConnectionRequest::Block(request)).unwrap()).is_err() {
+                self.connection_failed(source);
+                return Vec::new();
+            }
+        }
+        // Now and then the source is also asked for the other sources it knows, it responds after the blocks
+        let exchange = self.peer_exchange && self.peers_exchanged.get(source).is_none_or(|asked| asked.elapsed() >= Duration::from_secs(PEER_EXCHANGE_INTERVAL));
+        if exchange {
+            self.peers_exchanged.insert(source.clone(), Instant::now());
+            if write_frame(&mut stream, &serialize(&ConnectionRequest::Peers(hash.clone())).unwrap()).is_err() {
+                self.connection_failed(source);
+                return Vec::new();
+            }
+        }
+
+        let mut received = Vec::new();
+        let mut last = PreciseTime::now();
+        let allowed = if deadline { self.block_deadline.allowed(block_size) } else { None };
+        for block_id in blocks.iter() {
+            // The response is appended to the part of the block received earlier
+            let mut block = self.partial_blocks.remove(block_id).unwrap_or_default();
+            let resumed = block.len();
+            // Every block gets its own deadline since the source sends them one after another
+            match read_frame_into(&mut stream, allowed.map(|allowed| Instant::now() + allowed), &mut block) {
+                Ok(()) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    // The rest of the slow block would arrive before any further response so the connection is dropped
+                    warn!("Abandoning block {} at {}, it did not arrive within {:?}", block_id, to_hex_string(source), allowed.unwrap());
+                    self.keep_partial_block(*block_id, block);
+                    self.peers.lock().unwrap().record_timeout(source);
+                    self.usage.entry(source.clone()).or_default().timeouts += 1;
+                    return received;
                 },
-                Err(_) => {}
-            }
-        }
-
-        self.sources = convert_block_sources(file_size, block_sources);
-    }
-
-    fn allocate(&mut self) {
-        let file = self.file.lock().unwrap();
-        let size = file.metadata.size;
-        let path = file.local_path.clone();
-        drop(file);
-
-        let mut f = F::create(path).unwrap();
-        f.seek(SeekFrom::Start(size as u64)).unwrap();
-        f.write(&[0]).unwrap();
-        f.sync_all().unwrap();
-    }
-
-    pub fn download(&mut self) {
-        // self.allocate();
-        self.update_sources();
-        let mut metadata = self.file.lock().unwrap().metadata.clone();
-        let block_size = calculate_block_size(metadata.size);
-        let path = self.file.lock().unwrap().local_path.clone();
-        let mut f = F::create(path).unwrap();
-        // TODO: Update sources after every block download
-        for block_id in sort_by_block_availability(self.sources.clone()).iter() {
-            let ref current_sources = self.sources[*block_id];
-            if current_sources.len() > 0 {
-                for source in current_sources {
-                    match TcpStream::connect((*source, BASE_PORT)) {
-                        Ok(mut stream) => {
-                            let payload = serialize(&(metadata.hash.0.clone(), block_id), SizeLimit::Infinite).unwrap();
-                            stream.write_all(&payload).unwrap();
-                            stream.shutdown(Shutdown::Write).unwrap();
-
-                            let mut block = Vec::with_capacity(block_size);
-                            stream.read_to_end(&mut block).unwrap();
-                            if block.len() > 0 {
-                                let mut block_hash = Sha256::new();
-                                block_hash.input(&block);
-                                let mut buf = vec![0; block_hash.output_bytes()];
-                                block_hash.result(&mut buf);
-                                if buf != metadata.hash.1[*block_id] { exit!(1, "HASH MISMATCH"); }
-                                f.seek(SeekFrom::Start((block_id * block_size) as u64 )).unwrap();
-                                f.write_all(&mut block).unwrap();
-                                break;
-                            } else { warn!("Received invalid block data (zero_len)"); }
-                        },
-                        Err(_) => {}
-                    }
+                Err(_) => {
+                    // The connection is unusable so drop it along with the outstanding requests
+                    self.keep_partial_block(*block_id, block);
+                    self.connection_failed(source);
+                    return received;
+                }
+            }
+            let now = PreciseTime::now();
+            let bytes = block.len() - resumed;
+            if bytes == 0 {
+                warn!("Block {} is not available at {}", block_id, to_hex_string(source));
+                self.keep_partial_block(*block_id, block);
+                self.peers.lock().unwrap().record_failure(source);
+                self.sources[*block_id].retain(|s| s != source);
+                continue;
+            }
+            self.peers.lock().unwrap().record_block(source, bytes, latency, last.to(now));
+            self.connection_failures.remove(source);
+            // Waiting for the bandwidth limit must not count against the throughput of the source
+            self.limiter.throttle(bytes);
+            last = PreciseTime::now();
+
+            let valid = {
+                let file = self.file.lock().unwrap();
+                file.metadata.algorithm.digest(&block) == file.metadata.hash.1[*block_id]
+            };
+            if !valid && resumed > 0 {
+                // It is unknown which of the sources sent the damaged part, so the whole block is requested again
+                warn!("Block {} of {} does not match its hash after resuming it at {}", block_id, self.file.lock().unwrap().metadata.name,
+                    to_hex_string(source));
+                continue;
+            }
+            if !valid {
+                // Sources learned through peer exchange are unverified, so a damaged block only costs the source the block
+                warn!("Block {} of {} from {} does not match its hash", block_id, self.file.lock().unwrap().metadata.name, to_hex_string(source));
+                self.peers.lock().unwrap().record_failure(source);
+                self.sources[*block_id].retain(|s| s != source);
+                continue;
+            }
+            if let Err(e) = self.write_at(block_offset(size, *block_id), &block) {
+                error!("Failed to store block {} of {}: {}", block_id, self.file.lock().unwrap().metadata.name, e);
+                self.storage_failed = true;
+                return received;
+            }
+            self.completed[*block_id] = true;
+            let usage = self.usage.entry(source.clone()).or_default();
+            usage.blocks += 1;
+            usage.bytes += bytes;
+            self.publish_block(*block_id, Some(source), bytes);
+            if let Some(ref seed) = self.seed { seed.write().unwrap().blocks.push((*block_id, 0)); }
+            received.push(*block_id);
+        }
+
+        if exchange {
+            let mut response = Vec::new();
+            match read_frame_into(&mut stream, allowed.map(|allowed| Instant::now() + allowed), &mut response) {
+                Ok(()) => match deserialize::<PeerExchange>(&response) {
+                    Ok(exchange) => self.add_exchanged_peers(exchange, source),
+                    Err(_) => debug!("{} responded with malformed sources", to_hex_string(source))
+                },
+                // The blocks have arrived already, so only the connection is dropped
+                Err(_) => return received
+            }
+        }
+
+        self.connections.insert(source.clone(), stream);
+        received
+    }
+
+    /// Remember the beginning of a block whose transfer broke off, so only the rest is requested from the next source
+    fn keep_partial_block(&mut self, block_id: usize, data: Vec<u8>) {
+        if data.is_empty() { return }
+        trace!("Keeping {} bytes of block {} to resume it", data.len(), block_id);
+        self.partial_blocks.insert(block_id, data);
+    }
+
+    /// Download the block chosen by the block picker along with further blocks of the same source, returns false once
+    /// there is nothing left to download or the download is paused
+    pub fn download_block(&mut self) -> bool {
+        if self.paused || self.storage_failed { return false }
+        if !self.allocated {
+            let kept = self.keep_existing();
+            if kept > 0 { info!("Kept {} of {} blocks that are already present at the destination", kept, self.completed.len()); }
+            // Blocks that are complete already, e.g. while repairing a local copy, have to be kept
+            let fresh = !self.completed.iter().any(|completed| *completed);
+            if !self.allocate(fresh) { return false }
+            let cached = self.fill_from_cache();
+            if cached > 0 { info!("Took {} of {} blocks from local files", cached, self.completed.len()); }
+            self.publish_blocks();
+            // Nobody has to be asked if every block is present locally
+            if self.completed.iter().any(|completed| !completed) && !self.refresh_sources() { self.update_sources(); }
+        } else if self.sources_updated.is_some_and(|updated| updated.elapsed() > Duration::from_secs(SOURCE_REFRESH_INTERVAL)) {
+            // Gossip keeps the table up to date while the download is running
+            self.refresh_sources();
+        } else if self.expire_sources() {
+            // The sources that are still around confirm their blocks again
+            if !self.refresh_sources() { self.update_sources(); }
+        }
+
+        let block_id = match self.pick_block() {
+            Some(block_id) => { self.stalled_since = None; block_id },
+            None if self.distributed && self.completed.iter().any(|completed| !completed) => {
+                // Other downloaders of the distribution pick up the missing blocks over time
+                let stalled_since = *self.stalled_since.get_or_insert_with(Instant::now);
+                if stalled_since.elapsed() > Duration::from_secs(DISTRIBUTION_STALL_TIMEOUT) {
+                    warn!("No source for the missing blocks appeared within {} seconds", DISTRIBUTION_STALL_TIMEOUT);
+                    return false;
                 }
+                sleep(Duration::from_secs(SOURCE_REFRESH_INTERVAL));
+                if !self.refresh_sources() { self.update_sources(); }
+                return true;
+            },
+            None => return false
+        };
+        let block_size = calculate_block_size(self.file.lock().unwrap().metadata.size);
+
+        let mut current_sources = self.sources[block_id].clone();
+        // Re-rank the sources with the measurements collected while downloading the previous blocks
+        self.peers.lock().unwrap().rank(&mut current_sources, block_size);
+        for (index, source) in current_sources.iter().enumerate() {
+            let blocks = self.pick_pipeline(source, block_id, self.pipeline_depth);
+            // The last source is waited for however slow it is since the block could not be reassigned anyway
+            let deadline = index + 1 < current_sources.len();
+            if self.download_pipelined(source, &blocks, deadline).contains(&block_id) { return true }
+        }
+
+        // None of the sources delivered the block so forget about them
+        warn!("Failed to download block {} from any source", block_id);
+        self.sources[block_id].clear();
+        true
+    }
+
+    /// Download the missing blocks of `blocks` right away, e.g. to serve a read of a file that is not downloaded as a
+    /// whole. The output is kept between calls and the blocks it already contains are taken from the block cache.
+    /// Returns false if any of the blocks could not be retrieved.
+    pub fn download_range(&mut self, blocks: Range<usize>) -> bool {
+        let (hash, size, path) = {
+            let file = self.file.lock().unwrap();
+            (file.metadata.hash.1.clone(), file.metadata.size, file.local_path.clone())
+        };
+        // Blocks in other storages can not be read from the local path by the cache
+        let cache = if self.stores_locally() { self.cache.clone() } else { None };
+        if self.storage_failed { return false }
+        if !self.allocated {
+            if !self.allocate(false) { return false }
+            self.fill_from_cache();
+            let trailing_bytes = self.file.lock().unwrap().metadata.trailing_bytes.clone();
+            if self.write_at(trailing_offset(size), &trailing_bytes).is_err() { return false }
+            if !self.refresh_sources() { self.update_sources(); }
+        } else if self.expire_sources() && !self.refresh_sources() {
+            self.update_sources();
+        }
+
+        let end = min(blocks.end, self.completed.len());
+        let mut updated = false;
+        for block_id in blocks.start..end {
+            if self.completed[block_id] { continue }
+            // Ask for sources again once per call in case new ones appeared since the download started
+            if !updated && self.sources.get(block_id).is_none_or(|s| s.is_empty()) {
+                self.update_sources();
+                updated = true;
+            }
+            let mut current_sources = self.sources.get(block_id).cloned().unwrap_or(Vec::new());
+            self.peers.lock().unwrap().rank(&mut current_sources, calculate_block_size(size));
+            for (index, source) in current_sources.iter().enumerate() {
+                // Read ahead within the requested range only
+                let pipeline = (block_id..end)
+                    .filter(|id| !self.completed[*id] && self.sources[*id].contains(source))
+                    .take(self.pipeline_depth).collect::<Vec<_>>();
+                let received = self.download_pipelined(source, &pipeline, index + 1 < current_sources.len());
+                if let Some(ref cache) = cache {
+                    let mut cache = cache.lock().unwrap();
+                    for id in received.iter() { cache.insert_block(&hash[*id], &path, block_offset(size, *id), calculate_block_size(size)); }
+                }
+                if self.completed[block_id] { break }
             }
+            if !self.completed[block_id] { return false }
+        }
+        true
+    }
+
+    /// Write the trailing bytes and verify the downloaded file, returns whether the download is complete
+    pub fn finish(&mut self) -> bool {
+        // Connections are only kept alive while blocks are being downloaded
+        self.connections.clear();
+        if !self.is_complete() {
+            warn!("Download incomplete, {} blocks are missing", self.completed.iter().filter(|c| !**c).count());
+            return false;
+        }
+
+        if !self.allocated && !self.allocate(true) { return false }
+        let (size, trailing_bytes) = {
+            let file = self.file.lock().unwrap();
+            (file.metadata.size, file.metadata.trailing_bytes.clone())
+        };
+        if let Err(e) = self.write_at(trailing_offset(size), &trailing_bytes).and_then(|_| self.finalize()) {
+            error!("Failed to store {}: {}", self.file.lock().unwrap().metadata.name, e);
+            return false;
         }
 
-        f.seek(SeekFrom::Start((metadata.hash.1.len() * block_size) as u64)).unwrap();
-        f.write_all(&mut metadata.trailing_bytes).unwrap();
+        // Verify the whole file end-to-end since the trailing bytes are not covered by any block hash
+        if !self.file.lock().unwrap().verify() {
+            error!("Content of {} does not match its hash", self.file.lock().unwrap().metadata.name);
+            return false;
+        }
+        match self.key.clone() {
+            Some(key) => self.decrypt(key),
+            // Only local copies that are stored as they are distributed can provide blocks to other downloads
+            None if self.stores_locally() => {
+                let file = self.file.lock().unwrap();
+                if let Some(ref cache) = self.cache {
+                    let mut cache = cache.lock().unwrap();
+                    cache.insert(&file.metadata, &file.local_path);
+                    if let Err(e) = cache.save() { warn!("Failed to save the block cache: {}", e); }
+                }
+                if let Some(ref library) = self.library {
+                    if let Err(e) = library.insert(&file.metadata, &file.local_path) { warn!("Failed to record the metadata: {}", e); }
+                }
+            },
+            None => {}
+        }
+        true
+    }
+
+    /// Decrypt the verified local copy in place, it is encrypted again whenever blocks of it are served
+    fn decrypt(&mut self, key: Key) {
+        let local = self.stores_locally();
+        let mut file = self.file.lock().unwrap();
+        let nonce = match file.metadata.encryption {
+            Some(ref encryption) => encryption.nonce.clone(),
+            None => { warn!("{} is not encrypted, ignoring the key", file.metadata.name); return }
+        };
+        let decrypted = if local {
+            apply_keystream_to_file(&key, &nonce, &file.local_path, file.metadata.size)
+        } else {
+            apply_keystream_to_storage(&key, &nonce, &mut **self.storage.lock().unwrap(), file.metadata.size)
+        };
+        if let Err(e) = decrypted { fail!(Io, "Failed to decrypt {}: {}", file.metadata.name, e) }
+        file.key = Some(key);
+    }
+
+    /// Download the whole file, returns whether it is complete
+    /// If the download gets paused it returns early and may be continued by calling it again once resumed
+    pub fn download(&mut self) -> bool {
+        while self.download_block() {}
+        if self.paused { return false }
+        self.finish()
     }
 }
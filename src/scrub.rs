@@ -0,0 +1,179 @@
+//! Background re-verification of the local copies of shared files
+//!
+//! Local copies may rot or be modified while they are shared. Instead of waiting for a downloader to run into a hash
+//! mismatch, the scrubber re-hashes the blocks of all shared files at a low rate every scrub interval. Blocks that no
+//! longer match their hash or can not be read are no longer offered to other nodes and are appended to the corruption
+//! report in the state directory, one line of JSON per block.
+use std::cmp::min;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{spawn, sleep, JoinHandle};
+use std::time::Duration;
+
+use ext_time::get_time;
+use serde_json;
+
+use bandwidth::Limiter;
+use file::BlockState;
+use helpers::to_hex_string;
+use node::Node;
+use registry::SharedFile;
+
+/// Name of the file within the state directory that holds the corruption report
+const CORRUPTION_FILE: &'static str = "corruption.jsonl";
+/// Maximum time in seconds after the start of the node before the first pass
+const FIRST_PASS_DELAY: u64 = 300;
+/// Interval in seconds at which a disabled scrubber checks whether it has been enabled
+const DISABLED_CHECK_INTERVAL: u64 = 60;
+
+/// A block of a local copy that failed its re-verification
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CorruptBlock {
+    /// Hex encoded hash of the file
+    pub hash: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub block: usize,
+    /// Either `Corrupt` or `Missing`
+    pub state: BlockState,
+    /// Unix time in seconds at which the block failed
+    pub detected: i64
+}
+
+/// Corruption report in the state directory
+#[derive(Debug, Clone)]
+pub struct CorruptionReport {
+    path: PathBuf
+}
+
+impl CorruptionReport {
+    /// Open the report kept in the state directory, the file is created once the first block is recorded
+    pub fn new(state_dir: &Path) -> CorruptionReport {
+        CorruptionReport {
+            path: state_dir.join(CORRUPTION_FILE)
+        }
+    }
+
+    /// Append a block to the report
+    pub fn record(&self, block: &CorruptBlock) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() { fs::create_dir_all(dir)?; }
+        let mut line = serde_json::to_vec(block).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push(b'\n');
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(&line)
+    }
+
+    /// Read all blocks in the order they have been recorded, lines that can not be parsed are skipped
+    pub fn entries(&self) -> io::Result<Vec<CorruptBlock>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e)
+        };
+        Ok(content.lines().filter_map(|line| match serde_json::from_str(line) {
+            Ok(block) => Some(block),
+            Err(_) => { warn!("Skipping a malformed line of {}", self.path.display()); None }
+        }).collect())
+    }
+}
+
+/// Re-hash the blocks of the local copy of a shared file that are offered to other nodes, reading at most as fast as
+/// `limiter` allows. Blocks that fail are no longer offered and returned along with their state.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::io::{Seek, SeekFrom, Write};
+/// # use std::sync::{Arc, RwLock};
+/// # use ddp::bandwidth::Limiter;
+/// # use ddp::file::{BlockState, example_file};
+/// # use ddp::scrub::scrub_file;
+/// # fn main() {
+/// let file = example_file("ddp-scrub-example", 300000);
+/// let path = file.local_path.clone();
+/// let shared = Arc::new(RwLock::new(file));
+/// assert!(scrub_file(&shared, &Limiter::new()).is_empty());
+///
+/// // Rot in the first block
+/// let mut local = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+/// local.seek(SeekFrom::Start(10)).unwrap();
+/// local.write_all(&[8]).unwrap();
+///
+/// assert_eq!(scrub_file(&shared, &Limiter::new()), vec![(0, BlockState::Corrupt)]);
+/// assert!(!shared.read().unwrap().has_block(0));
+/// assert!(shared.read().unwrap().has_block(1));
+/// # }
+/// ```
+pub fn scrub_file(shared: &SharedFile, limiter: &Limiter) -> Vec<(usize, BlockState)> {
+    let blocks = shared.read().unwrap().blocks.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+    let mut failed = Vec::new();
+    for block_id in blocks {
+        // The lock is released between blocks so serving the file is not held up for long
+        let (state, length) = {
+            let file = shared.read().unwrap();
+            match file.try_get_block(block_id) {
+                Ok(ref data) if file.metadata.algorithm.digest(data) == file.metadata.hash.1[block_id] => (BlockState::Intact, data.len()),
+                Ok(ref data) => (BlockState::Corrupt, data.len()),
+                Err(_) => (BlockState::Missing, 0)
+            }
+        };
+        if state != BlockState::Intact { failed.push((block_id, state)); }
+        limiter.throttle(length);
+    }
+    if !failed.is_empty() {
+        shared.write().unwrap().blocks.retain(|&(id, _)| !failed.iter().any(|&(failed, _)| failed == id));
+    }
+    failed
+}
+
+/// Re-verify all shared files of the node once, returns the amount of blocks that failed
+fn scrub(node: &Node, limiter: &Limiter, report: Option<&CorruptionReport>) -> usize {
+    let mut failures = 0;
+    for shared in node.files.list() {
+        if shared.read().unwrap().paused { continue }
+        let failed = scrub_file(&shared, limiter);
+        if failed.is_empty() { continue }
+        failures += failed.len();
+        let file = shared.read().unwrap();
+        warn!("{} blocks of {} failed their re-verification and are no longer offered", failed.len(), file.local_path.display());
+        let report = match report { Some(report) => report, None => continue };
+        for &(block_id, state) in failed.iter() {
+            let block = CorruptBlock {
+                hash: to_hex_string(&file.metadata.hash.0),
+                name: file.metadata.name.clone(),
+                path: file.local_path.clone(),
+                block: block_id,
+                state: state,
+                detected: get_time().sec
+            };
+            if let Err(e) = report.record(&block) { warn!("Failed to record a corrupt block: {}", e); }
+        }
+    }
+    failures
+}
+
+/// Re-verify the shared files of the node every scrub interval of its configuration at the configured rate
+pub fn start_scrubber(node: Node) -> JoinHandle<()> {
+    spawn(move || {
+        let limiter = Limiter::new();
+        let mut wait = node.config().scrub_interval.map_or(Duration::from_secs(DISABLED_CHECK_INTERVAL),
+            |interval| min(interval, Duration::from_secs(FIRST_PASS_DELAY)));
+        loop {
+            sleep(wait);
+            // The configuration may change between passes
+            let (interval, rate, report) = {
+                let config = node.config();
+                (config.scrub_interval, config.scrub_rate, config.state_dir.as_ref().map(|dir| CorruptionReport::new(dir)))
+            };
+            match interval {
+                Some(interval) => wait = interval,
+                None => { wait = Duration::from_secs(DISABLED_CHECK_INTERVAL); continue }
+            }
+            limiter.set_rate(if rate > 0 { Some(rate) } else { None });
+            debug!("Re-verifying the shared files");
+            let failures = scrub(&node, &limiter, report.as_ref());
+            if failures > 0 { info!("Re-verification found {} damaged blocks", failures); }
+        }
+    })
+}
@@ -0,0 +1,209 @@
+//! Backends the blocks of downloads are written to
+//!
+//! Downloads write into a local file by default. Applications using the library can store the blocks elsewhere, e.g.
+//! in an object store or on a raw device, by giving the handle of a download a storage of their own. Blocks are served
+//! to other nodes from the storage of a file as well, both while and after it is downloaded.
+use std::fs::{File as F, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "mmap")]
+use memmap2::{Mmap, MmapMut};
+#[cfg(feature = "mmap")]
+use file::{map_file, map_output};
+
+/// Storage shared between a download and the block server
+pub type SharedStorage = Arc<Mutex<Box<dyn Storage>>>;
+
+/// Share a storage between a download and the block server
+pub fn shared<S: Storage + 'static>(storage: S) -> SharedStorage {
+    Arc::new(Mutex::new(Box::new(storage)))
+}
+
+/// Destination of the blocks of a download
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::io;
+/// # use ddp::storage::Storage;
+/// /// Keeps the whole file in memory
+/// struct Memory(Vec<u8>);
+///
+/// impl Storage for Memory {
+///     fn allocate(&mut self, size: usize, truncate: bool) -> io::Result<()> {
+///         if truncate { self.0.clear(); }
+///         self.0.resize(size, 0);
+///         Ok(())
+///     }
+///
+///     fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+///         self.0[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+///         Ok(())
+///     }
+///
+///     fn read_block(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+///         self.0.get(offset as usize..offset as usize + length).map(|data| data.to_vec())
+///             .ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "beyond the end of the file"))
+///     }
+///
+///     fn finalize(&mut self) -> io::Result<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// # fn main() {
+/// let mut storage = Memory(Vec::new());
+/// storage.allocate(8, true).unwrap();
+/// storage.write_block(4, &[1, 2, 3, 4]).unwrap();
+/// assert_eq!(storage.read_block(2, 4).unwrap(), vec![0, 0, 1, 2]);
+/// assert!(storage.read_block(6, 4).is_err());
+/// # }
+/// ```
+pub trait Storage: Send {
+    /// Prepare the storage for a file of `size` bytes before the first block is written, content that has been written
+    /// earlier is kept unless `truncate` is set
+    fn allocate(&mut self, size: usize, truncate: bool) -> io::Result<()>;
+
+    /// Write the bytes of a block, or of the trailing bytes, starting at `offset`
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Read `length` bytes starting at `offset`, e.g. to serve or verify them
+    fn read_block(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>>;
+
+    /// Persist everything written so far, called once the download is complete and again after an encrypted download
+    /// has been decrypted in place. Blocks are still read afterwards while the file is served.
+    fn finalize(&mut self) -> io::Result<()>;
+
+    /// Local file the blocks are stored in, which lets the block cache and the library index them
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Local file, the default storage of downloads and shared files
+pub struct FileStorage {
+    path: PathBuf,
+    /// Further local paths with the same content, blocks are read from them if the path can not be read
+    copies: Vec<PathBuf>,
+    output: Option<F>,
+    /// Writable memory mapping of the output, blocks are written with regular file IO if it is missing
+    #[cfg(feature = "mmap")]
+    mapping: Option<MmapMut>,
+    /// Memory mapping of the complete file, blocks are read with regular file IO if it is missing
+    #[cfg(feature = "mmap")]
+    complete: Option<Mmap>
+}
+
+impl FileStorage {
+    /// Storage of a file that is yet to be downloaded to `path`
+    pub fn new(path: PathBuf) -> FileStorage {
+        FileStorage {
+            path: path,
+            copies: Vec::new(),
+            output: None,
+            #[cfg(feature = "mmap")]
+            mapping: None,
+            #[cfg(feature = "mmap")]
+            complete: None
+        }
+    }
+
+    /// Storage of a complete local file at `path` and further `copies` of it
+    pub fn open(path: PathBuf, copies: Vec<PathBuf>) -> FileStorage {
+        FileStorage {
+            #[cfg(feature = "mmap")]
+            complete: map_file(&path),
+            copies: copies,
+            ..FileStorage::new(path)
+        }
+    }
+
+    fn output(&mut self) -> io::Result<&mut F> {
+        self.output.as_mut().ok_or(io::Error::new(io::ErrorKind::NotFound, "the output has not been allocated"))
+    }
+}
+
+impl Storage for FileStorage {
+    fn allocate(&mut self, size: usize, truncate: bool) -> io::Result<()> {
+        let f = OpenOptions::new().read(true).write(true).create(true).truncate(truncate).open(&self.path)?;
+        f.set_len(size as u64)?;
+        f.sync_all()?;
+        #[cfg(feature = "mmap")]
+        {
+            self.complete = None;
+            self.mapping = map_output(&f);
+        }
+        self.output = Some(f);
+        Ok(())
+    }
+
+    fn write_block(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "mmap")]
+        {
+            if let Some(ref mut mapping) = self.mapping {
+                mapping[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+                return Ok(());
+            }
+        }
+
+        let f = self.output()?;
+        f.seek(SeekFrom::Start(offset))?;
+        f.write_all(data)
+    }
+
+    fn read_block(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        #[cfg(feature = "mmap")]
+        {
+            let mapped = self.mapping.as_ref().map(|mapping| &mapping[..]).or(self.complete.as_ref().map(|mapping| &mapping[..]));
+            if let Some(mapped) = mapped {
+                return mapped.get(offset as usize..offset as usize + length).map(|data| data.to_vec())
+                    .ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "beyond the end of the mapping"));
+            }
+        }
+
+        if let Some(ref mut f) = self.output {
+            f.seek(SeekFrom::Start(offset))?;
+            let mut buf = vec![0; length];
+            f.read_exact(&mut buf)?;
+            return Ok(buf);
+        }
+
+        let mut result = read_range(&self.path, offset, length);
+        // Further copies stand in if the local path has been moved or deleted
+        for copy in self.copies.iter() {
+            if result.is_ok() { break }
+            result = read_range(copy, offset, length);
+        }
+        result
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        // The file is closed so it can be modified in place afterwards, e.g. to decrypt it
+        #[cfg(feature = "mmap")]
+        {
+            if let Some(mapping) = self.mapping.take() { mapping.flush()?; }
+        }
+        if let Some(f) = self.output.take() { f.sync_all()?; }
+        // The complete file is served from now on
+        #[cfg(feature = "mmap")]
+        { self.complete = map_file(&self.path); }
+        Ok(())
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// Read `length` bytes at `offset` of a local file
+fn read_range(path: &Path, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+    let f = F::open(path)?;
+    let mut reader = BufReader::with_capacity(length, f);
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; length];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
@@ -0,0 +1,240 @@
+//! Streams of unknown length, e.g. `tar c dir | ddp share -`, distributed as a growing sequence of segments
+//!
+//! Data read from a stream is spooled to a cache directory and cut into segments of a fixed size. Every segment is
+//! shared as a regular file as soon as it is complete so downloads start before the stream ends. The sharing node
+//! answers stream queries with the hashes of the segments known so far and whether the stream has ended.
+use std::fs::{self, File as F};
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::thread::{spawn, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bincode::{serialize, deserialize};
+use getrandom::getrandom;
+
+use announce::Message;
+use file::File;
+use helpers::{to_hex_string, HASH_LENGTH};
+use networking::{Overflow, BASE_PORT};
+use peers::NodeId;
+use transfer::{Priority, TransferState};
+use uri::Link;
+use node::Node;
+
+/// Size of a segment in bytes, only the last segment of a stream may be smaller
+pub const SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+/// Maximum amount of segment hashes sent in a single response to keep it within one datagram
+const MAX_RESPONSE_SEGMENTS: usize = 24;
+/// Interval in seconds at which a downloader asks for new segments while it has downloaded all known ones
+const POLL_INTERVAL: u64 = 1;
+/// Time in seconds after which a download gives up if the stream does not make any progress
+const STALL_TIMEOUT: u64 = 60;
+/// Maximum amount of received responses waiting to be handled by a downloader
+const MAX_QUEUED_RESPONSES: usize = 16;
+
+/// Query for the segments of a stream, sent via multicast or directly to a node known to share it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamQuery {
+    /// ID of the requested stream
+    pub id: Vec<u8>,
+    /// Only segments starting at this index are requested
+    pub from_segment: usize,
+    /// Pre-shared token required by the access control list of the queried node
+    pub token: Option<String>
+}
+
+/// Response to a stream query
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamResponse {
+    /// ID of the stream
+    pub id: Vec<u8>,
+    /// ID of the responding node
+    pub node_id: NodeId,
+    /// Port on which the responding node accepts queries and block requests
+    pub port: u16,
+    /// Index of the first segment contained in the response
+    pub first_segment: usize,
+    /// Hashes of the segments starting at `first_segment`
+    pub segments: Vec<Vec<u8>>,
+    /// Whether the stream has ended and there are no segments after the ones contained in the response
+    pub finished: bool
+}
+
+/// Stream shared by this node
+#[derive(Debug, Clone)]
+pub struct Stream {
+    /// Random ID the stream is referenced by since its content hash is unknown until it ends
+    pub id: Vec<u8>,
+    pub name: String,
+    /// Hashes of the segments that have been shared so far
+    pub segments: Vec<Vec<u8>>,
+    /// Whether the stream has ended
+    pub finished: bool
+}
+
+impl Stream {
+    /// Creates a stream with a random ID and without any segments
+    pub fn new(name: String) -> Stream {
+        let mut id = vec![0; HASH_LENGTH];
+        if let Err(e) = getrandom(&mut id) { fail!(Random, "{}", e) }
+        Stream {
+            id: id,
+            name: name,
+            segments: Vec::new(),
+            finished: false
+        }
+    }
+
+    /// Answer a query for the segments starting at `from_segment`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate ddp;
+    /// # use ddp::stream::Stream;
+    /// # fn main() {
+    /// let mut stream = Stream::new("backup.tar".to_string());
+    /// stream.segments = (0..30u8).map(|i| vec![i; 32]).collect();
+    /// stream.finished = true;
+    ///
+    /// // Long streams are answered in several responses, only the last one tells that the stream has ended
+    /// let first = stream.response(&vec![1], 0);
+    /// assert!(!first.finished);
+    /// let rest = stream.response(&vec![1], first.segments.len());
+    /// assert_eq!(first.segments.len() + rest.segments.len(), 30);
+    /// assert!(rest.finished);
+    /// # }
+    /// ```
+    pub fn response(&self, node_id: &NodeId, from_segment: usize) -> StreamResponse {
+        let first_segment = from_segment.min(self.segments.len());
+        let end = (first_segment + MAX_RESPONSE_SEGMENTS).min(self.segments.len());
+        StreamResponse {
+            id: self.id.clone(),
+            node_id: node_id.clone(),
+            port: BASE_PORT,
+            first_segment: first_segment,
+            segments: self.segments[first_segment..end].to_vec(),
+            finished: self.finished && end == self.segments.len()
+        }
+    }
+}
+
+/// Read `reader` until it ends, spooling the data into segments in `dir` which are shared by `node` as soon as they
+/// are complete. The stream has to be registered with the node beforehand.
+pub fn spool_stream<R: Read + Send + 'static>(node: Node, mut reader: R, id: Vec<u8>, dir: PathBuf) -> JoinHandle<()> {
+    spawn(move || {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            error!("Failed to create the spool directory {}: {}", dir.display(), e);
+            return;
+        }
+        let name = node.streams.lock().unwrap().iter().find(|s| s.id == id).map_or(String::new(), |s| s.name.clone());
+        let mut index = 0;
+        loop {
+            let path = dir.join(format!("{}.{:05}", name, index));
+            let len = match F::create(&path).and_then(|mut f| io::copy(&mut (&mut reader).take(SEGMENT_SIZE as u64), &mut f)) {
+                Ok(len) => len,
+                Err(e) => { error!("Failed to spool segment {} of {}: {}", index, name, e); return }
+            };
+
+            if len > 0 {
+                let algorithm = node.config().hash_algorithm;
+                let file = match File::prepare(path, algorithm) {
+                    Ok(file) => file,
+                    Err(e) => { error!("Failed to hash segment {} of {}: {}", index, name, e); return }
+                };
+                let hash = file.metadata.hash.0.clone();
+                node.cache_blocks(&file);
+                // Segments with the same content are only shared once
+                node.files.insert(file);
+                if let Some(stream) = node.streams.lock().unwrap().iter_mut().find(|s| s.id == id) {
+                    stream.segments.push(hash);
+                }
+                debug!("Shared segment {} of {} ({} bytes)", index, name, len);
+                index += 1;
+            } else {
+                let _ = fs::remove_file(&path);
+            }
+            // A short segment means the stream has ended
+            if len < SEGMENT_SIZE as u64 { break }
+        }
+
+        if let Some(stream) = node.streams.lock().unwrap().iter_mut().find(|s| s.id == id) {
+            stream.finished = true;
+        }
+        info!("Stream {} ended after {} segments", name, index);
+    })
+}
+
+/// Download the stream referenced by `link` to `path`, appending every segment as soon as it is complete. The
+/// segments are kept in a directory next to `path` and seeded like other downloads.
+pub fn fetch_stream(node: &Node, link: &Link, path: PathBuf) -> bool {
+    let spool = PathBuf::from(format!("{}.segments", path.display()));
+    let mut output = match fs::create_dir_all(&spool).and_then(|_| F::create(&path)) {
+        Ok(output) => output,
+        Err(e) => { error!("Failed to create {}: {}", path.display(), e); return false }
+    };
+
+    let sock = node.socket().create_handle();
+    let responses = sock.try_clone().unwrap().start_receiver(MAX_QUEUED_RESPONSES, Overflow::DropNewest);
+    let mut sources: Vec<SocketAddr> = link.peers.clone();
+    let mut segments: Vec<Vec<u8>> = Vec::new();
+    let mut finished = false;
+    let mut written = 0;
+    let mut progressed = Instant::now();
+    while progressed.elapsed() < Duration::from_secs(STALL_TIMEOUT) {
+        if written < segments.len() {
+            let hash = segments[written].clone();
+            let segment_path = spool.join(format!("{:05}", written));
+            let mut segment = Link::new(hash.clone());
+            segment.peers = sources.clone();
+            let handle = match node.fetch_link(&segment, Some(segment_path.clone())) {
+                Some(handle) => handle,
+                None => { warn!("Failed to retrieve the metadata of segment {}, retrying", written); continue }
+            };
+            node.transfers.add(handle, Priority::Normal);
+            match node.transfers.wait(&hash) {
+                Some(TransferState::Seeding) | Some(TransferState::Complete) => {},
+                state => { error!("Download of segment {} failed ({:?})", written, state); return false }
+            }
+            if let Err(e) = F::open(&segment_path).and_then(|mut segment| io::copy(&mut segment, &mut output)) {
+                error!("Failed to append segment {} to {}: {}", written, path.display(), e);
+                return false;
+            }
+            debug!("Appended segment {} of {}", written, to_hex_string(&link.hash));
+            written += 1;
+            progressed = Instant::now();
+            continue;
+        }
+        if finished { return true }
+
+        // Ask for the segments after the known ones, directly at the nodes that answered before
+        let query = serialize(&Message::StreamQuery(StreamQuery {
+            id: link.hash.clone(),
+            from_segment: segments.len(),
+            token: node.config().token.clone()
+        })).unwrap();
+        if let Err(e) = sock.send_to_multicast(&query) { warn!("Failed to query the stream: {}", e); }
+        for source in sources.iter() {
+            if let Err(e) = sock.send(&query, *source) { debug!("Failed to query the stream at {}: {}", source, e); }
+        }
+
+        let known = segments.len();
+        while let Some(datagram) = responses.recv_timeout(Duration::from_secs(POLL_INTERVAL)) {
+            let response: StreamResponse = match deserialize(&datagram) {
+                Ok(response) => response,
+                Err(_) => { warn!("Received malformed stream response from {}", datagram.src); continue }
+            };
+            // Late responses to previous queries are ignored
+            if response.id != link.hash || response.first_segment != segments.len() { continue }
+            let source = SocketAddr::new(datagram.src.ip(), response.port);
+            if !sources.contains(&source) { sources.push(source); }
+            segments.extend(response.segments);
+            finished = response.finished;
+            break;
+        }
+        if segments.len() > known || finished { progressed = Instant::now(); } else { sleep(Duration::from_secs(POLL_INTERVAL)); }
+    }
+    error!("Stream {} made no progress for {}s", to_hex_string(&link.hash), STALL_TIMEOUT);
+    false
+}
@@ -0,0 +1,189 @@
+//! Protection against abusing the announce listener to flood other hosts with responses
+//!
+//! Queries arrive as unauthenticated datagrams whose source address may be spoofed, so every response is a potential
+//! reflection. The listener only answers plausible sources, limits the rate of queries per source and caps the amount
+//! of metadata it pushes concurrently.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use if_addrs::{get_if_addrs, IfAddr};
+
+use acl::Cidr;
+
+/// Amount of sources whose rate is tracked before idle ones are forgotten
+const MAX_TRACKED_SOURCES: usize = 4096;
+/// Minimum interval in milliseconds between sweeps for idle sources, a sweep scans every tracked source
+const SWEEP_INTERVAL: u64 = 1000;
+/// Interval at which the subnets of the local interfaces are looked up again
+const SUBNET_REFRESH_INTERVAL: u64 = 60;
+
+/// Which sources the announce listener answers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceFilter {
+    /// Only sources within the subnet of a local interface
+    LocalSubnet,
+    /// Every plausible source
+    Any
+}
+
+/// Whether an address can be the source of a genuine query
+pub fn is_plausible_source(addr: &SocketAddr) -> bool {
+    let ip = addr.ip();
+    let broadcast = match ip {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        IpAddr::V6(_) => false
+    };
+    addr.port() != 0 && !ip.is_unspecified() && !ip.is_multicast() && !broadcast
+}
+
+/// Limits the rate of queries per source with a token bucket for each of them
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::throttle::RateLimiter;
+/// # fn main() {
+/// let mut limiter = RateLimiter::new(1.0, 3.0);
+/// let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+/// assert!((0..3).all(|_| limiter.allow(a)));
+/// assert!(!limiter.allow(a));
+/// // Every source has a bucket of its own
+/// assert!(limiter.allow(b));
+/// # }
+/// ```
+pub struct RateLimiter {
+    /// Tokens added to each bucket per second
+    rate: f64,
+    /// Capacity of each bucket
+    burst: f64,
+    /// Tokens left and time of the last refill by source
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+    /// Time of the last sweep for idle sources
+    swept: Instant
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> RateLimiter {
+        RateLimiter {
+            rate: rate,
+            burst: burst,
+            buckets: HashMap::new(),
+            swept: Instant::now()
+        }
+    }
+
+    /// Take a token from the bucket of a source, returns false if the source exceeded its rate
+    pub fn allow(&mut self, source: IpAddr) -> bool {
+        let now = Instant::now();
+        // Sweeping on every query would make each of them scan all sources while the table is full
+        if self.buckets.len() >= MAX_TRACKED_SOURCES && now.duration_since(self.swept) >= Duration::from_millis(SWEEP_INTERVAL) {
+            self.forget_idle(now);
+            self.swept = now;
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        let bucket = self.buckets.entry(source).or_insert((burst, now));
+        let elapsed = now.duration_since(bucket.1);
+        bucket.0 = (bucket.0 + rate * (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9)).min(burst);
+        bucket.1 = now;
+        if bucket.0 < 1.0 { return false }
+        bucket.0 -= 1.0;
+        true
+    }
+
+    /// Forget the sources whose buckets have been refilled completely since they are indistinguishable from new ones
+    fn forget_idle(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.1).as_secs() as f64;
+            bucket.0 + rate * elapsed < burst
+        });
+    }
+}
+
+/// Subnets of the local network interfaces, looked up again periodically since interfaces come and go
+pub struct LocalSubnets {
+    subnets: Vec<Cidr>,
+    refreshed: Instant
+}
+
+impl Default for LocalSubnets {
+    fn default() -> LocalSubnets {
+        LocalSubnets::new()
+    }
+}
+
+impl LocalSubnets {
+    pub fn new() -> LocalSubnets {
+        LocalSubnets {
+            subnets: lookup_subnets(),
+            refreshed: Instant::now()
+        }
+    }
+
+    /// Whether the address is within the subnet of a local interface
+    pub fn contains(&mut self, ip: &IpAddr) -> bool {
+        if self.refreshed.elapsed() > Duration::from_secs(SUBNET_REFRESH_INTERVAL) {
+            self.subnets = lookup_subnets();
+            self.refreshed = Instant::now();
+        }
+        self.subnets.iter().any(|subnet| subnet.contains(ip))
+    }
+}
+
+/// Addresses of the local interfaces, a node reached at one of them runs on this machine
+pub fn local_addresses() -> Vec<IpAddr> {
+    match get_if_addrs() {
+        Ok(interfaces) => interfaces.iter().map(|interface| interface.ip()).collect(),
+        Err(e) => { warn!("Failed to look up the local interfaces: {}", e); Vec::new() }
+    }
+}
+
+fn lookup_subnets() -> Vec<Cidr> {
+    match get_if_addrs() {
+        Ok(interfaces) => interfaces.iter().map(|interface| match interface.addr {
+            IfAddr::V4(ref addr) => Cidr { addr: IpAddr::V4(addr.ip), prefix: addr.prefixlen },
+            IfAddr::V6(ref addr) => Cidr { addr: IpAddr::V6(addr.ip), prefix: addr.prefixlen }
+        }).collect(),
+        Err(e) => { warn!("Failed to look up the local interfaces: {}", e); Vec::new() }
+    }
+}
+
+/// Caps the amount of concurrent outbound connections, cloning it yields another handle to the same counter
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    active: Arc<AtomicUsize>,
+    max: usize
+}
+
+/// Slot of a `ConnectionLimiter` that is released when it is dropped
+pub struct ConnectionPermit {
+    active: Arc<AtomicUsize>
+}
+
+impl ConnectionLimiter {
+    pub fn new(max: usize) -> ConnectionLimiter {
+        ConnectionLimiter {
+            active: Arc::new(AtomicUsize::new(0)),
+            max: max
+        }
+    }
+
+    /// Reserve a slot for a connection, returns `None` if all slots are taken
+    pub fn acquire(&self) -> Option<ConnectionPermit> {
+        let max = self.max;
+        self.active.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| if active < max { Some(active + 1) } else { None })
+            .ok()
+            .map(|_| ConnectionPermit { active: self.active.clone() })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,436 @@
+//! Export of shared files as BitTorrent metainfo files and import of the metadata contained in them, so DDP nodes and
+//! BitTorrent clients agree on the identity of the content
+//!
+//! Exported torrents are hybrid ones: they carry the SHA-1 piece hashes of BitTorrent v1 and the SHA-256 merkle tree of
+//! v2 for the same piece layout, so clients of either version accept them. DDP blocks do not line up with pieces, so the
+//! piece hashes are computed from the local copy and the DDP metadata is embedded as well under the `ddp` key outside
+//! of the info dictionary, leaving the info hashes untouched. Only single-file torrents are supported.
+use std::collections::BTreeMap;
+use std::fs::{self, File as F};
+use std::io::{self, Read};
+use std::path::Path;
+
+use sha1::Sha1;
+use sha2::{Sha256, Digest};
+
+use file::{File, FileMetadata};
+use metafile::MetaFile;
+use helpers::calculate_block_size;
+
+/// Extension of torrent files
+pub const EXTENSION: &'static str = "torrent";
+/// Size of the leaves of the merkle tree of BitTorrent v2
+const LEAF_SIZE: usize = 16 * 1024;
+/// Multihash prefix of SHA-256 digests in v2 magnet links
+const MULTIHASH_SHA256: &'static str = "1220";
+/// Key of the embedded DDP metadata
+const DDP_KEY: &'static str = "ddp";
+/// Maximum nesting of lists and dictionaries, deeper torrents are rejected before they exhaust the stack
+const MAX_DEPTH: usize = 64;
+
+/// Bencoded value
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dictionary(BTreeMap<Vec<u8>, Value>)
+}
+
+impl Value {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Value::Integer(value) => out.extend(format!("i{}e", value).into_bytes()),
+            Value::Bytes(ref bytes) => {
+                out.extend(format!("{}:", bytes.len()).into_bytes());
+                out.extend(bytes);
+            },
+            Value::List(ref values) => {
+                out.push(b'l');
+                for value in values { value.encode(out); }
+                out.push(b'e');
+            },
+            // Keys are kept sorted by the map as required by the format
+            Value::Dictionary(ref entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    Value::Bytes(key.clone()).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    /// Decode the value at the start of `data` nested within `depth` lists and dictionaries, returns the value and the
+    /// remaining data
+    fn decode(data: &[u8], depth: usize) -> Result<(Value, &[u8]), String> {
+        let truncated = || "Truncated torrent".to_string();
+        if depth > MAX_DEPTH { return Err("Torrent nested too deeply".to_string()) }
+        match data.first() {
+            Some(&b'i') => {
+                let end = data.iter().position(|b| *b == b'e').ok_or_else(truncated)?;
+                let value = ::std::str::from_utf8(&data[1..end]).ok().and_then(|s| s.parse().ok())
+                    .ok_or_else(|| "Invalid integer in torrent".to_string())?;
+                Ok((Value::Integer(value), &data[end + 1..]))
+            },
+            Some(&b'l') => {
+                let mut rest = &data[1..];
+                let mut values = Vec::new();
+                while rest.first() != Some(&b'e') {
+                    if rest.is_empty() { return Err(truncated()) }
+                    let (value, remaining) = Value::decode(rest, depth + 1)?;
+                    values.push(value);
+                    rest = remaining;
+                }
+                Ok((Value::List(values), &rest[1..]))
+            },
+            Some(&b'd') => {
+                let mut rest = &data[1..];
+                let mut entries = BTreeMap::new();
+                while rest.first() != Some(&b'e') {
+                    if rest.is_empty() { return Err(truncated()) }
+                    let key = match Value::decode(rest, depth + 1)? {
+                        (Value::Bytes(key), remaining) => { rest = remaining; key },
+                        _ => return Err("Invalid dictionary key in torrent".to_string())
+                    };
+                    let (value, remaining) = Value::decode(rest, depth + 1)?;
+                    entries.insert(key, value);
+                    rest = remaining;
+                }
+                Ok((Value::Dictionary(entries), &rest[1..]))
+            },
+            Some(&(b'0'..=b'9')) => {
+                let colon = data.iter().position(|b| *b == b':').ok_or_else(truncated)?;
+                let length = ::std::str::from_utf8(&data[..colon]).ok().and_then(|s| s.parse::<usize>().ok())
+                    .ok_or_else(|| "Invalid string length in torrent".to_string())?;
+                let start = colon + 1;
+                if data.len() - start < length { return Err(truncated()) }
+                Ok((Value::Bytes(data[start..start + length].to_vec()), &data[start + length..]))
+            },
+            Some(_) => Err("Not a torrent".to_string()),
+            None => Err(truncated())
+        }
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<&Value> {
+        match *self {
+            Value::Dictionary(ref entries) => entries.get(key.as_ref()),
+            _ => None
+        }
+    }
+
+    fn integer(&self) -> Option<i64> {
+        match *self { Value::Integer(value) => Some(value), _ => None }
+    }
+
+    fn bytes(&self) -> Option<&Vec<u8>> {
+        match *self { Value::Bytes(ref bytes) => Some(bytes), _ => None }
+    }
+}
+
+fn dictionary(entries: Vec<(&str, Value)>) -> Value {
+    Value::Dictionary(entries.into_iter().map(|(key, value)| (key.as_bytes().to_vec(), value)).collect())
+}
+
+/// Root of a merkle tree over `leaves` padded with zero hashes to `width` leaves
+fn merkle_root(leaves: &[Vec<u8>], width: usize) -> Vec<u8> {
+    merkle_root_padded(leaves, width, vec![0; 32])
+}
+
+/// Root of a merkle tree over `leaves` padded with `padding` to `width` leaves, `width` has to be a power of two
+fn merkle_root_padded(leaves: &[Vec<u8>], width: usize, mut padding: Vec<u8>) -> Vec<u8> {
+    let mut layer = leaves.to_vec();
+    let mut width = width.max(1);
+    while width > 1 {
+        if layer.len() % 2 == 1 { layer.push(padding.clone()); }
+        layer = layer.chunks(2).map(|pair| {
+            let mut hasher = Sha256::new();
+            hasher.update(&pair[0]);
+            hasher.update(&pair[1]);
+            hasher.finalize().to_vec()
+        }).collect();
+        let mut hasher = Sha256::new();
+        hasher.update(&padding);
+        hasher.update(&padding);
+        padding = hasher.finalize().to_vec();
+        width /= 2;
+    }
+    layer.pop().unwrap_or(padding)
+}
+
+/// Length of the pieces of a torrent of a file of `size` bytes, the smallest power of two that holds a DDP block so both
+/// split the file into about the same amount of parts
+fn piece_length(size: usize) -> usize {
+    calculate_block_size(size).next_power_of_two().max(LEAF_SIZE)
+}
+
+/// Fill `buffer` from `reader` as far as possible, returns the amount of bytes read
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(read)
+}
+
+/// Single-file BitTorrent metainfo
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::file::example_file;
+/// # use ddp::torrent::Torrent;
+/// # fn main() {
+/// let file = example_file("ddp-torrent-example", 3000);
+///
+/// let torrent = Torrent::from_file(&file).unwrap();
+/// // A file within a single leaf is identified by its SHA-256 digest in both networks
+/// assert_eq!(torrent.pieces_root.as_ref(), Some(&file.metadata.hash.0));
+///
+/// let imported = Torrent::decode(&torrent.encode()).unwrap();
+/// assert_eq!(imported.info_hash_v2(), torrent.info_hash_v2());
+/// assert_eq!(imported.to_metadata().unwrap().hash, file.metadata.hash);
+///
+/// // Deeply nested values are rejected instead of exhausting the stack
+/// assert!(Torrent::decode(&vec![b'l'; 1000000]).is_err());
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Torrent {
+    pub name: String,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Size of every piece in bytes, a power of two of at least 16 KiB
+    pub piece_length: u64,
+    /// SHA-1 hashes of the pieces, empty for torrents that only support v2
+    pub pieces: Vec<Vec<u8>>,
+    /// Root of the SHA-256 merkle tree over the 16 KiB leaves of the file, `None` for torrents that only support v1 and
+    /// for empty files
+    pub pieces_root: Option<Vec<u8>>,
+    /// SHA-256 merkle roots of the pieces, only present for files larger than a single piece
+    pub piece_layer: Vec<Vec<u8>>,
+    /// DDP metadata of the file, only present in torrents exported by DDP
+    pub metadata: Option<MetaFile>,
+    /// Entries of the info dictionary that are not interpreted, kept so the info hashes stay the same
+    extra_info: BTreeMap<Vec<u8>, Value>
+}
+
+impl Torrent {
+    /// Create a torrent from a shared file, its local copy is read to compute the piece hashes and checked against
+    /// the metadata on the way
+    pub fn from_file(file: &File) -> Result<Torrent, String> {
+        let metadata = &file.metadata;
+        // The local copy of an encrypted file differs from the content distributed by DDP
+        if metadata.encryption.is_some() { return Err("Encrypted files can not be exported as torrents".to_string()) }
+        let piece_length = piece_length(metadata.size);
+        let mut reader = F::open(&file.local_path).map_err(|e| format!("Failed to open {}: {}", file.local_path.display(), e))?;
+
+        let mut content = metadata.algorithm.hasher();
+        let mut pieces = Vec::new();
+        let mut piece_layer = Vec::new();
+        let mut leaves = Vec::new();
+        let mut buffer = vec![0; piece_length];
+        let mut size = 0;
+        loop {
+            let read = read_up_to(&mut reader, &mut buffer).map_err(|e| format!("Failed to read {}: {}", file.local_path.display(), e))?;
+            if read == 0 { break }
+            let piece = &buffer[..read];
+            size += read;
+            content.update(piece);
+            pieces.push(Sha1::digest(piece).to_vec());
+            leaves = piece.chunks(LEAF_SIZE).map(|leaf| Sha256::digest(leaf).to_vec()).collect::<Vec<_>>();
+            piece_layer.push(merkle_root(&leaves, piece_length / LEAF_SIZE));
+            if read < piece_length { break }
+        }
+        if size != metadata.size || content.finalize_reset() != metadata.hash.0 {
+            return Err(format!("{} does not match its metadata", file.local_path.display()));
+        }
+
+        let pieces_root = match piece_layer.len() {
+            0 => None,
+            // The tree of a file within a single piece only spans its own leaves
+            1 => Some(merkle_root(&leaves, leaves.len().next_power_of_two())),
+            // Pieces beyond the end of the file consist of zero leaves
+            _ => {
+                let padding = merkle_root(&[], piece_length / LEAF_SIZE);
+                Some(merkle_root_padded(&piece_layer, piece_layer.len().next_power_of_two(), padding))
+            }
+        };
+        Ok(Torrent {
+            name: metadata.name.clone(),
+            size: metadata.size as u64,
+            piece_length: piece_length as u64,
+            pieces: pieces,
+            pieces_root: pieces_root,
+            piece_layer: if piece_layer.len() > 1 { piece_layer } else { Vec::new() },
+            metadata: Some(MetaFile::from_metadata(metadata)),
+            extra_info: BTreeMap::new()
+        })
+    }
+
+    fn info(&self) -> Value {
+        let mut info = self.extra_info.clone();
+        let mut insert = |key: &str, value: Value| { info.insert(key.as_bytes().to_vec(), value); };
+        insert("name", Value::Bytes(self.name.as_bytes().to_vec()));
+        insert("piece length", Value::Integer(self.piece_length as i64));
+        if !self.pieces.is_empty() || self.pieces_root.is_none() {
+            insert("length", Value::Integer(self.size as i64));
+            insert("pieces", Value::Bytes(self.pieces.concat()));
+        }
+        if let Some(ref root) = self.pieces_root {
+            insert("meta version", Value::Integer(2));
+            let entry = dictionary(vec![("length", Value::Integer(self.size as i64)), ("pieces root", Value::Bytes(root.clone()))]);
+            let mut tree = BTreeMap::new();
+            tree.insert(self.name.as_bytes().to_vec(), dictionary(vec![("", entry)]));
+            insert("file tree", Value::Dictionary(tree));
+        }
+        Value::Dictionary(info)
+    }
+
+    /// SHA-1 hash of the info dictionary identifying the torrent in BitTorrent v1, `None` if it only supports v2
+    pub fn info_hash_v1(&self) -> Option<Vec<u8>> {
+        if self.pieces.is_empty() && self.pieces_root.is_some() { return None }
+        Some(Sha1::digest(self.info().to_bytes()).to_vec())
+    }
+
+    /// SHA-256 hash of the info dictionary identifying the torrent in BitTorrent v2, `None` if it only supports v1
+    pub fn info_hash_v2(&self) -> Option<Vec<u8>> {
+        self.pieces_root.as_ref().map(|_| Sha256::digest(self.info().to_bytes()).to_vec())
+    }
+
+    /// Magnet link of the torrent listing the info hashes of all versions it supports
+    pub fn magnet(&self) -> String {
+        let hex = |hash: Vec<u8>| hash.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let mut topics = Vec::new();
+        if let Some(hash) = self.info_hash_v1() { topics.push(format!("xt=urn:btih:{}", hex(hash))); }
+        if let Some(hash) = self.info_hash_v2() { topics.push(format!("xt=urn:btmh:{}{}", MULTIHASH_SHA256, hex(hash))); }
+        let name = self.name.bytes().map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b)
+        }).collect::<String>();
+        format!("magnet:?{}&dn={}", topics.join("&"), name)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entries = vec![
+            ("created by", Value::Bytes(format!("ddp {}", ::VERSION).into_bytes())),
+            ("info", self.info())
+        ];
+        if !self.piece_layer.is_empty() {
+            if let Some(ref root) = self.pieces_root {
+                let mut layers = BTreeMap::new();
+                layers.insert(root.clone(), Value::Bytes(self.piece_layer.concat()));
+                entries.push(("piece layers", Value::Dictionary(layers)));
+            }
+        }
+        if let Some(ref metadata) = self.metadata { entries.push((DDP_KEY, Value::Bytes(metadata.encode()))); }
+        dictionary(entries).to_bytes()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Torrent, String> {
+        let torrent = match Value::decode(data, 0)? {
+            (torrent @ Value::Dictionary(_), _) => torrent,
+            _ => return Err("Not a torrent".to_string())
+        };
+        let mut extra_info = match torrent.get("info") {
+            Some(Value::Dictionary(info)) => info.clone(),
+            _ => return Err("The torrent has no info dictionary".to_string())
+        };
+        let mut take = |key: &str| extra_info.remove(key.as_bytes());
+        let name = take("name").as_ref().and_then(|name| name.bytes().cloned())
+            .and_then(|name| String::from_utf8(name).ok()).ok_or_else(|| "The torrent has no name".to_string())?;
+        let piece_length = take("piece length").and_then(|length| length.integer())
+            .filter(|length| *length >= LEAF_SIZE as i64 && (*length as u64).is_power_of_two())
+            .ok_or_else(|| "Invalid piece length".to_string())? as u64;
+        if take("files").is_some() { return Err("Torrents with several files are not supported".to_string()) }
+        let length = take("length").and_then(|length| length.integer());
+        let pieces = take("pieces").and_then(|pieces| pieces.bytes().cloned());
+        let version = take("meta version").and_then(|version| version.integer());
+        let tree = take("file tree");
+
+        // The file tree of a single file consists of its name mapping to the entry of the file
+        let (size, pieces_root) = match (version, tree) {
+            (Some(2), Some(Value::Dictionary(ref tree))) => {
+                let entry = match tree.get(name.as_bytes()).and_then(|file| file.get("")) {
+                    Some(entry) if tree.len() == 1 => entry,
+                    _ => return Err("Torrents with several files are not supported".to_string())
+                };
+                let size = entry.get("length").and_then(|length| length.integer()).ok_or_else(|| "The file has no length".to_string())?;
+                let root = entry.get("pieces root").and_then(|root| root.bytes()).cloned();
+                if size > 0 && root.as_ref().is_none_or(|root| root.len() != 32) { return Err("Invalid pieces root".to_string()) }
+                if length.is_some_and(|length| length != size) { return Err("The versions of the torrent disagree on the size".to_string()) }
+                (size, root)
+            },
+            (Some(version), _) if version != 1 => return Err(format!("Unsupported torrent version {}", version)),
+            _ => (length.ok_or_else(|| "The file has no length".to_string())?, None)
+        };
+        if size < 0 { return Err("Invalid length".to_string()) }
+        let size = size as u64;
+        let piece_count = size.div_ceil(piece_length) as usize;
+
+        let pieces = match pieces {
+            Some(ref pieces) if pieces.len() == piece_count * 20 => pieces.chunks(20).map(|hash| hash.to_vec()).collect(),
+            Some(_) => return Err("The amount of pieces does not match the length".to_string()),
+            None if pieces_root.is_some() => Vec::new(),
+            None => return Err("The torrent has no pieces".to_string())
+        };
+        let piece_layer = match (torrent.get("piece layers"), pieces_root.as_ref()) {
+            (Some(layers), Some(root)) if piece_count > 1 => match layers.get(root).and_then(|layer| layer.bytes()) {
+                Some(layer) if layer.len() == piece_count * 32 => layer.chunks(32).map(|hash| hash.to_vec()).collect(),
+                _ => return Err("The piece layer does not match the length".to_string())
+            },
+            _ => Vec::new()
+        };
+        let metadata = match torrent.get(DDP_KEY).and_then(|data| data.bytes()) {
+            Some(data) => Some(MetaFile::decode(data)?),
+            None => None
+        };
+        Ok(Torrent {
+            name: name,
+            size: size,
+            piece_length: piece_length,
+            pieces: pieces,
+            pieces_root: pieces_root,
+            piece_layer: piece_layer,
+            metadata: metadata,
+            extra_info: extra_info
+        })
+    }
+
+    /// Turn the embedded DDP metadata into metadata after checking that it describes the same file as the torrent
+    pub fn to_metadata(self) -> Result<FileMetadata, String> {
+        let metadata = match self.metadata {
+            Some(metadata) => metadata,
+            None => return Err("The torrent has not been exported by DDP and carries no block hashes, \
+                download it with a BitTorrent client and share the file instead".to_string())
+        };
+        if metadata.size != self.size || metadata.name != self.name {
+            return Err("The embedded metadata does not match the torrent".to_string());
+        }
+        metadata.to_metadata()
+    }
+
+    /// Read the torrent file at `path`
+    pub fn load(path: &Path) -> Result<Torrent, String> {
+        let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Torrent::decode(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Write the torrent file to `path`
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.encode()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
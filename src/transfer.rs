@@ -0,0 +1,475 @@
+//! Scheduling of downloads, interleaving the blocks of all transfers by their priority
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{spawn, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+use std::cmp::{min, Reverse};
+
+use file::{File, FileHandle};
+use helpers::calculate_block_size;
+use hooks::{Hook, Outcome, TransferReport};
+use history::{History, HistoryEntry, PeerUsage};
+use helpers::to_hex_string;
+use registry::FileRegistry;
+use config::Config;
+use events::{Event, EventChannel};
+use bitfield::BlockSet;
+use peers::NodeId;
+use rate::TransferRate;
+
+/// Time to wait before checking for new transfers when there is nothing to do
+const IDLE_INTERVAL: u64 = 100;
+
+/// Importance of a transfer or a range of blocks, higher priorities are downloaded first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High
+}
+
+/// Limits after which a downloaded file is no longer served, seeding stops as soon as either limit is reached
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::time::Duration;
+/// # use ddp::transfer::SeedPolicy;
+/// # fn main() {
+/// let policy = SeedPolicy::new().ratio(2.0).time(Duration::from_secs(24 * 60 * 60));
+/// assert!(!policy.is_satisfied(1000, 1000, Duration::from_secs(60)));
+/// assert!(policy.is_satisfied(2000, 1000, Duration::from_secs(60)));
+/// assert!(policy.is_satisfied(0, 1000, Duration::from_secs(24 * 60 * 60)));
+/// assert!(!SeedPolicy::new().is_satisfied(1000000, 1000, Duration::from_secs(1000000)));
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeedPolicy {
+    /// Stop once this multiple of the file size has been uploaded
+    pub ratio: Option<f64>,
+    /// Stop once the file has been seeded for this long
+    pub time: Option<Duration>
+}
+
+impl Default for SeedPolicy {
+    fn default() -> SeedPolicy {
+        SeedPolicy::new()
+    }
+}
+
+impl SeedPolicy {
+    /// Creates a policy without any limits, seeding until the node is stopped
+    pub fn new() -> SeedPolicy {
+        SeedPolicy {
+            ratio: None,
+            time: None
+        }
+    }
+
+    /// Stop seeding once this multiple of the file size has been uploaded
+    pub fn ratio(mut self, ratio: f64) -> SeedPolicy {
+        self.ratio = Some(ratio);
+        self
+    }
+
+    /// Stop seeding after the given time
+    pub fn time(mut self, time: Duration) -> SeedPolicy {
+        self.time = Some(time);
+        self
+    }
+
+    /// Whether seeding a file of `size` bytes may stop after uploading `uploaded` bytes within `elapsed`
+    pub fn is_satisfied(&self, uploaded: usize, size: usize, elapsed: Duration) -> bool {
+        self.ratio.is_some_and(|ratio| uploaded as f64 >= ratio * size as f64) ||
+            self.time.is_some_and(|time| elapsed >= time)
+    }
+}
+
+/// Progress of a transfer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TransferState {
+    /// Blocks are still being downloaded
+    Downloading,
+    /// No new blocks are requested until the transfer is resumed
+    Paused,
+    /// The file has been downloaded and verified and is served until the seed policy is satisfied
+    Seeding,
+    /// All blocks have been downloaded, the file has been verified and seeding has stopped
+    Complete,
+    /// Some blocks could not be retrieved from any source
+    Incomplete
+}
+
+/// A download managed by the `TransferManager`
+pub struct Transfer {
+    /// Hash of the downloaded file
+    pub hash: Vec<u8>,
+    pub handle: Arc<Mutex<FileHandle>>,
+    pub priority: Priority,
+    pub state: TransferState,
+    /// Policy overriding the global one of the node
+    pub seed_policy: Option<SeedPolicy>,
+    /// Time at which seeding started
+    pub seeding_since: Option<Instant>,
+    /// Hooks run in addition to the global ones once the download has finished
+    pub hooks: Vec<Hook>,
+    /// Time at which the transfer has been queued
+    pub started: Instant
+}
+
+/// Progress and rates of a transfer, e.g. for the `status` command
+#[derive(Debug, Clone)]
+pub struct TransferStatus {
+    pub hash: Vec<u8>,
+    pub name: String,
+    pub state: TransferState,
+    /// Amount of downloaded bytes
+    pub downloaded: usize,
+    pub size: usize,
+    pub rate: TransferRate,
+    /// Current rates of the sources, the fastest first
+    pub sources: Vec<(NodeId, f64)>
+}
+
+/// Queue of all downloads of a node, cloning it yields another handle to the same queue
+#[derive(Clone)]
+pub struct TransferManager {
+    transfers: Arc<Mutex<Vec<Transfer>>>,
+    /// Threads running the hooks of finished transfers
+    hook_threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// Channel the transfers and changes of their state are published to
+    events: EventChannel
+}
+
+/// Amount of bytes of a transfer in the given state that have been downloaded
+fn downloaded(handle: &FileHandle, state: TransferState) -> usize {
+    let size = handle.file.lock().unwrap().metadata.size;
+    match state {
+        TransferState::Seeding | TransferState::Complete => size,
+        _ => min(handle.completed.iter().filter(|c| **c).count() * calculate_block_size(size), size)
+    }
+}
+
+/// Event introducing a transfer with the blocks of `handle` that are complete
+fn transfer_event(hash: &[u8], handle: &FileHandle, state: TransferState) -> Event {
+    let metadata = handle.file.lock().unwrap().metadata.clone();
+    let completed = (0..handle.completed.len()).filter(|id| handle.completed[*id]).collect::<Vec<_>>();
+    Event::Transfer {
+        hash: to_hex_string(hash),
+        name: metadata.name,
+        size: metadata.size,
+        blocks: handle.completed.len(),
+        completed: BlockSet::encode(&completed),
+        state: state
+    }
+}
+
+impl TransferManager {
+    pub fn new(events: EventChannel) -> TransferManager {
+        TransferManager {
+            transfers: Arc::new(Mutex::new(Vec::new())),
+            hook_threads: Arc::new(Mutex::new(Vec::new())),
+            events: events
+        }
+    }
+
+    /// Queue a download with the given priority and return a handle to it
+    pub fn add(&self, handle: FileHandle, priority: Priority) -> Arc<Mutex<FileHandle>> {
+        let hash = handle.file.lock().unwrap().metadata.hash.0.clone();
+        self.events.publish(|| transfer_event(&hash, &handle, TransferState::Downloading));
+        let handle = Arc::new(Mutex::new(handle));
+        self.transfers.lock().unwrap().push(Transfer {
+            hash: hash,
+            handle: handle.clone(),
+            priority: priority,
+            state: TransferState::Downloading,
+            seed_policy: None,
+            seeding_since: None,
+            hooks: Vec::new(),
+            started: Instant::now()
+        });
+        handle
+    }
+
+    /// Change the priority of the transfer of a file, returns false if there is no such transfer
+    pub fn set_priority(&self, hash: &Vec<u8>, priority: Priority) -> bool {
+        match self.transfers.lock().unwrap().iter_mut().find(|t| &t.hash == hash) {
+            Some(transfer) => { transfer.priority = priority; true },
+            None => false
+        }
+    }
+
+    /// Change the seed policy of the transfer of a file, returns false if there is no such transfer
+    pub fn set_seed_policy(&self, hash: &Vec<u8>, policy: SeedPolicy) -> bool {
+        match self.transfers.lock().unwrap().iter_mut().find(|t| &t.hash == hash) {
+            Some(transfer) => { transfer.seed_policy = Some(policy); true },
+            None => false
+        }
+    }
+
+    /// Run a hook once the transfer of a file has finished, returns false if there is no such transfer
+    pub fn add_hook(&self, hash: &Vec<u8>, hook: Hook) -> bool {
+        match self.transfers.lock().unwrap().iter_mut().find(|t| &t.hash == hash) {
+            Some(transfer) => { transfer.hooks.push(hook); true },
+            None => false
+        }
+    }
+
+    /// Stop requesting new blocks for a file, optionally stop serving it as well. Returns false if there is no such
+    /// transfer or it is not downloading
+    pub fn pause(&self, hash: &Vec<u8>, uploads: bool) -> bool {
+        match self.transfers.lock().unwrap().iter_mut().find(|t| &t.hash == hash && t.state == TransferState::Downloading) {
+            Some(transfer) => {
+                transfer.handle.lock().unwrap().pause(uploads);
+                transfer.state = TransferState::Paused;
+                self.publish_state(&transfer.hash, transfer.state);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Continue a paused transfer, returns false if there is no such transfer or it is not paused
+    pub fn resume(&self, hash: &Vec<u8>) -> bool {
+        match self.transfers.lock().unwrap().iter_mut().find(|t| &t.hash == hash && t.state == TransferState::Paused) {
+            Some(transfer) => {
+                transfer.handle.lock().unwrap().resume();
+                transfer.state = TransferState::Downloading;
+                self.publish_state(&transfer.hash, transfer.state);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Retrieve the state of the transfer of a file
+    pub fn state(&self, hash: &Vec<u8>) -> Option<TransferState> {
+        self.transfers.lock().unwrap().iter().find(|t| &t.hash == hash).map(|t| t.state)
+    }
+
+    /// Retrieve the amount of downloaded bytes and the size of the file of a transfer
+    pub fn progress(&self, hash: &Vec<u8>) -> Option<(usize, usize)> {
+        let (handle, state) = match self.transfers.lock().unwrap().iter().find(|t| &t.hash == hash) {
+            Some(transfer) => (transfer.handle.clone(), transfer.state),
+            None => return None
+        };
+        // The handle is locked while blocks are downloaded so the queue must not be locked while waiting for it
+        let handle = handle.lock().unwrap();
+        let size = handle.file.lock().unwrap().metadata.size;
+        Some((downloaded(&handle, state), size))
+    }
+
+    /// Progress and rates of all transfers
+    pub fn status(&self) -> Vec<TransferStatus> {
+        let transfers = self.transfers.lock().unwrap().iter().map(|t| (t.hash.clone(), t.handle.clone(), t.state)).collect::<Vec<_>>();
+        // The handles are locked while blocks are downloaded so the queue must not be locked while waiting for them
+        transfers.into_iter().map(|(hash, handle, state)| {
+            let handle = handle.lock().unwrap();
+            let (name, size) = {
+                let file = handle.file.lock().unwrap();
+                (file.metadata.name.clone(), file.metadata.size)
+            };
+            let (mut rate, sources) = handle.rates();
+            if state == TransferState::Seeding || state == TransferState::Complete { rate.eta = Some(0); }
+            TransferStatus {
+                hash: hash,
+                name: name,
+                state: state,
+                downloaded: downloaded(&handle, state),
+                size: size,
+                rate: rate,
+                sources: sources
+            }
+        }).collect()
+    }
+
+    /// Events introducing all transfers with the blocks that are complete so far, e.g. for a new subscriber of the events
+    pub fn snapshot(&self) -> Vec<Event> {
+        let transfers = self.transfers.lock().unwrap().iter().map(|t| (t.hash.clone(), t.handle.clone(), t.state)).collect::<Vec<_>>();
+        // The handles are locked while blocks are downloaded so the queue must not be locked while waiting for them
+        transfers.iter().map(|&(ref hash, ref handle, state)| transfer_event(hash, &handle.lock().unwrap(), state)).collect()
+    }
+
+    fn publish_state(&self, hash: &[u8], state: TransferState) {
+        self.events.publish(|| Event::State { hash: to_hex_string(hash), state: state });
+    }
+
+    /// Block until the download of a file has finished or failed and return the resulting state
+    pub fn wait(&self, hash: &Vec<u8>) -> Option<TransferState> {
+        loop {
+            match self.state(hash) {
+                Some(TransferState::Downloading) | Some(TransferState::Paused) => sleep(Duration::from_millis(IDLE_INTERVAL)),
+                state => return state
+            }
+        }
+    }
+
+    /// Block until the hooks of all finished transfers have run
+    pub fn wait_for_hooks(&self) {
+        let threads = self.hook_threads.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for thread in threads { let _ = thread.join(); }
+    }
+
+    /// Pick the transfer with the highest priority that is still downloading
+    /// Transfers with equal priority take turns since the picked one is moved to the back of the queue
+    fn next(&self) -> Option<Arc<Mutex<FileHandle>>> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let index = transfers.iter().enumerate()
+            .filter(|&(_, t)| t.state == TransferState::Downloading)
+            .max_by(|&(a_id, a), &(b_id, b)| a.priority.cmp(&b.priority).then(b_id.cmp(&a_id)))
+            .map(|(id, _)| id);
+        index.map(|index| {
+            let transfer = transfers.remove(index);
+            let handle = transfer.handle.clone();
+            transfers.push(transfer);
+            handle
+        })
+    }
+
+    fn set_state(&self, handle: &Arc<Mutex<FileHandle>>, state: TransferState) {
+        if let Some(transfer) = self.transfers.lock().unwrap().iter_mut().find(|t| Arc::ptr_eq(&t.handle, handle)) {
+            transfer.state = state;
+            self.publish_state(&transfer.hash, state);
+        }
+    }
+
+    /// Run the global and per-transfer hooks in the background so slow hooks do not stall other downloads
+    fn run_hooks(&self, handle: &Arc<Mutex<FileHandle>>, outcome: Outcome, global: &[Hook]) {
+        let (hash, hooks, started) = match self.transfers.lock().unwrap().iter().find(|t| Arc::ptr_eq(&t.handle, handle)) {
+            Some(transfer) => {
+                let hooks = global.iter().chain(transfer.hooks.iter()).cloned().collect::<Vec<_>>();
+                (transfer.hash.clone(), hooks, transfer.started)
+            },
+            None => return
+        };
+        if hooks.is_empty() { return }
+        // The handle is locked while blocks are downloaded so the queue must not be locked while waiting for it
+        let report = {
+            let handle = handle.lock().unwrap();
+            let file = handle.file.lock().unwrap();
+            (hooks, TransferReport::new(outcome, &hash, file.local_path.clone(), file.metadata.size, started.elapsed()))
+        };
+        let thread = spawn(move || {
+            let (hooks, report) = report;
+            for hook in hooks.iter() {
+                if let Err(e) = hook.run(&report) { warn!("Hook failed: {}", e); }
+            }
+        });
+        let mut threads = self.hook_threads.lock().unwrap();
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(thread);
+    }
+
+    /// Append a finished transfer along with what its sources contributed to the history
+    fn record_history(&self, handle: &Arc<Mutex<FileHandle>>, outcome: Outcome, history: &History) {
+        let (hash, started) = match self.transfers.lock().unwrap().iter().find(|t| Arc::ptr_eq(&t.handle, handle)) {
+            Some(transfer) => (transfer.hash.clone(), transfer.started),
+            None => return
+        };
+        // The handle is locked while blocks are downloaded so the queue must not be locked while waiting for it
+        let entry = {
+            let handle = handle.lock().unwrap();
+            let file = handle.file.lock().unwrap();
+            let duration = started.elapsed();
+            let path = file.local_path.canonicalize().unwrap_or_else(|_| file.local_path.clone());
+            let mut entry = HistoryEntry::new(to_hex_string(&hash), file.metadata.name.clone(), path,
+                file.metadata.size as u64, outcome, duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9);
+            let peers = handle.peers.lock().unwrap();
+            entry.peers = handle.usage.iter().map(|(node_id, usage)| PeerUsage {
+                node_id: to_hex_string(node_id),
+                blocks: usage.blocks,
+                bytes: usage.bytes as u64,
+                timeouts: usage.timeouts,
+                throughput: peers.stats(node_id).map_or(0.0, |stats| stats.throughput)
+            }).collect();
+            entry.peers.sort_by_key(|peer| Reverse(peer.bytes));
+            entry
+        };
+        if let Err(e) = history.record(&entry) { warn!("Failed to record the download in the history: {}", e); }
+    }
+
+    /// Serve a downloaded file alongside the shared `files` until the seed policy of its transfer is satisfied
+    fn start_seeding(&self, handle: &Arc<Mutex<FileHandle>>, files: &FileRegistry) {
+        {
+            let handle = handle.lock().unwrap();
+            let file = handle.file.lock().unwrap();
+            let mut seed = File::from_local(file.metadata.clone(), file.local_path.clone());
+            seed.key = file.key.clone();
+            seed.storage = file.storage.clone();
+            // The file may be shared from another local copy already
+            if !files.insert(seed) { debug!("{} is shared already, not seeding the download", file.metadata.name); }
+        }
+        if let Some(transfer) = self.transfers.lock().unwrap().iter_mut().find(|t| Arc::ptr_eq(&t.handle, handle)) {
+            transfer.state = TransferState::Seeding;
+            transfer.seeding_since = Some(Instant::now());
+            self.publish_state(&transfer.hash, transfer.state);
+        }
+    }
+
+    /// Stop serving the downloaded files whose seed policy is satisfied
+    fn enforce_seed_policies(&self, files: &FileRegistry, default: SeedPolicy) {
+        let seeding = self.transfers.lock().unwrap().iter().filter(|t| t.state == TransferState::Seeding)
+            .map(|t| (t.hash.clone(), t.handle.clone(), t.seeding_since, t.seed_policy)).collect::<Vec<_>>();
+        // The handles are locked while blocks are downloaded so the queue must not be locked while waiting for them
+        for (hash, handle, seeding_since, policy) in seeding {
+            let path = handle.lock().unwrap().file.lock().unwrap().local_path.clone();
+            // Only the copy seeded by the transfer is removed, not one shared from elsewhere
+            let (uploaded, size) = match files.get(&hash) {
+                Some(file) => {
+                    let file = file.read().unwrap();
+                    if file.local_path != path { continue }
+                    (file.uploaded, file.metadata.size)
+                },
+                None => continue
+            };
+            let elapsed = seeding_since.map_or(Duration::from_secs(0), |since| since.elapsed());
+            if !policy.unwrap_or(default).is_satisfied(uploaded, size, elapsed) { continue }
+            // The transfer may have been removed or stopped seeding in the meantime
+            let mut transfers = self.transfers.lock().unwrap();
+            if let Some(transfer) = transfers.iter_mut().find(|t| Arc::ptr_eq(&t.handle, &handle) && t.state == TransferState::Seeding) {
+                files.remove(&hash);
+                info!("Stopped seeding {} after uploading {} bytes", path.display(), uploaded);
+                transfer.state = TransferState::Complete;
+                self.publish_state(&transfer.hash, transfer.state);
+            }
+        }
+    }
+
+    /// Start the thread that downloads the queued transfers one block at a time and seeds finished downloads to the
+    /// shared `files` according to their seed policy or the default one of `config`. The global hooks of `config` are
+    /// run for every finished transfer.
+    pub fn start(&self, files: FileRegistry, config: Arc<RwLock<Config>>) -> JoinHandle<()> {
+        let manager = self.clone();
+        spawn(move || {
+            loop {
+                match manager.next() {
+                    Some(handle) => {
+                        let finished = {
+                            let mut file = handle.lock().unwrap();
+                            if file.paused || file.download_block() { None } else { Some(file.finish()) }
+                        };
+                        if let Some(completed) = finished {
+                            let outcome = if completed { Outcome::Completed } else { Outcome::Failed };
+                            let (hooks, history, serves) = {
+                                let config = config.read().unwrap();
+                                (config.hooks.clone(), config.state_dir.as_ref().map(|dir| History::new(dir)), config.mode.serves())
+                            };
+                            if let Some(ref history) = history { manager.record_history(&handle, outcome, history); }
+                            manager.run_hooks(&handle, outcome, &hooks);
+                            if completed && serves {
+                                manager.start_seeding(&handle, &files);
+                            } else if completed {
+                                manager.set_state(&handle, TransferState::Complete);
+                            } else {
+                                manager.set_state(&handle, TransferState::Incomplete);
+                            }
+                        }
+                    },
+                    None => sleep(Duration::from_millis(IDLE_INTERVAL))
+                }
+                let default = config.read().unwrap().seed_policy;
+                manager.enforce_seed_policies(&files, default);
+            }
+        })
+    }
+}
@@ -0,0 +1,265 @@
+//! Live view of the downloads of a running node for `ddp tui`
+//!
+//! The view is built from the events the node streams through its control socket: every download gets a bar with a
+//! cell per range of blocks, shaded by how many of them are complete, and the sources it receives blocks from along
+//! with their current rates. The most recent log messages of the node fill the rest of the screen. The screen is drawn
+//! with plain ANSI escape sequences, so it works in every terminal that understands them.
+use std::cmp::{min, max};
+use std::collections::{HashMap, VecDeque};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use events::Event;
+use transfer::TransferState;
+use helpers::format_bytes;
+use rate::TransferRate;
+
+/// Amount of log messages kept for display
+const MAX_LOG_LINES: usize = 200;
+/// Minimum amount of lines reserved for log messages
+const MIN_LOG_LINES: usize = 5;
+/// Amount of sources listed per download, the fastest first
+const MAX_LISTED_SOURCES: usize = 4;
+/// Time in seconds over which the rates of the sources are averaged
+const RATE_WINDOW: u64 = 5;
+/// Time in seconds for which the rates reported by the node are shown, they are only reported while blocks arrive
+const REPORT_VALIDITY: u64 = 3;
+/// Cells of a progress bar by the share of their blocks that are complete
+const SHADES: [char; 5] = [' ', '░', '▒', '▓', '█'];
+/// Size of the screen if the terminal can not tell
+const DEFAULT_SIZE: (usize, usize) = (80, 24);
+
+/// Switch to the alternate screen and hide the cursor
+pub const ENTER_SCREEN: &'static str = "\x1b[?1049h\x1b[?25l";
+/// Show the cursor and return to the screen the view has been started from
+pub const LEAVE_SCREEN: &'static str = "\x1b[?25h\x1b[?1049l";
+
+/// Blocks a source delivered to a download
+struct SourceView {
+    blocks: usize,
+    bytes: usize,
+    /// Time at which the view saw the first block of the source
+    first: Instant,
+    /// Time and size of the blocks received within the rate window
+    recent: VecDeque<(Instant, usize)>
+}
+
+impl SourceView {
+    /// Bytes per second received within the rate window, which drops as the source stalls
+    fn rate(&self) -> f64 {
+        let window = Duration::from_secs(RATE_WINDOW);
+        let bytes = self.recent.iter().filter(|&&(time, _)| time.elapsed() <= window).map(|&(_, bytes)| bytes).sum::<usize>();
+        // Sources seen for less than the window would appear slower than they are
+        let span = min(max(self.first.elapsed(), Duration::from_secs(1)), window);
+        bytes as f64 / (span.as_secs() as f64 + span.subsec_nanos() as f64 / 1e9)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while self.recent.front().is_some_and(|&(time, _)| now.duration_since(time) > Duration::from_secs(RATE_WINDOW)) {
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// A download as shown on the screen
+struct TransferView {
+    hash: String,
+    name: String,
+    size: usize,
+    completed: Vec<bool>,
+    state: TransferState,
+    /// Sources by their hex encoded ID, blocks taken from local files are listed without one
+    sources: HashMap<Option<String>, SourceView>,
+    /// Rates last reported by the node along with the time they arrived
+    reported: Option<(Instant, TransferRate)>
+}
+
+impl TransferView {
+    fn downloaded(&self) -> usize {
+        let blocks = self.completed.len().max(1);
+        let complete = self.completed.iter().filter(|c| **c).count();
+        if complete == blocks { self.size } else { self.size / blocks * complete }
+    }
+
+    /// Rates reported by the node unless they are outdated
+    fn reported(&self) -> Option<TransferRate> {
+        self.reported.and_then(|(time, rate)| if time.elapsed() < Duration::from_secs(REPORT_VALIDITY) { Some(rate) } else { None })
+    }
+
+    fn rate(&self) -> f64 {
+        self.reported().map_or_else(|| self.sources.values().map(|source| source.rate()).sum(), |rate| rate.current)
+    }
+
+    /// Estimated time remaining, only known while the download receives blocks
+    fn eta(&self) -> String {
+        match self.reported().and_then(|rate| rate.eta) {
+            Some(eta) if self.state == TransferState::Downloading => format!("  {} left", format_seconds(eta)),
+            _ => String::new()
+        }
+    }
+
+    /// Bar of `width` cells that are shaded by the share of complete blocks in the range they stand for
+    fn bar(&self, width: usize) -> String {
+        let blocks = self.completed.len();
+        if blocks == 0 || width == 0 { return String::new() }
+        (0..width).map(|cell| {
+            let start = cell * blocks / width;
+            let end = ((cell + 1) * blocks / width).max(start + 1).min(blocks);
+            let complete = self.completed[start..end].iter().filter(|c| **c).count();
+            SHADES[complete * (SHADES.len() - 1) / (end - start)]
+        }).collect()
+    }
+}
+
+/// What is shown on the screen, updated with every event
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::bitfield::BlockSet;
+/// # use ddp::events::Event;
+/// # use ddp::rate::TransferRate;
+/// # use ddp::transfer::TransferState;
+/// # use ddp::tui::Dashboard;
+/// # fn main() {
+/// let mut dashboard = Dashboard::new();
+/// dashboard.apply(Event::Transfer {
+///     hash: "AA".to_string(), name: "image.iso".to_string(), size: 4096, blocks: 4,
+///     completed: BlockSet::encode(&[0, 1]), state: TransferState::Downloading
+/// });
+/// dashboard.apply(Event::Block { hash: "AA".to_string(), block: 2, source: Some("BB".to_string()), bytes: 1024 });
+///
+/// let screen = dashboard.render(42, 12);
+/// assert!(screen[1].starts_with("Downloading  75.0%"));
+/// assert_eq!(screen[2], format!("[{}{}]", "█".repeat(30), " ".repeat(10)));
+/// assert!(screen[3].contains("BB"));
+///
+/// // The node reports the rates while blocks arrive
+/// dashboard.apply(Event::Rate { hash: "AA".to_string(), rate: TransferRate { current: 1024.0, average: 512.0, eta: Some(75) }, sources: vec![] });
+/// assert!(dashboard.render(80, 12)[1].contains("1.0 KiB/s  1m 15s left"));
+/// # }
+/// ```
+pub struct Dashboard {
+    transfers: Vec<TransferView>,
+    log: VecDeque<String>
+}
+
+impl Default for Dashboard {
+    fn default() -> Dashboard {
+        Dashboard::new()
+    }
+}
+
+impl Dashboard {
+    pub fn new() -> Dashboard {
+        Dashboard {
+            transfers: Vec::new(),
+            log: VecDeque::new()
+        }
+    }
+
+    /// Update the view with an event of the node
+    pub fn apply(&mut self, event: Event) {
+        let now = Instant::now();
+        match event {
+            Event::Transfer { hash, name, size, blocks, completed, state } => {
+                let mut view = TransferView {
+                    hash: hash,
+                    name: name,
+                    size: size,
+                    completed: vec![false; blocks],
+                    state: state,
+                    sources: HashMap::new(),
+                    reported: None
+                };
+                for id in completed.ids_within(blocks) { view.completed[id] = true; }
+                // The transfers are introduced again when the view reconnects
+                match self.transfers.iter().position(|transfer| transfer.hash == view.hash) {
+                    Some(index) => self.transfers[index] = view,
+                    None => self.transfers.push(view)
+                }
+            },
+            Event::Block { hash, block, source, bytes } => {
+                if let Some(transfer) = self.transfers.iter_mut().find(|transfer| transfer.hash == hash) {
+                    if block < transfer.completed.len() { transfer.completed[block] = true; }
+                    let source = transfer.sources.entry(source).or_insert_with(|| SourceView { blocks: 0, bytes: 0, first: now, recent: VecDeque::new() });
+                    source.blocks += 1;
+                    source.bytes += bytes;
+                    source.recent.push_back((now, bytes));
+                }
+            },
+            Event::Rate { hash, rate, .. } => {
+                if let Some(transfer) = self.transfers.iter_mut().find(|transfer| transfer.hash == hash) {
+                    transfer.reported = Some((now, rate));
+                }
+            },
+            Event::State { hash, state } => {
+                if let Some(transfer) = self.transfers.iter_mut().find(|transfer| transfer.hash == hash) {
+                    transfer.state = state;
+                    if state == TransferState::Seeding || state == TransferState::Complete {
+                        for completed in transfer.completed.iter_mut() { *completed = true; }
+                    }
+                }
+            },
+            Event::Log { level, message } => {
+                self.log.push_back(format!("{:>7} {}", level, message));
+                if self.log.len() > MAX_LOG_LINES { self.log.pop_front(); }
+            }
+        }
+        for source in self.transfers.iter_mut().flat_map(|transfer| transfer.sources.values_mut()) { source.expire(now); }
+    }
+
+    /// Lines of a screen of `width` columns and `height` rows
+    pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let downloading = self.transfers.iter().filter(|t| t.state == TransferState::Downloading).count();
+        let mut lines = vec![format!("ddp: {} transfers, {} downloading at {}/s", self.transfers.len(), downloading,
+            format_bytes(self.transfers.iter().map(|t| t.rate()).sum()))];
+
+        for transfer in self.transfers.iter() {
+            let percent = if transfer.size > 0 { transfer.downloaded() as f64 * 100.0 / transfer.size as f64 } else { 100.0 };
+            lines.push(format!("{:<11} {:>5.1}%  {} of {}  {}/s{}  {}", format!("{:?}", transfer.state), percent,
+                format_bytes(transfer.downloaded() as f64), format_bytes(transfer.size as f64), format_bytes(transfer.rate()), transfer.eta(),
+                transfer.name));
+            lines.push(format!("[{}]", transfer.bar(width.saturating_sub(2))));
+            let mut sources = transfer.sources.iter().collect::<Vec<_>>();
+            sources.sort_by(|a, b| b.1.rate().partial_cmp(&a.1.rate()).unwrap_or(::std::cmp::Ordering::Equal));
+            for (id, source) in sources.into_iter().take(MAX_LISTED_SOURCES) {
+                let id = id.as_ref().map_or("local files".to_string(), |id| id.chars().take(16).collect());
+                lines.push(format!("  {:<16} {:>11}/s  {} blocks, {}", id, format_bytes(source.rate()), source.blocks, format_bytes(source.bytes as f64)));
+            }
+        }
+
+        // Transfers that do not fit are cut off, the log fills the rest of the screen
+        lines.truncate(height.saturating_sub(MIN_LOG_LINES + 1).max(1));
+        lines.push("Log".to_string());
+        let shown = height.saturating_sub(lines.len());
+        lines.extend(self.log.iter().skip(self.log.len().saturating_sub(shown)).cloned());
+        lines.truncate(height);
+        lines.into_iter().map(|line| line.chars().take(width).collect()).collect()
+    }
+}
+
+/// Format seconds like `1h 02m`, `3m 05s` or `12s`
+fn format_seconds(seconds: u64) -> String {
+    match seconds {
+        s if s >= 3600 => format!("{}h {:02}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m {:02}s", s / 60, s % 60),
+        s => format!("{}s", s)
+    }
+}
+
+/// Columns and rows of the terminal, looked up with `stty` so it should only be called again once the terminal has been
+/// resized
+pub fn terminal_size() -> (usize, usize) {
+    let output = Command::new("stty").arg("size").stdin(Stdio::inherit()).stderr(Stdio::null()).output();
+    let size = output.ok().and_then(|output| {
+        let output = String::from_utf8_lossy(&output.stdout).to_string();
+        let mut parts = output.split_whitespace().map(|part| part.parse::<usize>().ok());
+        match (parts.next(), parts.next()) {
+            (Some(Some(rows)), Some(Some(columns))) if rows > 0 && columns > 0 => Some((columns, rows)),
+            _ => None
+        }
+    });
+    size.unwrap_or(DEFAULT_SIZE)
+}
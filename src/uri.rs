@@ -0,0 +1,126 @@
+//! `ddp://` links describing a file along with hints on where to find it
+//!
+//! A link looks like `ddp://<hash>?size=<bytes>&name=<name>&peer=<ip:port>&tracker=<address>` where every parameter is
+//! optional and `peer` and `tracker` may be repeated.
+use std::fmt;
+use std::str::FromStr;
+use std::net::SocketAddr;
+
+use helpers::{to_hex_string, from_hex_string};
+
+/// Scheme prefix of all links
+pub const SCHEME: &'static str = "ddp://";
+
+/// A reference to a file that can be shared as text
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use ddp::uri::Link;
+/// # fn main() {
+/// let mut link = Link::new(vec![0xAB, 0xCD]);
+/// link.size = Some(1337);
+/// link.name = Some("release notes & changes.txt".to_string());
+/// link.peers.push("10.0.0.1:8888".parse().unwrap());
+///
+/// let text = link.to_string();
+/// assert_eq!(text, "ddp://ABCD?size=1337&name=release%20notes%20%26%20changes.txt&peer=10.0.0.1%3A8888");
+/// assert_eq!(text.parse::<Link>().unwrap(), link);
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    /// Content hash of the file
+    pub hash: Vec<u8>,
+    /// Size of the file in bytes
+    pub size: Option<usize>,
+    /// Name to display and to save the file as
+    pub name: Option<String>,
+    /// Nodes that are likely to have the file and are queried directly
+    pub peers: Vec<SocketAddr>,
+    /// Addresses of trackers that know about the file
+    pub trackers: Vec<String>
+}
+
+impl Link {
+    /// Create a link that only contains the hash
+    pub fn new(hash: Vec<u8>) -> Link {
+        Link {
+            hash: hash,
+            size: None,
+            name: None,
+            peers: Vec::new(),
+            trackers: Vec::new()
+        }
+    }
+}
+
+/// Percent-encode everything except unreserved characters
+fn encode(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
+}
+
+fn decode(value: &str) -> Result<String, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let byte = value.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match byte {
+                Some(byte) => decoded.push(byte),
+                None => return Err(format!("Invalid escape sequence in '{}'", value))
+            }
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format!("Invalid UTF-8 in '{}'", value))
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut params = Vec::new();
+        if let Some(size) = self.size { params.push(format!("size={}", size)); }
+        if let Some(ref name) = self.name { params.push(format!("name={}", encode(name))); }
+        for peer in self.peers.iter() { params.push(format!("peer={}", encode(&peer.to_string()))); }
+        for tracker in self.trackers.iter() { params.push(format!("tracker={}", encode(tracker))); }
+
+        write!(f, "{}{}", SCHEME, to_hex_string(&self.hash).replace("-", ""))?;
+        if !params.is_empty() { write!(f, "?{}", params.join("&"))?; }
+        Ok(())
+    }
+}
+
+impl FromStr for Link {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Link, String> {
+        if !s.starts_with(SCHEME) { return Err(format!("Links have to start with {}", SCHEME)) }
+        let mut parts = s[SCHEME.len()..].splitn(2, '?');
+        let hash = parts.next().and_then(from_hex_string).ok_or("Invalid hash".to_string())?;
+        let mut link = Link::new(hash);
+
+        for param in parts.next().unwrap_or("").split('&').filter(|p| !p.is_empty()) {
+            let mut pair = param.splitn(2, '=');
+            let key = pair.next().unwrap_or("");
+            let value = decode(pair.next().unwrap_or(""))?;
+            match key {
+                "size" => link.size = Some(value.parse().map_err(|_| format!("Invalid size '{}'", value))?),
+                "name" => link.name = Some(value),
+                "peer" => link.peers.push(value.parse().map_err(|_| format!("Invalid peer address '{}'", value))?),
+                "tracker" => link.trackers.push(value),
+                // Ignore unknown parameters so newer links remain usable
+                _ => {}
+            }
+        }
+
+        Ok(link)
+    }
+}
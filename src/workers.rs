@@ -0,0 +1,136 @@
+//! Bounded pool of worker threads that send the responses of the announce listener
+//!
+//! Sending a response may block, e.g. while connecting to a node to push metadata to it. The announce listener hands
+//! the responses to a pool instead, so it keeps draining its queue of datagrams. Replies that are cheap to send are
+//! dispatched before slow ones, and the queued jobs of a priority are taken from the sources that queued them in turn
+//! so a single busy source can not delay everybody else.
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread::spawn;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Maximum amount of jobs of every source waiting for a worker
+const MAX_QUEUED_PER_SOURCE: usize = 16;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Order in which queued jobs are dispatched, all high priority jobs go first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobPriority {
+    High,
+    Low
+}
+
+/// Jobs of one priority, taken from the sources in turn
+struct FairQueue {
+    jobs: HashMap<IpAddr, VecDeque<Job>>,
+    /// Sources with queued jobs in the order they are served next
+    turns: VecDeque<IpAddr>
+}
+
+impl FairQueue {
+    fn new() -> FairQueue {
+        FairQueue {
+            jobs: HashMap::new(),
+            turns: VecDeque::new()
+        }
+    }
+
+    fn queued(&self, source: &IpAddr) -> usize {
+        self.jobs.get(source).map_or(0, |jobs| jobs.len())
+    }
+
+    fn push(&mut self, source: IpAddr, job: Job) {
+        let jobs = self.jobs.entry(source).or_default();
+        if jobs.is_empty() { self.turns.push_back(source); }
+        jobs.push_back(job);
+    }
+
+    fn pop(&mut self) -> Option<Job> {
+        let source = self.turns.pop_front()?;
+        let (job, remaining) = {
+            let jobs = self.jobs.get_mut(&source)?;
+            (jobs.pop_front(), jobs.len())
+        };
+        if remaining > 0 { self.turns.push_back(source); } else { self.jobs.remove(&source); }
+        job
+    }
+}
+
+struct Queue {
+    high: FairQueue,
+    low: FairQueue,
+    /// Amount of jobs of both priorities
+    len: usize
+}
+
+/// Pool of threads that run jobs in the order of their priority and fairly among their sources. Cloning it yields
+/// another handle to the same pool.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate ddp;
+/// # use std::sync::mpsc;
+/// # use ddp::workers::{WorkerPool, JobPriority};
+/// # fn main() {
+/// let pool = WorkerPool::new(2, 8);
+/// let (tx, rx) = mpsc::channel();
+/// for i in 0..4 {
+///     let tx = tx.clone();
+///     assert!(pool.submit(JobPriority::High, "10.0.0.1".parse().unwrap(), move || tx.send(i).unwrap()));
+/// }
+/// let mut done = rx.iter().take(4).collect::<Vec<_>>();
+/// done.sort();
+/// assert_eq!(done, vec![0, 1, 2, 3]);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct WorkerPool {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    capacity: usize
+}
+
+impl WorkerPool {
+    /// Start `workers` threads that take jobs from a queue holding at most `capacity` of them
+    pub fn new(workers: usize, capacity: usize) -> WorkerPool {
+        let pool = WorkerPool {
+            queue: Arc::new((Mutex::new(Queue { high: FairQueue::new(), low: FairQueue::new(), len: 0 }), Condvar::new())),
+            capacity: capacity
+        };
+        for _ in 0..workers.max(1) {
+            let queue = pool.queue.clone();
+            spawn(move || loop {
+                let job = {
+                    let (lock, available) = &*queue;
+                    let mut queue = lock.lock().unwrap();
+                    loop {
+                        let job = match queue.high.pop() { Some(job) => Some(job), None => queue.low.pop() };
+                        if let Some(job) = job { queue.len -= 1; break job }
+                        queue = available.wait(queue).unwrap();
+                    }
+                };
+                // A failing job must not take the worker down with it
+                if catch_unwind(AssertUnwindSafe(job)).is_err() { warn!("A worker job panicked"); }
+            });
+        }
+        pool
+    }
+
+    /// Queue a job on behalf of `source`, returns false if it was dropped because the queue or the share of the source
+    /// is full
+    pub fn submit<F: FnOnce() + Send + 'static>(&self, priority: JobPriority, source: IpAddr, job: F) -> bool {
+        let (lock, available) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        if queue.len >= self.capacity { return false }
+        {
+            let jobs = match priority { JobPriority::High => &mut queue.high, JobPriority::Low => &mut queue.low };
+            if jobs.queued(&source) >= MAX_QUEUED_PER_SOURCE { return false }
+            jobs.push(source, Box::new(job));
+        }
+        queue.len += 1;
+        available.notify_one();
+        true
+    }
+}